@@ -0,0 +1,21 @@
+/// Enhanced authentication of MQTT v5, such as a challenge response mechanism.
+///
+/// It is used for the exchange of AUTH packets of every connection and
+/// re-authentication, an implementation keeping state between the steps of an exchange
+/// restarts it in [`Authenticator::initial_data`].
+pub trait Authenticator: Send + Sync {
+    /// Authentication method of the CONNECT and AUTH packets.
+    fn method(&self) -> &str;
+
+    /// Authentication data starting an exchange.
+    fn initial_data(&self) -> Option<Vec<u8>>;
+
+    /// Replies to the authentication data the server continues the exchange with, an
+    /// error ends the connection.
+    fn continue_auth(&self, data: Option<&[u8]>) -> Result<Option<Vec<u8>>, String>;
+
+    /// Checks the authentication data the server ends a successful exchange with.
+    fn complete(&self, _data: Option<&[u8]>) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Stream;
+use mqtt_codec_kit::{
+    common::{QualityOfService, TopicFilter},
+    v5::{control::AuthenticateReasonCode, packet::subscribe::SubscribeOptions},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::{
+    connection::{Connack, Connection, Incoming, Outgoing},
+    error::Error,
+    message::Message,
+    options::ClientOptions,
+    session::{Reply, Session},
+};
+
+const REQUEST_CHANNEL_SIZE: usize = 128;
+const EVENT_CHANNEL_SIZE: usize = 1024;
+
+enum Request {
+    Publish(Message, Reply<()>),
+    Subscribe(TopicFilter, SubscribeOptions, Reply<QualityOfService>),
+    Unsubscribe(TopicFilter, Reply<()>),
+    Reauthenticate(Reply<()>),
+    Disconnect(Reply<()>),
+}
+
+#[derive(Debug)]
+pub enum Event {
+    /// The client connected or reconnected, tells whether the server resumed the
+    /// session.
+    Connected {
+        session_present: bool,
+    },
+    Message(Message),
+    /// The connection is lost, the client reconnects unless disabled.
+    Disconnected(Error),
+}
+
+/// Events of a client, they must be received for the client to go on once
+/// `EVENT_CHANNEL_SIZE` of them are waiting. Dropping it discards the next ones.
+pub struct Events {
+    events: mpsc::Receiver<Event>,
+}
+
+impl Events {
+    /// Returns `None` once the client is closed.
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+}
+
+impl Stream for Events {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// Handle of an MQTT client whose connection is served by a task, which reconnects
+/// when it is lost. The client is closed by [`Client::disconnect`] or once all the
+/// handles are dropped.
+#[derive(Clone)]
+pub struct Client {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Client {
+    /// Connects to the server, the first connection must succeed.
+    pub async fn connect(options: ClientOptions) -> Result<(Self, Events), Error> {
+        let (connection, connack) = Connection::connect(&options).await?;
+        let (requests_tx, requests_rx) = mpsc::channel(REQUEST_CHANNEL_SIZE);
+        let (events_tx, events_rx) = mpsc::channel(EVENT_CHANNEL_SIZE);
+        let event_loop = EventLoop {
+            session: Session::new(options.max_inflight),
+            options,
+            requests: requests_rx,
+            events: events_tx,
+            reauth: None,
+            closing: None,
+        };
+        tokio::spawn(event_loop.run(connection, connack));
+        Ok((
+            Self {
+                requests: requests_tx,
+            },
+            Events { events: events_rx },
+        ))
+    }
+
+    /// Publishes a message, returns once it is sent at QoS 0, once the server
+    /// acknowledged it at QoS 1 and 2. While disconnected, it waits for the connection.
+    pub async fn publish(&self, message: Message) -> Result<(), Error> {
+        self.request(|reply| Request::Publish(message, reply)).await
+    }
+
+    /// Subscribes to a topic filter, returns the QoS granted by the server.
+    pub async fn subscribe(
+        &self,
+        filter: TopicFilter,
+        qos: QualityOfService,
+    ) -> Result<QualityOfService, Error> {
+        let options = SubscribeOptions {
+            qos,
+            ..Default::default()
+        };
+        self.subscribe_with_options(filter, options).await
+    }
+
+    /// Subscribes with the options of MQTT v5, only the QoS is used with MQTT v3.1.1.
+    pub async fn subscribe_with_options(
+        &self,
+        filter: TopicFilter,
+        options: SubscribeOptions,
+    ) -> Result<QualityOfService, Error> {
+        self.request(|reply| Request::Subscribe(filter, options, reply))
+            .await
+    }
+
+    pub async fn unsubscribe(&self, filter: TopicFilter) -> Result<(), Error> {
+        self.request(|reply| Request::Unsubscribe(filter, reply))
+            .await
+    }
+
+    /// Authenticates again with the authenticator of the options, MQTT v5 only.
+    pub async fn reauthenticate(&self) -> Result<(), Error> {
+        self.request(Request::Reauthenticate).await
+    }
+
+    /// Disconnects normally and closes the client, the messages not acknowledged yet
+    /// fail.
+    pub async fn disconnect(&self) -> Result<(), Error> {
+        self.request(Request::Disconnect).await
+    }
+
+    async fn request<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> Result<T, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .send(request(tx))
+            .await
+            .map_err(|_| Error::Closed)?;
+        rx.await.map_err(|_| Error::Closed)?
+    }
+}
+
+struct EventLoop {
+    options: ClientOptions,
+    session: Session,
+    requests: mpsc::Receiver<Request>,
+    events: mpsc::Sender<Event>,
+    // Re-authentication waiting for the server
+    reauth: Option<Reply<()>>,
+    // Disconnection asked by the client
+    closing: Option<Reply<()>>,
+}
+
+impl EventLoop {
+    async fn run(mut self, connection: Connection, connack: Connack) {
+        let mut connected = Some((connection, connack));
+        loop {
+            let next = match connected.take() {
+                Some(connected) => Some(connected),
+                None => self.reconnect().await,
+            };
+            let Some((mut connection, connack)) = next else {
+                break;
+            };
+            self.send_event(Event::Connected {
+                session_present: connack.session_present,
+            })
+            .await;
+            let result = self.serve(&mut connection, connack).await;
+            self.session.disconnected();
+            self.reauth = None;
+            match result {
+                Ok(()) => break,
+                Err(err) => {
+                    log::warn!("client#{} connection lost: {err}", self.options.client_id);
+                    self.send_event(Event::Disconnected(err)).await;
+                    if self.options.reconnect_interval.is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+        self.session.close();
+        if let Some(reply) = self.closing.take() {
+            let _ = reply.send(Ok(()));
+        }
+    }
+
+    // Connects again after the reconnect interval until it succeeds, the requests
+    // received meanwhile wait in the session.
+    async fn reconnect(&mut self) -> Option<(Connection, Connack)> {
+        let interval = self.options.reconnect_interval?;
+        loop {
+            let sleep = time::sleep(interval);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    request = self.requests.recv() => {
+                        self.handle_request(request?, false);
+                        if self.closing.is_some() {
+                            return None;
+                        }
+                    }
+                }
+            }
+            match Connection::connect(&self.options).await {
+                Ok(connected) => return Some(connected),
+                Err(err) => log::warn!(
+                    "client#{} reconnect to {}: {err}",
+                    self.options.client_id,
+                    self.options.addr
+                ),
+            }
+        }
+    }
+
+    async fn serve(&mut self, connection: &mut Connection, connack: Connack) -> Result<(), Error> {
+        for packet in self
+            .session
+            .resume(connack.session_present, connack.receive_maximum)
+        {
+            connection.send(packet).await?;
+        }
+
+        let keep_alive = connack.server_keep_alive.unwrap_or(self.options.keep_alive);
+        let period = Duration::from_secs(u64::from(keep_alive.max(1)));
+        let mut ping = time::interval_at(time::Instant::now() + period, period);
+        // Whether a packet was received since the last ping
+        let mut received = true;
+        loop {
+            tokio::select! {
+                incoming = connection.next() => {
+                    received = true;
+                    self.handle_incoming(connection, incoming?).await?;
+                }
+                request = self.requests.recv() => {
+                    // All the handles are dropped.
+                    let Some(request) = request else {
+                        connection.send(Outgoing::Disconnect).await?;
+                        return Ok(());
+                    };
+                    if let Some(packet) = self.handle_request(request, true) {
+                        connection.send(packet).await?;
+                    }
+                    if self.closing.is_some() {
+                        return Ok(());
+                    }
+                }
+                _ = ping.tick(), if keep_alive > 0 => {
+                    if !received {
+                        return Err(Error::KeepAliveTimeout);
+                    }
+                    received = false;
+                    connection.send(Outgoing::Pingreq).await?;
+                }
+            }
+        }
+    }
+
+    fn handle_request(&mut self, request: Request, online: bool) -> Option<Outgoing> {
+        match request {
+            Request::Publish(message, reply) => self.session.publish(message, Some(reply)),
+            Request::Subscribe(filter, options, reply) => {
+                self.session.subscribe(filter, options, reply)
+            }
+            Request::Unsubscribe(filter, reply) => self.session.unsubscribe(filter, reply),
+            Request::Reauthenticate(reply) => {
+                let Some(authenticator) = &self.options.authenticator else {
+                    let _ = reply.send(Err(Error::Auth("no authenticator".to_owned())));
+                    return None;
+                };
+                if !online {
+                    let _ = reply.send(Err(Error::ConnectionClosed));
+                    return None;
+                }
+                if self.reauth.is_some() {
+                    let _ =
+                        reply.send(Err(Error::Auth("re-authentication in progress".to_owned())));
+                    return None;
+                }
+                let data = authenticator.initial_data();
+                self.reauth = Some(reply);
+                Some(Outgoing::Auth(AuthenticateReasonCode::ReAuthenticate, data))
+            }
+            Request::Disconnect(reply) => {
+                self.closing = Some(reply);
+                online.then_some(Outgoing::Disconnect)
+            }
+        }
+    }
+
+    async fn handle_incoming(
+        &mut self,
+        connection: &mut Connection,
+        incoming: Incoming,
+    ) -> Result<(), Error> {
+        let packets = match incoming {
+            Incoming::Publish(packet_id, message) => {
+                let (message, ack) = self.session.handle_publish(packet_id, message);
+                if let Some(message) = message {
+                    self.send_event(Event::Message(message)).await;
+                }
+                ack.into_iter().collect()
+            }
+            Incoming::Puback(packet_id, result) => self.session.handle_puback(packet_id, result),
+            Incoming::Pubrec(packet_id, result) => self.session.handle_pubrec(packet_id, result),
+            Incoming::Pubrel(packet_id) => vec![self.session.handle_pubrel(packet_id)],
+            Incoming::Pubcomp(packet_id) => self.session.handle_pubcomp(packet_id),
+            Incoming::Suback(packet_id, granted) => {
+                self.session.handle_suback(packet_id, granted);
+                Vec::new()
+            }
+            Incoming::Unsuback(packet_id, result) => {
+                self.session.handle_unsuback(packet_id, result);
+                Vec::new()
+            }
+            Incoming::Pingresp => Vec::new(),
+            Incoming::Auth(reason_code, data) => {
+                self.handle_auth(reason_code, data)?.into_iter().collect()
+            }
+            Incoming::Disconnect(reason) => return Err(Error::Disconnected(reason)),
+            Incoming::Connack(_) => return Err(Error::Protocol("unexpected CONNACK".to_owned())),
+        };
+        for packet in packets {
+            connection.send(packet).await?;
+        }
+        Ok(())
+    }
+
+    // Continues or ends a re-authentication.
+    fn handle_auth(
+        &mut self,
+        reason_code: AuthenticateReasonCode,
+        data: Option<Vec<u8>>,
+    ) -> Result<Option<Outgoing>, Error> {
+        let Some(authenticator) = &self.options.authenticator else {
+            return Err(Error::Protocol("unexpected AUTH".to_owned()));
+        };
+        match reason_code {
+            AuthenticateReasonCode::ContinueAuthentication => {
+                let data = authenticator
+                    .continue_auth(data.as_deref())
+                    .map_err(Error::Auth)?;
+                Ok(Some(Outgoing::Auth(
+                    AuthenticateReasonCode::ContinueAuthentication,
+                    data,
+                )))
+            }
+            AuthenticateReasonCode::Success => {
+                let result = authenticator.complete(data.as_deref());
+                if let Some(reply) = self.reauth.take() {
+                    let _ = reply.send(result.clone().map_err(Error::Auth));
+                }
+                result.map(|_| None).map_err(Error::Auth)
+            }
+            AuthenticateReasonCode::ReAuthenticate => {
+                Err(Error::Protocol("unexpected AUTH ReAuthenticate".to_owned()))
+            }
+        }
+    }
+
+    // Events are discarded once the application dropped them.
+    async fn send_event(&self, event: Event) {
+        let _ = self.events.send(event).await;
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+
+use futures::{SinkExt as _, StreamExt as _};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+    v4::{self, control::ConnectReturnCode, packet::suback::SubscribeReturnCode},
+    v5::{
+        self,
+        control::{
+            AuthProperties, AuthenticateReasonCode, ConnectReasonCode, DisconnectReasonCode,
+            PubackReasonCode, PubrecReasonCode,
+        },
+        packet::{
+            connect::ConnectProperties, suback::SubscribeReasonCode, subscribe::SubscribeOptions,
+            unsuback::UnsubscribeReasonCode,
+        },
+    },
+};
+use tokio::time;
+use tokio_util::codec::Framed;
+
+use crate::{
+    error::Error,
+    message::Message,
+    options::{ClientOptions, Protocol},
+    transport::{open, BoxStream},
+};
+
+/// Packet received from the server.
+#[derive(Debug)]
+pub(crate) enum Incoming {
+    Connack(Connack),
+    Auth(AuthenticateReasonCode, Option<Vec<u8>>),
+    /// The packet identifier is set at QoS 1 and 2
+    Publish(Option<u16>, Message),
+    Puback(u16, Result<(), String>),
+    Pubrec(u16, Result<(), String>),
+    Pubrel(u16),
+    Pubcomp(u16),
+    /// The QoS granted to each topic filter of the SUBSCRIBE
+    Suback(u16, Vec<Result<QualityOfService, String>>),
+    Unsuback(u16, Result<(), String>),
+    Pingresp,
+    Disconnect(String),
+}
+
+#[derive(Debug)]
+pub(crate) struct Connack {
+    pub(crate) session_present: bool,
+    pub(crate) receive_maximum: Option<u16>,
+    pub(crate) server_keep_alive: Option<u16>,
+    topic_alias_max: Option<u16>,
+    auth_data: Option<Vec<u8>>,
+}
+
+/// Packet sent to the server.
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+    /// The packet identifier is set at QoS 1 and 2
+    Publish(Option<u16>, Message),
+    Puback(u16),
+    Pubrec(u16),
+    Pubrel(u16),
+    Pubcomp(u16),
+    Subscribe(u16, Vec<(TopicFilter, SubscribeOptions)>),
+    Unsubscribe(u16, Vec<TopicFilter>),
+    Pingreq,
+    Disconnect,
+    Auth(AuthenticateReasonCode, Option<Vec<u8>>),
+}
+
+enum Transport {
+    V4(Framed<BoxStream, v4::packet::MqttCodec>),
+    V5(Framed<BoxStream, v5::packet::MqttCodec>),
+}
+
+/// Connection to the server, the topic aliases only last as long as it.
+pub(crate) struct Connection {
+    transport: Transport,
+    auth_method: Option<String>,
+    // Topics of the aliases the server sends, up to the maximum of the client
+    incoming_aliases: HashMap<u16, TopicName>,
+    incoming_alias_max: u16,
+    // Aliases of the topics the client sends, up to the maximum of the server
+    outgoing_aliases: HashMap<TopicName, u16>,
+    outgoing_alias_max: u16,
+}
+
+impl Connection {
+    /// Connects and waits for the CONNACK, going through the enhanced authentication
+    /// exchange if any.
+    pub(crate) async fn connect(options: &ClientOptions) -> Result<(Self, Connack), Error> {
+        time::timeout(options.connect_timeout, Self::handshake(options))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    async fn handshake(options: &ClientOptions) -> Result<(Self, Connack), Error> {
+        if options.protocol == Protocol::V4 && options.authenticator.is_some() {
+            return Err(Error::Auth(
+                "enhanced authentication needs MQTT v5".to_owned(),
+            ));
+        }
+        let stream = open(options).await?;
+        let transport = match options.protocol {
+            Protocol::V4 => {
+                let mut framed = Framed::new(stream, v4::packet::MqttCodec::new());
+                let mut packet = v4::packet::ConnectPacket::new(options.client_id.clone());
+                packet.set_clean_session(options.clean_session);
+                packet.set_keep_alive(options.keep_alive);
+                packet.set_username(options.username.clone());
+                packet.set_password(options.password.clone());
+                if let Some(will) = &options.will {
+                    let last_will = v4::packet::connect::LastWill::new(
+                        will.topic_name.to_string(),
+                        will.payload.to_vec(),
+                    )
+                    .map_err(|err| Error::Protocol(err.to_string()))?;
+                    packet.set_will(Some(last_will));
+                    packet.set_will_qos(will.qos as u8);
+                    packet.set_will_retain(will.retain);
+                }
+                framed.send(packet).await?;
+                Transport::V4(framed)
+            }
+            Protocol::V5 => {
+                let mut framed = Framed::new(stream, v5::packet::MqttCodec::new());
+                let mut packet = v5::packet::ConnectPacket::new(options.client_id.clone());
+                packet.set_clean_session(options.clean_session);
+                packet.set_keep_alive(options.keep_alive);
+                packet.set_username(options.username.clone());
+                packet.set_password(options.password.clone());
+                if let Some(will) = &options.will {
+                    let last_will = v5::packet::connect::LastWill::new(
+                        will.topic_name.to_string(),
+                        will.payload.to_vec(),
+                    )
+                    .map_err(|err| Error::Protocol(err.to_string()))?;
+                    packet.set_will(Some(last_will));
+                    packet.set_will_qos(will.qos as u8);
+                    packet.set_will_retain(will.retain);
+                }
+                let mut properties = ConnectProperties::default();
+                properties.set_session_expiry_interval(options.session_expiry_interval);
+                if options.topic_alias_maximum > 0 {
+                    properties.set_topic_alias_max(Some(options.topic_alias_maximum));
+                }
+                if let Some(authenticator) = &options.authenticator {
+                    properties.set_authentication_method(Some(authenticator.method().to_owned()));
+                    properties.set_authentication_data(authenticator.initial_data());
+                }
+                packet.set_properties(properties);
+                framed.send(packet).await?;
+                Transport::V5(framed)
+            }
+        };
+
+        let mut connection = Self {
+            transport,
+            auth_method: options
+                .authenticator
+                .as_ref()
+                .map(|authenticator| authenticator.method().to_owned()),
+            incoming_aliases: HashMap::new(),
+            incoming_alias_max: options.topic_alias_maximum,
+            outgoing_aliases: HashMap::new(),
+            outgoing_alias_max: 0,
+        };
+        loop {
+            match connection.next().await? {
+                Incoming::Connack(connack) => {
+                    if let Some(authenticator) = &options.authenticator {
+                        authenticator
+                            .complete(connack.auth_data.as_deref())
+                            .map_err(Error::Auth)?;
+                    }
+                    connection.outgoing_alias_max = connack.topic_alias_max.unwrap_or(0);
+                    return Ok((connection, connack));
+                }
+                Incoming::Auth(AuthenticateReasonCode::ContinueAuthentication, data) => {
+                    let Some(authenticator) = &options.authenticator else {
+                        return Err(Error::Protocol("unexpected AUTH".to_owned()));
+                    };
+                    let data = authenticator
+                        .continue_auth(data.as_deref())
+                        .map_err(Error::Auth)?;
+                    connection
+                        .send(Outgoing::Auth(
+                            AuthenticateReasonCode::ContinueAuthentication,
+                            data,
+                        ))
+                        .await?;
+                }
+                packet => return Err(Error::Protocol(format!("expected CONNACK, got {packet:?}"))),
+            }
+        }
+    }
+
+    pub(crate) async fn send(&mut self, packet: Outgoing) -> Result<(), Error> {
+        match &mut self.transport {
+            Transport::V4(framed) => match packet {
+                Outgoing::Publish(packet_id, message) => {
+                    let mut packet = v4::packet::PublishPacket::new(
+                        message.topic_name().to_owned(),
+                        qos_with_packet_id(message.qos(), packet_id),
+                        message.payload().clone(),
+                    );
+                    packet.set_retain(message.retain());
+                    packet.set_dup(message.dup());
+                    framed.send(packet).await?;
+                }
+                Outgoing::Puback(packet_id) => {
+                    framed
+                        .send(v4::packet::PubackPacket::new(packet_id))
+                        .await?
+                }
+                Outgoing::Pubrec(packet_id) => {
+                    framed
+                        .send(v4::packet::PubrecPacket::new(packet_id))
+                        .await?
+                }
+                Outgoing::Pubrel(packet_id) => {
+                    framed
+                        .send(v4::packet::PubrelPacket::new(packet_id))
+                        .await?
+                }
+                Outgoing::Pubcomp(packet_id) => {
+                    framed
+                        .send(v4::packet::PubcompPacket::new(packet_id))
+                        .await?
+                }
+                Outgoing::Subscribe(packet_id, subscribes) => {
+                    let subscribes = subscribes
+                        .into_iter()
+                        .map(|(filter, options)| (filter, options.qos))
+                        .collect();
+                    framed
+                        .send(v4::packet::SubscribePacket::new(packet_id, subscribes))
+                        .await?;
+                }
+                Outgoing::Unsubscribe(packet_id, filters) => {
+                    framed
+                        .send(v4::packet::UnsubscribePacket::new(packet_id, filters))
+                        .await?;
+                }
+                Outgoing::Pingreq => framed.send(v4::packet::PingreqPacket::new()).await?,
+                Outgoing::Disconnect => framed.send(v4::packet::DisconnectPacket::new()).await?,
+                Outgoing::Auth(..) => {
+                    return Err(Error::Auth(
+                        "enhanced authentication needs MQTT v5".to_owned(),
+                    ))
+                }
+            },
+            Transport::V5(framed) => match packet {
+                Outgoing::Publish(packet_id, message) => {
+                    let mut properties = message.properties().cloned().unwrap_or_default();
+                    let mut topic_name = message.topic_name().to_owned();
+                    if self.outgoing_alias_max > 0 {
+                        if let Some(alias) = self.outgoing_aliases.get(&topic_name) {
+                            properties.set_topic_alias(Some(*alias));
+                            // SAFETY: the topic alias stands for the topic name
+                            topic_name = unsafe { TopicName::new_unchecked(String::new()) };
+                        } else if self.outgoing_aliases.len() < self.outgoing_alias_max as usize {
+                            let alias = self.outgoing_aliases.len() as u16 + 1;
+                            self.outgoing_aliases.insert(topic_name.clone(), alias);
+                            properties.set_topic_alias(Some(alias));
+                        }
+                    }
+                    let mut packet = v5::packet::PublishPacket::new(
+                        topic_name,
+                        qos_with_packet_id(message.qos(), packet_id),
+                        message.payload().clone(),
+                    );
+                    packet.set_retain(message.retain());
+                    packet.set_dup(message.dup());
+                    packet.set_properties(properties);
+                    framed.send(packet).await?;
+                }
+                Outgoing::Puback(packet_id) => {
+                    framed
+                        .send(v5::packet::PubackPacket::new_success(packet_id))
+                        .await?
+                }
+                Outgoing::Pubrec(packet_id) => {
+                    framed
+                        .send(v5::packet::PubrecPacket::new_success(packet_id))
+                        .await?
+                }
+                Outgoing::Pubrel(packet_id) => {
+                    framed
+                        .send(v5::packet::PubrelPacket::new_success(packet_id))
+                        .await?
+                }
+                Outgoing::Pubcomp(packet_id) => {
+                    framed
+                        .send(v5::packet::PubcompPacket::new_success(packet_id))
+                        .await?
+                }
+                Outgoing::Subscribe(packet_id, subscribes) => {
+                    framed
+                        .send(v5::packet::SubscribePacket::new(packet_id, subscribes))
+                        .await?;
+                }
+                Outgoing::Unsubscribe(packet_id, filters) => {
+                    framed
+                        .send(v5::packet::UnsubscribePacket::new(packet_id, filters))
+                        .await?;
+                }
+                Outgoing::Pingreq => framed.send(v5::packet::PingreqPacket::new()).await?,
+                Outgoing::Disconnect => {
+                    framed
+                        .send(v5::packet::DisconnectPacket::new(
+                            DisconnectReasonCode::NormalDisconnection,
+                        ))
+                        .await?
+                }
+                Outgoing::Auth(reason_code, data) => {
+                    let mut properties = AuthProperties::default();
+                    properties.set_authentication_method(self.auth_method.clone());
+                    properties.set_authentication_data(data);
+                    let mut packet = v5::packet::AuthPacket::new(reason_code);
+                    packet.set_properties(Some(properties));
+                    framed.send(packet).await?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn next(&mut self) -> Result<Incoming, Error> {
+        let incoming = match &mut self.transport {
+            Transport::V4(framed) => match next_packet(framed).await? {
+                v4::packet::VariablePacket::ConnackPacket(packet) => {
+                    match packet.connect_return_code() {
+                        ConnectReturnCode::ConnectionAccepted => Incoming::Connack(Connack {
+                            session_present: packet.connack_flags().session_present,
+                            receive_maximum: None,
+                            server_keep_alive: None,
+                            topic_alias_max: None,
+                            auth_data: None,
+                        }),
+                        code => return Err(Error::Refused(format!("{code:?}"))),
+                    }
+                }
+                v4::packet::VariablePacket::PublishPacket(packet) => {
+                    let (qos, packet_id) = packet.qos().split();
+                    let mut message = Message::new(
+                        packet.topic_name().to_owned(),
+                        packet.payload().clone(),
+                        qos,
+                    );
+                    message.set_retain(packet.retain());
+                    message.set_dup(packet.dup());
+                    Incoming::Publish(packet_id, message)
+                }
+                v4::packet::VariablePacket::PubackPacket(packet) => {
+                    Incoming::Puback(packet.packet_identifier(), Ok(()))
+                }
+                v4::packet::VariablePacket::PubrecPacket(packet) => {
+                    Incoming::Pubrec(packet.packet_identifier(), Ok(()))
+                }
+                v4::packet::VariablePacket::PubrelPacket(packet) => {
+                    Incoming::Pubrel(packet.packet_identifier())
+                }
+                v4::packet::VariablePacket::PubcompPacket(packet) => {
+                    Incoming::Pubcomp(packet.packet_identifier())
+                }
+                v4::packet::VariablePacket::SubackPacket(packet) => {
+                    let granted = packet
+                        .subscribes()
+                        .iter()
+                        .map(|code| match code {
+                            SubscribeReturnCode::MaximumQoSLevel0 => Ok(QualityOfService::Level0),
+                            SubscribeReturnCode::MaximumQoSLevel1 => Ok(QualityOfService::Level1),
+                            SubscribeReturnCode::MaximumQoSLevel2 => Ok(QualityOfService::Level2),
+                            SubscribeReturnCode::Failure => Err(format!("{code:?}")),
+                        })
+                        .collect();
+                    Incoming::Suback(packet.packet_identifier(), granted)
+                }
+                v4::packet::VariablePacket::UnsubackPacket(packet) => {
+                    Incoming::Unsuback(packet.packet_identifier(), Ok(()))
+                }
+                v4::packet::VariablePacket::PingrespPacket(_) => Incoming::Pingresp,
+                packet => return Err(Error::Protocol(format!("unexpected packet {packet:?}"))),
+            },
+            Transport::V5(framed) => match next_packet(framed).await? {
+                v5::packet::VariablePacket::ConnackPacket(packet) => {
+                    let properties = packet.properties();
+                    match packet.connect_reason_code() {
+                        ConnectReasonCode::Success => Incoming::Connack(Connack {
+                            session_present: packet.connack_flags().session_present,
+                            receive_maximum: properties.receive_maximum(),
+                            server_keep_alive: properties.server_keep_alive(),
+                            topic_alias_max: properties.topic_alias_max(),
+                            auth_data: properties
+                                .authentication_data()
+                                .as_ref()
+                                .map(|data| data.0.clone()),
+                        }),
+                        code => {
+                            return Err(Error::Refused(with_reason(
+                                code,
+                                properties.reason_string(),
+                            )))
+                        }
+                    }
+                }
+                v5::packet::VariablePacket::AuthPacket(packet) => {
+                    let data = packet
+                        .properties()
+                        .as_ref()
+                        .and_then(|properties| properties.authentication_data().as_ref())
+                        .map(|data| data.0.clone());
+                    Incoming::Auth(packet.reason_code(), data)
+                }
+                v5::packet::VariablePacket::PublishPacket(packet) => {
+                    let (qos, packet_id) = packet.qos().split();
+                    let mut properties = packet.properties().clone();
+                    let topic_name = match properties.topic_alias() {
+                        Some(alias) => {
+                            properties.set_topic_alias(None);
+                            self.resolve_alias(alias, packet.topic_name())?
+                        }
+                        None => packet.topic_name().to_owned(),
+                    };
+                    let mut message = Message::new(topic_name, packet.payload().clone(), qos);
+                    message.set_retain(packet.retain());
+                    message.set_dup(packet.dup());
+                    if !properties.is_empty() {
+                        message.set_properties(Some(properties));
+                    }
+                    Incoming::Publish(packet_id, message)
+                }
+                v5::packet::VariablePacket::PubackPacket(packet) => {
+                    let result = match packet.reason_code() {
+                        PubackReasonCode::Success | PubackReasonCode::NoMatchingSubscribers => {
+                            Ok(())
+                        }
+                        code => Err(with_reason(code, packet.properties().reason_string())),
+                    };
+                    Incoming::Puback(packet.packet_identifier(), result)
+                }
+                v5::packet::VariablePacket::PubrecPacket(packet) => {
+                    let result = match packet.reason_code() {
+                        PubrecReasonCode::Success | PubrecReasonCode::NoMatchingSubscribers => {
+                            Ok(())
+                        }
+                        code => Err(with_reason(code, packet.properties().reason_string())),
+                    };
+                    Incoming::Pubrec(packet.packet_identifier(), result)
+                }
+                v5::packet::VariablePacket::PubrelPacket(packet) => {
+                    Incoming::Pubrel(packet.packet_identifier())
+                }
+                v5::packet::VariablePacket::PubcompPacket(packet) => {
+                    Incoming::Pubcomp(packet.packet_identifier())
+                }
+                v5::packet::VariablePacket::SubackPacket(packet) => {
+                    let granted = packet
+                        .reason_code()
+                        .iter()
+                        .map(|code| match code {
+                            SubscribeReasonCode::GrantedQos0 => Ok(QualityOfService::Level0),
+                            SubscribeReasonCode::GrantedQos1 => Ok(QualityOfService::Level1),
+                            SubscribeReasonCode::GrantedQos2 => Ok(QualityOfService::Level2),
+                            code => Err(with_reason(code, packet.properties().reason_string())),
+                        })
+                        .collect();
+                    Incoming::Suback(packet.packet_identifier(), granted)
+                }
+                v5::packet::VariablePacket::UnsubackPacket(packet) => {
+                    let result = packet
+                        .reason_code()
+                        .iter()
+                        .find(|code| {
+                            !matches!(
+                                code,
+                                UnsubscribeReasonCode::Success
+                                    | UnsubscribeReasonCode::NoSubscriptionExisted
+                            )
+                        })
+                        .map_or(Ok(()), |code| {
+                            Err(with_reason(code, packet.properties().reason_string()))
+                        });
+                    Incoming::Unsuback(packet.packet_identifier(), result)
+                }
+                v5::packet::VariablePacket::PingrespPacket(_) => Incoming::Pingresp,
+                v5::packet::VariablePacket::DisconnectPacket(packet) => Incoming::Disconnect(
+                    with_reason(packet.reason_code(), packet.properties().reason_string()),
+                ),
+                packet => return Err(Error::Protocol(format!("unexpected packet {packet:?}"))),
+            },
+        };
+        Ok(incoming)
+    }
+
+    // A topic name with an alias sets the alias, an empty one refers to it.
+    fn resolve_alias(&mut self, alias: u16, topic_name: &TopicName) -> Result<TopicName, Error> {
+        if alias == 0 || alias > self.incoming_alias_max {
+            return Err(Error::Protocol(format!("invalid topic alias {alias}")));
+        }
+        if topic_name.is_empty() {
+            return self
+                .incoming_aliases
+                .get(&alias)
+                .cloned()
+                .ok_or_else(|| Error::Protocol(format!("unknown topic alias {alias}")));
+        }
+        self.incoming_aliases.insert(alias, topic_name.to_owned());
+        Ok(topic_name.to_owned())
+    }
+}
+
+fn qos_with_packet_id(qos: QualityOfService, packet_id: Option<u16>) -> QoSWithPacketIdentifier {
+    match (qos, packet_id) {
+        (QualityOfService::Level1, Some(packet_id)) => QoSWithPacketIdentifier::Level1(packet_id),
+        (QualityOfService::Level2, Some(packet_id)) => QoSWithPacketIdentifier::Level2(packet_id),
+        _ => QoSWithPacketIdentifier::Level0,
+    }
+}
+
+fn with_reason(code: impl std::fmt::Debug, reason: &Option<String>) -> String {
+    match reason {
+        Some(reason) => format!("{code:?}: {reason}"),
+        None => format!("{code:?}"),
+    }
+}
+
+async fn next_packet<C>(framed: &mut Framed<BoxStream, C>) -> Result<C::Item, Error>
+where
+    C: tokio_util::codec::Decoder,
+    C::Error: std::fmt::Display,
+{
+    match framed.next().await {
+        Some(Ok(packet)) => Ok(packet),
+        Some(Err(err)) => Err(Error::Decode(err.to_string())),
+        None => Err(Error::ConnectionClosed),
+    }
+}
@@ -0,0 +1,40 @@
+use std::io;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Io Error : {0}")]
+    Io(#[from] io::Error),
+    #[error("tls: {0}")]
+    Tls(String),
+    #[cfg(feature = "ws")]
+    #[error("websocket: {0}")]
+    WebSocket(Box<tungstenite::Error>),
+    #[error("decode packet: {0}")]
+    Decode(String),
+    #[error("connection refused: {0}")]
+    Refused(String),
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    /// The server answered a publish, subscribe or unsubscribe with a failure
+    #[error("rejected by the server: {0}")]
+    Rejected(String),
+    #[error("disconnected by the server: {0}")]
+    Disconnected(String),
+    #[error("connection closed by the server")]
+    ConnectionClosed,
+    #[error("no packet received within the keep alive")]
+    KeepAliveTimeout,
+    #[error("connect timeout")]
+    Timeout,
+    #[error("client closed")]
+    Closed,
+}
+
+#[cfg(feature = "ws")]
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(err))
+    }
+}
@@ -0,0 +1,20 @@
+mod auth;
+mod client;
+mod connection;
+mod error;
+mod message;
+mod options;
+mod session;
+mod transport;
+#[cfg(feature = "ws")]
+mod ws_stream;
+
+pub use mqtt_codec_kit;
+
+pub use self::{
+    auth::Authenticator,
+    client::{Client, Event, Events},
+    error::Error,
+    message::Message,
+    options::{ClientCert, ClientOptions, Protocol, TlsOptions, Will, DEFAULT_CONNECT_TIMEOUT},
+};
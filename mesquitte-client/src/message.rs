@@ -0,0 +1,68 @@
+use bytes::Bytes;
+use mqtt_codec_kit::{
+    common::{QualityOfService, TopicName},
+    v5::control::PublishProperties,
+};
+
+/// Message published by the client or received on its subscriptions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    topic_name: TopicName,
+    payload: Bytes,
+    qos: QualityOfService,
+    retain: bool,
+    dup: bool,
+    properties: Option<PublishProperties>,
+}
+
+impl Message {
+    pub fn new(topic_name: TopicName, payload: impl Into<Bytes>, qos: QualityOfService) -> Self {
+        Self {
+            topic_name,
+            payload: payload.into(),
+            qos,
+            retain: false,
+            dup: false,
+            properties: None,
+        }
+    }
+
+    pub fn topic_name(&self) -> &TopicName {
+        &self.topic_name
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn qos(&self) -> QualityOfService {
+        self.qos
+    }
+
+    pub fn retain(&self) -> bool {
+        self.retain
+    }
+
+    pub fn set_retain(&mut self, retain: bool) {
+        self.retain = retain
+    }
+
+    /// Whether the server may have delivered the message before, always false for the
+    /// messages published by the client.
+    pub fn dup(&self) -> bool {
+        self.dup
+    }
+
+    pub(crate) fn set_dup(&mut self, dup: bool) {
+        self.dup = dup
+    }
+
+    /// Properties of MQTT v5, without the topic alias which is handled by the client.
+    pub fn properties(&self) -> Option<&PublishProperties> {
+        self.properties.as_ref()
+    }
+
+    pub fn set_properties(&mut self, properties: Option<PublishProperties>) {
+        self.properties = properties
+    }
+}
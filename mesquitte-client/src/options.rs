@@ -0,0 +1,120 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use mqtt_codec_kit::common::{QualityOfService, TopicName};
+
+use crate::auth::Authenticator;
+
+/// Default time allowed to open the connection and receive the CONNACK.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    V4,
+    #[default]
+    V5,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    /// PEM encoded certificates the server certificate is checked against.
+    pub ca_file: PathBuf,
+    pub client_cert: Option<ClientCert>,
+    /// Name checked against the server certificate, defaults to the host of `addr`.
+    pub server_name: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn new(ca_file: impl Into<PathBuf>) -> Self {
+        Self {
+            ca_file: ca_file.into(),
+            client_cert: None,
+            server_name: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientCert {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+/// Message the server publishes when the connection is lost.
+#[derive(Clone, Debug)]
+pub struct Will {
+    pub topic_name: TopicName,
+    pub payload: Bytes,
+    pub qos: QualityOfService,
+    pub retain: bool,
+}
+
+impl Will {
+    pub fn new(topic_name: TopicName, payload: impl Into<Bytes>, qos: QualityOfService) -> Self {
+        Self {
+            topic_name,
+            payload: payload.into(),
+            qos,
+            retain: false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientOptions {
+    /// Address of the server as `host:port`.
+    pub addr: String,
+    pub protocol: Protocol,
+    pub tls: Option<TlsOptions>,
+    /// Connect over WebSocket to this path, e.g. `/mqtt`.
+    pub websocket_path: Option<String>,
+    pub client_id: String,
+    /// Start a new session on every connection. Otherwise the session of the server
+    /// is resumed after a reconnection, and the messages it did not acknowledge are
+    /// sent again.
+    pub clean_session: bool,
+    /// Keep alive in seconds, 0 disables the pings.
+    pub keep_alive: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub will: Option<Will>,
+    /// MQTT v5, how long in seconds the server keeps the session once the connection is
+    /// closed. A session which is not clean needs it to be resumed with MQTT v5.
+    pub session_expiry_interval: Option<u32>,
+    /// MQTT v5, the number of topic aliases the server may use in the messages it
+    /// sends, 0 disables them. The client uses the aliases the server allows for the
+    /// messages it publishes.
+    pub topic_alias_maximum: u16,
+    /// MQTT v5 enhanced authentication.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    pub connect_timeout: Duration,
+    /// Interval between reconnection attempts once the connection is lost, `None`
+    /// closes the client instead.
+    pub reconnect_interval: Option<Duration>,
+    /// Maximum of QoS 1 and 2 messages sent and not acknowledged yet, lowered to the
+    /// receive maximum of the server. The others wait in the client.
+    pub max_inflight: u16,
+}
+
+impl ClientOptions {
+    pub fn new(addr: impl Into<String>, client_id: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            protocol: Protocol::default(),
+            tls: None,
+            websocket_path: None,
+            client_id: client_id.into(),
+            clean_session: true,
+            keep_alive: 60,
+            username: None,
+            password: None,
+            will: None,
+            session_expiry_interval: None,
+            topic_alias_maximum: 0,
+            authenticator: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            reconnect_interval: Some(Duration::from_secs(5)),
+            max_inflight: 100,
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use mqtt_codec_kit::{
+    common::{QualityOfService, TopicFilter},
+    v5::packet::subscribe::SubscribeOptions,
+};
+use tokio::sync::oneshot;
+
+use crate::{connection::Outgoing, error::Error, message::Message};
+
+pub(crate) type Reply<T> = oneshot::Sender<Result<T, Error>>;
+
+// QoS 1 or 2 message sent and not acknowledged yet
+struct Inflight {
+    packet_id: u16,
+    message: Message,
+    // QoS 2 message received by the server, waiting for PUBCOMP
+    released: bool,
+    reply: Option<Reply<()>>,
+}
+
+// SUBSCRIBE or UNSUBSCRIBE sent and not acknowledged yet
+enum Request {
+    Subscribe(
+        Vec<(TopicFilter, SubscribeOptions)>,
+        Option<Reply<QualityOfService>>,
+    ),
+    Unsubscribe(Vec<TopicFilter>, Option<Reply<()>>),
+}
+
+/// State of the client kept across its connections: the QoS 1 and 2 flows in both
+/// directions, and the subscriptions made again when the server lost the session.
+///
+/// It tells which packets to send, the client sends them.
+pub(crate) struct Session {
+    online: bool,
+    last_packet_id: u16,
+    max_inflight: usize,
+    receive_maximum: usize,
+    inflight: VecDeque<Inflight>,
+    // Messages waiting for the connection, or for room in the inflight window
+    pending: VecDeque<(Message, Option<Reply<()>>)>,
+    // QoS 2 messages received and not released yet
+    incoming: HashSet<u16>,
+    requests: BTreeMap<u16, Request>,
+    subscriptions: BTreeMap<TopicFilter, SubscribeOptions>,
+}
+
+impl Session {
+    pub(crate) fn new(max_inflight: u16) -> Self {
+        Self {
+            online: false,
+            last_packet_id: 0,
+            max_inflight: max_inflight.max(1) as usize,
+            receive_maximum: usize::MAX,
+            inflight: VecDeque::new(),
+            pending: VecDeque::new(),
+            incoming: HashSet::new(),
+            requests: BTreeMap::new(),
+            subscriptions: BTreeMap::new(),
+        }
+    }
+
+    /// A new connection is up, returns the packets to send first: the requests and
+    /// messages not acknowledged on the previous connections, then the pending messages.
+    pub(crate) fn resume(
+        &mut self,
+        session_present: bool,
+        receive_maximum: Option<u16>,
+    ) -> Vec<Outgoing> {
+        self.online = true;
+        self.receive_maximum = receive_maximum.map_or(usize::MAX, usize::from);
+
+        let mut packets: Vec<Outgoing> = self
+            .requests
+            .iter()
+            .map(|(packet_id, request)| match request {
+                Request::Subscribe(subscribes, _) => {
+                    Outgoing::Subscribe(*packet_id, subscribes.clone())
+                }
+                Request::Unsubscribe(filters, _) => {
+                    Outgoing::Unsubscribe(*packet_id, filters.clone())
+                }
+            })
+            .collect();
+        if !session_present {
+            self.incoming.clear();
+            if !self.subscriptions.is_empty() {
+                let subscribes: Vec<_> = self
+                    .subscriptions
+                    .iter()
+                    .map(|(filter, options)| (filter.clone(), options.clone()))
+                    .collect();
+                let packet_id = self.next_packet_id();
+                self.requests
+                    .insert(packet_id, Request::Subscribe(subscribes.clone(), None));
+                packets.push(Outgoing::Subscribe(packet_id, subscribes));
+            }
+        }
+
+        self.inflight.retain_mut(|inflight| {
+            if !inflight.released {
+                inflight.message.set_dup(true);
+                packets.push(Outgoing::Publish(
+                    Some(inflight.packet_id),
+                    inflight.message.clone(),
+                ));
+                return true;
+            }
+            if session_present {
+                packets.push(Outgoing::Pubrel(inflight.packet_id));
+                return true;
+            }
+            // Received by the server, which lost it with the session.
+            if let Some(reply) = inflight.reply.take() {
+                let _ = reply.send(Ok(()));
+            }
+            false
+        });
+        packets.extend(self.drain_pending());
+        packets
+    }
+
+    pub(crate) fn disconnected(&mut self) {
+        self.online = false;
+    }
+
+    /// The client is closed, fails the requests and messages waiting for an answer.
+    pub(crate) fn close(&mut self) {
+        self.online = false;
+        for inflight in self.inflight.drain(..) {
+            if let Some(reply) = inflight.reply {
+                let _ = reply.send(Err(Error::Closed));
+            }
+        }
+        for (_, reply) in self.pending.drain(..) {
+            if let Some(reply) = reply {
+                let _ = reply.send(Err(Error::Closed));
+            }
+        }
+        for (_, request) in std::mem::take(&mut self.requests) {
+            match request {
+                Request::Subscribe(_, Some(reply)) => {
+                    let _ = reply.send(Err(Error::Closed));
+                }
+                Request::Unsubscribe(_, Some(reply)) => {
+                    let _ = reply.send(Err(Error::Closed));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the PUBLISH to send, unless the message waits for the connection or for
+    /// room in the inflight window. QoS 0 messages are done once sent.
+    pub(crate) fn publish(
+        &mut self,
+        message: Message,
+        reply: Option<Reply<()>>,
+    ) -> Option<Outgoing> {
+        if !self.online
+            || (message.qos() != QualityOfService::Level0 && self.inflight.len() >= self.window())
+        {
+            self.pending.push_back((message, reply));
+            return None;
+        }
+        Some(self.send_publish(message, reply))
+    }
+
+    pub(crate) fn handle_puback(
+        &mut self,
+        packet_id: u16,
+        result: Result<(), String>,
+    ) -> Vec<Outgoing> {
+        let Some(index) = self.inflight.iter().position(|inflight| {
+            inflight.packet_id == packet_id && inflight.message.qos() == QualityOfService::Level1
+        }) else {
+            log::debug!("PUBACK of unknown packet {packet_id}");
+            return Vec::new();
+        };
+        self.complete(index, result);
+        self.drain_pending()
+    }
+
+    pub(crate) fn handle_pubrec(
+        &mut self,
+        packet_id: u16,
+        result: Result<(), String>,
+    ) -> Vec<Outgoing> {
+        let Some(index) = self.inflight.iter().position(|inflight| {
+            inflight.packet_id == packet_id && inflight.message.qos() == QualityOfService::Level2
+        }) else {
+            log::debug!("PUBREC of unknown packet {packet_id}");
+            return Vec::new();
+        };
+        if result.is_err() {
+            self.complete(index, result);
+            return self.drain_pending();
+        }
+        self.inflight[index].released = true;
+        vec![Outgoing::Pubrel(packet_id)]
+    }
+
+    pub(crate) fn handle_pubcomp(&mut self, packet_id: u16) -> Vec<Outgoing> {
+        let Some(index) = self
+            .inflight
+            .iter()
+            .position(|inflight| inflight.packet_id == packet_id && inflight.released)
+        else {
+            log::debug!("PUBCOMP of unknown packet {packet_id}");
+            return Vec::new();
+        };
+        self.complete(index, Ok(()));
+        self.drain_pending()
+    }
+
+    /// Returns the message to deliver to the application, unless it is a QoS 2 message
+    /// already delivered, and the acknowledgement to send.
+    pub(crate) fn handle_publish(
+        &mut self,
+        packet_id: Option<u16>,
+        message: Message,
+    ) -> (Option<Message>, Option<Outgoing>) {
+        match (message.qos(), packet_id) {
+            (QualityOfService::Level1, Some(packet_id)) => {
+                (Some(message), Some(Outgoing::Puback(packet_id)))
+            }
+            (QualityOfService::Level2, Some(packet_id)) => {
+                let first = self.incoming.insert(packet_id);
+                (first.then_some(message), Some(Outgoing::Pubrec(packet_id)))
+            }
+            _ => (Some(message), None),
+        }
+    }
+
+    pub(crate) fn handle_pubrel(&mut self, packet_id: u16) -> Outgoing {
+        self.incoming.remove(&packet_id);
+        Outgoing::Pubcomp(packet_id)
+    }
+
+    pub(crate) fn subscribe(
+        &mut self,
+        filter: TopicFilter,
+        options: SubscribeOptions,
+        reply: Reply<QualityOfService>,
+    ) -> Option<Outgoing> {
+        let packet_id = self.next_packet_id();
+        let subscribes = vec![(filter, options)];
+        self.requests.insert(
+            packet_id,
+            Request::Subscribe(subscribes.clone(), Some(reply)),
+        );
+        self.online
+            .then(|| Outgoing::Subscribe(packet_id, subscribes))
+    }
+
+    pub(crate) fn handle_suback(
+        &mut self,
+        packet_id: u16,
+        granted: Vec<Result<QualityOfService, String>>,
+    ) {
+        let Some(Request::Subscribe(subscribes, reply)) = self.requests.remove(&packet_id) else {
+            log::debug!("SUBACK of unknown packet {packet_id}");
+            return;
+        };
+        for ((filter, options), result) in subscribes.into_iter().zip(&granted) {
+            match result {
+                Ok(_) => {
+                    self.subscriptions.insert(filter, options);
+                }
+                Err(err) => {
+                    log::warn!("subscribe {filter:?}: {err}");
+                    self.subscriptions.remove(&filter);
+                }
+            }
+        }
+        if let Some(reply) = reply {
+            let result = match granted.into_iter().next() {
+                Some(result) => result.map_err(Error::Rejected),
+                None => Err(Error::Protocol("empty SUBACK".to_owned())),
+            };
+            let _ = reply.send(result);
+        }
+    }
+
+    pub(crate) fn unsubscribe(
+        &mut self,
+        filter: TopicFilter,
+        reply: Reply<()>,
+    ) -> Option<Outgoing> {
+        self.subscriptions.remove(&filter);
+        let packet_id = self.next_packet_id();
+        let filters = vec![filter];
+        self.requests.insert(
+            packet_id,
+            Request::Unsubscribe(filters.clone(), Some(reply)),
+        );
+        self.online
+            .then(|| Outgoing::Unsubscribe(packet_id, filters))
+    }
+
+    pub(crate) fn handle_unsuback(&mut self, packet_id: u16, result: Result<(), String>) {
+        let Some(Request::Unsubscribe(_, reply)) = self.requests.remove(&packet_id) else {
+            log::debug!("UNSUBACK of unknown packet {packet_id}");
+            return;
+        };
+        if let Some(reply) = reply {
+            let _ = reply.send(result.map_err(Error::Rejected));
+        }
+    }
+
+    fn window(&self) -> usize {
+        self.max_inflight.min(self.receive_maximum)
+    }
+
+    fn send_publish(&mut self, message: Message, reply: Option<Reply<()>>) -> Outgoing {
+        if message.qos() == QualityOfService::Level0 {
+            if let Some(reply) = reply {
+                let _ = reply.send(Ok(()));
+            }
+            return Outgoing::Publish(None, message);
+        }
+        let packet_id = self.next_packet_id();
+        self.inflight.push_back(Inflight {
+            packet_id,
+            message: message.clone(),
+            released: false,
+            reply,
+        });
+        Outgoing::Publish(Some(packet_id), message)
+    }
+
+    // Sends the pending messages the inflight window has room for.
+    fn drain_pending(&mut self) -> Vec<Outgoing> {
+        let mut packets = Vec::new();
+        while self.online
+            && self.pending.front().is_some_and(|(message, _)| {
+                message.qos() == QualityOfService::Level0 || self.inflight.len() < self.window()
+            })
+        {
+            if let Some((message, reply)) = self.pending.pop_front() {
+                packets.push(self.send_publish(message, reply));
+            }
+        }
+        packets
+    }
+
+    fn complete(&mut self, index: usize, result: Result<(), String>) {
+        if let Some(inflight) = self.inflight.remove(index) {
+            if let Some(reply) = inflight.reply {
+                let _ = reply.send(result.map_err(Error::Rejected));
+            }
+        }
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        loop {
+            self.last_packet_id = self.last_packet_id.wrapping_add(1).max(1);
+            let packet_id = self.last_packet_id;
+            if !self.requests.contains_key(&packet_id)
+                && !self
+                    .inflight
+                    .iter()
+                    .any(|inflight| inflight.packet_id == packet_id)
+            {
+                return packet_id;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mqtt_codec_kit::common::TopicName;
+
+    fn message(qos: QualityOfService) -> Message {
+        Message::new(TopicName::new("a/b").unwrap(), "hello", qos)
+    }
+
+    fn packet_ids(packets: &[Outgoing]) -> Vec<Option<u16>> {
+        packets
+            .iter()
+            .map(|packet| match packet {
+                Outgoing::Publish(packet_id, _) => *packet_id,
+                packet => panic!("unexpected packet {packet:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_publish_qos1_window() {
+        let mut session = Session::new(2);
+        session.resume(false, None);
+
+        let (tx, mut rx) = oneshot::channel();
+        assert!(session
+            .publish(message(QualityOfService::Level1), Some(tx))
+            .is_some());
+        assert!(session
+            .publish(message(QualityOfService::Level1), None)
+            .is_some());
+        // The window is full, QoS 0 messages do not wait.
+        assert!(session
+            .publish(message(QualityOfService::Level1), None)
+            .is_none());
+        assert!(session
+            .publish(message(QualityOfService::Level0), None)
+            .is_some());
+
+        let packets = session.handle_puback(1, Ok(()));
+        assert_eq!(packet_ids(&packets), vec![Some(3)]);
+        assert!(rx.try_recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_publish_qos2() {
+        let mut session = Session::new(10);
+        session.resume(false, Some(5));
+
+        let (tx, mut rx) = oneshot::channel();
+        session.publish(message(QualityOfService::Level2), Some(tx));
+        assert!(matches!(
+            session.handle_pubrec(1, Ok(()))[..],
+            [Outgoing::Pubrel(1)]
+        ));
+        assert!(rx.try_recv().is_err());
+        session.handle_pubcomp(1);
+        assert!(rx.try_recv().unwrap().is_ok());
+
+        let (tx, mut rx) = oneshot::channel();
+        session.publish(message(QualityOfService::Level2), Some(tx));
+        session.handle_pubrec(2, Err("QuotaExceeded".to_owned()));
+        assert!(matches!(rx.try_recv().unwrap(), Err(Error::Rejected(_))));
+    }
+
+    #[test]
+    fn test_receive_qos2_once() {
+        let mut session = Session::new(10);
+        session.resume(false, None);
+
+        let (delivered, ack) = session.handle_publish(Some(7), message(QualityOfService::Level2));
+        assert!(delivered.is_some());
+        assert!(matches!(ack, Some(Outgoing::Pubrec(7))));
+        let (delivered, _) = session.handle_publish(Some(7), message(QualityOfService::Level2));
+        assert!(delivered.is_none());
+
+        assert!(matches!(session.handle_pubrel(7), Outgoing::Pubcomp(7)));
+        let (delivered, _) = session.handle_publish(Some(7), message(QualityOfService::Level2));
+        assert!(delivered.is_some());
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut session = Session::new(10);
+        assert!(session
+            .publish(message(QualityOfService::Level1), None)
+            .is_none());
+        session.resume(false, None);
+        session.publish(message(QualityOfService::Level2), None);
+        session.handle_pubrec(2, Ok(()));
+        let (tx, _rx) = oneshot::channel();
+        let filter = TopicFilter::new("a/#").unwrap();
+        session.subscribe(filter.clone(), SubscribeOptions::default(), tx);
+        session.handle_suback(3, vec![Ok(QualityOfService::Level0)]);
+        session.disconnected();
+
+        // The messages not acknowledged are sent again as duplicates.
+        let packets = session.resume(true, None);
+        assert!(matches!(
+            &packets[..],
+            [Outgoing::Publish(Some(1), message), Outgoing::Pubrel(2)] if message.dup()
+        ));
+
+        // The server lost the session, the subscriptions are made again.
+        session.disconnected();
+        let packets = session.resume(false, None);
+        assert!(matches!(
+            &packets[..],
+            [Outgoing::Subscribe(4, subscribes), Outgoing::Publish(Some(1), _)]
+                if subscribes[0].0 == filter
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::{
+    error::Error,
+    options::{ClientOptions, TlsOptions},
+};
+
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+pub(crate) type BoxStream = Box<dyn Stream>;
+
+/// Opens a TCP connection to the server, with TLS and WebSocket on top of it when
+/// configured.
+pub(crate) async fn open(options: &ClientOptions) -> Result<BoxStream, Error> {
+    let host = match options.addr.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => &options.addr,
+    };
+    let stream = TcpStream::connect(&options.addr).await?;
+    stream.set_nodelay(true)?;
+    let stream = match &options.tls {
+        Some(tls) => tls_connect(tls, host, stream).await?,
+        None => Box::new(stream),
+    };
+    match &options.websocket_path {
+        Some(path) => ws_connect(stream, &options.addr, path, options.tls.is_some()).await,
+        None => Ok(stream),
+    }
+}
+
+#[cfg(feature = "rustls")]
+async fn tls_connect(tls: &TlsOptions, host: &str, stream: TcpStream) -> Result<BoxStream, Error> {
+    use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+    use rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    };
+    use tokio_rustls::TlsConnector;
+
+    fn tls_error(err: impl std::fmt::Display) -> Error {
+        Error::Tls(err.to_string())
+    }
+
+    fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+        let file = &mut BufReader::new(File::open(path)?);
+        Ok(rustls_pemfile::certs(file).collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+        let file = &mut BufReader::new(File::open(path)?);
+        rustls_pemfile::private_key(file)?
+            .ok_or_else(|| Error::Tls(format!("no private key in {}", path.display())))
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&tls.ca_file)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let client_config = match &tls.client_cert {
+        Some(files) => {
+            let certs = load_certs(&files.cert_file)?;
+            let key = load_key(&files.key_file)?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(tls_error)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_name = tls.server_name.as_deref().unwrap_or(host).to_owned();
+    let server_name = ServerName::try_from(server_name).map_err(tls_error)?;
+    let stream = TlsConnector::from(Arc::new(client_config))
+        .connect(server_name, stream)
+        .await?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(feature = "rustls"))]
+async fn tls_connect(
+    _tls: &TlsOptions,
+    _host: &str,
+    _stream: TcpStream,
+) -> Result<BoxStream, Error> {
+    Err(Error::Tls("built without the rustls feature".to_owned()))
+}
+
+#[cfg(feature = "ws")]
+async fn ws_connect(
+    stream: BoxStream,
+    addr: &str,
+    path: &str,
+    tls: bool,
+) -> Result<BoxStream, Error> {
+    use async_tungstenite::{client_async, tokio::TokioAdapter};
+    use tungstenite::{client::IntoClientRequest as _, http::HeaderValue};
+
+    use crate::ws_stream::WsByteStream;
+
+    let scheme = if tls { "wss" } else { "ws" };
+    let mut request = format!("{scheme}://{addr}{path}").into_client_request()?;
+    // see: [MQTT-6.0.0-3]
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+    let (stream, _) = client_async(request, TokioAdapter::new(stream)).await?;
+    Ok(Box::new(WsByteStream::new(stream)))
+}
+
+#[cfg(not(feature = "ws"))]
+async fn ws_connect(
+    _stream: BoxStream,
+    _addr: &str,
+    _path: &str,
+    _tls: bool,
+) -> Result<BoxStream, Error> {
+    Err(Error::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "built without the ws feature",
+    )))
+}
@@ -0,0 +1,174 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{future::join_all, SinkExt as _, StreamExt as _};
+use mesquitte_core::{
+    server::{process_client, state::GlobalState},
+    store::memory::queue::MemoryQueue,
+    types::connection::{ConnectionInfo, Transport},
+};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+    v4::packet::{ConnectPacket, MqttCodec, PublishPacket, SubscribePacket, VariablePacket},
+};
+use tokio::{
+    io::{duplex, DuplexStream},
+    runtime::Runtime,
+};
+use tokio_util::codec::Framed;
+
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+type Client = Framed<DuplexStream, MqttCodec>;
+
+/// Connects a client to the broker through an in-memory pipe.
+async fn connect(global: &Arc<GlobalState<MemoryQueue>>, client_id: &str) -> Client {
+    let (client, server) = duplex(PIPE_CAPACITY);
+    tokio::spawn(process_client(
+        server,
+        ConnectionInfo::new(Transport::default(), None),
+        global.clone(),
+    ));
+    let mut client = Framed::new(client, MqttCodec::new());
+    client
+        .send(ConnectPacket::new(client_id))
+        .await
+        .expect("send connect");
+    match client.next().await {
+        Some(Ok(VariablePacket::ConnackPacket(_))) => client,
+        packet => panic!("expect connack, got {packet:?}"),
+    }
+}
+
+async fn subscribe(client: &mut Client, topic_filter: &str) {
+    let topic_filter = TopicFilter::new(topic_filter).expect("topic filter");
+    client
+        .send(SubscribePacket::new(
+            1,
+            vec![(topic_filter, QualityOfService::Level0)],
+        ))
+        .await
+        .expect("send subscribe");
+    match client.next().await {
+        Some(Ok(VariablePacket::SubackPacket(_))) => {}
+        packet => panic!("expect suback, got {packet:?}"),
+    }
+}
+
+struct Scenario {
+    name: &'static str,
+    publish_qos: QoSWithPacketIdentifier,
+    payload_size: usize,
+    subscribers: usize,
+}
+
+/// Time taken to publish `iters` messages and to receive them on every subscriber. QoS 1
+/// messages also wait for their PUBACK.
+async fn publish_receive(
+    publisher: &mut Client,
+    subscribers: &mut [Client],
+    publish: &PublishPacket,
+    iters: u64,
+) -> Duration {
+    let acked = publish.qos() != QoSWithPacketIdentifier::Level0;
+    let start = Instant::now();
+    let (mut sink, mut acks) = publisher.split();
+    let send = async {
+        for i in 0..iters {
+            let mut packet = publish.clone();
+            if acked {
+                // Packet identifiers cycle through 1..=65535
+                packet.set_qos(QoSWithPacketIdentifier::Level1((i % 65535) as u16 + 1));
+            }
+            sink.feed(packet).await.expect("send publish");
+        }
+        sink.flush().await.expect("flush publishes");
+        if acked {
+            for _ in 0..iters {
+                match acks.next().await {
+                    Some(Ok(VariablePacket::PubackPacket(_))) => {}
+                    packet => panic!("expect puback, got {packet:?}"),
+                }
+            }
+        }
+    };
+    let receive = join_all(subscribers.iter_mut().map(|subscriber| async move {
+        for _ in 0..iters {
+            match subscriber.next().await {
+                Some(Ok(VariablePacket::PublishPacket(_))) => {}
+                packet => panic!("expect publish, got {packet:?}"),
+            }
+        }
+    }));
+    tokio::join!(send, receive);
+    start.elapsed()
+}
+
+fn publish(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("tokio runtime");
+    let topic_name = TopicName::new("bench/publish").expect("topic name");
+
+    let mut group = c.benchmark_group("publish");
+    for scenario in [
+        Scenario {
+            name: "qos0_64b",
+            publish_qos: QoSWithPacketIdentifier::Level0,
+            payload_size: 64,
+            subscribers: 1,
+        },
+        Scenario {
+            name: "qos0_16kb",
+            publish_qos: QoSWithPacketIdentifier::Level0,
+            payload_size: 16 * 1024,
+            subscribers: 1,
+        },
+        Scenario {
+            name: "qos1_64b",
+            publish_qos: QoSWithPacketIdentifier::Level1(1),
+            payload_size: 64,
+            subscribers: 1,
+        },
+        Scenario {
+            name: "qos0_64b_fanout_8",
+            publish_qos: QoSWithPacketIdentifier::Level0,
+            payload_size: 64,
+            subscribers: 8,
+        },
+    ] {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(1024, 30)));
+        let (mut publisher, mut subscribers) = runtime.block_on(async {
+            let publisher = connect(&global, "publisher").await;
+            let mut subscribers = Vec::with_capacity(scenario.subscribers);
+            for i in 0..scenario.subscribers {
+                let mut subscriber = connect(&global, &format!("subscriber-{i}")).await;
+                subscribe(&mut subscriber, &topic_name).await;
+                subscribers.push(subscriber);
+            }
+            (publisher, subscribers)
+        });
+        let packet = PublishPacket::new(
+            topic_name.clone(),
+            scenario.publish_qos,
+            vec![0x5a; scenario.payload_size],
+        );
+
+        group.throughput(Throughput::Elements(1));
+        group.bench_function(BenchmarkId::new("end_to_end", scenario.name), |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(publish_receive(
+                    &mut publisher,
+                    &mut subscribers,
+                    &packet,
+                    iters,
+                ))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, publish);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mesquitte_core::types::{
+    publish::PublishMessage, retain_content::RetainContent, retain_table::RetainTable,
+};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, TopicName},
+    v4::packet::PublishPacket,
+};
+
+const SITES: usize = 10;
+const BUILDINGS: usize = 10;
+const FLOORS: usize = 10;
+const SENSORS: usize = 100;
+
+fn sensor_topic(site: usize, building: usize, floor: usize, sensor: usize) -> String {
+    format!("site/{site}/building/{building}/floor/{floor}/sensor/{sensor}/state")
+}
+
+fn retain_content(topic_name: &str) -> Arc<RetainContent> {
+    let topic_name = TopicName::new(topic_name).expect("topic name");
+    let packet = PublishPacket::new(topic_name, QoSWithPacketIdentifier::Level0, &b"online"[..]);
+    Arc::new(("sensor", &PublishMessage::from(packet)).into())
+}
+
+/// The last state of every sensor, 100k retained messages.
+fn populate() -> RetainTable {
+    let table = RetainTable::default();
+    for site in 0..SITES {
+        for building in 0..BUILDINGS {
+            for floor in 0..FLOORS {
+                for sensor in 0..SENSORS {
+                    table.insert(retain_content(&sensor_topic(site, building, floor, sensor)));
+                }
+            }
+        }
+    }
+    table
+}
+
+fn retain_table(c: &mut Criterion) {
+    let table = populate();
+
+    let mut group = c.benchmark_group("retain_table");
+    for (name, filter) in [
+        ("exact", sensor_topic(3, 7, 5, 42)),
+        ("floor", "site/3/building/7/floor/5/#".to_owned()),
+        (
+            "sensor_everywhere",
+            "site/+/building/+/floor/+/sensor/42/+".to_owned(),
+        ),
+        ("building", "site/3/building/7/#".to_owned()),
+        ("all", "#".to_owned()),
+    ] {
+        let matches = table.get_matches(&filter).len();
+        group.throughput(Throughput::Elements(matches as u64));
+        group.bench_with_input(
+            BenchmarkId::new("get_matches", name),
+            &filter,
+            |b, filter| b.iter(|| table.get_matches(filter)),
+        );
+    }
+
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("replace", |b| {
+        let content = retain_content(&sensor_topic(3, 7, 5, 42));
+        b.iter(|| table.insert(Arc::clone(&content)))
+    });
+    group.bench_function("insert_remove", |b| {
+        let topic_name = sensor_topic(SITES, 0, 0, 0);
+        let content = retain_content(&topic_name);
+        b.iter(|| {
+            table.insert(Arc::clone(&content));
+            table.remove(&topic_name)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, retain_table);
+criterion_main!(benches);
@@ -0,0 +1,417 @@
+use std::{
+    hint::black_box,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mesquitte_core::types::topic_router::RouteTable;
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+
+const DEVICES: usize = 10_000;
+const PUBLISHERS: usize = 4;
+
+trait Router: Default + Sync {
+    fn subscribe(&self, topic_filter: &TopicFilter, id: &str);
+
+    fn unsubscribe(&self, topic_filter: &TopicFilter, id: &str);
+
+    /// Number of subscriptions a message published on `topic_name` is sent to.
+    fn dispatch(&self, topic_name: &TopicName) -> usize;
+}
+
+impl Router for RouteTable {
+    fn subscribe(&self, topic_filter: &TopicFilter, id: &str) {
+        RouteTable::subscribe(self, topic_filter, id, QualityOfService::Level1);
+    }
+
+    fn unsubscribe(&self, topic_filter: &TopicFilter, id: &str) {
+        RouteTable::unsubscribe(self, topic_filter, id);
+    }
+
+    fn dispatch(&self, topic_name: &TopicName) -> usize {
+        self.get_matches(topic_name)
+            .iter()
+            .map(|content| content.clients.len())
+            .sum()
+    }
+}
+
+impl Router for baseline::RouteTable {
+    fn subscribe(&self, topic_filter: &TopicFilter, id: &str) {
+        baseline::RouteTable::subscribe(self, topic_filter, id, QualityOfService::Level1);
+    }
+
+    fn unsubscribe(&self, topic_filter: &TopicFilter, id: &str) {
+        baseline::RouteTable::unsubscribe(self, topic_filter, id);
+    }
+
+    fn dispatch(&self, topic_name: &TopicName) -> usize {
+        self.get_matches(topic_name)
+            .iter()
+            .map(|content| content.read().clients.len())
+            .sum()
+    }
+}
+
+fn device_filter(device: usize) -> TopicFilter {
+    TopicFilter::new(format!("devices/{device}/cmd")).expect("topic filter")
+}
+
+fn device_topic(device: usize) -> TopicName {
+    TopicName::new(format!("devices/{device}/cmd")).expect("topic name")
+}
+
+/// Every device subscribes to its own command topic, monitoring clients subscribe to all of
+/// them with wildcards.
+fn populate<R: Router>() -> R {
+    let router = R::default();
+    for device in 0..DEVICES {
+        router.subscribe(&device_filter(device), &format!("device-{device}"));
+    }
+    for (id, filter) in [("monitor-0", "devices/#"), ("monitor-1", "devices/+/cmd")] {
+        router.subscribe(&TopicFilter::new(filter).expect("topic filter"), id);
+    }
+    router
+}
+
+/// Time taken by every publisher to dispatch `iters` messages while devices keep
+/// reconnecting, each reconnect being an unsubscribe followed by a subscribe.
+fn mixed_load<R: Router>(router: &R, topics: &[TopicName], iters: u64, churn: bool) -> Duration {
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        if churn {
+            s.spawn(|| {
+                let mut device = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let filter = device_filter(device);
+                    let id = format!("device-{device}");
+                    router.unsubscribe(&filter, &id);
+                    router.subscribe(&filter, &id);
+                    device = (device + 1) % DEVICES;
+                }
+            });
+        }
+        let publishers: Vec<_> = (0..PUBLISHERS)
+            .map(|publisher| {
+                s.spawn(move || {
+                    let start = Instant::now();
+                    for i in 0..iters as usize {
+                        let topic = &topics[(i * PUBLISHERS + publisher) % topics.len()];
+                        black_box(router.dispatch(topic));
+                    }
+                    start.elapsed()
+                })
+            })
+            .collect();
+        let elapsed = publishers
+            .into_iter()
+            .map(|publisher| publisher.join().expect("publisher"))
+            .sum::<Duration>();
+        stop.store(true, Ordering::Relaxed);
+        elapsed / PUBLISHERS as u32
+    })
+}
+
+fn bench_router<R: Router>(c: &mut Criterion, name: &str) {
+    let router = populate::<R>();
+    let topics: Vec<_> = (0..DEVICES).step_by(7).map(device_topic).collect();
+
+    let mut group = c.benchmark_group("topic_router");
+    group.bench_function(BenchmarkId::new("subscribe_unsubscribe", name), |b| {
+        let filter = device_filter(DEVICES);
+        b.iter(|| {
+            router.subscribe(&filter, "device");
+            router.unsubscribe(&filter, "device");
+        })
+    });
+    group.bench_function(BenchmarkId::new("dispatch", name), |b| {
+        b.iter_custom(|iters| mixed_load(&router, &topics, iters, false))
+    });
+    group.bench_function(BenchmarkId::new("dispatch_with_churn", name), |b| {
+        b.iter_custom(|iters| mixed_load(&router, &topics, iters, true))
+    });
+    group.finish();
+}
+
+fn topic_router(c: &mut Criterion) {
+    bench_router::<RouteTable>(c, "snapshot");
+    bench_router::<baseline::RouteTable>(c, "locked");
+}
+
+/// Subscriptions of a filter set as `(client id, topic filter)` with the topic names
+/// published on.
+struct FilterSet {
+    name: &'static str,
+    subscriptions: Vec<(String, String)>,
+    topics: Vec<String>,
+}
+
+/// Sensors of 20 sites watched by dashboards and alerting services through `+` and `#`.
+fn wildcard_heavy() -> FilterSet {
+    let mut subscriptions = Vec::new();
+    for site in 0..20 {
+        for client in 0..50 {
+            let id = format!("dashboard-{site}-{client}");
+            subscriptions.push((id.clone(), format!("site/{site}/+/+/temperature")));
+            subscriptions.push((id, format!("site/{site}/building/{client}/#")));
+        }
+        subscriptions.push((
+            format!("alerting-{site}"),
+            format!("site/{site}/+/+/+/alarm"),
+        ));
+    }
+    subscriptions.push(("archive".to_owned(), "site/#".to_owned()));
+    subscriptions.push(("audit".to_owned(), "+/+/building/+/#".to_owned()));
+    let topics = (0..1000)
+        .map(|i| format!("site/{}/building/{}/temperature", i % 20, i % 50))
+        .collect();
+    FilterSet {
+        name: "wildcard_heavy",
+        subscriptions,
+        topics,
+    }
+}
+
+/// Twelve level topics, each device subscribing to its own subtree.
+fn deep_topics() -> FilterSet {
+    let topic = |i: usize| {
+        format!(
+            "region/{}/zone/{}/site/{}/line/{}/cell/{}/device/{i}",
+            i % 4,
+            i % 16,
+            i % 64,
+            i % 256,
+            i % 1024,
+        )
+    };
+    let mut subscriptions: Vec<_> = (0..DEVICES)
+        .map(|i| (format!("device-{i}"), topic(i)))
+        .collect();
+    subscriptions.push((
+        "line-monitor".to_owned(),
+        "region/+/zone/+/site/+/line/+/#".to_owned(),
+    ));
+    let topics = (0..DEVICES).step_by(7).map(topic).collect();
+    FilterSet {
+        name: "deep_topics",
+        subscriptions,
+        topics,
+    }
+}
+
+/// Worker pools sharing the jobs of each queue, besides an observer of every queue.
+fn shared_groups() -> FilterSet {
+    let mut subscriptions = Vec::new();
+    for queue in 0..100 {
+        for worker in 0..20 {
+            subscriptions.push((
+                format!("worker-{queue}-{worker}"),
+                format!("$share/pool-{queue}/jobs/{queue}/+"),
+            ));
+        }
+        subscriptions.push((
+            format!("standby-{queue}"),
+            format!("$share/standby/jobs/{queue}/+"),
+        ));
+    }
+    subscriptions.push(("observer".to_owned(), "jobs/#".to_owned()));
+    let topics = (0..1000).map(|i| format!("jobs/{}/{i}", i % 100)).collect();
+    FilterSet {
+        name: "shared_groups",
+        subscriptions,
+        topics,
+    }
+}
+
+fn get_matches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_matches");
+    for set in [wildcard_heavy(), deep_topics(), shared_groups()] {
+        let router = RouteTable::default();
+        for (id, filter) in &set.subscriptions {
+            let filter = TopicFilter::new(filter.as_str()).expect("topic filter");
+            router.subscribe(&filter, id, QualityOfService::Level1);
+        }
+        let topics: Vec<_> = set
+            .topics
+            .into_iter()
+            .map(|topic| TopicName::new(topic).expect("topic name"))
+            .collect();
+
+        group.bench_function(set.name, |b| {
+            let mut topics = topics.iter().cycle();
+            b.iter(|| router.get_matches(topics.next().expect("topic")))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, topic_router, get_matches);
+criterion_main!(benches);
+
+/// The previous route table, with a lock on every level and on every matched content,
+/// without shared subscriptions.
+mod baseline {
+    use std::sync::Arc;
+
+    use hashbrown::HashMap;
+    use mqtt_codec_kit::common::{
+        QualityOfService, TopicFilter, TopicName, LEVEL_SEP, MATCH_ALL_STR, MATCH_ONE_STR,
+    };
+    use parking_lot::RwLock;
+
+    #[derive(Default)]
+    pub struct RouteTable {
+        nodes: RwLock<HashMap<String, RouteNode>>,
+    }
+
+    struct RouteNode {
+        content: Arc<RwLock<RouteContent>>,
+        nodes: Arc<RwLock<HashMap<String, RouteNode>>>,
+    }
+
+    pub struct RouteContent {
+        pub topic_filter: Option<TopicFilter>,
+        pub clients: HashMap<String, QualityOfService>,
+    }
+
+    fn split_topic(topic: &str) -> (&str, Option<&str>) {
+        if let Some((head, rest)) = topic.split_once(LEVEL_SEP) {
+            (head, Some(rest))
+        } else {
+            (topic, None)
+        }
+    }
+
+    impl RouteTable {
+        pub fn get_matches(&self, topic_name: &TopicName) -> Vec<Arc<RwLock<RouteContent>>> {
+            let (topic_item, rest_items) = split_topic(topic_name);
+            let mut filters = Vec::new();
+
+            let nodes = self.nodes.read();
+            if let Some(node) = nodes.get(topic_item) {
+                node.get_matches(topic_item, rest_items, &mut filters);
+            }
+            if !topic_name.starts_with('$') {
+                for item in [MATCH_ALL_STR, MATCH_ONE_STR] {
+                    if let Some(node) = nodes.get(item) {
+                        node.get_matches(item, rest_items, &mut filters);
+                    }
+                }
+            }
+            filters
+        }
+
+        pub fn subscribe(&self, topic_filter: &TopicFilter, id: &str, qos: QualityOfService) {
+            let (filter_item, rest_items) = split_topic(topic_filter);
+            self.nodes
+                .write()
+                .entry(filter_item.to_string())
+                .or_insert_with(RouteNode::new)
+                .insert(topic_filter, rest_items, id, qos);
+        }
+
+        pub fn unsubscribe(&self, topic_filter: &TopicFilter, id: &str) {
+            let (filter_item, rest_items) = split_topic(topic_filter);
+            let mut remove_node = false;
+            let mut nodes = self.nodes.write();
+            if let Some(node) = nodes.get_mut(filter_item) {
+                remove_node = node.remove(rest_items, id);
+            }
+            if remove_node {
+                nodes.remove(filter_item);
+            }
+        }
+    }
+
+    impl RouteNode {
+        fn new() -> RouteNode {
+            RouteNode {
+                content: Arc::new(RwLock::new(RouteContent {
+                    topic_filter: None,
+                    clients: HashMap::new(),
+                })),
+                nodes: Arc::new(RwLock::new(HashMap::new())),
+            }
+        }
+
+        fn get_matches(
+            &self,
+            prev_item: &str,
+            topic_items: Option<&str>,
+            filters: &mut Vec<Arc<RwLock<RouteContent>>>,
+        ) {
+            if prev_item == MATCH_ALL_STR {
+                if !self.content.read().clients.is_empty() {
+                    filters.push(Arc::clone(&self.content));
+                }
+            } else if let Some(topic_items) = topic_items {
+                let nodes = self.nodes.read();
+                let (topic_item, rest_items) = split_topic(topic_items);
+                for item in [topic_item, MATCH_ALL_STR, MATCH_ONE_STR] {
+                    if let Some(node) = nodes.get(item) {
+                        node.get_matches(item, rest_items, filters);
+                    }
+                }
+            } else {
+                if !self.content.read().clients.is_empty() {
+                    filters.push(Arc::clone(&self.content));
+                }
+                if let Some(node) = self.nodes.read().get(MATCH_ALL_STR) {
+                    if !node.content.read().clients.is_empty() {
+                        filters.push(Arc::clone(&node.content));
+                    }
+                }
+            }
+        }
+
+        fn insert(
+            &self,
+            topic_filter: &TopicFilter,
+            filter_items: Option<&str>,
+            id: &str,
+            qos: QualityOfService,
+        ) {
+            if let Some(filter_items) = filter_items {
+                let (filter_item, rest_items) = split_topic(filter_items);
+                self.nodes
+                    .write()
+                    .entry(filter_item.to_string())
+                    .or_insert_with(RouteNode::new)
+                    .insert(topic_filter, rest_items, id, qos);
+            } else {
+                let mut content = self.content.write();
+                if content.topic_filter.is_none() {
+                    content.topic_filter = Some(topic_filter.clone());
+                }
+                content.clients.insert(id.to_owned(), qos);
+            }
+        }
+
+        fn remove(&self, filter_items: Option<&str>, id: &str) -> bool {
+            if let Some(filter_items) = filter_items {
+                let (filter_item, rest_items) = split_topic(filter_items);
+                let mut nodes = self.nodes.write();
+                let mut remove_node = false;
+                if let Some(node) = nodes.get_mut(filter_item) {
+                    remove_node = node.remove(rest_items, id);
+                }
+                if remove_node {
+                    nodes.remove(filter_item);
+                    self.content.read().clients.is_empty() && nodes.is_empty()
+                } else {
+                    false
+                }
+            } else {
+                let mut content = self.content.write();
+                content.clients.remove(id);
+                if content.clients.is_empty() {
+                    content.topic_filter = None;
+                    return self.nodes.read().is_empty();
+                }
+                false
+            }
+        }
+    }
+}
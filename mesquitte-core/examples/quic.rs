@@ -1,6 +1,9 @@
 use std::{env, path::Path, sync::Arc};
 
-use mesquitte_core::server::{quic::server::QuicServer, state::GlobalState};
+use mesquitte_core::{
+    server::{quic::server::QuicServer, state::GlobalState},
+    store::memory::queue::MemoryQueue,
+};
 
 #[tokio::main]
 async fn main() {
@@ -10,7 +13,7 @@ async fn main() {
     );
     env_logger::init();
 
-    let global = Arc::new(GlobalState::new());
+    let global = Arc::new(GlobalState::new(MemoryQueue::new(1024, 30)));
     let broker = QuicServer::bind(
         "0.0.0.0:1883".parse().unwrap(),
         (
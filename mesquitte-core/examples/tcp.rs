@@ -1,13 +1,19 @@
 use std::{env, io, sync::Arc};
 
-use mesquitte_core::server::{state::GlobalState, tcp::server::TcpServer};
+use mesquitte_core::{
+    server::{state::GlobalState, tcp::server::TcpServer},
+    store::memory::queue::MemoryQueue,
+};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    env::set_var("RUST_LOG", "tcp=trace,mesquitte_core=trace,mqtt_codec_kit=info");
+    env::set_var(
+        "RUST_LOG",
+        "tcp=trace,mesquitte_core=trace,mqtt_codec_kit=info",
+    );
     env_logger::init();
 
-    let global = Arc::new(GlobalState::new());
+    let global = Arc::new(GlobalState::new(MemoryQueue::new(1024, 30)));
     let broker = TcpServer::bind("0.0.0.0:1883".parse().unwrap(), global)
         .await
         .unwrap();
@@ -1,13 +1,19 @@
 use std::{env, io, sync::Arc};
 
-use mesquitte_core::server::{state::GlobalState, ws::server::WsServer};
+use mesquitte_core::{
+    server::{state::GlobalState, ws::server::WsServer},
+    store::memory::queue::MemoryQueue,
+};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    env::set_var("RUST_LOG", "ws=trace,mesquitte_core=trace,mqtt_codec_kit=info");
+    env::set_var(
+        "RUST_LOG",
+        "ws=trace,mesquitte_core=trace,mqtt_codec_kit=info",
+    );
     env_logger::init();
 
-    let global = GlobalState::new();
+    let global = GlobalState::new(MemoryQueue::new(1024, 30));
 
     let broker = WsServer::bind("0.0.0.0:6666".parse().unwrap(), Arc::new(global))
         .await
@@ -1,5 +1,5 @@
 mod protocols;
-mod store;
-mod types;
 
 pub mod server;
+pub mod store;
+pub mod types;
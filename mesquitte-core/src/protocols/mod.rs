@@ -1,2 +1,2 @@
 pub(crate) mod v4;
-// pub(crate) mod v5;
+pub(crate) mod v5;
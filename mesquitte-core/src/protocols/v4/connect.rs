@@ -15,6 +15,7 @@ use crate::{
     store::queue::Queue,
     types::{
         client::AddClientReceipt,
+        connection::ConnectionInfo,
         outgoing::Outgoing,
         session::{LastWill, Session},
     },
@@ -22,6 +23,7 @@ use crate::{
 
 pub(super) async fn handle_connect<Q>(
     packet: ConnectPacket,
+    connection: ConnectionInfo,
     global: Arc<GlobalState<Q>>,
 ) -> Result<(ConnackPacket, Session, mpsc::Receiver<Outgoing>), ConnackPacket>
 where
@@ -29,6 +31,7 @@ where
 {
     log::debug!(
         r#"client#{} received a connect packet:
+    connection : {:?}
 protocol level : {:?}
  protocol name : {:?}
  clean session : {}
@@ -37,6 +40,7 @@ protocol level : {:?}
     keep-alive : {}s
           will : {:?}"#,
         packet.client_identifier(),
+        connection,
         packet.protocol_level(),
         packet.protocol_name(),
         packet.clean_session(),
@@ -45,6 +49,10 @@ protocol level : {:?}
         packet.keep_alive(),
         packet.will(),
     );
+    global
+        .hooks()
+        .client_connecting(packet.client_identifier(), &connection)
+        .await;
 
     let level = packet.protocol_level();
     if ProtocolLevel::Version311.ne(&level) && ProtocolLevel::Version310.ne(&level) {
@@ -68,7 +76,25 @@ protocol level : {:?}
         ));
     }
 
-    if packet.client_identifier().is_empty() && !packet.clean_session() {
+    if let Some(limit_exceeded) = connection.limit_exceeded() {
+        log::debug!("handle connect refused: {:?}", limit_exceeded);
+
+        return Err(ConnackPacket::new(
+            false,
+            ConnectReturnCode::ServiceUnavailable,
+        ));
+    }
+
+    if connection.cert_identity_missing() {
+        log::debug!("handle connect refused: client certificate lacks the identity field");
+
+        return Err(ConnackPacket::new(false, ConnectReturnCode::NotAuthorized));
+    }
+
+    if packet.client_identifier().is_empty()
+        && !packet.clean_session()
+        && connection.cert_client_id().is_none()
+    {
         return Err(ConnackPacket::new(
             false,
             ConnectReturnCode::IdentifierRejected,
@@ -77,11 +103,17 @@ protocol level : {:?}
 
     // TODO: handle auth
 
-    let (assigned_client_id, client_id) = if packet.client_identifier().is_empty() {
+    let (assigned_client_id, client_id) = if let Some(client_id) = connection.cert_client_id() {
+        (false, client_id.to_owned())
+    } else if packet.client_identifier().is_empty() {
         (true, nanoid!())
     } else {
         (false, packet.client_identifier().to_owned())
     };
+    let username = match connection.cert_username() {
+        Some(username) => Some(username.to_owned()),
+        None => packet.username().map(|name| name.to_owned()),
+    };
 
     // TODO: config: max inflight size
     // TODO: config: max inflight message size
@@ -89,8 +121,9 @@ protocol level : {:?}
     // TODO: config: max packet size
 
     let mut session = Session::new(client_id, assigned_client_id, 12);
+    session.set_connection(connection);
     session.set_clean_session(packet.clean_session());
-    session.set_username(packet.username().map(|name| name.to_owned()));
+    session.set_username(username);
     session.set_keep_alive(packet.keep_alive());
     let server_keep_alive = session.keep_alive() != packet.keep_alive();
     session.set_server_keep_alive(server_keep_alive);
@@ -129,6 +162,8 @@ protocol level : {:?}
 
     // FIXME: to many clients cause memory leak
 
+    session.set_publish_quota(global.publish_quota(session.client_id()));
+
     // TODO: outgoing channel size
     let (outgoing_tx, outgoing_rx) = mpsc::channel::<Outgoing>(8);
     let receipt = global.add_client(session.client_id(), outgoing_tx).await;
@@ -143,11 +178,15 @@ protocol level : {:?}
                     "{} session removed due to reconnect with clean session",
                     packet.client_identifier(),
                 );
+                global.remove_packets(session.client_id()).await;
                 false
             }
         }
         AddClientReceipt::New => false,
     };
+    if !session_present {
+        global.hooks().session_created(&session).await;
+    }
 
     Ok((
         ConnackPacket::new(session_present, ConnectReturnCode::ConnectionAccepted),
@@ -165,3 +204,27 @@ pub(super) async fn handle_disconnect(session: &mut Session) {
     session.clear_last_will();
     session.set_client_disconnected();
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{store::memory::queue::MemoryQueue, types::connection::Transport};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cert_identity_missing() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let mut connection = ConnectionInfo::new(Transport::Tls, None);
+        connection.set_cert_identity_missing();
+        let mut packet = ConnectPacket::new("v4");
+        packet.set_username(Some("user".to_owned()));
+        let Err(connack) = handle_connect(packet, connection, global.clone()).await else {
+            panic!("connected without the certificate identity");
+        };
+        assert_eq!(
+            connack.connect_return_code(),
+            ConnectReturnCode::NotAuthorized
+        );
+        assert!(global.get_outgoing_sender("v4").is_none());
+    }
+}
@@ -1,5 +1,5 @@
 mod connect;
-mod publish;
+pub(crate) mod publish;
 mod subscribe;
 
 pub mod read_write_loop;
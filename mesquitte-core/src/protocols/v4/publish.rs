@@ -2,16 +2,17 @@ use std::{cmp, io, sync::Arc};
 
 use mqtt_codec_kit::{
     common::{
-        qos::QoSWithPacketIdentifier, QualityOfService, MATCH_ALL_STR, MATCH_ONE_STR, SHARED_PREFIX,
+        qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, MATCH_ALL_STR, MATCH_ONE_STR,
+        SHARED_PREFIX,
     },
     v4::packet::{
-        DisconnectPacket, PubackPacket, PubcompPacket, PublishPacket, PubrecPacket, PubrelPacket,
-        VariablePacket,
+        DisconnectPacket, PubackPacket, PubcompPacket, PublishFrame, PublishPacket, PubrecPacket,
+        PubrelPacket, VariablePacket,
     },
 };
 
 use crate::{
-    server::state::GlobalState,
+    server::{hook::DropReason, limits::QuotaCheck, state::GlobalState},
     store::queue::Queue,
     types::{
         outgoing::Outgoing,
@@ -59,27 +60,54 @@ topic name : {:?}
         return Ok((true, Some(DisconnectPacket::new().into())));
     }
 
+    match session.publish_quota().check(packet.payload().len()) {
+        QuotaCheck::Allow => {}
+        QuotaCheck::Reject => {
+            log::debug!("client#{} is over its publish quota", session.client_id());
+            let ack = match packet.qos() {
+                QoSWithPacketIdentifier::Level0 => None,
+                QoSWithPacketIdentifier::Level1(packet_id) => {
+                    Some(PubackPacket::new(packet_id).into())
+                }
+                QoSWithPacketIdentifier::Level2(packet_id) => {
+                    Some(PubrecPacket::new(packet_id).into())
+                }
+            };
+            global
+                .hooks()
+                .message_dropped(
+                    session.client_id(),
+                    &packet.into(),
+                    DropReason::QuotaExceeded,
+                )
+                .await;
+            return Ok((false, ack));
+        }
+    }
+
     match packet.qos() {
         QoSWithPacketIdentifier::Level0 => {
             dispatch_publish(session, packet.into(), global).await;
             Ok((false, None))
         }
         QoSWithPacketIdentifier::Level1(packet_id) => {
-            if !packet.dup() {
-                dispatch_publish(session, packet.into(), global).await;
-            }
+            // A DUP message may not have been received before, QoS 1 is at least once.
+            dispatch_publish(session, packet.into(), global).await;
             Ok((false, Some(PubackPacket::new(packet_id).into())))
         }
         QoSWithPacketIdentifier::Level2(packet_id) => {
-            if !packet.dup() {
-                if let Err(err) = global
-                    .packets_queue()
-                    .push_qos2_back(session.client_id(), packet_id, packet.into())
-                    .await
-                {
-                    log::error!("client#");
-                    return Err(io::ErrorKind::InvalidData.into());
-                }
+            // A DUP message may not have been received before, resent ones are kept once.
+            if let Err(err) = global
+                .packets_queue()
+                .push_qos2_back(session.client_id(), packet_id, packet.into())
+                .await
+            {
+                log::error!(
+                    "client#{} push incoming packet: {:?}",
+                    session.client_id(),
+                    err
+                );
+                return Err(io::ErrorKind::InvalidData.into());
             }
             Ok((false, Some(PubrecPacket::new(packet_id).into())))
         }
@@ -87,7 +115,7 @@ topic name : {:?}
 }
 
 // Dispatch a publish message from client or will to matched clients
-pub(super) async fn dispatch_publish<Q>(
+pub(crate) async fn dispatch_publish<Q>(
     session: &mut Session,
     packet: PublishMessage,
     global: Arc<GlobalState<Q>>,
@@ -107,6 +135,30 @@ topic name : {:?}
         packet.dup(),
     );
 
+    let Some(packet) = global.hooks().message_publish(session, packet).await else {
+        log::debug!(
+            "client#{} publish message dropped by a hook",
+            session.client_id()
+        );
+        return;
+    };
+
+    let republished = global.rule_engine().apply(session.client_id(), &packet);
+    route_publish(session, packet, &global).await;
+    // Not run through the hooks and rules again, they could republish in a loop.
+    for message in republished {
+        route_publish(session, message, &global).await;
+    }
+}
+
+// Retain a publish message and send it to the matched clients
+pub(crate) async fn route_publish<Q>(
+    session: &Session,
+    packet: PublishMessage,
+    global: &GlobalState<Q>,
+) where
+    Q: Queue,
+{
     if packet.retain() {
         if packet.payload().is_empty() {
             global.retain_table().remove(packet.topic_name());
@@ -117,34 +169,69 @@ topic name : {:?}
         }
     }
 
+    // The other nodes of the cluster retain and route it the same way.
+    global.forward_publish(&packet).await;
+
     let matches = global.route_table().get_matches(packet.topic_name());
     let mut senders = Vec::with_capacity(matches.len());
     for content in matches {
-        let content = content.read();
-        match content.topic_filter.as_ref() {
-            Some(filter) => {
-                for (client_id, subscribe_qos) in &content.clients {
-                    senders.push((client_id.to_owned(), filter.clone(), *subscribe_qos));
-                }
-            }
-            None => log::warn!("topic filter is empty in content : {:?}", content),
+        let filter = &content.topic_filter;
+        for (client_id, subscribe_qos) in &content.clients {
+            senders.push((client_id.to_owned(), filter.clone(), *subscribe_qos));
+        }
+        // Shared subscriptions of v5 clients. Messages forwarded by the cluster went to
+        // the shared subscriptions of the node they were published on.
+        if packet.forwarded_from().is_some() {
+            continue;
         }
+        for (group_name, shared_clients) in &content.groups {
+            let (client_id, subscribe_qos) = global.select_shared_client(
+                group_name,
+                shared_clients,
+                session.client_id(),
+                packet.topic_name(),
+            );
+            let shared_filter: &str = filter;
+            let full_filter =
+                TopicFilter::new(format!("{SHARED_PREFIX}{group_name}/{shared_filter}"))
+                    .expect("full topic filter");
+            senders.push((client_id, full_filter, subscribe_qos));
+        }
+    }
+    if senders.is_empty() {
+        global
+            .hooks()
+            .message_dropped(session.client_id(), &packet, DropReason::NoSubscribers)
+            .await;
     }
 
-    for (receiver_client_id, _, qos) in senders {
+    for (receiver_client_id, subscribe_filter, qos) in senders {
+        // Messages of a bridge are not sent back to it.
+        if packet.bridged_by() == Some(receiver_client_id.as_str()) {
+            continue;
+        }
         if let Some(sender) = global.get_outgoing_sender(&receiver_client_id) {
             if sender.is_closed() {
                 log::warn!(
                     "client#{:?} outgoing sender channel is closed",
                     receiver_client_id,
                 );
+                global
+                    .hooks()
+                    .message_dropped(&receiver_client_id, &packet, DropReason::ReceiverClosed)
+                    .await;
                 continue;
             }
-            if let Err(err) = sender
-                .send(Outgoing::Publish(qos, Box::new(packet.clone())))
-                .await
-            {
-                log::error!("{} send publish message: {}", receiver_client_id, err,)
+            let mut message = packet.clone();
+            if subscribe_filter.is_shared() {
+                message.set_shared_filter(Some(subscribe_filter));
+            }
+            if let Err(err) = sender.send(Outgoing::Publish(qos, Box::new(message))).await {
+                log::error!("{} send publish message: {}", receiver_client_id, err,);
+                global
+                    .hooks()
+                    .message_dropped(&receiver_client_id, &packet, DropReason::ReceiverClosed)
+                    .await;
             }
         }
     }
@@ -164,25 +251,36 @@ where
         pid
     );
 
-    session.pending_packets().clean_incoming();
-    let mut start_idx = 0;
-    while let Some((idx, msg)) = session
-        .pending_packets()
-        .get_ready_incoming_packet(start_idx)
-    {
-        start_idx = idx + 1;
-        let inner = msg.message().to_owned();
-        dispatch_publish(session, inner, global.clone()).await;
+    let queue = global.packets_queue();
+    if let Err(err) = queue.clean_incoming(session.client_id()).await {
+        log::error!(
+            "client#{} clean incoming packets: {:?}",
+            session.client_id(),
+            err
+        );
+    }
+    match queue.pubrel(session.client_id(), pid).await {
+        Ok(Some(message)) => dispatch_publish(session, message, global.clone()).await,
+        Ok(None) => log::debug!(
+            "client#{} released an unknown packet id: {}",
+            session.client_id(),
+            pid
+        ),
+        Err(err) => log::error!("client#{} pubrel {}: {:?}", session.client_id(), pid, err),
     }
 
     PubcompPacket::new(pid)
 }
 
-pub(super) fn receive_outgoing_publish(
+pub(super) async fn receive_outgoing_publish<Q>(
     session: &mut Session,
     subscribe_qos: QualityOfService,
     message: PublishMessage,
-) -> PublishPacket {
+    global: &GlobalState<Q>,
+) -> PublishFrame
+where
+    Q: Queue,
+{
     log::debug!(
         r#"client#{} receive outgoing publish message:
 topic name : {:?}
@@ -209,51 +307,100 @@ topic name : {:?}
             (Some(packet_id), QoSWithPacketIdentifier::Level2(packet_id))
         }
     };
-    let mut packet = PublishPacket::new(message.topic_name().to_owned(), qos, message.payload());
-    packet.set_dup(message.dup());
+    // The message is encoded once for all receivers, only the flags and the packet
+    // identifier differ.
+    let mut frame = message.v4_frame().clone();
+    frame.set_qos(qos);
+    frame.set_dup(message.dup());
 
     if let Some(packet_id) = packet_id {
-        session
-            .pending_packets()
-            .push_outgoing(packet_id, subscribe_qos, message);
+        if let Err(err) = global
+            .packets_queue()
+            .push_outgoing_back(session.client_id(), packet_id, subscribe_qos, message)
+            .await
+        {
+            log::error!(
+                "client#{} push outgoing packet: {:?}",
+                session.client_id(),
+                err
+            );
+        }
     }
 
-    packet
+    frame
 }
 
-pub(super) fn handle_puback(session: &mut Session, pid: u16) {
+pub(super) async fn handle_puback<Q>(session: &mut Session, global: Arc<GlobalState<Q>>, pid: u16)
+where
+    Q: Queue,
+{
     log::debug!(
         "client#{} received a puback packet, id : {}",
         session.client_id(),
         pid
     );
 
-    let _matched = session.pending_packets().puback(pid);
-
-    session.pending_packets().clean_outgoing();
+    let matched = global
+        .packets_queue()
+        .puback(session.client_id(), pid)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("client#{} puback {}: {:?}", session.client_id(), pid, err);
+            false
+        });
+    clean_outgoing(session, &global).await;
+    if matched {
+        global.hooks().message_acked(session, pid).await;
+    }
 }
 
-pub(super) fn handle_pubrec(session: &mut Session, pid: u16) -> PubrelPacket {
+pub(super) async fn handle_pubrec<Q>(
+    session: &mut Session,
+    global: Arc<GlobalState<Q>>,
+    pid: u16,
+) -> PubrelPacket
+where
+    Q: Queue,
+{
     log::debug!(
         "client#{} received a pubrec packet, id : {}",
         session.client_id(),
         pid
     );
 
-    let _matched = session.pending_packets().pubrec(pid);
+    if let Err(err) = global
+        .packets_queue()
+        .pubrec(session.client_id(), pid)
+        .await
+    {
+        log::error!("client#{} pubrec {}: {:?}", session.client_id(), pid, err);
+    }
 
     PubrelPacket::new(pid)
 }
 
-pub(super) fn handle_pubcomp(session: &mut Session, pid: u16) {
+pub(super) async fn handle_pubcomp<Q>(session: &mut Session, global: Arc<GlobalState<Q>>, pid: u16)
+where
+    Q: Queue,
+{
     log::debug!(
         "client#{} received a pubcomp packet, id : {}",
         session.client_id(),
         pid
     );
 
-    session.pending_packets().pubcomp(pid);
-    session.pending_packets().clean_outgoing();
+    let matched = global
+        .packets_queue()
+        .pubcomp(session.client_id(), pid)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("client#{} pubcomp {}: {:?}", session.client_id(), pid, err);
+            false
+        });
+    clean_outgoing(session, &global).await;
+    if matched {
+        global.hooks().message_acked(session, pid).await;
+    }
 }
 
 pub(super) async fn handle_will<Q>(session: &mut Session, global: Arc<GlobalState<Q>>)
@@ -277,32 +424,133 @@ server side disconnected : {}
     }
 }
 
-pub(crate) fn get_unsent_outgoing_packet<Q>(
+async fn clean_outgoing<Q>(session: &Session, global: &GlobalState<Q>)
+where
+    Q: Queue,
+{
+    if let Err(err) = global
+        .packets_queue()
+        .clean_outgoing(session.client_id())
+        .await
+    {
+        log::error!(
+            "client#{} clean outgoing packets: {:?}",
+            session.client_id(),
+            err
+        );
+    }
+}
+
+pub(crate) async fn get_unsent_outgoing_packet<Q>(
     session: &mut Session,
     global: Arc<GlobalState<Q>>,
-) -> Vec<PublishPacket>
+) -> Vec<PublishFrame>
 where
     Q: Queue,
 {
-    let mut packets = Vec::new();
-    let mut start_idx = 0;
-    while let Some((idx, msg)) = session
-        .pending_packets()
-        .get_unsent_outgoing_packet(start_idx)
+    let unsent = match global
+        .packets_queue()
+        .get_unsent_outgoing_packets(session.client_id())
+        .await
     {
-        start_idx = idx + 1;
-
+        Ok(unsent) => unsent.unwrap_or_default(),
+        Err(err) => {
+            log::error!(
+                "client#{} get unsent outgoing packets: {:?}",
+                session.client_id(),
+                err
+            );
+            return Vec::new();
+        }
+    };
+    let mut packets = Vec::with_capacity(unsent.len());
+    for msg in unsent {
         let qos = match msg.final_qos() {
             QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
             QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(msg.packet_id()),
             QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(msg.packet_id()),
         };
-        let topic_name = msg.message().topic_name().to_owned();
-        let mut packet = PublishPacket::new(topic_name, qos, msg.message().payload());
-        packet.set_dup(msg.message().dup());
+        let mut frame = msg.message().v4_frame().clone();
+        frame.set_qos(qos);
+        frame.set_dup(msg.message().dup());
 
-        packets.push(packet);
+        packets.push(frame);
     }
 
     packets
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use mqtt_codec_kit::common::TopicName;
+    use tokio::sync::mpsc;
+
+    use crate::store::memory::queue::MemoryQueue;
+
+    use super::*;
+
+    fn publish(qos: QoSWithPacketIdentifier) -> PublishPacket {
+        let mut packet = PublishPacket::new(TopicName::new("t").unwrap(), qos, "payload");
+        packet.set_dup(true);
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_publish() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(8);
+        global.add_client("subscriber", outgoing_tx).await;
+        global.subscribe(
+            &TopicFilter::new("t").unwrap(),
+            "subscriber",
+            QualityOfService::Level2,
+        );
+        let mut session = Session::new("publisher".to_owned(), false, 12);
+
+        // The first copy of a DUP message may have been lost.
+        let packet = publish(QoSWithPacketIdentifier::Level1(1));
+        handle_publish(&mut session, packet, global.clone())
+            .await
+            .unwrap();
+        assert!(matches!(outgoing_rx.try_recv(), Ok(Outgoing::Publish(..))));
+
+        for _ in 0..2 {
+            let packet = publish(QoSWithPacketIdentifier::Level2(2));
+            handle_publish(&mut session, packet, global.clone())
+                .await
+                .unwrap();
+        }
+        assert!(outgoing_rx.try_recv().is_err());
+        handle_pubrel(&mut session, global.clone(), 2).await;
+        assert!(matches!(outgoing_rx.try_recv(), Ok(Outgoing::Publish(..))));
+        handle_pubrel(&mut session, global.clone(), 2).await;
+        assert!(outgoing_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_shared_subscription() {
+        let global = GlobalState::new(MemoryQueue::new(10, 10));
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(8);
+        global.add_client("member", outgoing_tx).await;
+        global.subscribe(
+            &TopicFilter::new("$share/group/t").unwrap(),
+            "member",
+            QualityOfService::Level1,
+        );
+        let session = Session::new("publisher".to_owned(), false, 12);
+        let mut message = PublishMessage::new(
+            TopicName::new("t").unwrap(),
+            Bytes::from_static(b"payload"),
+            QualityOfService::Level1,
+            false,
+        );
+        route_publish(&session, message.clone(), &global).await;
+        assert!(matches!(outgoing_rx.try_recv(), Ok(Outgoing::Publish(..))));
+
+        // The node it was published on chose a member of the group.
+        message.set_forwarded_from(Some(Arc::from("node")));
+        route_publish(&session, message, &global).await;
+        assert!(outgoing_rx.try_recv().is_err());
+    }
+}
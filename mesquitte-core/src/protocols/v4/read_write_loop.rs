@@ -1,21 +1,27 @@
 use std::{io, sync::Arc, time::Duration};
 
-use futures::{SinkExt as _, StreamExt as _};
+use futures_util::{SinkExt as _, StreamExt as _};
 use mqtt_codec_kit::v4::packet::{
-    DisconnectPacket, MqttDecoder, MqttEncoder, PingrespPacket, VariablePacket, VariablePacketError,
+    DisconnectPacket, MqttDecoder, MqttEncoder, PingrespPacket, PublishFrame, VariablePacket,
+    VariablePacketError,
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::mpsc,
-    time::{interval_at, Instant},
+    task::JoinSet,
+    time::{self, interval_at, Instant},
 };
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 
 use crate::{
     protocols::v4::publish::handle_will,
-    server::state::GlobalState,
+    server::{limits::ReadPause, state::GlobalState},
     store::queue::Queue,
-    types::{outgoing::Outgoing, session::Session},
+    types::{
+        connection::ConnectionInfo,
+        outgoing::{KickReason, Outgoing},
+        session::Session,
+    },
 };
 
 use super::{
@@ -27,12 +33,19 @@ use super::{
     subscribe::{handle_subscribe, handle_unsubscribe},
 };
 
-async fn read_from_client<T, D>(mut reader: FramedRead<T, D>, sender: mpsc::Sender<VariablePacket>)
-where
+// Reading pauses while the client is throttled, the transport pushes back on it.
+async fn read_from_client<T, D>(
+    mut reader: FramedRead<T, D>,
+    sender: mpsc::Sender<VariablePacket>,
+    read_pause: Arc<ReadPause>,
+) where
     T: AsyncRead + Unpin,
     D: Decoder<Item = VariablePacket, Error = VariablePacketError>,
 {
     loop {
+        if let Some(delay) = read_pause.remaining() {
+            time::sleep(delay).await;
+        }
         match reader.next().await {
             None => {
                 log::info!("client closed");
@@ -60,7 +73,7 @@ pub(super) async fn handle_incoming<T, E, Q>(
 ) -> io::Result<bool>
 where
     T: AsyncWrite + Unpin,
-    E: Encoder<VariablePacket, Error = io::Error>,
+    E: Encoder<VariablePacket, Error = io::Error> + Encoder<PublishFrame, Error = io::Error>,
     Q: Queue + 'static,
 {
     log::debug!(
@@ -74,10 +87,10 @@ where
         VariablePacket::PingreqPacket(_packet) => {
             let pkt = PingrespPacket::new();
             log::debug!("write pingresp packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            writer.send(VariablePacket::from(pkt)).await?;
         }
         VariablePacket::PublishPacket(packet) => {
-            let (stop, ack) = handle_publish(session, packet, global.clone()).await;
+            let (stop, ack) = handle_publish(session, packet, global.clone()).await?;
             if let Some(pkt) = ack {
                 log::debug!("write puback packet: {:?}", pkt);
                 writer.send(pkt).await?;
@@ -87,30 +100,32 @@ where
         VariablePacket::PubrelPacket(packet) => {
             let pkt = handle_pubrel(session, global.clone(), packet.packet_identifier()).await;
             log::debug!("write pubcomp packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            writer.send(VariablePacket::from(pkt)).await?;
         }
         VariablePacket::PubackPacket(packet) => {
-            handle_puback(session, packet.packet_identifier());
+            handle_puback(session, global.clone(), packet.packet_identifier()).await;
         }
         VariablePacket::PubrecPacket(packet) => {
-            let pkt = handle_pubrec(session, packet.packet_identifier());
+            let pkt = handle_pubrec(session, global.clone(), packet.packet_identifier()).await;
             log::debug!("write pubrel packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            writer.send(VariablePacket::from(pkt)).await?;
         }
         VariablePacket::SubscribePacket(packet) => {
-            let packets = handle_subscribe(session, packet, global.clone());
-            log::debug!("write suback packets: {:?}", packets);
-            for pkt in packets {
+            let (pkt, retain_packets) = handle_subscribe(session, packet, global.clone()).await;
+            log::debug!("write suback packet: {:?}", pkt);
+            writer.send(VariablePacket::from(pkt)).await?;
+            for pkt in retain_packets {
+                log::debug!("write retain publish packet: {:?}", pkt);
                 writer.send(pkt).await?;
             }
         }
         VariablePacket::PubcompPacket(packet) => {
-            handle_pubcomp(session, packet.packet_identifier());
+            handle_pubcomp(session, global.clone(), packet.packet_identifier()).await;
         }
         VariablePacket::UnsubscribePacket(packet) => {
-            let pkt = handle_unsubscribe(session, &packet, global.clone());
+            let pkt = handle_unsubscribe(session, &packet, global.clone()).await;
             log::debug!("write unsuback packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            writer.send(VariablePacket::from(pkt)).await?;
         }
         VariablePacket::DisconnectPacket(_packet) => {
             handle_disconnect(session).await;
@@ -125,22 +140,32 @@ where
     Ok(should_stop)
 }
 
+// Packet written to the client for an outgoing message, publish messages are pre-encoded
+// once for all receivers.
+pub(super) enum OutgoingPacket {
+    Packet(VariablePacket),
+    Publish(PublishFrame),
+}
+
 pub(super) async fn receive_outgoing<Q>(
     session: &mut Session,
     packet: Outgoing,
     global: Arc<GlobalState<Q>>,
-) -> (bool, Option<VariablePacket>)
+) -> (bool, Option<OutgoingPacket>)
 where
     Q: Queue,
 {
     let mut should_stop = false;
     let resp = match packet {
         Outgoing::Publish(subscribe_qos, packet) => {
-            let resp = receive_outgoing_publish(session, subscribe_qos, *packet);
+            if !session.disconnected() {
+                global.hooks().message_delivered(session, &packet).await;
+            }
+            let resp = receive_outgoing_publish(session, subscribe_qos, *packet, &global).await;
             if session.disconnected() {
                 None
             } else {
-                Some(resp.into())
+                Some(OutgoingPacket::Publish(resp))
             }
         }
         Outgoing::Online(sender) => {
@@ -160,7 +185,7 @@ where
             if session.disconnected() {
                 None
             } else {
-                Some(DisconnectPacket::new().into())
+                Some(OutgoingPacket::Packet(DisconnectPacket::new().into()))
             }
         }
         Outgoing::Kick(reason) => {
@@ -170,12 +195,16 @@ where
                 reason,
             );
 
-            if session.disconnected() && !session.clean_session() {
+            // A session taken over on another node is resumed there.
+            if session.disconnected() && !session.clean_session() && reason != KickReason::Takeover
+            {
                 None
             } else {
                 should_stop = true;
                 global.remove_client(session.client_id(), session.subscriptions());
-                Some(DisconnectPacket::new().into())
+                global.remove_packets(session.client_id()).await;
+                global.hooks().session_expired(session).await;
+                Some(OutgoingPacket::Packet(DisconnectPacket::new().into()))
             }
         }
     };
@@ -190,16 +219,24 @@ pub(super) async fn handle_outgoing<T, E, Q>(
 ) -> bool
 where
     T: AsyncWrite + Unpin,
-    E: Encoder<VariablePacket, Error = io::Error>,
+    E: Encoder<VariablePacket, Error = io::Error> + Encoder<PublishFrame, Error = io::Error>,
     Q: Queue,
 {
     let (should_stop, resp) = receive_outgoing(session, packet, global).await;
-    if let Some(packet) = resp {
-        log::debug!("write packet: {:?}", packet);
-        if let Err(err) = writer.send(packet).await {
-            log::error!("write packet failed: {err}");
-            return true;
+    let result = match resp {
+        Some(OutgoingPacket::Packet(packet)) => {
+            log::debug!("write packet: {:?}", packet);
+            writer.send(packet).await
+        }
+        Some(OutgoingPacket::Publish(frame)) => {
+            log::debug!("write publish packet: {:?}", frame);
+            writer.send(frame).await
         }
+        None => Ok(()),
+    };
+    if let Err(err) = result {
+        log::error!("write packet failed: {err}");
+        return true;
     }
 
     should_stop
@@ -230,6 +267,8 @@ pub(super) async fn handle_clean_session<Q>(
 
     if session.clean_session() {
         global.remove_client(session.client_id(), session.subscriptions());
+        global.remove_packets(session.client_id()).await;
+        global.hooks().session_expired(&session).await;
         return;
     }
 
@@ -249,7 +288,7 @@ async fn write_to_client<T, E, Q>(
     global: Arc<GlobalState<Q>>,
 ) where
     T: AsyncWrite + Unpin,
-    E: Encoder<VariablePacket, Error = io::Error>,
+    E: Encoder<VariablePacket, Error = io::Error> + Encoder<PublishFrame, Error = io::Error>,
     Q: Queue + Send + 'static,
 {
     if session.keep_alive() > 0 {
@@ -282,7 +321,10 @@ async fn write_to_client<T, E, Q>(
                     }
                 },
                 _ = keep_alive_tick.tick() => {
-                    if session.last_packet_at().elapsed() > keep_alive_timeout {
+                    // The packets left unread while the client is throttled keep it alive.
+                    if session.publish_quota().read_pause().remaining().is_some() {
+                        session.renew_last_packet_at();
+                    } else if session.last_packet_at().elapsed() > keep_alive_timeout {
                         break;
                     }
                 },
@@ -318,18 +360,102 @@ async fn write_to_client<T, E, Q>(
         }
     };
 
+    global.set_client_offline(session.client_id());
+    global.hooks().client_disconnected(&session).await;
     tokio::spawn(handle_clean_session(session, outgoing_rx, global.clone()));
 }
 
-pub async fn read_write_loop<R, W, Q>(reader: R, writer: W, global: Arc<GlobalState<Q>>)
-where
+// Read packets from the first stream and from data streams opened later, all
+// of them feed the same session. The session ends with the first stream.
+async fn read_from_streams<T, S>(
+    reader: FramedRead<T, MqttDecoder>,
+    mut streams: mpsc::Receiver<S>,
+    max_streams: usize,
+    sender: mpsc::Sender<VariablePacket>,
+    read_pause: Arc<ReadPause>,
+) where
+    T: AsyncRead + Unpin,
+    S: AsyncRead + Unpin + Send + 'static,
+{
+    let max_packet_size = reader.decoder().max_packet_size();
+    let mut readers = JoinSet::new();
+    let control = read_from_client(reader, sender.clone(), read_pause.clone());
+    tokio::pin!(control);
+    loop {
+        tokio::select! {
+            _ = &mut control => break,
+            Some(stream) = streams.recv() => {
+                if readers.len() >= max_streams {
+                    log::warn!("too many data streams, max: {max_streams}");
+                    continue;
+                }
+                let mut decoder = MqttDecoder::new();
+                decoder.set_max_packet_size(max_packet_size);
+                let reader = FramedRead::new(stream, decoder);
+                readers.spawn(read_from_client(reader, sender.clone(), read_pause.clone()));
+            }
+            Some(_) = readers.join_next(), if !readers.is_empty() => {}
+        }
+    }
+}
+
+pub async fn read_write_loop<R, W, Q>(
+    reader: R,
+    writer: W,
+    connection: ConnectionInfo,
+    global: Arc<GlobalState<Q>>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    Q: Queue + Send + 'static,
+{
+    session_loop::<_, _, R, _>(reader, writer, None, connection, global).await
+}
+
+/// Run a session whose client may open up to `max_data_streams` additional
+/// streams after CONNECT, e.g. QUIC streams per topic. Packets read from them
+/// are handled like packets from `reader`, all responses go to `writer`.
+pub async fn read_write_loop_with_streams<R, W, S, Q>(
+    reader: R,
+    writer: W,
+    data_streams: mpsc::Receiver<S>,
+    max_data_streams: usize,
+    connection: ConnectionInfo,
+    global: Arc<GlobalState<Q>>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    S: AsyncRead + Unpin + Send + 'static,
+    Q: Queue + Send + 'static,
+{
+    let data_streams = Some((data_streams, max_data_streams));
+    session_loop(reader, writer, data_streams, connection, global).await
+}
+
+async fn session_loop<R, W, S, Q>(
+    reader: R,
+    writer: W,
+    data_streams: Option<(mpsc::Receiver<S>, usize)>,
+    connection: ConnectionInfo,
+    global: Arc<GlobalState<Q>>,
+) where
     R: AsyncRead + Unpin + Send + 'static,
     W: AsyncWrite + Unpin + Send + 'static,
+    S: AsyncRead + Unpin + Send + 'static,
     Q: Queue + Send + 'static,
 {
-    let mut frame_reader = FramedRead::new(reader, MqttDecoder::new());
+    let mut decoder = MqttDecoder::new();
+    decoder.set_max_packet_size(global.max_packet_size());
+    let mut frame_reader = FramedRead::new(reader, decoder);
     let mut frame_writer = FramedWrite::new(writer, MqttEncoder::new());
 
+    // Repeated refused CONNECTs get the client address banned for a while, refusals
+    // because of connection limits do not count, nor connections closed before one.
+    let ban_ip = match connection.limit_exceeded() {
+        Some(_) => None,
+        None => connection.remote_addr().map(|addr| addr.ip()),
+    };
+
     let packet = match frame_reader.next().await {
         Some(Ok(VariablePacket::ConnectPacket(packet))) => packet,
         _ => {
@@ -338,7 +464,8 @@ where
         }
     };
 
-    let (mut session, outgoing_rx) = match handle_connect(packet, global.clone()).await {
+    let (mut session, outgoing_rx) = match handle_connect(packet, connection, global.clone()).await
+    {
         Ok((pkt, session, outgoing_rx)) => {
             if let Err(err) = frame_writer.send(pkt).await {
                 log::error!("handle connect write connect ack: {err}");
@@ -347,6 +474,9 @@ where
             (session, outgoing_rx)
         }
         Err(pkt) => {
+            if let Some(ip) = ban_ip {
+                global.ban_list().record_failure(ip);
+            }
             if let Err(err) = frame_writer.send(pkt).await {
                 log::error!("handle connect write connect ack: {err}");
             }
@@ -354,17 +484,25 @@ where
         }
     };
 
-    let packets = get_unsent_outgoing_packet(&mut session, global.clone());
+    let packets = get_unsent_outgoing_packet(&mut session, global.clone()).await;
     for pkt in packets {
         if let Err(err) = frame_writer.send(pkt).await {
             log::error!("write pending packet failed: {err}");
             return;
         }
     }
+    global.set_client_online(session.client_id());
+    global.hooks().client_connected(&session).await;
 
     let (msg_tx, msg_rx) = mpsc::channel(8);
+    let read_pause = session.publish_quota().read_pause().clone();
     let mut read_task = tokio::spawn(async move {
-        read_from_client(frame_reader, msg_tx).await;
+        match data_streams {
+            Some((streams, max)) => {
+                read_from_streams(frame_reader, streams, max, msg_tx, read_pause).await
+            }
+            None => read_from_client(frame_reader, msg_tx, read_pause).await,
+        }
     });
 
     let mut write_task = tokio::spawn(async move {
@@ -376,3 +514,92 @@ where
         read_task.abort();
     };
 }
+
+#[cfg(test)]
+mod test {
+    use mqtt_codec_kit::{
+        common::{
+            qos::QoSWithPacketIdentifier, Encodable, QualityOfService, TopicFilter, TopicName,
+        },
+        v4::packet::{ConnectPacket, PublishPacket},
+    };
+    use tokio::io::{split, AsyncWriteExt as _, DuplexStream};
+
+    use crate::{store::memory::queue::MemoryQueue, types::connection::Transport};
+
+    use super::*;
+
+    fn publish(topic_name: &str, packet_id: u16) -> Vec<u8> {
+        let packet = PublishPacket::new(
+            TopicName::new(topic_name).unwrap(),
+            QoSWithPacketIdentifier::Level1(packet_id),
+            "payload",
+        );
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        buf
+    }
+
+    async fn recv_topic(rx: &mut mpsc::Receiver<Outgoing>) -> Option<String> {
+        match time::timeout(Duration::from_millis(200), rx.recv()).await {
+            Ok(Some(Outgoing::Publish(_, message))) => Some(message.topic_name().to_string()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_data_streams() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(8);
+        global.add_client("subscriber", outgoing_tx).await;
+        global.subscribe(
+            &TopicFilter::new("t/#").unwrap(),
+            "subscriber",
+            QualityOfService::Level1,
+        );
+
+        let (control, server) = tokio::io::duplex(1024);
+        let (rd, wr) = split(server);
+        let (streams_tx, streams_rx) = mpsc::channel::<DuplexStream>(4);
+        let connection = ConnectionInfo::new(Transport::Quic, None);
+        tokio::spawn(read_write_loop_with_streams(
+            rd, wr, streams_rx, 2, connection, global,
+        ));
+        let (rd, mut control) = split(control);
+        let mut control_reader = FramedRead::new(rd, MqttDecoder::new());
+        let mut connect = Vec::new();
+        ConnectPacket::new("quic").encode(&mut connect).unwrap();
+        control.write_all(&connect).await.unwrap();
+        assert!(matches!(
+            control_reader.next().await,
+            Some(Ok(VariablePacket::ConnackPacket(_)))
+        ));
+
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            let (client, server) = tokio::io::duplex(1024);
+            streams_tx.send(server).await.unwrap();
+            streams.push(client);
+        }
+        // A packet still arriving on a stream does not hold up the others, the
+        // stream over the limit is not read.
+        let blocked = publish("t/a", 1);
+        streams[0].write_all(&blocked[..4]).await.unwrap();
+        streams[1].write_all(&publish("t/b", 2)).await.unwrap();
+        let _ = streams[2].write_all(&publish("t/c", 3)).await;
+        assert_eq!(recv_topic(&mut outgoing_rx).await.as_deref(), Some("t/b"));
+        assert_eq!(recv_topic(&mut outgoing_rx).await, None);
+
+        streams[0].write_all(&blocked[4..]).await.unwrap();
+        assert_eq!(recv_topic(&mut outgoing_rx).await.as_deref(), Some("t/a"));
+        // Acknowledged on the control stream
+        for packet_id in [2, 1] {
+            match control_reader.next().await {
+                Some(Ok(VariablePacket::PubackPacket(puback))) => {
+                    assert_eq!(puback.packet_identifier(), packet_id);
+                }
+                other => panic!("unexpected packet: {other:?}"),
+            }
+        }
+    }
+}
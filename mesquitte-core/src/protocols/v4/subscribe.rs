@@ -1,19 +1,19 @@
-use std::{collections::VecDeque, sync::Arc};
+use std::sync::Arc;
 
 use mqtt_codec_kit::v4::packet::{
-    suback::SubscribeReturnCode, SubackPacket, SubscribePacket, UnsubackPacket, UnsubscribePacket,
-    VariablePacket,
+    suback::SubscribeReturnCode, PublishFrame, SubackPacket, SubscribePacket, UnsubackPacket,
+    UnsubscribePacket,
 };
 
 use crate::{server::state::GlobalState, store::queue::Queue, types::session::Session};
 
 use super::publish::receive_outgoing_publish;
 
-pub(super) fn handle_subscribe<Q>(
+pub(super) async fn handle_subscribe<Q>(
     session: &mut Session,
     packet: SubscribePacket,
     global: Arc<GlobalState<Q>>,
-) -> Vec<VariablePacket>
+) -> (SubackPacket, Vec<PublishFrame>)
 where
     Q: Queue,
 {
@@ -26,7 +26,7 @@ packet id : {}
         packet.subscribes(),
     );
     let mut return_codes = Vec::with_capacity(packet.subscribes().len());
-    let mut retain_packets: Vec<VariablePacket> = Vec::new();
+    let mut retain_packets = Vec::new();
     for (filter, subscribe_qos) in packet.subscribes() {
         if filter.is_shared() {
             log::warn!("mqtt v3.x don't support shared subscription");
@@ -38,23 +38,26 @@ packet id : {}
         let granted_qos = subscribe_qos.to_owned();
         session.subscribe(filter.clone());
         global.subscribe(filter, session.client_id(), granted_qos);
+        global.hooks().subscribe(session, filter, granted_qos).await;
 
         for msg in global.retain_table().get_matches(filter) {
-            let mut packet = receive_outgoing_publish(session, granted_qos, msg.into());
+            let mut packet =
+                receive_outgoing_publish(session, granted_qos, msg.into(), &global).await;
             packet.set_retain(true);
 
-            retain_packets.push(packet.into());
+            retain_packets.push(packet);
         }
 
         return_codes.push(granted_qos.into());
     }
 
-    let mut queue: VecDeque<VariablePacket> = VecDeque::from(retain_packets);
-    queue.push_front(SubackPacket::new(packet.packet_identifier(), return_codes).into());
-    queue.into()
+    (
+        SubackPacket::new(packet.packet_identifier(), return_codes),
+        retain_packets,
+    )
 }
 
-pub(super) fn handle_unsubscribe<Q>(
+pub(super) async fn handle_unsubscribe<Q>(
     session: &mut Session,
     packet: &UnsubscribePacket,
     global: Arc<GlobalState<Q>>,
@@ -73,6 +76,7 @@ packet id : {}
     for filter in packet.subscribes() {
         global.unsubscribe(filter, session.client_id());
         session.unsubscribe(filter);
+        global.hooks().unsubscribe(session, filter).await;
     }
 
     UnsubackPacket::new(packet.packet_identifier())
@@ -4,7 +4,7 @@ use mqtt_codec_kit::{
         control::{
             ConnackProperties, ConnectReasonCode, DisconnectProperties, DisconnectReasonCode,
         },
-        packet::{ConnackPacket, DisconnectPacket},
+        packet::{ConnackPacket, DisconnectPacket, VariablePacket},
     },
 };
 
@@ -50,3 +50,28 @@ pub(crate) fn build_error_disconnect<S: Into<String>>(
 
     disconnect_packet
 }
+
+/// Drop the optional properties, reason strings and user properties, of an
+/// outgoing packet which exceeds the Maximum Packet Size of the client.
+pub(crate) fn fit_max_packet_size(session: &Session, mut packet: VariablePacket) -> VariablePacket {
+    if packet.encoded_length() <= session.max_packet_size() {
+        return packet;
+    }
+
+    match &mut packet {
+        VariablePacket::PublishPacket(pkt) => {
+            let mut properties = pkt.properties().clone();
+            properties.clear_user_properties();
+            pkt.set_properties(properties);
+        }
+        VariablePacket::PubackPacket(pkt) => pkt.set_properties(Default::default()),
+        VariablePacket::PubrecPacket(pkt) => pkt.set_properties(Default::default()),
+        VariablePacket::PubrelPacket(pkt) => pkt.set_properties(Default::default()),
+        VariablePacket::PubcompPacket(pkt) => pkt.set_properties(Default::default()),
+        VariablePacket::SubackPacket(pkt) => pkt.set_properties(Default::default()),
+        VariablePacket::UnsubackPacket(pkt) => pkt.set_properties(Default::default()),
+        VariablePacket::DisconnectPacket(pkt) => pkt.set_properties(Default::default()),
+        _ => {}
+    }
+    packet
+}
@@ -13,9 +13,11 @@ use nanoid::nanoid;
 use tokio::sync::mpsc;
 
 use crate::{
-    server::state::GlobalState,
+    server::{limits::LimitExceeded, state::GlobalState},
+    store::queue::Queue,
     types::{
         client::AddClientReceipt,
+        connection::ConnectionInfo,
         outgoing::Outgoing,
         session::{LastWill, Session},
     },
@@ -23,12 +25,17 @@ use crate::{
 
 use super::common::build_error_connack;
 
-pub(super) async fn handle_connect(
+pub(super) async fn handle_connect<Q>(
     packet: ConnectPacket,
-    global: Arc<GlobalState>,
-) -> Result<(ConnackPacket, Session, mpsc::Receiver<Outgoing>), ConnackPacket> {
+    connection: ConnectionInfo,
+    global: Arc<GlobalState<Q>>,
+) -> Result<(ConnackPacket, Session, mpsc::Receiver<Outgoing>), ConnackPacket>
+where
+    Q: Queue,
+{
     log::debug!(
         r#"client#{} received a connect packet:
+    connection : {:?}
 protocol level : {:?}
  protocol name : {:?}
  clean session : {}
@@ -38,6 +45,7 @@ protocol level : {:?}
           will : {:?}
     properties : {:?}"#,
         packet.client_identifier(),
+        connection,
         packet.protocol_level(),
         packet.protocol_name(),
         packet.clean_session(),
@@ -47,6 +55,10 @@ protocol level : {:?}
         packet.will(),
         packet.properties(),
     );
+    global
+        .hooks()
+        .client_connecting(packet.client_identifier(), &connection)
+        .await;
 
     let level = packet.protocol_level();
     if ProtocolLevel::Version50.ne(&level) {
@@ -67,6 +79,22 @@ protocol level : {:?}
         ));
     }
 
+    if let Some(limit_exceeded) = connection.limit_exceeded() {
+        log::debug!("handle connect refused: {:?}", limit_exceeded);
+
+        let reason_code = match limit_exceeded {
+            LimitExceeded::Connections => ConnectReasonCode::ServerBusy,
+            LimitExceeded::AcceptRate => ConnectReasonCode::ConnectionRateExceeded,
+        };
+        return Err(ConnackPacket::new(false, reason_code));
+    }
+
+    if connection.cert_identity_missing() {
+        log::debug!("handle connect refused: client certificate lacks the identity field");
+
+        return Err(ConnackPacket::new(false, ConnectReasonCode::NotAuthorized));
+    }
+
     // TODO: handle auth
 
     // TODO: config: max inflight size
@@ -74,15 +102,22 @@ protocol level : {:?}
     // TODO: config: inflight message timeout
     // TODO: config: max packet size
 
-    let (assigned_client_id, client_id) = if packet.client_identifier().is_empty() {
+    let (assigned_client_id, client_id) = if let Some(client_id) = connection.cert_client_id() {
+        (false, client_id.to_owned())
+    } else if packet.client_identifier().is_empty() {
         (true, nanoid!())
     } else {
         (false, packet.client_identifier().to_owned())
     };
+    let username = match connection.cert_username() {
+        Some(username) => Some(username.to_owned()),
+        None => packet.username().map(|name| name.to_owned()),
+    };
 
     let mut session = Session::new(client_id, assigned_client_id, 12);
+    session.set_connection(connection);
     session.set_clean_session(packet.clean_session());
-    session.set_username(packet.username().map(|name| name.to_owned()));
+    session.set_username(username);
     session.set_keep_alive(packet.keep_alive());
     let server_keep_alive = session.keep_alive() != packet.keep_alive();
     session.set_server_keep_alive(server_keep_alive);
@@ -213,6 +248,8 @@ protocol level : {:?}
 
     // FIXME: to many clients cause memory leak
 
+    session.set_publish_quota(global.publish_quota(session.client_id()));
+
     // TODO: outgoing channel size
     let (outgoing_tx, outgoing_rx) = mpsc::channel::<Outgoing>(8);
     let receipt = global.add_client(session.client_id(), outgoing_tx).await;
@@ -227,11 +264,15 @@ protocol level : {:?}
                     "{} session removed due to reconnect with clean session",
                     packet.client_identifier(),
                 );
+                global.remove_packets(session.client_id()).await;
                 false
             }
         }
         AddClientReceipt::New => false,
     };
+    if !session_present {
+        global.hooks().session_created(&session).await;
+    }
 
     // build and send connack packet
     let mut connack_properties = ConnackProperties::default();
@@ -243,8 +284,7 @@ protocol level : {:?}
     connack_properties.set_max_qos(Some(QualityOfService::Level2 as u8));
     // TODO: config: retain available
     connack_properties.set_retain_available(Some(1));
-    // TODO: config: max packet size
-    connack_properties.set_max_packet_size(Some(session.max_packet_size()));
+    connack_properties.set_max_packet_size(global.max_packet_size());
     if session.assigned_client_id() {
         connack_properties.set_assigned_client_identifier(Some(session.client_id().to_string()));
     }
@@ -255,8 +295,7 @@ protocol level : {:?}
     // TODO: config: subscription_identifiers_available
     connack_properties.set_subscription_identifiers_available(Some(1));
     // TODO: config: shared_subscription_available
-    // BUG: publish or subscribe QoS1/2 connect ack failed？
-    // connack_properties.set_shared_subscription_available(Some(1));
+    connack_properties.set_shared_subscription_available(Some(1));
 
     // TODO: config: min/max keep alive
     if session.server_keep_alive() {
@@ -291,3 +330,49 @@ pub(super) async fn handle_disconnect(
     session.set_client_disconnected();
     None
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{store::memory::queue::MemoryQueue, types::connection::Transport};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limit_exceeded() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        for (limit_exceeded, reason_code) in [
+            (LimitExceeded::Connections, ConnectReasonCode::ServerBusy),
+            (
+                LimitExceeded::AcceptRate,
+                ConnectReasonCode::ConnectionRateExceeded,
+            ),
+        ] {
+            let mut connection = ConnectionInfo::new(Transport::Tcp, None);
+            connection.set_limit_exceeded(limit_exceeded);
+            let Err(connack) =
+                handle_connect(ConnectPacket::new("v5"), connection, global.clone()).await
+            else {
+                panic!("connected over the limits");
+            };
+            assert_eq!(connack.connect_reason_code(), reason_code);
+        }
+        assert!(global.get_outgoing_sender("v5").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cert_identity_missing() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let mut connection = ConnectionInfo::new(Transport::Tls, None);
+        connection.set_cert_identity_missing();
+        let mut packet = ConnectPacket::new("v5");
+        packet.set_username(Some("user".to_owned()));
+        let Err(connack) = handle_connect(packet, connection, global.clone()).await else {
+            panic!("connected without the certificate identity");
+        };
+        assert_eq!(
+            connack.connect_reason_code(),
+            ConnectReasonCode::NotAuthorized
+        );
+        assert!(global.get_outgoing_sender("v5").is_none());
+    }
+}
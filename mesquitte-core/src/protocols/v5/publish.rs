@@ -1,9 +1,9 @@
-use std::{cmp, sync::Arc};
+use std::{cmp, io, sync::Arc};
 
 use mqtt_codec_kit::{
     common::{
-        qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, MATCH_ALL_STR, MATCH_ONE_STR,
-        SHARED_PREFIX,
+        qos::QoSWithPacketIdentifier, Encodable, QualityOfService, TopicFilter, MATCH_ALL_STR,
+        MATCH_ONE_STR, SHARED_PREFIX,
     },
     v5::{
         control::{
@@ -11,27 +11,33 @@ use mqtt_codec_kit::{
             PubrelReasonCode,
         },
         packet::{
-            PubackPacket, PubcompPacket, PublishPacket, PubrecPacket, PubrelPacket, VariablePacket,
+            PubackPacket, PubcompPacket, PublishFrame, PublishPacket, PubrecPacket, PubrelPacket,
+            VariablePacket,
         },
     },
 };
 
 use crate::{
-    server::state::GlobalState,
+    server::{hook::DropReason, limits::QuotaCheck, state::GlobalState},
+    store::queue::Queue,
     types::{
         outgoing::Outgoing,
         publish::PublishMessage,
         session::{LastWill, Session},
+        topic_router::RouteContent,
     },
 };
 
-use super::common::build_error_disconnect;
+use super::common::{build_error_disconnect, fit_max_packet_size};
 
-pub(super) async fn handle_publish(
+pub(super) async fn handle_publish<Q>(
     session: &mut Session,
     packet: PublishPacket,
-    global: Arc<GlobalState>,
-) -> (bool, Option<VariablePacket>) {
+    global: Arc<GlobalState<Q>>,
+) -> io::Result<(bool, Option<VariablePacket>)>
+where
+    Q: Queue,
+{
     log::debug!(
         r#"client#{} received a publish packet:
 topic name : {:?}
@@ -45,15 +51,6 @@ topic name : {:?}
         packet.dup(),
     );
 
-    if session.pending_packets().incoming_len() >= session.receive_maximum().into() {
-        let err_pkt = build_error_disconnect(
-            session,
-            DisconnectReasonCode::ReceiveMaximumExceeded,
-            "received more than Receive Maximum publication",
-        );
-        return (true, Some(err_pkt.into()));
-    }
-
     let topic_name = packet.topic_name();
     // TODO: topic alias and max topic alias
     if topic_name.is_empty() {
@@ -62,7 +59,7 @@ topic name : {:?}
             DisconnectReasonCode::TopicNameInvalid,
             "topic name cannot be empty",
         );
-        return (true, Some(err_pkt.into()));
+        return Ok((true, Some(err_pkt.into())));
     }
 
     if topic_name.contains(MATCH_ALL_STR) || topic_name.contains(MATCH_ONE_STR) {
@@ -71,7 +68,7 @@ topic name : {:?}
             DisconnectReasonCode::TopicNameInvalid,
             "topic name cannot start with '$' or contain '+' or '#'",
         );
-        return (true, Some(err_pkt.into()));
+        return Ok((true, Some(err_pkt.into())));
     }
     if packet.qos() == QoSWithPacketIdentifier::Level0 && packet.dup() {
         let err_pkt = build_error_disconnect(
@@ -79,44 +76,89 @@ topic name : {:?}
             DisconnectReasonCode::ProtocolError,
             "invalid duplicate flag in QoS 0 publish message",
         );
-        return (true, Some(err_pkt.into()));
+        return Ok((true, Some(err_pkt.into())));
+    }
+
+    match session.publish_quota().check(packet.payload().len()) {
+        QuotaCheck::Allow => {}
+        QuotaCheck::Reject => {
+            log::debug!("client#{} is over its publish quota", session.client_id());
+            let ack = match packet.qos() {
+                QoSWithPacketIdentifier::Level0 => None,
+                QoSWithPacketIdentifier::Level1(packet_id) => {
+                    Some(PubackPacket::new(packet_id, PubackReasonCode::QuotaExceeded).into())
+                }
+                QoSWithPacketIdentifier::Level2(packet_id) => {
+                    Some(PubrecPacket::new(packet_id, PubrecReasonCode::QuotaExceeded).into())
+                }
+            };
+            global
+                .hooks()
+                .message_dropped(
+                    session.client_id(),
+                    &packet.into(),
+                    DropReason::QuotaExceeded,
+                )
+                .await;
+            return Ok((false, ack));
+        }
     }
 
     match packet.qos() {
         QoSWithPacketIdentifier::Level0 => {
             dispatch_publish(session, packet.into(), global).await;
-            (false, None)
+            Ok((false, None))
         }
         QoSWithPacketIdentifier::Level1(packet_id) => {
-            if !packet.dup() {
-                dispatch_publish(session, packet.into(), global).await;
-            }
+            // A DUP message may not have been received before, QoS 1 is at least once.
+            dispatch_publish(session, packet.into(), global).await;
 
-            (
+            Ok((
                 false,
                 Some(PubackPacket::new(packet_id, PubackReasonCode::Success).into()),
-            )
+            ))
         }
         QoSWithPacketIdentifier::Level2(packet_id) => {
-            if !packet.dup() {
-                session
-                    .pending_packets()
-                    .push_incoming(packet_id, packet.into());
+            // A DUP message may not have been received before, resent ones are kept once.
+            let full = match global
+                .packets_queue()
+                .push_qos2_back(session.client_id(), packet_id, packet.into())
+                .await
+            {
+                Ok(full) => full,
+                Err(err) => {
+                    log::error!(
+                        "client#{} push incoming packet: {:?}",
+                        session.client_id(),
+                        err
+                    );
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+            };
+            if full {
+                let err_pkt = build_error_disconnect(
+                    session,
+                    DisconnectReasonCode::ReceiveMaximumExceeded,
+                    "received more than Receive Maximum publication",
+                );
+                return Ok((true, Some(err_pkt.into())));
             }
-            (
+            Ok((
                 false,
                 Some(PubrecPacket::new(packet_id, PubrecReasonCode::Success).into()),
-            )
+            ))
         }
     }
 }
 
 // Dispatch a publish message from client or will to matched clients
-pub(super) async fn dispatch_publish(
+pub(super) async fn dispatch_publish<Q>(
     session: &mut Session,
     packet: PublishMessage,
-    global: Arc<GlobalState>,
-) {
+    global: Arc<GlobalState<Q>>,
+) where
+    Q: Queue,
+{
     log::debug!(
         r#"client#{} dispatch publish message:
 topic name : {:?}
@@ -132,6 +174,27 @@ properties : {:?}
         packet.dup(),
     );
 
+    let Some(packet) = global.hooks().message_publish(session, packet).await else {
+        log::debug!(
+            "client#{} publish message dropped by a hook",
+            session.client_id()
+        );
+        return;
+    };
+
+    let republished = global.rule_engine().apply(session.client_id(), &packet);
+    route_publish(session, packet, &global).await;
+    // Not run through the hooks and rules again, they could republish in a loop.
+    for message in republished {
+        route_publish(session, message, &global).await;
+    }
+}
+
+// Retain a publish message and send it to the matched clients
+async fn route_publish<Q>(session: &Session, packet: PublishMessage, global: &GlobalState<Q>)
+where
+    Q: Queue,
+{
     if packet.retain() {
         if packet.payload().is_empty() {
             global.retain_table().remove(packet.topic_name());
@@ -142,81 +205,128 @@ properties : {:?}
         }
     }
 
+    // The other nodes of the cluster retain and route it the same way.
+    global.forward_publish(&packet).await;
+
     let matches = global.route_table().get_matches(packet.topic_name());
     let mut senders = Vec::with_capacity(matches.len());
     for content in matches {
-        let content = content.read();
-        let subscribe_filter = content.topic_filter.as_ref().unwrap();
-        match content.topic_filter.as_ref() {
-            Some(filter) => {
-                for (client_id, subscribe_qos) in &content.clients {
-                    senders.push((client_id.to_owned(), filter.clone(), *subscribe_qos));
-                }
-            }
-            None => log::warn!("topic filter is empty in content : {:?}", content),
+        let filter = &content.topic_filter;
+        for (client_id, subscribe_qos) in &content.clients {
+            senders.push((client_id.to_owned(), filter.clone(), *subscribe_qos));
+        }
+        // Messages forwarded by the cluster went to the shared subscriptions of
+        // the node they were published on.
+        if packet.forwarded_from().is_some() {
+            continue;
         }
         for (group_name, shared_clients) in &content.groups {
             // TODO: config: shared subscription available
-            // TODO: config: shared subscription mode
-            let (client_id, subscribe_qos) = shared_clients.get_by_hash(session.client_id());
+            let (client_id, subscribe_qos) = global.select_shared_client(
+                group_name,
+                shared_clients,
+                session.client_id(),
+                packet.topic_name(),
+            );
             // TODO: optimize this alloc later
-            let full_filter = TopicFilter::new(format!(
-                "{SHARED_PREFIX}{group_name}/{:?}",
-                subscribe_filter,
-            ))
-            .expect("full topic filter");
+            let shared_filter: &str = filter;
+            let full_filter =
+                TopicFilter::new(format!("{SHARED_PREFIX}{group_name}/{shared_filter}"))
+                    .expect("full topic filter");
             senders.push((client_id, full_filter, subscribe_qos));
         }
     }
+    if senders.is_empty() {
+        global
+            .hooks()
+            .message_dropped(session.client_id(), &packet, DropReason::NoSubscribers)
+            .await;
+    }
 
-    for (receiver_client_id, _subscribe_filter, qos) in senders {
+    for (receiver_client_id, subscribe_filter, qos) in senders {
+        // Messages of a bridge are not sent back to it.
+        if packet.bridged_by() == Some(receiver_client_id.as_str()) {
+            continue;
+        }
         if let Some(sender) = global.get_outgoing_sender(&receiver_client_id) {
             if sender.is_closed() {
                 // TODO: client identifier
                 log::warn!("{} offline", receiver_client_id);
+                global
+                    .hooks()
+                    .message_dropped(&receiver_client_id, &packet, DropReason::ReceiverClosed)
+                    .await;
                 continue;
             }
-            if let Err(err) = sender
-                .send(Outgoing::Publish(qos, Box::new(packet.clone())))
-                .await
-            {
-                log::error!("{} send publish message: {}", receiver_client_id, err,)
+            let mut message = packet.clone();
+            if subscribe_filter.is_shared() {
+                message.set_shared_filter(Some(subscribe_filter));
+            }
+            if let Err(err) = sender.send(Outgoing::Publish(qos, Box::new(message))).await {
+                log::error!("{} send publish message: {}", receiver_client_id, err,);
+                global
+                    .hooks()
+                    .message_dropped(&receiver_client_id, &packet, DropReason::ReceiverClosed)
+                    .await;
             }
         }
     }
 }
 
-pub(super) async fn handle_pubrel(
+pub(super) async fn handle_pubrel<Q>(
     session: &mut Session,
-    global: Arc<GlobalState>,
+    global: Arc<GlobalState<Q>>,
     pid: u16,
-) -> PubcompPacket {
+) -> PubcompPacket
+where
+    Q: Queue,
+{
     log::debug!(
         "client#{} received a pubrel packet, id : {}",
         session.client_id(),
         pid
     );
 
-    session.pending_packets().clean_incoming();
-    let mut start_idx = 0;
-    while let Some((idx, msg)) = session
-        .pending_packets()
-        .get_ready_incoming_packet(start_idx)
-    {
-        start_idx = idx + 1;
-        let message = msg.message().to_owned();
-        dispatch_publish(session, message, global.clone()).await;
+    let queue = global.packets_queue();
+    if let Err(err) = queue.clean_incoming(session.client_id()).await {
+        log::error!(
+            "client#{} clean incoming packets: {:?}",
+            session.client_id(),
+            err
+        );
     }
+    let reason_code = match queue.pubrel(session.client_id(), pid).await {
+        Ok(Some(message)) => {
+            dispatch_publish(session, message, global.clone()).await;
+            PubcompReasonCode::Success
+        }
+        Ok(None) => {
+            log::debug!(
+                "client#{} released an unknown packet id: {}",
+                session.client_id(),
+                pid
+            );
+            PubcompReasonCode::PacketIdentifierNotFound
+        }
+        Err(err) => {
+            log::error!("client#{} pubrel {}: {:?}", session.client_id(), pid, err);
+            PubcompReasonCode::PacketIdentifierNotFound
+        }
+    };
 
-    PubcompPacket::new(pid, PubcompReasonCode::Success)
+    PubcompPacket::new(pid, reason_code)
 }
 
-pub(super) fn receive_outgoing_publish(
+pub(super) async fn receive_outgoing_publish<Q>(
     session: &mut Session,
     subscribe_qos: QualityOfService,
     // retain_as_published: bool,
     message: PublishMessage,
-) -> PublishPacket {
+    global: &GlobalState<Q>,
+) -> Option<PublishFrame>
+where
+    Q: Queue,
+{
     log::debug!(
         r#"client#{} receive outgoing publish message:
 topic name : {:?}
@@ -241,7 +351,6 @@ properties : {:?}
     //         return None;
     //     };
 
-    let properties = message.properties().cloned().unwrap_or_default();
     // TODO: add_subscription_identifiers?
     // for identifier in subscription_identifiers {
     //     properties.add_subscription_identifier(identifier);
@@ -260,56 +369,153 @@ properties : {:?}
         }
     };
 
-    let mut packet = PublishPacket::new(message.topic_name().to_owned(), qos, message.payload());
-    packet.set_dup(message.dup());
-    packet.set_properties(properties);
+    let frame = fit_publish_frame(session, &message, qos)?;
 
     if let Some(packet_id) = packet_id {
-        session
-            .pending_packets()
-            .push_outgoing(packet_id, subscribe_qos, message);
+        if message.shared_filter().is_some() {
+            session.track_shared(packet_id, final_qos, message.clone());
+        }
+        if let Err(err) = global
+            .packets_queue()
+            .push_outgoing_back(session.client_id(), packet_id, subscribe_qos, message)
+            .await
+        {
+            log::error!(
+                "client#{} push outgoing packet: {:?}",
+                session.client_id(),
+                err
+            );
+        }
+    }
+
+    Some(frame)
+}
+
+// The message is encoded once for all receivers, only the flags and the packet identifier
+// differ. The client must never receive a packet larger than its Maximum Packet Size, the
+// properties the server may leave out are dropped, or the message is discarded as if it
+// had been delivered.
+fn fit_publish_frame(
+    session: &mut Session,
+    message: &PublishMessage,
+    qos: QoSWithPacketIdentifier,
+) -> Option<PublishFrame> {
+    let mut frame = message.v5_frame().clone();
+    frame.set_qos(qos);
+    frame.set_dup(message.dup());
+    if frame.encoded_length() <= session.max_packet_size() {
+        return Some(frame);
     }
 
-    packet
+    let mut packet = PublishPacket::new(
+        message.topic_name().to_owned(),
+        qos,
+        message.payload().clone(),
+    );
+    packet.set_dup(message.dup());
+    packet.set_properties(message.properties().cloned().unwrap_or_default());
+    match fit_max_packet_size(session, packet.into()) {
+        VariablePacket::PublishPacket(packet)
+            if packet.encoded_length() <= session.max_packet_size() =>
+        {
+            Some(PublishFrame::from(&packet))
+        }
+        _ => {
+            log::debug!(
+                "client#{} discard publish message exceeding maximum packet size {}",
+                session.client_id(),
+                session.max_packet_size(),
+            );
+            session.publish_quota().counters().record_discarded();
+            None
+        }
+    }
 }
 
-pub(super) fn handle_puback(session: &mut Session, pid: u16) {
+pub(super) async fn handle_puback<Q>(session: &mut Session, global: Arc<GlobalState<Q>>, pid: u16)
+where
+    Q: Queue,
+{
     log::debug!(
         "client#{} received a puback packet, id : {}",
         session.client_id(),
         pid
     );
 
-    session.pending_packets().puback(pid);
-    session.pending_packets().clean_outgoing();
+    session.ack_shared(pid);
+    let matched = global
+        .packets_queue()
+        .puback(session.client_id(), pid)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("client#{} puback {}: {:?}", session.client_id(), pid, err);
+            false
+        });
+    clean_outgoing(session, &global).await;
+    if matched {
+        global.hooks().message_acked(session, pid).await;
+    }
 }
 
-pub(super) fn handle_pubrec(session: &mut Session, pid: u16) -> PubrelPacket {
+pub(super) async fn handle_pubrec<Q>(
+    session: &mut Session,
+    global: Arc<GlobalState<Q>>,
+    pid: u16,
+) -> PubrelPacket
+where
+    Q: Queue,
+{
     log::debug!(
         "client#{} received a pubrec packet, id : {}",
         session.client_id(),
         pid
     );
 
-    if session.pending_packets().pubrec(pid) {
+    // Received by the client, even if not yet released.
+    session.ack_shared(pid);
+    let matched = global
+        .packets_queue()
+        .pubrec(session.client_id(), pid)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("client#{} pubrec {}: {:?}", session.client_id(), pid, err);
+            false
+        });
+    if matched {
         PubrelPacket::new(pid, PubrelReasonCode::Success)
     } else {
         PubrelPacket::new(pid, PubrelReasonCode::PacketIdentifierNotFound)
     }
 }
 
-pub(super) fn handle_pubcomp(session: &mut Session, pid: u16) {
+pub(super) async fn handle_pubcomp<Q>(session: &mut Session, global: Arc<GlobalState<Q>>, pid: u16)
+where
+    Q: Queue,
+{
     log::debug!(
         "client#{} received a pubcomp packet, id : {}",
         session.client_id(),
         pid
     );
 
-    session.pending_packets().pubcomp(pid);
-    session.pending_packets().clean_outgoing();
+    let matched = global
+        .packets_queue()
+        .pubcomp(session.client_id(), pid)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("client#{} pubcomp {}: {:?}", session.client_id(), pid, err);
+            false
+        });
+    clean_outgoing(session, &global).await;
+    if matched {
+        global.hooks().message_acked(session, pid).await;
+    }
 }
 
-pub(super) async fn handle_will(session: &mut Session, global: Arc<GlobalState>) {
+pub(super) async fn handle_will<Q>(session: &mut Session, global: Arc<GlobalState<Q>>)
+where
+    Q: Queue,
+{
     log::debug!(
         r#"client#{} handle last will:
 client side disconnected : {}
@@ -327,29 +533,445 @@ server side disconnected : {}
     }
 }
 
-pub(crate) fn get_unsent_outgoing_packet(session: &mut Session) -> Vec<PublishPacket> {
-    let mut packets = Vec::new();
-    let mut start_idx = 0;
-    while let Some((idx, msg)) = session
-        .pending_packets()
-        .get_unsent_outgoing_packet(start_idx)
+// Messages of shared subscriptions the client did not acknowledge go to another member of
+// the group once its session is over [MQTT-4.8.2-6], `messages` are those the session had
+// not received yet. The client must already be removed from the route table.
+pub(super) async fn redeliver_shared_publish<Q>(
+    session: &mut Session,
+    mut messages: Vec<PublishMessage>,
+    global: Arc<GlobalState<Q>>,
+) where
+    Q: Queue,
+{
+    match global
+        .packets_queue()
+        .get_unsent_outgoing_packets(session.client_id())
+        .await
     {
-        start_idx = idx + 1;
+        Ok(unsent) => messages.extend(
+            unsent
+                .unwrap_or_default()
+                .into_iter()
+                .map(|msg| msg.message().to_owned()),
+        ),
+        Err(err) => log::error!(
+            "client#{} get unsent outgoing packets: {:?}",
+            session.client_id(),
+            err
+        ),
+    }
 
-        let qos = match msg.final_qos() {
-            QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
-            QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(msg.packet_id()),
-            QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(msg.packet_id()),
+    for mut message in messages {
+        let Some((group_name, filter)) = message.shared_filter().and_then(|f| f.shared_info())
+        else {
+            continue;
+        };
+        let receiver = shared_route(&global, &message, filter).and_then(|content| {
+            content.groups.get(group_name).map(|shared_clients| {
+                global.select_shared_client(
+                    group_name,
+                    shared_clients,
+                    session.client_id(),
+                    message.topic_name(),
+                )
+            })
+        });
+        let Some((receiver_client_id, qos)) = receiver else {
+            log::debug!(
+                "client#{} no member left in shared group {} for {:?}",
+                session.client_id(),
+                group_name,
+                message.topic_name(),
+            );
+            global
+                .hooks()
+                .message_dropped(session.client_id(), &message, DropReason::NoSubscribers)
+                .await;
+            continue;
         };
-        let topic_name = msg.message().topic_name().to_owned();
-        let mut packet = PublishPacket::new(topic_name, qos, msg.message().payload());
-        packet.set_dup(msg.message().dup());
-        if let Some(properties) = msg.message().properties() {
-            packet.set_properties(properties.clone());
+
+        log::debug!(
+            "client#{} redeliver shared publish message to client#{}",
+            session.client_id(),
+            receiver_client_id,
+        );
+        if let Some(sender) = global.get_outgoing_sender(&receiver_client_id) {
+            // First delivery to that client
+            message.clear_dup();
+            if let Err(err) = sender.send(Outgoing::Publish(qos, Box::new(message))).await {
+                log::error!("{} send publish message: {}", receiver_client_id, err,)
+            }
         }
+    }
+}
 
-        packets.push(packet);
+// Messages of shared subscriptions the client did not acknowledge within the timeout go to
+// another online member of the group, they are settled with the client as if it had
+// acknowledged them. They stay with the client when it is the only member online.
+pub(super) async fn redeliver_unacked_shared_publish<Q>(
+    session: &mut Session,
+    global: Arc<GlobalState<Q>>,
+) where
+    Q: Queue,
+{
+    let Some(timeout) = global.shared_ack_timeout() else {
+        return;
+    };
+    for (packet_id, sent_qos, mut message) in session.take_expired_shared(timeout) {
+        let Some((group_name, filter)) = message.shared_filter().and_then(|f| f.shared_info())
+        else {
+            continue;
+        };
+        let receiver = shared_route(&global, &message, filter).and_then(|content| {
+            content.groups.get(group_name).and_then(|shared_clients| {
+                global.select_other_shared_client(
+                    group_name,
+                    shared_clients,
+                    session.client_id(),
+                    message.topic_name(),
+                )
+            })
+        });
+        let Some((receiver_client_id, qos)) = receiver else {
+            session.track_shared(packet_id, sent_qos, message);
+            continue;
+        };
+        let Some(sender) = global.get_outgoing_sender(&receiver_client_id) else {
+            session.track_shared(packet_id, sent_qos, message);
+            continue;
+        };
+
+        log::debug!(
+            "client#{} did not acknowledge shared publish message {} in {:?}, redeliver to client#{}",
+            session.client_id(),
+            packet_id,
+            timeout,
+            receiver_client_id,
+        );
+        settle_outgoing(session, packet_id, sent_qos, &global).await;
+        clean_outgoing(session, &global).await;
+        // First delivery to that client
+        message.clear_dup();
+        if let Err(err) = sender.send(Outgoing::Publish(qos, Box::new(message))).await {
+            log::error!("{} send publish message: {}", receiver_client_id, err,)
+        }
+    }
+}
+
+// Route of the filter of a shared subscription message, with the members of its groups.
+fn shared_route<Q>(
+    global: &GlobalState<Q>,
+    message: &PublishMessage,
+    filter: &str,
+) -> Option<Arc<RouteContent>>
+where
+    Q: Queue,
+{
+    global
+        .route_table()
+        .get_matches(message.topic_name())
+        .into_iter()
+        .find(|content| &**content.topic_filter == filter)
+}
+
+// Completes the delivery of an outgoing message the client will not acknowledge.
+async fn settle_outgoing<Q>(
+    session: &Session,
+    packet_id: u16,
+    qos: QualityOfService,
+    global: &GlobalState<Q>,
+) where
+    Q: Queue,
+{
+    let queue = global.packets_queue();
+    let client_id = session.client_id();
+    let result = match qos {
+        QualityOfService::Level0 => Ok(true),
+        QualityOfService::Level1 => queue.puback(client_id, packet_id).await,
+        QualityOfService::Level2 => match queue.pubrec(client_id, packet_id).await {
+            Ok(_) => queue.pubcomp(client_id, packet_id).await,
+            Err(err) => Err(err),
+        },
+    };
+    if let Err(err) = result {
+        log::error!("client#{} settle {}: {:?}", client_id, packet_id, err);
+    }
+}
+
+async fn clean_outgoing<Q>(session: &Session, global: &GlobalState<Q>)
+where
+    Q: Queue,
+{
+    if let Err(err) = global
+        .packets_queue()
+        .clean_outgoing(session.client_id())
+        .await
+    {
+        log::error!(
+            "client#{} clean outgoing packets: {:?}",
+            session.client_id(),
+            err
+        );
+    }
+}
+
+// Packets resent to a client resuming its session, the messages which no longer fit are
+// acknowledged and discarded like new ones.
+pub(crate) async fn get_unsent_outgoing_packet<Q>(
+    session: &mut Session,
+    global: Arc<GlobalState<Q>>,
+) -> Vec<PublishFrame>
+where
+    Q: Queue,
+{
+    let unsent = match global
+        .packets_queue()
+        .get_unsent_outgoing_packets(session.client_id())
+        .await
+    {
+        Ok(unsent) => unsent.unwrap_or_default(),
+        Err(err) => {
+            log::error!(
+                "client#{} get unsent outgoing packets: {:?}",
+                session.client_id(),
+                err
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut packets = Vec::with_capacity(unsent.len());
+    let mut discarded = false;
+    for msg in unsent {
+        let (packet_id, final_qos, message) = (msg.packet_id(), msg.final_qos(), msg.message());
+        let qos = match final_qos {
+            QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+            QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(packet_id),
+            QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(packet_id),
+        };
+        match fit_publish_frame(session, message, qos) {
+            Some(frame) => packets.push(frame),
+            None => {
+                settle_outgoing(session, packet_id, final_qos, &global).await;
+                discarded = true;
+            }
+        }
+    }
+    if discarded {
+        clean_outgoing(session, &global).await;
     }
 
     packets
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use mqtt_codec_kit::{common::TopicName, v5::control::PublishProperties};
+
+    use crate::{
+        server::limits::{PublishCounters, PublishLimits, PublishQuota},
+        store::memory::queue::MemoryQueue,
+    };
+
+    use super::*;
+
+    fn message(payload_size: usize) -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new("t").unwrap(),
+            Bytes::from(vec![0; payload_size]),
+            QualityOfService::Level1,
+            false,
+        )
+    }
+
+    async fn unsent_len(global: &GlobalState<MemoryQueue>, client_id: &str) -> usize {
+        global
+            .packets_queue()
+            .get_unsent_outgoing_packets(client_id)
+            .await
+            .unwrap()
+            .unwrap_or_default()
+            .len()
+    }
+
+    #[tokio::test]
+    async fn test_outgoing_publish_max_packet_size() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let mut session = Session::new("subscriber".to_owned(), false, 12);
+        session.set_max_packet_size(128);
+
+        // The user properties are left out to fit.
+        let mut properties = PublishProperties::default();
+        properties.add_user_property("key".to_owned(), "v".repeat(128));
+        let mut with_properties = message(64);
+        with_properties.set_properties(Some(properties));
+        assert!(with_properties.v5_frame().encoded_length() > 128);
+        let frame = receive_outgoing_publish(
+            &mut session,
+            QualityOfService::Level1,
+            with_properties,
+            &global,
+        )
+        .await
+        .unwrap();
+        assert!(frame.encoded_length() <= 128);
+        assert_eq!(unsent_len(&global, "subscriber").await, 1);
+
+        // Too large even without properties, discarded as if delivered.
+        let frame = receive_outgoing_publish(
+            &mut session,
+            QualityOfService::Level1,
+            message(256),
+            &global,
+        )
+        .await;
+        assert!(frame.is_none());
+        assert_eq!(session.publish_quota().counters().discarded(), 1);
+        assert_eq!(unsent_len(&global, "subscriber").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_resend_max_packet_size() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let queue = global.packets_queue();
+        for (packet_id, payload_size) in [(1, 16), (2, 256)] {
+            queue
+                .push_outgoing_back(
+                    "subscriber",
+                    packet_id,
+                    QualityOfService::Level1,
+                    message(payload_size),
+                )
+                .await
+                .unwrap();
+        }
+
+        // The client resumes its session with a smaller Maximum Packet Size.
+        let mut session = Session::new("subscriber".to_owned(), false, 12);
+        session.set_max_packet_size(128);
+        let packets = get_unsent_outgoing_packet(&mut session, global.clone()).await;
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].encoded_length() <= 128);
+        assert_eq!(session.publish_quota().counters().discarded(), 1);
+        // The discarded message is settled, it is not resent again.
+        assert_eq!(unsent_len(&global, "subscriber").await, 1);
+        assert_eq!(
+            get_unsent_outgoing_packet(&mut session, global.clone())
+                .await
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_quota_exceeded() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let mut session = Session::new("publisher".to_owned(), false, 12);
+        let limits = PublishLimits {
+            max_payload_size: Some(8),
+            ..Default::default()
+        };
+        session.set_publish_quota(PublishQuota::new(
+            limits,
+            Arc::new(PublishCounters::default()),
+        ));
+
+        let topic_name = TopicName::new("t").unwrap();
+        let packet = PublishPacket::new(
+            topic_name.clone(),
+            QoSWithPacketIdentifier::Level1(1),
+            vec![0; 16],
+        );
+        match handle_publish(&mut session, packet, global.clone()).await {
+            Ok((false, Some(VariablePacket::PubackPacket(puback)))) => {
+                assert_eq!(puback.reason_code(), PubackReasonCode::QuotaExceeded)
+            }
+            _ => panic!("over quota QoS 1 publish must be acknowledged"),
+        }
+        let packet =
+            PublishPacket::new(topic_name, QoSWithPacketIdentifier::Level2(2), vec![0; 16]);
+        match handle_publish(&mut session, packet, global.clone()).await {
+            Ok((false, Some(VariablePacket::PubrecPacket(pubrec)))) => {
+                assert_eq!(pubrec.reason_code(), PubrecReasonCode::QuotaExceeded)
+            }
+            _ => panic!("over quota QoS 2 publish must be acknowledged"),
+        }
+        // Nothing was kept for a PUBREL.
+        assert!(global
+            .packets_queue()
+            .pubrel("publisher", 2)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(session.publish_quota().counters().rejected(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_publish() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let (outgoing_tx, mut outgoing_rx) = tokio::sync::mpsc::channel(8);
+        global.add_client("subscriber", outgoing_tx).await;
+        global.subscribe(
+            &TopicFilter::new("t").unwrap(),
+            "subscriber",
+            QualityOfService::Level2,
+        );
+        let mut session = Session::new("publisher".to_owned(), false, 12);
+        let publish = |qos| {
+            let mut packet = PublishPacket::new(TopicName::new("t").unwrap(), qos, "payload");
+            packet.set_dup(true);
+            packet
+        };
+
+        // The first copy of a DUP message may have been lost.
+        let packet = publish(QoSWithPacketIdentifier::Level1(1));
+        handle_publish(&mut session, packet, global.clone())
+            .await
+            .unwrap();
+        assert!(matches!(outgoing_rx.try_recv(), Ok(Outgoing::Publish(..))));
+
+        for _ in 0..2 {
+            let packet = publish(QoSWithPacketIdentifier::Level2(2));
+            handle_publish(&mut session, packet, global.clone())
+                .await
+                .unwrap();
+        }
+        assert!(outgoing_rx.try_recv().is_err());
+        let pubcomp = handle_pubrel(&mut session, global.clone(), 2).await;
+        assert_eq!(pubcomp.reason_code(), PubcompReasonCode::Success);
+        assert!(matches!(outgoing_rx.try_recv(), Ok(Outgoing::Publish(..))));
+        let pubcomp = handle_pubrel(&mut session, global.clone(), 2).await;
+        assert_eq!(
+            pubcomp.reason_code(),
+            PubcompReasonCode::PacketIdentifierNotFound
+        );
+        assert!(outgoing_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_shared_subscription() {
+        let global = GlobalState::new(MemoryQueue::new(10, 10));
+        let (outgoing_tx, mut outgoing_rx) = tokio::sync::mpsc::channel(8);
+        global.add_client("member", outgoing_tx).await;
+        global.subscribe(
+            &TopicFilter::new("$share/group/t").unwrap(),
+            "member",
+            QualityOfService::Level1,
+        );
+        let session = Session::new("publisher".to_owned(), false, 12);
+        let mut message = PublishMessage::new(
+            TopicName::new("t").unwrap(),
+            Bytes::from_static(b"payload"),
+            QualityOfService::Level1,
+            false,
+        );
+        route_publish(&session, message.clone(), &global).await;
+        assert!(matches!(outgoing_rx.try_recv(), Ok(Outgoing::Publish(..))));
+
+        // The node it was published on chose a member of the group.
+        message.set_forwarded_from(Some(Arc::from("node")));
+        route_publish(&session, message, &global).await;
+        assert!(outgoing_rx.try_recv().is_err());
+    }
+}
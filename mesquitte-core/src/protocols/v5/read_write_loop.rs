@@ -4,44 +4,53 @@ use std::{
     time::{Duration, Instant},
 };
 
-use futures::{SinkExt as _, StreamExt as _};
+use futures_util::{SinkExt as _, StreamExt as _};
 use mqtt_codec_kit::v5::{
-    control::DisconnectReasonCode,
+    control::{ConnectReasonCode, DisconnectReasonCode},
     packet::{
-        DisconnectPacket, MqttDecoder, MqttEncoder, PingrespPacket, VariablePacket,
-        VariablePacketError,
+        ConnackPacket, DisconnectPacket, MqttDecoder, MqttEncoder, PingrespPacket, PublishFrame,
+        VariablePacket, VariablePacketError,
     },
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::mpsc,
-    time::interval_at,
+    time::{self, interval_at},
 };
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 
 use crate::{
-    server::state::GlobalState,
-    types::{outgoing::Outgoing, session::Session},
+    server::{limits::ReadPause, state::GlobalState},
+    store::queue::Queue,
+    types::{
+        connection::ConnectionInfo,
+        outgoing::{KickReason, Outgoing},
+        publish::PublishMessage,
+        session::Session,
+    },
 };
 
 use super::{
+    common::{build_error_disconnect, fit_max_packet_size},
     connect::{handle_connect, handle_disconnect},
     publish::{
         get_unsent_outgoing_packet, handle_puback, handle_pubcomp, handle_publish, handle_pubrec,
-        handle_pubrel, handle_will, receive_outgoing_publish,
+        handle_pubrel, handle_will, receive_outgoing_publish, redeliver_shared_publish,
+        redeliver_unacked_shared_publish,
     },
     subscribe::{handle_subscribe, handle_unsubscribe},
 };
 
-pub(super) async fn handle_incoming<T, E>(
+pub(super) async fn handle_incoming<T, E, Q>(
     writer: &mut FramedWrite<T, E>,
     session: &mut Session,
     packet: VariablePacket,
-    global: Arc<GlobalState>,
+    global: Arc<GlobalState<Q>>,
 ) -> io::Result<bool>
 where
     T: AsyncWrite + Unpin,
-    E: Encoder<VariablePacket, Error = io::Error>,
+    E: Encoder<VariablePacket, Error = io::Error> + Encoder<PublishFrame, Error = io::Error>,
+    Q: Queue,
 {
     log::debug!(
         r#"client#{} receive mqtt client incoming message: {:?}"#,
@@ -54,56 +63,74 @@ where
         VariablePacket::PingreqPacket(_packet) => {
             let pkt = PingrespPacket::new();
             log::debug!("write pingresp packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            writer
+                .send(fit_max_packet_size(session, pkt.into()))
+                .await?;
         }
         VariablePacket::PublishPacket(packet) => {
-            let (stop, ack) = handle_publish(session, packet, global.clone()).await;
+            let (stop, ack) = handle_publish(session, packet, global.clone()).await?;
             if let Some(pkt) = ack {
                 log::debug!("write puback packet: {:?}", pkt);
-                writer.send(pkt).await?;
+                writer.send(fit_max_packet_size(session, pkt)).await?;
             }
             should_stop = stop;
         }
         VariablePacket::PubrelPacket(packet) => {
             let pkt = handle_pubrel(session, global.clone(), packet.packet_identifier()).await;
             log::debug!("write pubcomp packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            writer
+                .send(fit_max_packet_size(session, pkt.into()))
+                .await?;
         }
         VariablePacket::PubackPacket(packet) => {
-            handle_puback(session, packet.packet_identifier());
+            handle_puback(session, global.clone(), packet.packet_identifier()).await;
         }
         VariablePacket::PubrecPacket(packet) => {
-            let pkt = handle_pubrec(session, packet.packet_identifier());
+            let pkt = handle_pubrec(session, global.clone(), packet.packet_identifier()).await;
             log::debug!("write pubrel packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            writer
+                .send(fit_max_packet_size(session, pkt.into()))
+                .await?;
         }
         VariablePacket::SubscribePacket(packet) => {
-            match handle_subscribe(session, packet, global.clone()) {
-                Ok(packets) => {
-                    log::debug!("write suback packets: {:?}", packets);
-                    for pkt in packets {
+            match handle_subscribe(session, packet, global.clone()).await {
+                Ok((pkt, retain_packets)) => {
+                    log::debug!("write suback packet: {:?}", pkt);
+                    writer
+                        .send(fit_max_packet_size(session, pkt.into()))
+                        .await?;
+                    // Already fitted to the Maximum Packet Size of the client, like
+                    // the other outgoing messages.
+                    for pkt in retain_packets {
+                        log::debug!("write retain publish packet: {:?}", pkt);
                         writer.send(pkt).await?;
                     }
                 }
                 Err(pkt) => {
                     log::debug!("write disconnect packet: {:?}", pkt);
-                    writer.send(pkt.into()).await?;
+                    writer
+                        .send(fit_max_packet_size(session, pkt.into()))
+                        .await?;
                     should_stop = true;
                 }
             }
         }
         VariablePacket::PubcompPacket(packet) => {
-            handle_pubcomp(session, packet.packet_identifier());
+            handle_pubcomp(session, global.clone(), packet.packet_identifier()).await;
         }
         VariablePacket::UnsubscribePacket(packet) => {
-            let pkt = handle_unsubscribe(session, &packet, global.clone());
+            let pkt = handle_unsubscribe(session, &packet, global.clone()).await;
             log::debug!("write unsuback packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            writer
+                .send(fit_max_packet_size(session, pkt.into()))
+                .await?;
         }
         VariablePacket::DisconnectPacket(packet) => {
             if let Some(pkt) = handle_disconnect(session, packet).await {
                 log::debug!("write disconnect packet: {:?}", pkt);
-                writer.send(pkt.into()).await?;
+                writer
+                    .send(fit_max_packet_size(session, pkt.into()))
+                    .await?;
             }
             should_stop = true;
         }
@@ -118,19 +145,32 @@ where
     Ok(should_stop)
 }
 
-pub(super) async fn receive_outgoing(
+// Packet written to the client for an outgoing message, publish messages are pre-encoded
+// once for all receivers.
+pub(super) enum OutgoingPacket {
+    Packet(Box<VariablePacket>),
+    Publish(PublishFrame),
+}
+
+pub(super) async fn receive_outgoing<Q>(
     session: &mut Session,
     packet: Outgoing,
-    global: Arc<GlobalState>,
-) -> (bool, Option<VariablePacket>) {
+    global: Arc<GlobalState<Q>>,
+) -> (bool, Option<OutgoingPacket>)
+where
+    Q: Queue,
+{
     let mut should_stop = false;
     let resp = match packet {
         Outgoing::Publish(subscribe_qos, packet) => {
-            let packet = receive_outgoing_publish(session, subscribe_qos, *packet);
-            if session.disconnected() {
-                None
-            } else {
-                Some(packet.into())
+            // Messages too large for the client are discarded as if they had been
+            // delivered.
+            if !session.disconnected() {
+                global.hooks().message_delivered(session, &packet).await;
+            }
+            match receive_outgoing_publish(session, subscribe_qos, *packet, &global).await {
+                Some(frame) if !session.disconnected() => Some(OutgoingPacket::Publish(frame)),
+                _ => None,
             }
         }
         Outgoing::Online(sender) => {
@@ -149,7 +189,9 @@ pub(super) async fn receive_outgoing(
             if session.disconnected() {
                 None
             } else {
-                Some(DisconnectPacket::new(DisconnectReasonCode::SessionTakenOver).into())
+                Some(OutgoingPacket::Packet(Box::new(
+                    DisconnectPacket::new(DisconnectReasonCode::SessionTakenOver).into(),
+                )))
             }
         }
         Outgoing::Kick(reason) => {
@@ -159,12 +201,22 @@ pub(super) async fn receive_outgoing(
                 reason,
             );
 
-            if session.disconnected() && !session.clean_session() {
+            // A session taken over on another node is resumed there.
+            if session.disconnected() && !session.clean_session() && reason != KickReason::Takeover
+            {
                 None
             } else {
                 should_stop = true;
                 global.remove_client(session.client_id(), session.subscriptions());
-                Some(DisconnectPacket::new(DisconnectReasonCode::AdministrativeAction).into())
+                global.remove_packets(session.client_id()).await;
+                global.hooks().session_expired(session).await;
+                let reason_code = match reason {
+                    KickReason::FromAdmin => DisconnectReasonCode::AdministrativeAction,
+                    KickReason::Takeover => DisconnectReasonCode::SessionTakenOver,
+                };
+                Some(OutgoingPacket::Packet(Box::new(
+                    DisconnectPacket::new(reason_code).into(),
+                )))
             }
         }
     };
@@ -172,33 +224,45 @@ pub(super) async fn receive_outgoing(
     (should_stop, resp)
 }
 
-pub(super) async fn handle_outgoing<T, E>(
+pub(super) async fn handle_outgoing<T, E, Q>(
     writer: &mut FramedWrite<T, E>,
     session: &mut Session,
     packet: Outgoing,
-    global: Arc<GlobalState>,
+    global: Arc<GlobalState<Q>>,
 ) -> bool
 where
     T: AsyncWrite + Unpin,
-    E: Encoder<VariablePacket, Error = io::Error>,
+    E: Encoder<VariablePacket, Error = io::Error> + Encoder<PublishFrame, Error = io::Error>,
+    Q: Queue,
 {
     let (should_stop, resp) = receive_outgoing(session, packet, global).await;
-    if let Some(packet) = resp {
-        log::debug!("write packet: {:?}", packet);
-        if let Err(err) = writer.send(packet).await {
-            log::error!("write packet failed: {err}");
-            return true;
+    let result = match resp {
+        Some(OutgoingPacket::Packet(packet)) => {
+            log::debug!("write packet: {:?}", packet);
+            writer.send(fit_max_packet_size(session, *packet)).await
         }
+        // Publish frames already fit the client's Maximum Packet Size.
+        Some(OutgoingPacket::Publish(frame)) => {
+            log::debug!("write publish packet: {:?}", frame);
+            writer.send(frame).await
+        }
+        None => Ok(()),
+    };
+    if let Err(err) = result {
+        log::error!("write packet failed: {err}");
+        return true;
     }
 
     should_stop
 }
 
-pub(super) async fn handle_clean_session(
+pub(super) async fn handle_clean_session<Q>(
     mut session: Session,
     mut outgoing_rx: mpsc::Receiver<Outgoing>,
-    global: Arc<GlobalState>,
-) {
+    global: Arc<GlobalState<Q>>,
+) where
+    Q: Queue,
+{
     log::debug!(
         r#"client#{} handle offline:
  clean session : {}
@@ -236,6 +300,11 @@ session expiry : {}"#,
                 }
                 _ = tick.tick() => {
                     log::debug!("handle clean session client#{} session expired", session.client_id());
+                    global.remove_client(session.client_id(), session.subscriptions());
+                    global.hooks().session_expired(&session).await;
+                    let messages = take_outgoing_publish(&mut outgoing_rx);
+                    redeliver_shared_publish(&mut session, messages, global.clone()).await;
+                    global.remove_packets(session.client_id()).await;
                     break;
                 }
             }
@@ -243,6 +312,10 @@ session expiry : {}"#,
     } else {
         if session.clean_session() {
             global.remove_client(session.client_id(), session.subscriptions());
+            global.hooks().session_expired(&session).await;
+            let messages = take_outgoing_publish(&mut outgoing_rx);
+            redeliver_shared_publish(&mut session, messages, global.clone()).await;
+            global.remove_packets(session.client_id()).await;
             return;
         }
 
@@ -255,23 +328,36 @@ session expiry : {}"#,
     }
 }
 
-async fn read_from_client<T, D>(mut reader: FramedRead<T, D>, msg_tx: mpsc::Sender<VariablePacket>)
-where
+// Reading pauses while the client is throttled, the transport pushes back on it.
+async fn read_from_client<T, D>(
+    mut reader: FramedRead<T, D>,
+    msg_tx: mpsc::Sender<Result<VariablePacket, DisconnectReasonCode>>,
+    read_pause: Arc<ReadPause>,
+) where
     T: AsyncRead + Unpin,
     D: Decoder<Item = VariablePacket, Error = VariablePacketError>,
 {
     loop {
+        if let Some(delay) = read_pause.remaining() {
+            time::sleep(delay).await;
+        }
         match reader.next().await {
             None => {
                 log::info!("client closed");
                 break;
             }
+            Some(Err(VariablePacketError::PacketTooLarge(size, max))) => {
+                log::warn!("read from client: packet size {size} exceeds {max}");
+                // Let the write task tell the client before closing
+                let _ = msg_tx.send(Err(DisconnectReasonCode::PacketTooLarge)).await;
+                break;
+            }
             Some(Err(e)) => {
                 log::warn!("read from client: {}", e);
                 break;
             }
             Some(Ok(packet)) => {
-                if let Err(err) = msg_tx.send(packet).await {
+                if let Err(err) = msg_tx.send(Ok(packet)).await {
                     log::error!("receiver closed: {}", err);
                     break;
                 }
@@ -280,16 +366,34 @@ where
     }
 }
 
-async fn write_to_client<T, E>(
+// Publish messages left in the channel of a session that is over
+fn take_outgoing_publish(outgoing_rx: &mut mpsc::Receiver<Outgoing>) -> Vec<PublishMessage> {
+    outgoing_rx.close();
+    let mut messages = Vec::new();
+    while let Ok(packet) = outgoing_rx.try_recv() {
+        if let Outgoing::Publish(_, message) = packet {
+            messages.push(*message);
+        }
+    }
+    messages
+}
+
+async fn write_to_client<T, E, Q>(
     mut session: Session,
     mut writer: FramedWrite<T, E>,
-    mut incoming_rx: mpsc::Receiver<VariablePacket>,
+    mut incoming_rx: mpsc::Receiver<Result<VariablePacket, DisconnectReasonCode>>,
     mut outgoing_rx: mpsc::Receiver<Outgoing>,
-    global: Arc<GlobalState>,
+    global: Arc<GlobalState<Q>>,
 ) where
     T: AsyncWrite + Unpin,
-    E: Encoder<VariablePacket, Error = io::Error>,
+    E: Encoder<VariablePacket, Error = io::Error> + Encoder<PublishFrame, Error = io::Error>,
+    Q: Queue + 'static,
 {
+    // Messages of shared subscriptions not acknowledged in time go to other members.
+    let shared_ack_period = global
+        .shared_ack_timeout()
+        .map(|timeout| (timeout / 2).max(Duration::from_millis(10)));
+    let mut shared_ack_tick = time::interval(shared_ack_period.unwrap_or(Duration::from_secs(1)));
     if session.keep_alive() > 0 {
         let half_interval = Duration::from_millis(session.keep_alive() as u64 * 500);
         let mut keep_alive_tick =
@@ -298,7 +402,15 @@ async fn write_to_client<T, E>(
         loop {
             tokio::select! {
                 packet = incoming_rx.recv() => match packet {
-                    Some(p) => match handle_incoming(&mut writer, &mut session, p, global.clone()).await {
+                    Some(Err(reason_code)) => {
+                        let pkt = build_error_disconnect(&mut session, reason_code, "packet too large");
+                        log::debug!("write disconnect packet: {:?}", pkt);
+                        if let Err(err) = writer.send(VariablePacket::from(pkt)).await {
+                            log::error!("write disconnect packet failed: {err}");
+                        }
+                        break;
+                    }
+                    Some(Ok(p)) => match handle_incoming(&mut writer, &mut session, p, global.clone()).await {
                         Ok(true) => break,
                         Ok(false) => continue,
                         Err(err) => {
@@ -320,8 +432,14 @@ async fn write_to_client<T, E>(
                         break;
                     }
                 },
+                _ = shared_ack_tick.tick(), if shared_ack_period.is_some() => {
+                    redeliver_unacked_shared_publish(&mut session, global.clone()).await;
+                },
                 _ = keep_alive_tick.tick() => {
-                    if session.last_packet_at().elapsed() > keep_alive_timeout {
+                    // The packets left unread while the client is throttled keep it alive.
+                    if session.publish_quota().read_pause().remaining().is_some() {
+                        session.renew_last_packet_at();
+                    } else if session.last_packet_at().elapsed() > keep_alive_timeout {
                         break;
                     }
                 },
@@ -331,7 +449,15 @@ async fn write_to_client<T, E>(
         loop {
             tokio::select! {
                 packet = incoming_rx.recv() => match packet {
-                    Some(p) => match handle_incoming(&mut writer, &mut session, p, global.clone()).await {
+                    Some(Err(reason_code)) => {
+                        let pkt = build_error_disconnect(&mut session, reason_code, "packet too large");
+                        log::debug!("write disconnect packet: {:?}", pkt);
+                        if let Err(err) = writer.send(VariablePacket::from(pkt)).await {
+                            log::error!("write disconnect packet failed: {err}");
+                        }
+                        break;
+                    }
+                    Some(Ok(p)) => match handle_incoming(&mut writer, &mut session, p, global.clone()).await {
                         Ok(true) => break,
                         Ok(false) => continue,
                         Err(err) => {
@@ -353,30 +479,58 @@ async fn write_to_client<T, E>(
                         break;
                     }
                 },
+                _ = shared_ack_tick.tick(), if shared_ack_period.is_some() => {
+                    redeliver_unacked_shared_publish(&mut session, global.clone()).await;
+                },
             }
         }
     };
 
+    global.set_client_offline(session.client_id());
+    global.hooks().client_disconnected(&session).await;
     tokio::spawn(handle_clean_session(session, outgoing_rx, global.clone()));
 }
 
-pub async fn read_write_loop<R, W>(reader: R, writer: W, global: Arc<GlobalState>)
-where
+pub async fn read_write_loop<R, W, Q>(
+    reader: R,
+    writer: W,
+    connection: ConnectionInfo,
+    global: Arc<GlobalState<Q>>,
+) where
     R: AsyncRead + Unpin + Send + 'static,
     W: AsyncWrite + Unpin + Send + 'static,
+    Q: Queue + Send + 'static,
 {
-    let mut frame_reader = FramedRead::new(reader, MqttDecoder::new());
+    let mut decoder = MqttDecoder::new();
+    decoder.set_max_packet_size(global.max_packet_size());
+    let mut frame_reader = FramedRead::new(reader, decoder);
     let mut frame_writer = FramedWrite::new(writer, MqttEncoder::new());
 
+    // Repeated refused CONNECTs get the client address banned for a while, refusals
+    // because of connection limits do not count, nor connections closed before one.
+    let ban_ip = match connection.limit_exceeded() {
+        Some(_) => None,
+        None => connection.remote_addr().map(|addr| addr.ip()),
+    };
+
     let packet = match frame_reader.next().await {
         Some(Ok(VariablePacket::ConnectPacket(packet))) => packet,
+        Some(Err(VariablePacketError::PacketTooLarge(size, max))) => {
+            log::warn!("CONNECT packet size {size} exceeds {max}");
+            let pkt = ConnackPacket::new(false, ConnectReasonCode::PacketTooLarge);
+            if let Err(err) = frame_writer.send(VariablePacket::from(pkt)).await {
+                log::error!("write connect ack: {err}");
+            }
+            return;
+        }
         _ => {
             log::warn!("first packet is not CONNECT packet");
             return;
         }
     };
 
-    let (mut session, outgoing_rx) = match handle_connect(packet, global.clone()).await {
+    let (mut session, outgoing_rx) = match handle_connect(packet, connection, global.clone()).await
+    {
         Ok((pkt, session, outgoing_rx)) => {
             if let Err(err) = frame_writer.send(pkt).await {
                 log::error!("handle connect write connect ack: {err}");
@@ -385,6 +539,9 @@ where
             (session, outgoing_rx)
         }
         Err(pkt) => {
+            if let Some(ip) = ban_ip {
+                global.ban_list().record_failure(ip);
+            }
             if let Err(err) = frame_writer.send(pkt).await {
                 log::error!("handle connect write connect ack: {err}");
             }
@@ -392,17 +549,20 @@ where
         }
     };
 
-    let packets = get_unsent_outgoing_packet(&mut session);
+    let packets = get_unsent_outgoing_packet(&mut session, global.clone()).await;
     for pkt in packets {
         if let Err(err) = frame_writer.send(pkt).await {
             log::error!("write pending packet failed: {err}");
             return;
         }
     }
+    global.set_client_online(session.client_id());
+    global.hooks().client_connected(&session).await;
 
     let (msg_tx, msg_rx) = mpsc::channel(8);
+    let read_pause = session.publish_quota().read_pause().clone();
     let mut read_task = tokio::spawn(async move {
-        read_from_client(frame_reader, msg_tx).await;
+        read_from_client(frame_reader, msg_tx, read_pause).await;
     });
 
     let mut write_task = tokio::spawn(async move {
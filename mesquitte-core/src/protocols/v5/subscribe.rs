@@ -1,25 +1,28 @@
-use std::{collections::VecDeque, sync::Arc};
+use std::sync::Arc;
 
 use mqtt_codec_kit::{
-    common::QualityOfService,
+    common::{QualityOfService, TopicFilter, MATCH_ALL_CHAR, MATCH_ONE_CHAR, SHARED_PREFIX},
     v5::{
         control::DisconnectReasonCode,
         packet::{
-            suback::SubscribeReasonCode, subscribe::RetainHandling, DisconnectPacket, SubackPacket,
-            SubscribePacket, UnsubackPacket, UnsubscribePacket, VariablePacket,
+            suback::SubscribeReasonCode, subscribe::RetainHandling, DisconnectPacket, PublishFrame,
+            SubackPacket, SubscribePacket, UnsubackPacket, UnsubscribePacket,
         },
     },
 };
 
-use crate::{server::state::GlobalState, types::session::Session};
+use crate::{server::state::GlobalState, store::queue::Queue, types::session::Session};
 
 use super::{common::build_error_disconnect, publish::receive_outgoing_publish};
 
-pub(super) fn handle_subscribe(
+pub(super) async fn handle_subscribe<Q>(
     session: &mut Session,
     packet: SubscribePacket,
-    global: Arc<GlobalState>,
-) -> Result<Vec<VariablePacket>, DisconnectPacket> {
+    global: Arc<GlobalState<Q>>,
+) -> Result<(SubackPacket, Vec<PublishFrame>), DisconnectPacket>
+where
+    Q: Queue,
+{
     log::debug!(
         r#"{} received a subscribe packet:
  packet id : {}
@@ -45,16 +48,31 @@ properties : {:?}"#,
     // properties.identifier().is_some() && !config.subscription_id_available()
 
     let mut reason_codes = Vec::with_capacity(packet.subscribes().len());
-    let mut retain_packets: Vec<VariablePacket> = Vec::new();
+    let mut retain_packets = Vec::new();
     for (filter, subscribe_opts) in packet.subscribes() {
-        // TODO: shared subscribe
-        // SubscribeReasonCode::SharedSubscriptionNotSupported
         // SubscribeReasonCode::WildcardSubscriptionsNotSupported topic contain +/#
+        if filter.starts_with(SHARED_PREFIX) {
+            // [MQTT-3.8.3-4] It is a Protocol Error to set the No Local bit to 1 on a
+            // Shared Subscription
+            if subscribe_opts.no_local {
+                let disconnect_packet = build_error_disconnect(
+                    session,
+                    DisconnectReasonCode::ProtocolError,
+                    "No Local is not allowed on shared subscriptions",
+                );
+                return Err(disconnect_packet);
+            }
+            if !is_valid_shared_filter(filter) {
+                reason_codes.push(SubscribeReasonCode::TopicFilterInvalid);
+                continue;
+            }
+        }
 
         let granted_qos = subscribe_opts.qos().to_owned();
         // TODO: granted max qos from config
         let exist = session.subscribe(filter.clone());
         global.subscribe(filter, session.client_id(), granted_qos);
+        global.hooks().subscribe(session, filter, granted_qos).await;
 
         // TODO: config: retain available?
         let send_retain = !filter.is_shared()
@@ -70,10 +88,12 @@ properties : {:?}"#,
                     continue;
                 }
 
-                let mut packet = receive_outgoing_publish(session, granted_qos, msg.into());
-                packet.set_retain(true);
-
-                retain_packets.push(packet.into());
+                if let Some(mut packet) =
+                    receive_outgoing_publish(session, granted_qos, msg.into(), &global).await
+                {
+                    packet.set_retain(true);
+                    retain_packets.push(packet);
+                }
             }
         }
 
@@ -85,18 +105,31 @@ properties : {:?}"#,
 
         reason_codes.push(reason_code);
     }
-    let mut queue: VecDeque<VariablePacket> = VecDeque::from(retain_packets);
     let suback_packet = SubackPacket::new(packet.packet_identifier(), reason_codes);
     // TODO: user properties
-    queue.push_front(suback_packet.into());
-    Ok(queue.into())
+    Ok((suback_packet, retain_packets))
+}
+
+// `$share/{group}/{filter}`, the group name must not be empty nor contain wildcards
+fn is_valid_shared_filter(filter: &TopicFilter) -> bool {
+    match filter.shared_info() {
+        Some((group_name, shared_filter)) => {
+            !group_name.is_empty()
+                && !group_name.contains([MATCH_ALL_CHAR, MATCH_ONE_CHAR])
+                && !shared_filter.is_empty()
+        }
+        None => false,
+    }
 }
 
-pub(super) fn handle_unsubscribe(
+pub(super) async fn handle_unsubscribe<Q>(
     session: &mut Session,
     packet: &UnsubscribePacket,
-    global: Arc<GlobalState>,
-) -> UnsubackPacket {
+    global: Arc<GlobalState<Q>>,
+) -> UnsubackPacket
+where
+    Q: Queue,
+{
     log::debug!(
         r#"client#{} received a unsubscribe packet:
 packet id : {}
@@ -110,7 +143,54 @@ packet id : {}
     for filter in packet.subscribes() {
         global.unsubscribe(filter, session.client_id());
         session.unsubscribe(filter);
+        global.hooks().unsubscribe(session, filter).await;
     }
 
     UnsubackPacket::new(packet.packet_identifier(), reason_codes)
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use mqtt_codec_kit::{
+        common::{Encodable, TopicName},
+        v5::packet::subscribe::SubscribeOptions,
+    };
+
+    use crate::{
+        store::memory::queue::MemoryQueue,
+        types::{publish::PublishMessage, retain_content::RetainContent},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retained_exceeding_max_packet_size() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        for (topic_name, size) in [("r/small", 16), ("r/large", 1024)] {
+            let message = PublishMessage::new(
+                TopicName::new(topic_name).unwrap(),
+                Bytes::from(vec![0; size]),
+                QualityOfService::Level1,
+                true,
+            );
+            global
+                .retain_table()
+                .insert(Arc::new(RetainContent::from(("publisher", &message))));
+        }
+
+        let mut session = Session::new("subscriber".to_owned(), false, 12);
+        session.set_max_packet_size(256);
+        let mut options = SubscribeOptions::default();
+        options.set_qos(QualityOfService::Level1);
+        let packet = SubscribePacket::new(1, vec![(TopicFilter::new("r/#").unwrap(), options)]);
+        let (suback, retain_packets) = handle_subscribe(&mut session, packet, global.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(suback.reason_code(), [SubscribeReasonCode::GrantedQos1]);
+        assert_eq!(retain_packets.len(), 1);
+        assert!(retain_packets[0].encoded_length() <= 256);
+        assert_eq!(session.publish_quota().counters().discarded(), 1);
+    }
+}
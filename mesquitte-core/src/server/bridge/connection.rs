@@ -0,0 +1,388 @@
+use futures_util::{SinkExt as _, StreamExt as _};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter},
+    v4::{self, control::ConnectReturnCode, packet::suback::SubscribeReturnCode},
+    v5::{
+        self,
+        control::ConnectReasonCode,
+        packet::{suback::SubscribeReasonCode, subscribe::SubscribeOptions},
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time,
+};
+use tokio_util::codec::Framed;
+
+use crate::{server::config::DEFAULT_HANDSHAKE_TIMEOUT, types::publish::PublishMessage};
+
+use super::{BridgeConfig, BridgeProtocol, BridgeTlsConfig, Error};
+
+pub(super) trait RemoteStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> RemoteStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+type BoxStream = Box<dyn RemoteStream>;
+
+pub(super) enum Event {
+    Publish(Box<PublishMessage>, Option<u16>),
+    Puback(u16),
+    /// Whether all the subscriptions were granted
+    Suback(bool),
+    Other,
+}
+
+enum Transport {
+    V4(Framed<BoxStream, v4::packet::MqttCodec>),
+    V5(Framed<BoxStream, v5::packet::MqttCodec>),
+}
+
+/// Connection of a bridge to its remote broker, with a clean session.
+pub(super) struct Connection {
+    transport: Transport,
+    packet_id: u16,
+}
+
+impl Connection {
+    /// Connects and sends the subscriptions, their SUBACK is received as an [`Event`].
+    pub(super) async fn connect(
+        config: &BridgeConfig,
+        subscribes: &[(TopicFilter, QualityOfService)],
+    ) -> Result<Self, Error> {
+        let stream = time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, open(config))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        let transport = match config.protocol {
+            BridgeProtocol::V4 => {
+                let mut framed = Framed::new(stream, v4::packet::MqttCodec::new());
+                let mut packet = v4::packet::ConnectPacket::new(config.client_id.clone());
+                packet.set_clean_session(true);
+                packet.set_keep_alive(config.keep_alive);
+                packet.set_username(config.username.clone());
+                packet.set_password(config.password.clone());
+                framed.send(packet).await?;
+                Transport::V4(framed)
+            }
+            BridgeProtocol::V5 => {
+                let mut framed = Framed::new(stream, v5::packet::MqttCodec::new());
+                let mut packet = v5::packet::ConnectPacket::new(config.client_id.clone());
+                packet.set_clean_session(true);
+                packet.set_keep_alive(config.keep_alive);
+                packet.set_username(config.username.clone());
+                packet.set_password(config.password.clone());
+                framed.send(packet).await?;
+                Transport::V5(framed)
+            }
+        };
+
+        let mut connection = Self {
+            transport,
+            packet_id: 0,
+        };
+        time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, connection.connack())
+            .await
+            .map_err(|_| Error::Timeout)??;
+        if !subscribes.is_empty() {
+            connection.subscribe(subscribes).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn connack(&mut self) -> Result<(), Error> {
+        match &mut self.transport {
+            Transport::V4(framed) => match next_v4(framed).await? {
+                v4::packet::VariablePacket::ConnackPacket(packet) => {
+                    match packet.connect_return_code() {
+                        ConnectReturnCode::ConnectionAccepted => Ok(()),
+                        code => Err(Error::Refused(format!("{code:?}"))),
+                    }
+                }
+                packet => Err(Error::UnexpectedPacket(format!("{packet:?}"))),
+            },
+            Transport::V5(framed) => match next_v5(framed).await? {
+                v5::packet::VariablePacket::ConnackPacket(packet) => {
+                    match packet.connect_reason_code() {
+                        ConnectReasonCode::Success => Ok(()),
+                        code => Err(Error::Refused(format!("{code:?}"))),
+                    }
+                }
+                packet => Err(Error::UnexpectedPacket(format!("{packet:?}"))),
+            },
+        }
+    }
+
+    async fn subscribe(
+        &mut self,
+        subscribes: &[(TopicFilter, QualityOfService)],
+    ) -> Result<(), Error> {
+        let packet_id = self.next_packet_id();
+        match &mut self.transport {
+            Transport::V4(framed) => {
+                let packet = v4::packet::SubscribePacket::new(packet_id, subscribes.to_vec());
+                framed.send(packet).await?;
+            }
+            Transport::V5(framed) => {
+                // The remote broker does not send back the messages of the bridge.
+                let subscribes = subscribes
+                    .iter()
+                    .map(|(filter, qos)| {
+                        let options = SubscribeOptions {
+                            qos: *qos,
+                            no_local: true,
+                            ..Default::default()
+                        };
+                        (filter.clone(), options)
+                    })
+                    .collect();
+                let packet = v5::packet::SubscribePacket::new(packet_id, subscribes);
+                framed.send(packet).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a message at QoS 0 or 1, returns its packet identifier at QoS 1.
+    pub(super) async fn publish(&mut self, message: &PublishMessage) -> Result<Option<u16>, Error> {
+        let (packet_id, qos) = match message.qos() {
+            QualityOfService::Level0 => (None, QoSWithPacketIdentifier::Level0),
+            _ => {
+                let packet_id = self.next_packet_id();
+                (Some(packet_id), QoSWithPacketIdentifier::Level1(packet_id))
+            }
+        };
+        let dup = packet_id.is_some() && message.dup();
+
+        match &mut self.transport {
+            Transport::V4(framed) => {
+                let mut packet = v4::packet::PublishPacket::new(
+                    message.topic_name().to_owned(),
+                    qos,
+                    message.payload().clone(),
+                );
+                packet.set_retain(message.retain());
+                packet.set_dup(dup);
+                framed.send(packet).await?;
+            }
+            Transport::V5(framed) => {
+                let mut packet = v5::packet::PublishPacket::new(
+                    message.topic_name().to_owned(),
+                    qos,
+                    message.payload().clone(),
+                );
+                packet.set_retain(message.retain());
+                packet.set_dup(dup);
+                if let Some(properties) = message.properties() {
+                    // Aliases are specific to the connection the message came from.
+                    let mut properties = properties.clone();
+                    properties.set_topic_alias(None);
+                    packet.set_properties(properties);
+                }
+                framed.send(packet).await?;
+            }
+        }
+        Ok(packet_id)
+    }
+
+    pub(super) async fn puback(&mut self, packet_id: u16) -> Result<(), Error> {
+        match &mut self.transport {
+            Transport::V4(framed) => {
+                framed
+                    .send(v4::packet::PubackPacket::new(packet_id))
+                    .await?
+            }
+            Transport::V5(framed) => {
+                framed
+                    .send(v5::packet::PubackPacket::new_success(packet_id))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) async fn ping(&mut self) -> Result<(), Error> {
+        match &mut self.transport {
+            Transport::V4(framed) => framed.send(v4::packet::PingreqPacket::new()).await?,
+            Transport::V5(framed) => framed.send(v5::packet::PingreqPacket::new()).await?,
+        }
+        Ok(())
+    }
+
+    pub(super) async fn next(&mut self) -> Result<Event, Error> {
+        let event = match &mut self.transport {
+            Transport::V4(framed) => match next_v4(framed).await? {
+                v4::packet::VariablePacket::PublishPacket(packet) => {
+                    let packet_id = packet_id(packet.qos())?;
+                    Event::Publish(Box::new(packet.into()), packet_id)
+                }
+                v4::packet::VariablePacket::PubackPacket(packet) => {
+                    Event::Puback(packet.packet_identifier())
+                }
+                v4::packet::VariablePacket::SubackPacket(packet) => {
+                    Event::Suback(!packet.subscribes().contains(&SubscribeReturnCode::Failure))
+                }
+                v4::packet::VariablePacket::DisconnectPacket(_) => return Err(Error::Closed),
+                _ => Event::Other,
+            },
+            Transport::V5(framed) => match next_v5(framed).await? {
+                v5::packet::VariablePacket::PublishPacket(packet) => {
+                    let packet_id = packet_id(packet.qos())?;
+                    Event::Publish(Box::new(packet.into()), packet_id)
+                }
+                v5::packet::VariablePacket::PubackPacket(packet) => {
+                    Event::Puback(packet.packet_identifier())
+                }
+                v5::packet::VariablePacket::SubackPacket(packet) => {
+                    Event::Suback(packet.reason_code().iter().all(|code| {
+                        matches!(
+                            code,
+                            SubscribeReasonCode::GrantedQos0
+                                | SubscribeReasonCode::GrantedQos1
+                                | SubscribeReasonCode::GrantedQos2
+                        )
+                    }))
+                }
+                v5::packet::VariablePacket::DisconnectPacket(_) => return Err(Error::Closed),
+                _ => Event::Other,
+            },
+        };
+        Ok(event)
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        self.packet_id = self.packet_id.wrapping_add(1).max(1);
+        self.packet_id
+    }
+}
+
+// The bridge subscribes at QoS 1 at most.
+fn packet_id(qos: QoSWithPacketIdentifier) -> Result<Option<u16>, Error> {
+    match qos {
+        QoSWithPacketIdentifier::Level0 => Ok(None),
+        QoSWithPacketIdentifier::Level1(packet_id) => Ok(Some(packet_id)),
+        QoSWithPacketIdentifier::Level2(_) => {
+            Err(Error::UnexpectedPacket("QoS 2 publish".to_owned()))
+        }
+    }
+}
+
+async fn next_v4(
+    framed: &mut Framed<BoxStream, v4::packet::MqttCodec>,
+) -> Result<v4::packet::VariablePacket, Error> {
+    match framed.next().await {
+        Some(Ok(packet)) => Ok(packet),
+        Some(Err(err)) => Err(Error::Decode(err.to_string())),
+        None => Err(Error::Closed),
+    }
+}
+
+async fn next_v5(
+    framed: &mut Framed<BoxStream, v5::packet::MqttCodec>,
+) -> Result<v5::packet::VariablePacket, Error> {
+    match framed.next().await {
+        Some(Ok(packet)) => Ok(packet),
+        Some(Err(err)) => Err(Error::Decode(err.to_string())),
+        None => Err(Error::Closed),
+    }
+}
+
+async fn open(config: &BridgeConfig) -> Result<BoxStream, Error> {
+    let host = match config.addr.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => &config.addr,
+    };
+    let stream = TcpStream::connect(&config.addr).await?;
+    stream.set_nodelay(true)?;
+    let stream = match &config.tls {
+        Some(tls) => tls_connect(tls, host, stream).await?,
+        None => Box::new(stream),
+    };
+    match &config.websocket_path {
+        Some(path) => ws_connect(stream, &config.addr, path, config.tls.is_some()).await,
+        None => Ok(stream),
+    }
+}
+
+#[cfg(feature = "rustls")]
+async fn tls_connect(
+    tls: &BridgeTlsConfig,
+    host: &str,
+    stream: TcpStream,
+) -> Result<BoxStream, Error> {
+    use std::sync::Arc;
+
+    use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use crate::server::rustls::{load_certs, load_key};
+
+    fn tls_error(err: impl std::fmt::Display) -> Error {
+        Error::Tls(err.to_string())
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&tls.ca_file).map_err(tls_error)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let client_config = match &tls.client_cert {
+        Some(files) => {
+            let certs = load_certs(&files.cert_file).map_err(tls_error)?;
+            let key = load_key(&files.key_file).map_err(tls_error)?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(tls_error)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_name = tls.server_name.as_deref().unwrap_or(host).to_owned();
+    let server_name = ServerName::try_from(server_name).map_err(tls_error)?;
+    let stream = TlsConnector::from(Arc::new(client_config))
+        .connect(server_name, stream)
+        .await?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(feature = "rustls"))]
+async fn tls_connect(
+    _tls: &BridgeTlsConfig,
+    _host: &str,
+    _stream: TcpStream,
+) -> Result<BoxStream, Error> {
+    Err(Error::Tls("built without the rustls feature".to_owned()))
+}
+
+#[cfg(any(feature = "ws", feature = "wss"))]
+async fn ws_connect(
+    stream: BoxStream,
+    addr: &str,
+    path: &str,
+    tls: bool,
+) -> Result<BoxStream, Error> {
+    use async_tungstenite::{client_async, tokio::TokioAdapter};
+    use tungstenite::{client::IntoClientRequest as _, http::HeaderValue};
+
+    use crate::server::ws::ws_stream::WsByteStream;
+
+    let scheme = if tls { "wss" } else { "ws" };
+    let mut request = format!("{scheme}://{addr}{path}").into_client_request()?;
+    // see: [MQTT-6.0.0-3]
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+    let (stream, _) = client_async(request, TokioAdapter::new(stream)).await?;
+    Ok(Box::new(WsByteStream::new(stream)))
+}
+
+#[cfg(not(any(feature = "ws", feature = "wss")))]
+async fn ws_connect(
+    _stream: BoxStream,
+    _addr: &str,
+    _path: &str,
+    _tls: bool,
+) -> Result<BoxStream, Error> {
+    Err(Error::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "built without the ws feature",
+    )))
+}
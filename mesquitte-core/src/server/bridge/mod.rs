@@ -0,0 +1,705 @@
+use std::{collections::VecDeque, io, path::PathBuf, sync::Arc, time::Duration};
+
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+use tokio::{
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+use crate::{
+    protocols::v4::publish::dispatch_publish,
+    server::{config::CertFiles, hook::DropReason, state::GlobalState},
+    store::queue::Queue,
+    types::{outgoing::Outgoing, publish::PublishMessage, session::Session},
+};
+
+use self::connection::{Connection, Event};
+
+mod connection;
+
+/// Default number of messages kept for the remote broker while it is unreachable.
+pub const DEFAULT_MAX_BUFFERED: usize = 1000;
+
+// QoS 1 messages sent to the remote broker and not acknowledged yet
+const MAX_INFLIGHT: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid bridge topic: {0}")]
+    InvalidTopic(String),
+    #[error("Io Error : {0}")]
+    Io(#[from] io::Error),
+    #[error("tls error: {0}")]
+    Tls(String),
+    #[cfg(any(feature = "ws", feature = "wss"))]
+    #[error("websocket error: {0}")]
+    WebSocket(Box<tungstenite::Error>),
+    #[error("decode packet: {0}")]
+    Decode(String),
+    #[error("connection refused: {0}")]
+    Refused(String),
+    #[error("unexpected packet: {0}")]
+    UnexpectedPacket(String),
+    #[error("connection closed by the remote broker")]
+    Closed,
+    #[error("remote broker timed out")]
+    Timeout,
+}
+
+#[cfg(any(feature = "ws", feature = "wss"))]
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(err))
+    }
+}
+
+/// MQTT version spoken with the remote broker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BridgeProtocol {
+    /// MQTT v3.1.1, messages the bridge sends to the remote broker come back when the
+    /// bridge also subscribes to their topic.
+    V4,
+    /// MQTT v5, the remote subscriptions are made with the `no_local` option.
+    #[default]
+    V5,
+}
+
+/// Direction messages of a bridge topic are forwarded in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BridgeDirection {
+    /// From the local broker to the remote broker
+    Out,
+    /// From the remote broker to the local broker
+    In,
+    Both,
+}
+
+impl BridgeDirection {
+    fn outgoing(&self) -> bool {
+        matches!(self, BridgeDirection::Out | BridgeDirection::Both)
+    }
+
+    fn incoming(&self) -> bool {
+        matches!(self, BridgeDirection::In | BridgeDirection::Both)
+    }
+}
+
+/// Topics forwarded by a bridge, with the semantics of mosquitto: messages published on
+/// `{local_prefix}{pattern}` locally are published on the same topic with `local_prefix`
+/// replaced by `remote_prefix` remotely, and the other way around.
+#[derive(Clone, Debug)]
+pub struct BridgeTopic {
+    pub pattern: String,
+    pub direction: BridgeDirection,
+    /// Maximum QoS of the forwarded messages, QoS 2 is downgraded to QoS 1.
+    pub qos: QualityOfService,
+    pub local_prefix: String,
+    pub remote_prefix: String,
+}
+
+impl BridgeTopic {
+    pub fn new(
+        pattern: impl Into<String>,
+        direction: BridgeDirection,
+        qos: QualityOfService,
+    ) -> Self {
+        Self {
+            pattern: pattern.into(),
+            direction,
+            qos,
+            local_prefix: String::new(),
+            remote_prefix: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BridgeTlsConfig {
+    /// PEM encoded certificates the remote broker certificate is checked against.
+    pub ca_file: PathBuf,
+    pub client_cert: Option<CertFiles>,
+    /// Name checked against the remote broker certificate, the host of `addr` by
+    /// default.
+    pub server_name: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BridgeConfig {
+    /// Name of the bridge, the bridge subscribes locally as client `$bridge/{name}`.
+    pub name: String,
+    /// Remote broker, as `host:port`.
+    pub addr: String,
+    pub protocol: BridgeProtocol,
+    pub tls: Option<BridgeTlsConfig>,
+    /// Connect over WebSocket with this request path, e.g. `/mqtt`.
+    pub websocket_path: Option<String>,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive: u16,
+    pub topics: Vec<BridgeTopic>,
+    pub reconnect_interval: Duration,
+    /// Messages kept for the remote broker while it is unreachable, the oldest ones are
+    /// dropped beyond.
+    pub max_buffered: usize,
+}
+
+impl BridgeConfig {
+    pub fn new(name: impl Into<String>, addr: impl Into<String>) -> Self {
+        let name = name.into();
+        Self {
+            client_id: name.clone(),
+            name,
+            addr: addr.into(),
+            protocol: BridgeProtocol::default(),
+            tls: None,
+            websocket_path: None,
+            username: None,
+            password: None,
+            keep_alive: 60,
+            topics: Vec::new(),
+            reconnect_interval: Duration::from_secs(5),
+            max_buffered: DEFAULT_MAX_BUFFERED,
+        }
+    }
+}
+
+struct Route {
+    direction: BridgeDirection,
+    qos: QualityOfService,
+    local_prefix: String,
+    remote_prefix: String,
+    local_filter: TopicFilter,
+    remote_filter: TopicFilter,
+}
+
+impl TryFrom<&BridgeTopic> for Route {
+    type Error = Error;
+
+    fn try_from(topic: &BridgeTopic) -> Result<Self, Self::Error> {
+        let filter = |prefix: &str| {
+            TopicFilter::new(format!("{prefix}{}", topic.pattern))
+                .map_err(|err| Error::InvalidTopic(format!("{prefix}{}: {err}", topic.pattern)))
+        };
+        Ok(Self {
+            direction: topic.direction,
+            qos: topic.qos.min(QualityOfService::Level1),
+            local_prefix: topic.local_prefix.clone(),
+            remote_prefix: topic.remote_prefix.clone(),
+            local_filter: filter(&topic.local_prefix)?,
+            remote_filter: filter(&topic.remote_prefix)?,
+        })
+    }
+}
+
+// Topic with `from` replaced by `to`
+fn replace_prefix(topic_name: &TopicName, from: &str, to: &str) -> Option<TopicName> {
+    let rest = topic_name.strip_prefix(from)?;
+    TopicName::new(format!("{to}{rest}")).ok()
+}
+
+/// Forwards messages between the local broker and a remote broker. Messages from the
+/// remote broker go through the same hooks, rules and routes as those of local clients.
+///
+/// Messages for the remote broker are kept while it is unreachable, and the ones it did
+/// not acknowledge are sent again on reconnection.
+pub struct Bridge<Q>
+where
+    Q: Queue,
+{
+    config: BridgeConfig,
+    routes: Vec<Route>,
+    local_id: String,
+    global: Arc<GlobalState<Q>>,
+    buffer: VecDeque<PublishMessage>,
+}
+
+impl<Q> Bridge<Q>
+where
+    Q: Queue + Send + 'static,
+{
+    pub fn new(config: BridgeConfig, global: Arc<GlobalState<Q>>) -> Result<Self, Error> {
+        let routes = config
+            .topics
+            .iter()
+            .map(Route::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            local_id: format!("$bridge/{}", config.name),
+            config,
+            routes,
+            global,
+            buffer: VecDeque::new(),
+        })
+    }
+
+    /// Client the bridge subscribes locally as.
+    pub fn local_client_id(&self) -> &str {
+        &self.local_id
+    }
+
+    /// Connects to the remote broker, and again whenever the connection is lost, until
+    /// the local client of the bridge is kicked or taken over.
+    pub async fn run(mut self) {
+        // TODO: outgoing channel size
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<Outgoing>(8);
+        self.global.add_client(&self.local_id, outgoing_tx).await;
+        for route in self.routes.iter().filter(|r| r.direction.outgoing()) {
+            self.global
+                .subscribe(&route.local_filter, &self.local_id, route.qos);
+        }
+        self.global.set_client_online(&self.local_id);
+        let mut session = Session::new(self.local_id.clone(), false, 12);
+
+        let subscribes: Vec<_> = self
+            .routes
+            .iter()
+            .filter(|r| r.direction.incoming())
+            .map(|r| (r.remote_filter.clone(), r.qos))
+            .collect();
+        loop {
+            match Connection::connect(&self.config, &subscribes).await {
+                Ok(connection) => {
+                    log::info!(
+                        "bridge {} connected to {}",
+                        self.config.name,
+                        self.config.addr
+                    );
+                    match self.serve(connection, &mut outgoing_rx, &mut session).await {
+                        Ok(()) => break,
+                        Err(err) => log::warn!(
+                            "bridge {} disconnected from {}: {err}",
+                            self.config.name,
+                            self.config.addr
+                        ),
+                    }
+                }
+                Err(err) => log::warn!(
+                    "bridge {} connect to {}: {err}",
+                    self.config.name,
+                    self.config.addr
+                ),
+            }
+
+            // Local publishers are not blocked by the bridge while it waits.
+            let reconnect = time::sleep(self.config.reconnect_interval);
+            tokio::pin!(reconnect);
+            let stopped = loop {
+                tokio::select! {
+                    _ = &mut reconnect => break false,
+                    outgoing = outgoing_rx.recv() => {
+                        if !self.receive_outgoing(outgoing, &session).await {
+                            break true;
+                        }
+                    }
+                }
+            };
+            if stopped {
+                break;
+            }
+        }
+
+        self.global.set_client_offline(&self.local_id);
+        log::info!("bridge {} stopped", self.config.name);
+    }
+
+    // Returns when the connection is lost, or with `Ok` when the bridge has to stop.
+    async fn serve(
+        &mut self,
+        mut connection: Connection,
+        outgoing_rx: &mut mpsc::Receiver<Outgoing>,
+        session: &mut Session,
+    ) -> Result<(), Error> {
+        let mut inflight: VecDeque<(u16, PublishMessage)> = VecDeque::new();
+        let keep_alive = Duration::from_secs(self.config.keep_alive.max(1) as u64);
+        let mut ping = time::interval_at(Instant::now() + keep_alive, keep_alive);
+        let mut last_received = Instant::now();
+
+        let result = loop {
+            if let Err(err) = self.send_buffered(&mut connection, &mut inflight).await {
+                break Err(err);
+            }
+
+            tokio::select! {
+                event = connection.next() => {
+                    last_received = Instant::now();
+                    let result = match event {
+                        Ok(Event::Publish(message, packet_id)) => {
+                            self.receive_remote(*message, session).await;
+                            match packet_id {
+                                Some(packet_id) => connection.puback(packet_id).await,
+                                None => Ok(()),
+                            }
+                        }
+                        Ok(Event::Puback(packet_id)) => {
+                            inflight.retain(|(id, _)| *id != packet_id);
+                            Ok(())
+                        }
+                        Ok(Event::Suback(granted)) => {
+                            if !granted {
+                                log::warn!(
+                                    "bridge {} subscriptions refused by {}",
+                                    self.config.name,
+                                    self.config.addr
+                                );
+                            }
+                            Ok(())
+                        }
+                        Ok(Event::Other) => Ok(()),
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = result {
+                        break Err(err);
+                    }
+                }
+                outgoing = outgoing_rx.recv() => {
+                    if !self.receive_outgoing(outgoing, session).await {
+                        break Ok(());
+                    }
+                }
+                _ = ping.tick() => {
+                    if last_received.elapsed() > keep_alive * 3 / 2 {
+                        break Err(Error::Timeout);
+                    }
+                    if let Err(err) = connection.ping().await {
+                        break Err(err);
+                    }
+                }
+            }
+        };
+
+        // Sent again first on reconnection
+        while let Some((_, mut message)) = inflight.pop_back() {
+            message.set_dup();
+            self.buffer.push_front(message);
+        }
+        result
+    }
+
+    async fn send_buffered(
+        &mut self,
+        connection: &mut Connection,
+        inflight: &mut VecDeque<(u16, PublishMessage)>,
+    ) -> Result<(), Error> {
+        while inflight.len() < MAX_INFLIGHT {
+            let Some(message) = self.buffer.pop_front() else {
+                break;
+            };
+            match connection.publish(&message).await {
+                Ok(Some(packet_id)) => inflight.push_back((packet_id, message)),
+                Ok(None) => {}
+                Err(err) => {
+                    self.buffer.push_front(message);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns whether the bridge keeps running.
+    async fn receive_outgoing(&mut self, outgoing: Option<Outgoing>, session: &Session) -> bool {
+        match outgoing {
+            Some(Outgoing::Publish(subscribe_qos, message)) => {
+                if let Some(message) = self.remote_message(subscribe_qos, *message) {
+                    self.push_buffer(message).await;
+                }
+                true
+            }
+            Some(Outgoing::Online(sender)) => {
+                log::warn!(
+                    "bridge {} client {} taken over by another client",
+                    self.config.name,
+                    self.local_id
+                );
+                if let Err(err) = sender.send(session.server_packet_id()).await {
+                    log::warn!("send server packet id: {err}");
+                }
+                false
+            }
+            Some(Outgoing::Kick(reason)) => {
+                log::info!("bridge {} {reason}", self.config.name);
+                self.global
+                    .remove_client(&self.local_id, self.routes.iter().map(|r| &r.local_filter));
+                false
+            }
+            None => false,
+        }
+    }
+
+    // The message as published on the remote broker
+    fn remote_message(
+        &self,
+        subscribe_qos: QualityOfService,
+        mut message: PublishMessage,
+    ) -> Option<PublishMessage> {
+        let route = self.routes.iter().find(|r| {
+            r.direction.outgoing() && r.local_filter.get_matcher().is_match(message.topic_name())
+        })?;
+        let topic_name = replace_prefix(
+            message.topic_name(),
+            &route.local_prefix,
+            &route.remote_prefix,
+        )?;
+        message.set_topic_name(topic_name);
+        message.set_qos(message.qos().min(subscribe_qos).min(route.qos));
+        Some(message)
+    }
+
+    async fn push_buffer(&mut self, message: PublishMessage) {
+        if self.buffer.len() >= self.config.max_buffered {
+            let Some(dropped) = self.buffer.pop_front() else {
+                return;
+            };
+            log::warn!(
+                "bridge {} buffer is full, drop message of {:?}",
+                self.config.name,
+                dropped.topic_name()
+            );
+            self.global
+                .hooks()
+                .message_dropped(&self.local_id, &dropped, DropReason::BufferFull)
+                .await;
+        }
+        self.buffer.push_back(message);
+    }
+
+    async fn receive_remote(&self, mut message: PublishMessage, session: &mut Session) {
+        let Some(route) = self.routes.iter().find(|r| {
+            r.direction.incoming() && r.remote_filter.get_matcher().is_match(message.topic_name())
+        }) else {
+            log::debug!(
+                "bridge {} received a message of unknown topic {:?}",
+                self.config.name,
+                message.topic_name()
+            );
+            return;
+        };
+        let Some(topic_name) = replace_prefix(
+            message.topic_name(),
+            &route.remote_prefix,
+            &route.local_prefix,
+        ) else {
+            return;
+        };
+        message.set_topic_name(topic_name);
+        message.clear_dup();
+        // Not sent back to the remote broker by the bridge
+        message.set_bridged_by(Some(Arc::from(self.local_id.as_str())));
+        dispatch_publish(session, message, self.global.clone()).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use mqtt_codec_kit::{
+        common::qos::QoSWithPacketIdentifier,
+        v4::{
+            self,
+            control::ConnectReturnCode,
+            packet::{ConnackPacket, MqttCodec, PubackPacket, VariablePacket},
+        },
+    };
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    use crate::{
+        server::process_client,
+        store::memory::queue::MemoryQueue,
+        types::connection::{ConnectionInfo, Transport},
+    };
+
+    use super::*;
+
+    type State = Arc<GlobalState<MemoryQueue>>;
+
+    fn global() -> State {
+        Arc::new(GlobalState::new(MemoryQueue::new(10, 10)))
+    }
+
+    // Serves the clients of a second broker instance.
+    async fn serve(listener: TcpListener, global: State) {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(process_client(
+                stream,
+                ConnectionInfo::new(Transport::Tcp, None),
+                global.clone(),
+            ));
+        }
+    }
+
+    async fn subscriber(global: &State, client_id: &str, filter: &str) -> mpsc::Receiver<Outgoing> {
+        let (tx, rx) = mpsc::channel(16);
+        global.add_client(client_id, tx).await;
+        global.subscribe(
+            &TopicFilter::new(filter).unwrap(),
+            client_id,
+            QualityOfService::Level1,
+        );
+        rx
+    }
+
+    async fn publish(global: &State, topic_name: &str) {
+        let mut session = Session::new("publisher".to_owned(), false, 12);
+        let message = PublishMessage::new(
+            TopicName::new(topic_name).unwrap(),
+            Bytes::from_static(b"hello"),
+            QualityOfService::Level1,
+            false,
+        );
+        dispatch_publish(&mut session, message, global.clone()).await;
+    }
+
+    async fn recv_topic(rx: &mut mpsc::Receiver<Outgoing>) -> Option<String> {
+        match time::timeout(Duration::from_millis(800), rx.recv()).await {
+            Ok(Some(Outgoing::Publish(_, message))) => Some(message.topic_name().to_string()),
+            _ => None,
+        }
+    }
+
+    fn config(addr: String) -> BridgeConfig {
+        let mut config = BridgeConfig::new("test", addr);
+        config.protocol = BridgeProtocol::V4;
+        config.reconnect_interval = Duration::from_millis(200);
+        config.keep_alive = 5;
+        let mut outgoing =
+            BridgeTopic::new("out/#", BridgeDirection::Out, QualityOfService::Level2);
+        outgoing.remote_prefix = "remote/".to_owned();
+        let mut incoming = BridgeTopic::new("in/#", BridgeDirection::In, QualityOfService::Level1);
+        incoming.local_prefix = "local/".to_owned();
+        let both = BridgeTopic::new("both/#", BridgeDirection::Both, QualityOfService::Level1);
+        config.topics = vec![outgoing, incoming, both];
+        config
+    }
+
+    #[tokio::test]
+    async fn test_forward_both_ways() {
+        let local = global();
+        let remote = global();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener, remote.clone()));
+
+        let mut remote_rx = subscriber(&remote, "remote-sub", "#").await;
+        let mut local_rx = subscriber(&local, "local-sub", "local/#").await;
+        let mut local_both = subscriber(&local, "local-both", "both/#").await;
+
+        let bridge = Bridge::new(config(addr), local.clone()).unwrap();
+        tokio::spawn(bridge.run());
+        time::sleep(Duration::from_millis(300)).await;
+
+        publish(&local, "out/x").await;
+        assert_eq!(
+            recv_topic(&mut remote_rx).await.as_deref(),
+            Some("remote/out/x")
+        );
+
+        publish(&remote, "in/y").await;
+        assert_eq!(recv_topic(&mut remote_rx).await.as_deref(), Some("in/y"));
+        assert_eq!(
+            recv_topic(&mut local_rx).await.as_deref(),
+            Some("local/in/y")
+        );
+
+        // The v4 remote broker sends the message back once, it is not forwarded again.
+        publish(&local, "both/z").await;
+        assert_eq!(recv_topic(&mut local_both).await.as_deref(), Some("both/z"));
+        assert_eq!(recv_topic(&mut remote_rx).await.as_deref(), Some("both/z"));
+        assert_eq!(recv_topic(&mut local_both).await.as_deref(), Some("both/z"));
+        assert_eq!(recv_topic(&mut remote_rx).await, None);
+        assert_eq!(recv_topic(&mut local_both).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_while_remote_is_down() {
+        let local = global();
+        let remote = global();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let bridge = Bridge::new(config(addr.to_string()), local.clone()).unwrap();
+        tokio::spawn(bridge.run());
+        time::sleep(Duration::from_millis(100)).await;
+        for i in 0..3 {
+            publish(&local, &format!("out/{i}")).await;
+        }
+
+        let mut remote_rx = subscriber(&remote, "remote-sub", "#").await;
+        let listener = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(serve(listener, remote.clone()));
+        for i in 0..3 {
+            assert_eq!(
+                recv_topic(&mut remote_rx).await,
+                Some(format!("remote/out/{i}"))
+            );
+        }
+    }
+
+    // Accepts the bridge as a remote broker which never acknowledges its messages.
+    async fn accept_bridge(listener: &TcpListener) -> Framed<TcpStream, MqttCodec> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(stream, MqttCodec::new());
+        match framed.next().await {
+            Some(Ok(VariablePacket::ConnectPacket(_))) => {}
+            packet => panic!("expected CONNECT, got {packet:?}"),
+        }
+        framed
+            .send(ConnackPacket::new(
+                false,
+                ConnectReturnCode::ConnectionAccepted,
+            ))
+            .await
+            .unwrap();
+        framed
+    }
+
+    async fn next_publish(framed: &mut Framed<TcpStream, MqttCodec>) -> v4::packet::PublishPacket {
+        loop {
+            match time::timeout(Duration::from_secs(1), framed.next()).await {
+                Ok(Some(Ok(VariablePacket::PublishPacket(packet)))) => return packet,
+                Ok(Some(Ok(_))) => {}
+                packet => panic!("expected PUBLISH, got {packet:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resend_unacknowledged() {
+        let local = global();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let bridge = Bridge::new(config(addr), local.clone()).unwrap();
+        tokio::spawn(bridge.run());
+
+        let mut remote = accept_bridge(&listener).await;
+        publish(&local, "out/1").await;
+        let packet = next_publish(&mut remote).await;
+        assert_eq!(&packet.topic_name()[..], "remote/out/1");
+        assert!(!packet.dup());
+        drop(remote);
+
+        // Sent again on the next connection, with DUP
+        let mut remote = accept_bridge(&listener).await;
+        let packet = next_publish(&mut remote).await;
+        assert_eq!(&packet.topic_name()[..], "remote/out/1");
+        assert!(packet.dup());
+        let QoSWithPacketIdentifier::Level1(packet_id) = packet.qos() else {
+            panic!("expected QoS 1, got {:?}", packet.qos());
+        };
+        remote.send(PubackPacket::new(packet_id)).await.unwrap();
+
+        // Acknowledged messages are not sent again.
+        publish(&local, "out/2").await;
+        let packet = next_publish(&mut remote).await;
+        assert_eq!(&packet.topic_name()[..], "remote/out/2");
+        assert!(!packet.dup());
+        drop(remote);
+        let mut remote = accept_bridge(&listener).await;
+        let packet = next_publish(&mut remote).await;
+        assert_eq!(&packet.topic_name()[..], "remote/out/2");
+        assert!(packet.dup());
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use mqtt_codec_kit::{
+    common::{QualityOfService, TopicFilter, TopicName},
+    v5::control::PublishProperties,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    protocols::v4::publish::dispatch_publish,
+    server::state::GlobalState,
+    store::queue::Queue,
+    types::{
+        outgoing::{KickReason, Outgoing},
+        publish::PublishMessage,
+        session::Session,
+    },
+};
+
+/// Client the messages published through a [`Broker`] are published by.
+pub const BROKER_CLIENT_ID: &str = "$embedded";
+
+// TODO: config subscription channel size
+const SUBSCRIPTION_CHANNEL_SIZE: usize = 128;
+
+// Virtual clients of all the handles, their ids must be unique in the route table.
+static NEXT_SUBSCRIBER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Handle publishing and subscribing from the process embedding the broker, without a
+/// connection. Messages go through the same hooks, rules and routes as those of
+/// network clients.
+pub struct Broker<Q>
+where
+    Q: Queue,
+{
+    global: Arc<GlobalState<Q>>,
+}
+
+impl<Q> Clone for Broker<Q>
+where
+    Q: Queue,
+{
+    fn clone(&self) -> Self {
+        Self {
+            global: self.global.clone(),
+        }
+    }
+}
+
+impl<Q> Broker<Q>
+where
+    Q: Queue,
+{
+    pub fn new(global: Arc<GlobalState<Q>>) -> Self {
+        Self { global }
+    }
+
+    pub fn global(&self) -> &Arc<GlobalState<Q>> {
+        &self.global
+    }
+
+    /// Publishes a message as client [`BROKER_CLIENT_ID`], it is retained, or the
+    /// retained message of the topic removed when the payload is empty, like a message
+    /// published by a network client.
+    pub async fn publish(
+        &self,
+        topic_name: TopicName,
+        payload: impl Into<Bytes>,
+        qos: QualityOfService,
+        retain: bool,
+        properties: Option<PublishProperties>,
+    ) {
+        let mut message = PublishMessage::new(topic_name, payload.into(), qos, retain);
+        message.set_properties(properties);
+        let mut session = Session::new(BROKER_CLIENT_ID.to_owned(), false, 12);
+        dispatch_publish(&mut session, message, self.global.clone()).await;
+    }
+
+    /// Subscribes a virtual client to `filter`, shared subscriptions included. The
+    /// messages received are downgraded to `qos`, and the retained messages matching
+    /// a filter which is not shared come first.
+    ///
+    /// The subscription lasts until the returned stream is dropped, or ends when a
+    /// client with the same id takes it over or it is kicked.
+    pub async fn subscribe(&self, filter: TopicFilter, qos: QualityOfService) -> Subscription<Q> {
+        let client_id = format!(
+            "{BROKER_CLIENT_ID}/{}",
+            NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(SUBSCRIPTION_CHANNEL_SIZE);
+        self.global.add_client(&client_id, outgoing_tx).await;
+
+        let session = Session::new(client_id.clone(), false, 12);
+        self.global.subscribe(&filter, &client_id, qos);
+        self.global.hooks().subscribe(&session, &filter, qos).await;
+        self.global.set_client_online(&client_id);
+
+        let retained = if filter.is_shared() {
+            VecDeque::new()
+        } else {
+            self.global
+                .retain_table()
+                .get_matches(&filter)
+                .into_iter()
+                .map(|content| {
+                    let mut message = PublishMessage::from(content);
+                    message.set_qos(message.qos().min(qos));
+                    message.set_retain(true);
+                    message
+                })
+                .collect()
+        };
+
+        Subscription {
+            client_id,
+            filter,
+            global: self.global.clone(),
+            retained,
+            outgoing: outgoing_rx,
+            ended: false,
+            taken_over: false,
+        }
+    }
+}
+
+/// Messages received by a subscription of a [`Broker`], with their QoS downgraded to
+/// the QoS of the subscription.
+pub struct Subscription<Q>
+where
+    Q: Queue,
+{
+    client_id: String,
+    filter: TopicFilter,
+    global: Arc<GlobalState<Q>>,
+    // Retained messages matching the filter, received first
+    retained: VecDeque<PublishMessage>,
+    outgoing: mpsc::Receiver<Outgoing>,
+    ended: bool,
+    taken_over: bool,
+}
+
+impl<Q> Subscription<Q>
+where
+    Q: Queue,
+{
+    /// Virtual client of the subscription in the route table.
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn filter(&self) -> &TopicFilter {
+        &self.filter
+    }
+}
+
+impl<Q> Stream for Subscription<Q>
+where
+    Q: Queue,
+{
+    type Item = PublishMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(message) = self.retained.pop_front() {
+            return Poll::Ready(Some(message));
+        }
+        loop {
+            if self.ended {
+                return Poll::Ready(None);
+            }
+            match self.outgoing.poll_recv(cx) {
+                Poll::Ready(Some(Outgoing::Publish(subscribe_qos, message))) => {
+                    let mut message = *message;
+                    message.set_qos(message.qos().min(subscribe_qos));
+                    return Poll::Ready(Some(message));
+                }
+                Poll::Ready(Some(Outgoing::Online(sender))) => {
+                    log::debug!(
+                        "client#{} subscription taken over by another client",
+                        self.client_id
+                    );
+                    // The subscription keeps no packet identifiers.
+                    if let Err(err) = sender.try_send(1) {
+                        log::warn!("client#{} send session state: {err}", self.client_id);
+                    }
+                    // Its id now belongs to the other client.
+                    self.global.unsubscribe(&self.filter, &self.client_id);
+                    self.taken_over = true;
+                    self.ended = true;
+                }
+                // Virtual clients only live on this node, the same id on another
+                // node is another client.
+                Poll::Ready(Some(Outgoing::Kick(KickReason::Takeover))) => {}
+                Poll::Ready(Some(Outgoing::Kick(reason))) => {
+                    log::info!("client#{} subscription {reason}", self.client_id);
+                    self.ended = true;
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<Q> Drop for Subscription<Q>
+where
+    Q: Queue,
+{
+    fn drop(&mut self) {
+        self.global.set_client_offline(&self.client_id);
+        if !self.taken_over {
+            self.global.remove_client(&self.client_id, [&self.filter]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures_util::StreamExt as _;
+    use tokio::time;
+
+    use crate::store::memory::queue::MemoryQueue;
+
+    use super::*;
+
+    fn broker() -> Broker<MemoryQueue> {
+        Broker::new(Arc::new(GlobalState::new(MemoryQueue::new(10, 10))))
+    }
+
+    fn topic(name: &str) -> TopicName {
+        TopicName::new(name).unwrap()
+    }
+
+    fn filter(filter: &str) -> TopicFilter {
+        TopicFilter::new(filter).unwrap()
+    }
+
+    async fn next(subscription: &mut Subscription<MemoryQueue>) -> Option<PublishMessage> {
+        time::timeout(Duration::from_millis(100), subscription.next())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let broker = broker();
+        let mut subscription = broker
+            .subscribe(filter("a/+"), QualityOfService::Level1)
+            .await;
+
+        broker
+            .publish(topic("a/b"), "qos2", QualityOfService::Level2, false, None)
+            .await;
+        broker
+            .publish(topic("a/c"), "qos0", QualityOfService::Level0, false, None)
+            .await;
+        broker
+            .publish(topic("b/c"), "other", QualityOfService::Level0, false, None)
+            .await;
+
+        let message = next(&mut subscription).await.unwrap();
+        assert_eq!(message.topic_name(), &topic("a/b"));
+        assert_eq!(&message.payload()[..], b"qos2");
+        assert_eq!(message.qos(), QualityOfService::Level1);
+        let message = next(&mut subscription).await.unwrap();
+        assert_eq!(message.topic_name(), &topic("a/c"));
+        assert_eq!(message.qos(), QualityOfService::Level0);
+        assert!(next(&mut subscription).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_retained() {
+        let broker = broker();
+        broker
+            .publish(topic("r/1"), "old", QualityOfService::Level2, true, None)
+            .await;
+
+        let mut subscription = broker
+            .subscribe(filter("r/#"), QualityOfService::Level0)
+            .await;
+        let mut shared = broker
+            .subscribe(filter("$share/g/r/#"), QualityOfService::Level1)
+            .await;
+        broker
+            .publish(topic("r/2"), "new", QualityOfService::Level1, false, None)
+            .await;
+
+        let message = next(&mut subscription).await.unwrap();
+        assert_eq!(&message.payload()[..], b"old");
+        assert!(message.retain());
+        assert_eq!(message.qos(), QualityOfService::Level0);
+        let message = next(&mut subscription).await.unwrap();
+        assert_eq!(&message.payload()[..], b"new");
+        assert!(!message.retain());
+
+        let message = next(&mut shared).await.unwrap();
+        assert_eq!(&message.payload()[..], b"new");
+        assert!(next(&mut shared).await.is_none());
+
+        // An empty retained payload removes the retained message.
+        broker
+            .publish(topic("r/1"), "", QualityOfService::Level0, true, None)
+            .await;
+        assert!(broker
+            .global()
+            .retain_table()
+            .get_matches(&filter("r/#"))
+            .is_empty());
+        let mut subscription = broker
+            .subscribe(filter("r/#"), QualityOfService::Level0)
+            .await;
+        assert!(next(&mut subscription).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscriptions() {
+        let broker = broker();
+        let mut first = broker
+            .subscribe(filter("$share/g/t"), QualityOfService::Level1)
+            .await;
+        let mut second = broker
+            .subscribe(filter("$share/g/t"), QualityOfService::Level1)
+            .await;
+        assert_ne!(first.client_id(), second.client_id());
+
+        for i in 0..4 {
+            broker
+                .publish(
+                    topic("t"),
+                    i.to_string(),
+                    QualityOfService::Level1,
+                    false,
+                    None,
+                )
+                .await;
+        }
+        let mut received = Vec::new();
+        for subscription in [&mut first, &mut second] {
+            let mut count = 0;
+            while let Some(message) = next(subscription).await {
+                received.push(message.payload().clone());
+                count += 1;
+            }
+            assert_eq!(count, 2);
+        }
+        received.sort();
+        assert_eq!(received, ["0", "1", "2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn test_drop_subscription() {
+        let broker = broker();
+        let subscription = broker
+            .subscribe(filter("d/#"), QualityOfService::Level1)
+            .await;
+        let client_id = subscription.client_id().to_owned();
+        assert_eq!(
+            broker
+                .global()
+                .route_table()
+                .get_matches(&topic("d/1"))
+                .len(),
+            1
+        );
+        assert!(broker.global().is_client_online(&client_id));
+
+        drop(subscription);
+        assert!(broker
+            .global()
+            .route_table()
+            .get_matches(&topic("d/1"))
+            .is_empty());
+        assert!(broker.global().get_outgoing_sender(&client_id).is_none());
+        assert!(!broker.global().is_client_online(&client_id));
+    }
+}
@@ -0,0 +1,137 @@
+use bytes::{BufMut as _, Bytes, BytesMut};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter},
+    v5::packet::{MqttDecoder, MqttEncoder, PublishPacket, VariablePacket},
+};
+use tokio_util::codec::{Decoder as _, Encoder as _};
+
+use crate::types::publish::PublishMessage;
+
+use super::Error;
+
+const HELLO: u8 = 0;
+const SUBSCRIPTIONS: u8 = 1;
+const PUBLISH: u8 = 2;
+const RETAIN: u8 = 3;
+const TAKEOVER: u8 = 4;
+const CHALLENGE: u8 = 5;
+const PROOF: u8 = 6;
+
+/// Message exchanged between nodes, a kind byte followed by:
+///
+/// - `Hello`: the node id
+/// - `Subscriptions`: the topic filters as a JSON array
+/// - `Publish`, `Retain`: a v5 PUBLISH packet
+/// - `Takeover`: the client id
+/// - `Challenge`, `Proof`: the raw bytes
+#[derive(Debug)]
+pub(super) enum Frame {
+    Hello(String),
+    /// All the topic filters subscribed on the node sending it
+    Subscriptions(Vec<TopicFilter>),
+    Publish(Box<PublishMessage>),
+    /// Retained message of a node joining the cluster
+    Retain(Box<PublishMessage>),
+    Takeover(String),
+    /// Random nonce the other node signs with the cluster secret
+    Challenge(Bytes),
+    /// Signature of the nonce received in the challenge
+    Proof(Bytes),
+}
+
+impl Frame {
+    pub(super) fn encode(&self) -> Result<Bytes, Error> {
+        let mut buf = BytesMut::new();
+        match self {
+            Frame::Hello(node_id) => {
+                buf.put_u8(HELLO);
+                buf.put_slice(node_id.as_bytes());
+            }
+            Frame::Subscriptions(filters) => {
+                let filters: Vec<&str> = filters.iter().map(|filter| &filter[..]).collect();
+                buf.put_u8(SUBSCRIPTIONS);
+                buf.put_slice(&serde_json::to_vec(&filters)?);
+            }
+            Frame::Publish(message) => {
+                buf.put_u8(PUBLISH);
+                encode_message(message, &mut buf)?;
+            }
+            Frame::Retain(message) => {
+                buf.put_u8(RETAIN);
+                encode_message(message, &mut buf)?;
+            }
+            Frame::Takeover(client_id) => {
+                buf.put_u8(TAKEOVER);
+                buf.put_slice(client_id.as_bytes());
+            }
+            Frame::Challenge(nonce) => {
+                buf.put_u8(CHALLENGE);
+                buf.put_slice(nonce);
+            }
+            Frame::Proof(signature) => {
+                buf.put_u8(PROOF);
+                buf.put_slice(signature);
+            }
+        }
+        Ok(buf.freeze())
+    }
+
+    pub(super) fn decode(mut frame: BytesMut) -> Result<Self, Error> {
+        if frame.is_empty() {
+            return Err(Error::InvalidFrame("empty frame".to_owned()));
+        }
+        let kind = frame.split_to(1)[0];
+        let frame = match kind {
+            HELLO => Frame::Hello(decode_string(&frame)?),
+            SUBSCRIPTIONS => {
+                let filters: Vec<String> = serde_json::from_slice(&frame)?;
+                let filters = filters
+                    .into_iter()
+                    .map(TopicFilter::new)
+                    .collect::<Result<_, _>>()
+                    .map_err(|err| Error::InvalidFrame(err.to_string()))?;
+                Frame::Subscriptions(filters)
+            }
+            PUBLISH => Frame::Publish(Box::new(decode_message(frame)?)),
+            RETAIN => Frame::Retain(Box::new(decode_message(frame)?)),
+            TAKEOVER => Frame::Takeover(decode_string(&frame)?),
+            CHALLENGE => Frame::Challenge(frame.freeze()),
+            PROOF => Frame::Proof(frame.freeze()),
+            kind => return Err(Error::InvalidFrame(format!("unknown frame kind {kind}"))),
+        };
+        Ok(frame)
+    }
+}
+
+fn decode_string(frame: &[u8]) -> Result<String, Error> {
+    String::from_utf8(frame.to_vec()).map_err(|err| Error::InvalidFrame(err.to_string()))
+}
+
+// The packet identifier only tells the QoS apart.
+fn encode_message(message: &PublishMessage, buf: &mut BytesMut) -> Result<(), Error> {
+    let qos = match message.qos() {
+        QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+        QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(1),
+        QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(1),
+    };
+    let mut packet = PublishPacket::new(
+        message.topic_name().to_owned(),
+        qos,
+        message.payload().clone(),
+    );
+    packet.set_retain(message.retain());
+    if let Some(properties) = message.properties() {
+        packet.set_properties(properties.clone());
+    }
+    MqttEncoder::new().encode(packet, buf)?;
+    Ok(())
+}
+
+fn decode_message(mut frame: BytesMut) -> Result<PublishMessage, Error> {
+    match MqttDecoder::new().decode(&mut frame) {
+        Ok(Some(VariablePacket::PublishPacket(packet))) => Ok(packet.into()),
+        Ok(Some(packet)) => Err(Error::InvalidFrame(format!("unexpected packet {packet:?}"))),
+        Ok(None) => Err(Error::InvalidFrame("truncated publish packet".to_owned())),
+        Err(err) => Err(Error::InvalidFrame(err.to_string())),
+    }
+}
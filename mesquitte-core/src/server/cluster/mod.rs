@@ -0,0 +1,642 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use aws_lc_rs::hmac;
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures_util::{SinkExt as _, StreamExt as _};
+use mqtt_codec_kit::common::TopicFilter;
+use parking_lot::Mutex;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
+    time,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::{
+    protocols::v4::publish::route_publish,
+    server::{config::DEFAULT_HANDSHAKE_TIMEOUT, state::GlobalState},
+    store::queue::Queue,
+    types::{
+        outgoing::{KickReason, Outgoing},
+        publish::PublishMessage,
+        session::Session,
+    },
+};
+
+use self::frame::Frame;
+
+mod frame;
+
+// Events waiting for the cluster task, and frames waiting for each peer
+const EVENT_CHANNEL_SIZE: usize = 1024;
+const PEER_CHANNEL_SIZE: usize = 1024;
+
+const NONCE_LEN: usize = 32;
+// Tell the proofs of the connecting and the accepting node apart, so that one cannot
+// be replayed as the other.
+const CONNECT_ROLE: &[u8] = b"connect";
+const SERVE_ROLE: &[u8] = b"serve";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Io Error : {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid cluster frame: {0}")]
+    InvalidFrame(String),
+    #[error("encode cluster frame: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("peer closed the connection")]
+    Closed,
+    #[error("peer handshake timeout")]
+    HandshakeTimeout,
+    #[error("peer failed to prove the cluster secret")]
+    Unauthorized,
+    #[error("a cluster is already running on this state")]
+    AlreadyRunning,
+}
+
+/// Event of the local node sent to the other nodes.
+pub(crate) enum ClusterEvent {
+    Publish(Box<PublishMessage>),
+    Takeover(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    /// Identifier of this node, unique in the cluster.
+    pub node_id: String,
+    /// Address the other nodes connect to.
+    pub addr: SocketAddr,
+    /// The other nodes, as `host:port`, every node connects to all the others.
+    pub peers: Vec<String>,
+    /// How often the topic filters subscribed on this node are checked and sent to the
+    /// other nodes when they changed.
+    pub gossip_interval: Duration,
+    pub reconnect_interval: Duration,
+    /// Secret shared by the nodes, each node proves it knows the secret when it
+    /// connects. Without it anyone reaching `addr` can join the cluster.
+    pub secret: Option<String>,
+}
+
+impl ClusterConfig {
+    pub fn new(node_id: impl Into<String>, addr: SocketAddr, peers: Vec<String>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            addr,
+            peers,
+            gossip_interval: Duration::from_secs(1),
+            reconnect_interval: Duration::from_secs(5),
+            secret: None,
+        }
+    }
+}
+
+// Connection to a peer, made by this node to send it frames.
+struct Peer {
+    frames: mpsc::Sender<Frame>,
+    // Learnt from the reply to the hello frame
+    node_id: Arc<Mutex<Option<String>>>,
+}
+
+/// Joins the broker to other brokers connected in a full mesh:
+///
+/// - the nodes exchange the topic filters subscribed on each of them, and a message
+///   published on a node is forwarded to the nodes with a matching subscription
+/// - a client connecting to a node evicts its session from the other nodes, the session
+///   state is not moved to the new node
+/// - retained messages are sent to all the nodes, and a node sends its retained messages
+///   to the nodes it connects to, which keep theirs for the topics they have both
+///
+/// Messages forwarded while a peer is unreachable are lost, and shared subscription
+/// groups only receive the messages published on their own node. Client ids, bridge
+/// names included, must be unique in the cluster.
+pub struct Cluster<Q>
+where
+    Q: Queue,
+{
+    config: ClusterConfig,
+    global: Arc<GlobalState<Q>>,
+    // Topic filters subscribed on each peer, by node id
+    subscriptions: Arc<DashMap<String, Vec<TopicFilter>, ahash::RandomState>>,
+}
+
+impl<Q> Cluster<Q>
+where
+    Q: Queue + Send + 'static,
+{
+    pub fn new(config: ClusterConfig, global: Arc<GlobalState<Q>>) -> Self {
+        Self {
+            config,
+            global,
+            subscriptions: Default::default(),
+        }
+    }
+
+    /// Serves the other nodes and connects to them, until the listener fails. Dropping
+    /// the future leaves the cluster.
+    pub async fn run(self) -> Result<(), Error> {
+        let (event_tx, mut event_rx) = mpsc::channel(EVENT_CHANNEL_SIZE);
+        if !self.global.set_cluster(event_tx) {
+            return Err(Error::AlreadyRunning);
+        }
+        let listener = TcpListener::bind(self.config.addr).await?;
+        log::info!(
+            "cluster node {} listen on {}",
+            self.config.node_id,
+            self.config.addr
+        );
+
+        let node_id: Arc<str> = Arc::from(self.config.node_id.as_str());
+        let mut tasks = JoinSet::new();
+        let mut peers = Vec::with_capacity(self.config.peers.len());
+        for addr in &self.config.peers {
+            let (frames_tx, frames_rx) = mpsc::channel(PEER_CHANNEL_SIZE);
+            let peer_node_id = Arc::new(Mutex::new(None));
+            tasks.spawn(connect_peer(
+                addr.to_owned(),
+                node_id.clone(),
+                self.config.secret.clone(),
+                self.config.reconnect_interval,
+                peer_node_id.clone(),
+                frames_rx,
+                self.global.clone(),
+            ));
+            peers.push(Peer {
+                frames: frames_tx,
+                node_id: peer_node_id,
+            });
+        }
+
+        let mut gossip = time::interval(self.config.gossip_interval);
+        let mut filters = Vec::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, addr) = accepted?;
+                    let node_id = node_id.clone();
+                    let secret = self.config.secret.clone();
+                    let global = self.global.clone();
+                    let subscriptions = self.subscriptions.clone();
+                    tasks.spawn(async move {
+                        let served = serve_peer(stream, node_id, secret, global, subscriptions);
+                        if let Err(err) = served.await {
+                            log::warn!("cluster peer {addr}: {err}");
+                        }
+                    });
+                }
+                Some(event) = event_rx.recv() => match event {
+                    ClusterEvent::Publish(message) => self.forward(&peers, message),
+                    ClusterEvent::Takeover(client_id) => {
+                        for peer in &peers {
+                            send_frame(peer, Frame::Takeover(client_id.clone()));
+                        }
+                    }
+                },
+                // Reap the tasks of the peers which left
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+                _ = gossip.tick() => {
+                    let current = self.global.route_table().topic_filters();
+                    if current != filters {
+                        for peer in &peers {
+                            send_frame(peer, Frame::Subscriptions(current.clone()));
+                        }
+                        filters = current;
+                    }
+                }
+            }
+        }
+    }
+
+    // Retained messages go to all the peers, others to the peers subscribed to them.
+    fn forward(&self, peers: &[Peer], message: Box<PublishMessage>) {
+        for peer in peers {
+            let Some(node_id) = peer.node_id.lock().clone() else {
+                continue;
+            };
+            let subscribed = self.subscriptions.get(&node_id).is_some_and(|filters| {
+                filters
+                    .iter()
+                    .any(|filter| filter.get_matcher().is_match(message.topic_name()))
+            });
+            if message.retain() || subscribed {
+                send_frame(peer, Frame::Publish(message.clone()));
+            }
+        }
+    }
+}
+
+// Frames for a peer which is slow or unreachable are dropped.
+fn send_frame(peer: &Peer, frame: Frame) {
+    if let Err(err) = peer.frames.try_send(frame) {
+        log::debug!(
+            "cluster drop frame for peer {:?}: {err}",
+            peer.node_id.lock()
+        );
+    }
+}
+
+async fn read_frame(framed: &mut Framed<TcpStream, LengthDelimitedCodec>) -> Result<Frame, Error> {
+    match framed.next().await {
+        Some(frame) => Frame::decode(frame?),
+        None => Err(Error::Closed),
+    }
+}
+
+async fn read_challenge(
+    framed: &mut Framed<TcpStream, LengthDelimitedCodec>,
+) -> Result<Bytes, Error> {
+    match read_frame(framed).await? {
+        Frame::Challenge(nonce) => Ok(nonce),
+        frame => Err(Error::InvalidFrame(format!(
+            "expected challenge, got {frame:?}"
+        ))),
+    }
+}
+
+// Reads the proof of the peer and checks it signs our nonce.
+async fn read_proof(
+    framed: &mut Framed<TcpStream, LengthDelimitedCodec>,
+    key: &hmac::Key,
+    role: &[u8],
+    nonce: &[u8],
+    peer_id: &str,
+) -> Result<(), Error> {
+    let signature = match read_frame(framed).await? {
+        Frame::Proof(signature) => signature,
+        frame => {
+            return Err(Error::InvalidFrame(format!(
+                "expected proof, got {frame:?}"
+            )))
+        }
+    };
+    hmac::verify(key, &proof_data(role, nonce, peer_id), &signature)
+        .map_err(|_| Error::Unauthorized)
+}
+
+fn sign_proof(key: &hmac::Key, role: &[u8], nonce: &[u8], node_id: &str) -> Frame {
+    let tag = hmac::sign(key, &proof_data(role, nonce, node_id));
+    Frame::Proof(Bytes::copy_from_slice(tag.as_ref()))
+}
+
+fn proof_data(role: &[u8], nonce: &[u8], node_id: &str) -> Vec<u8> {
+    [role, nonce, node_id.as_bytes()].concat()
+}
+
+fn new_nonce() -> Bytes {
+    Bytes::copy_from_slice(&rand::random::<[u8; NONCE_LEN]>())
+}
+
+async fn write_frame(
+    framed: &mut Framed<TcpStream, LengthDelimitedCodec>,
+    frame: Frame,
+) -> Result<(), Error> {
+    framed.send(frame.encode()?).await?;
+    Ok(())
+}
+
+// Sends the peer the subscriptions and retained messages of this node, then the frames
+// of the cluster task, connecting again whenever the connection is lost.
+async fn connect_peer<Q>(
+    addr: String,
+    node_id: Arc<str>,
+    secret: Option<String>,
+    reconnect_interval: Duration,
+    peer_node_id: Arc<Mutex<Option<String>>>,
+    mut frames: mpsc::Receiver<Frame>,
+    global: Arc<GlobalState<Q>>,
+) where
+    Q: Queue,
+{
+    let key = secret.map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()));
+    loop {
+        let result = async {
+            let stream = TcpStream::connect(&addr).await?;
+            stream.set_nodelay(true)?;
+            let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+            let handshake = async {
+                write_frame(&mut framed, Frame::Hello(node_id.to_string())).await?;
+                let nonce = new_nonce();
+                if key.is_some() {
+                    write_frame(&mut framed, Frame::Challenge(nonce.clone())).await?;
+                }
+                let hello = read_frame(&mut framed).await?;
+                let Frame::Hello(peer_id) = hello else {
+                    return Err(Error::InvalidFrame(format!(
+                        "expected hello, got {hello:?}"
+                    )));
+                };
+                if let Some(key) = &key {
+                    let peer_nonce = read_challenge(&mut framed).await?;
+                    read_proof(&mut framed, key, SERVE_ROLE, &nonce, &peer_id).await?;
+                    let proof = sign_proof(key, CONNECT_ROLE, &peer_nonce, &node_id);
+                    write_frame(&mut framed, proof).await?;
+                }
+                Ok(peer_id)
+            };
+            let peer_id = time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, handshake)
+                .await
+                .map_err(|_| Error::HandshakeTimeout)??;
+            log::info!("cluster node {node_id} connected to {peer_id} at {addr}");
+
+            let filters = global.route_table().topic_filters();
+            write_frame(&mut framed, Frame::Subscriptions(filters)).await?;
+            for content in global.retain_table().get_matches("#") {
+                let message = PublishMessage::from(content);
+                write_frame(&mut framed, Frame::Retain(Box::new(message))).await?;
+            }
+            *peer_node_id.lock() = Some(peer_id);
+
+            loop {
+                tokio::select! {
+                    frame = frames.recv() => match frame {
+                        Some(frame) => write_frame(&mut framed, frame).await?,
+                        None => return Ok(()),
+                    },
+                    // The peer sends nothing more on this connection.
+                    _ = framed.next() => return Err(Error::Closed),
+                }
+            }
+        }
+        .await;
+        *peer_node_id.lock() = None;
+        match result {
+            Ok(()) => return,
+            Err(err) => log::warn!("cluster node {node_id} connection to {addr}: {err}"),
+        }
+
+        // Frames for the peer are dropped until it is reachable again.
+        let reconnect = time::sleep(reconnect_interval);
+        tokio::pin!(reconnect);
+        loop {
+            tokio::select! {
+                _ = &mut reconnect => break,
+                frame = frames.recv() => {
+                    if frame.is_none() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Receives the frames of a peer, applying them to this node.
+async fn serve_peer<Q>(
+    stream: TcpStream,
+    node_id: Arc<str>,
+    secret: Option<String>,
+    global: Arc<GlobalState<Q>>,
+    subscriptions: Arc<DashMap<String, Vec<TopicFilter>, ahash::RandomState>>,
+) -> Result<(), Error>
+where
+    Q: Queue,
+{
+    stream.set_nodelay(true)?;
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    let key = secret.map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()));
+    // Nothing the peer sends is applied before it proved the secret.
+    let handshake = async {
+        let hello = read_frame(&mut framed).await?;
+        let Frame::Hello(peer_id) = hello else {
+            return Err(Error::InvalidFrame(format!(
+                "expected hello, got {hello:?}"
+            )));
+        };
+        let peer_nonce = match &key {
+            Some(_) => Some(read_challenge(&mut framed).await?),
+            None => None,
+        };
+        write_frame(&mut framed, Frame::Hello(node_id.to_string())).await?;
+        if let (Some(key), Some(peer_nonce)) = (&key, peer_nonce) {
+            let nonce = new_nonce();
+            write_frame(&mut framed, Frame::Challenge(nonce.clone())).await?;
+            let proof = sign_proof(key, SERVE_ROLE, &peer_nonce, &node_id);
+            write_frame(&mut framed, proof).await?;
+            read_proof(&mut framed, key, CONNECT_ROLE, &nonce, &peer_id).await?;
+        }
+        Ok(peer_id)
+    };
+    let peer_id = time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| Error::HandshakeTimeout)??;
+
+    let forwarded_from: Arc<str> = Arc::from(peer_id.as_str());
+    let session = Session::new(format!("$cluster/{peer_id}"), false, 12);
+    let result = loop {
+        let frame = match read_frame(&mut framed).await {
+            Ok(frame) => frame,
+            Err(err) => break Err(err),
+        };
+        match frame {
+            Frame::Subscriptions(filters) => {
+                subscriptions.insert(peer_id.clone(), filters);
+            }
+            Frame::Publish(mut message) => {
+                message.set_forwarded_from(Some(forwarded_from.clone()));
+                route_publish(&session, *message, &global).await;
+            }
+            Frame::Retain(message) => {
+                // Retained messages of this node are kept.
+                if global
+                    .retain_table()
+                    .get_matches(message.topic_name())
+                    .is_empty()
+                {
+                    global
+                        .retain_table()
+                        .insert(Arc::new((session.client_id(), &*message).into()));
+                }
+            }
+            Frame::Takeover(client_id) => {
+                if let Some(sender) = global.get_outgoing_sender(&client_id) {
+                    if let Err(err) = sender.send(Outgoing::Kick(KickReason::Takeover)).await {
+                        log::debug!("client#{client_id} send takeover: {err}");
+                    }
+                }
+            }
+            frame @ (Frame::Hello(_) | Frame::Challenge(_) | Frame::Proof(_)) => {
+                break Err(Error::InvalidFrame(format!("unexpected {frame:?}")));
+            }
+        }
+    };
+    subscriptions.remove(&peer_id);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use mqtt_codec_kit::common::{QualityOfService, TopicName};
+
+    use crate::{protocols::v4::publish::dispatch_publish, store::memory::queue::MemoryQueue};
+
+    use super::*;
+
+    type State = Arc<GlobalState<MemoryQueue>>;
+
+    async fn free_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn node(node_id: &str, addr: SocketAddr, peers: &[SocketAddr]) -> State {
+        let global: State = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let peers = peers.iter().map(|addr| addr.to_string()).collect();
+        let mut config = ClusterConfig::new(node_id, addr, peers);
+        config.gossip_interval = Duration::from_millis(50);
+        config.reconnect_interval = Duration::from_millis(100);
+        config.secret = Some("secret".to_owned());
+        let cluster = Cluster::new(config, global.clone());
+        tokio::spawn(async move { cluster.run().await.unwrap() });
+        global
+    }
+
+    async fn subscriber(global: &State, client_id: &str, filter: &str) -> mpsc::Receiver<Outgoing> {
+        let (tx, rx) = mpsc::channel(16);
+        global.add_client(client_id, tx).await;
+        global.subscribe(
+            &TopicFilter::new(filter).unwrap(),
+            client_id,
+            QualityOfService::Level1,
+        );
+        rx
+    }
+
+    fn message(topic_name: &str, retain: bool) -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new(topic_name).unwrap(),
+            Bytes::from_static(b"hello"),
+            QualityOfService::Level1,
+            retain,
+        )
+    }
+
+    async fn publish(global: &State, topic_name: &str, retain: bool) {
+        let mut session = Session::new("publisher".to_owned(), false, 12);
+        dispatch_publish(&mut session, message(topic_name, retain), global.clone()).await;
+    }
+
+    async fn recv_topic(rx: &mut mpsc::Receiver<Outgoing>) -> Option<String> {
+        match time::timeout(Duration::from_millis(500), rx.recv()).await {
+            Ok(Some(Outgoing::Publish(_, message))) => Some(message.topic_name().to_string()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_publish() {
+        let addrs = [free_addr().await, free_addr().await, free_addr().await];
+        let a = node("a", addrs[0], &[addrs[1], addrs[2]]);
+        let b = node("b", addrs[1], &[addrs[0], addrs[2]]);
+        let c = node("c", addrs[2], &[addrs[0], addrs[1]]);
+
+        let mut sub_a = subscriber(&a, "sub-a", "t/#").await;
+        let mut sub_b = subscriber(&b, "sub-b", "t/#").await;
+        let mut sub_c = subscriber(&c, "sub-c", "other/#").await;
+        // Connections and subscription gossip
+        time::sleep(Duration::from_millis(300)).await;
+
+        publish(&a, "t/1", false).await;
+        assert_eq!(recv_topic(&mut sub_a).await.as_deref(), Some("t/1"));
+        assert_eq!(recv_topic(&mut sub_b).await.as_deref(), Some("t/1"));
+        // Forwarded messages are not forwarded again.
+        assert_eq!(recv_topic(&mut sub_a).await, None);
+        assert_eq!(recv_topic(&mut sub_b).await, None);
+        assert_eq!(recv_topic(&mut sub_c).await, None);
+
+        publish(&c, "t/2", false).await;
+        assert_eq!(recv_topic(&mut sub_a).await.as_deref(), Some("t/2"));
+        assert_eq!(recv_topic(&mut sub_b).await.as_deref(), Some("t/2"));
+        assert_eq!(recv_topic(&mut sub_a).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_retained_replication() {
+        let addrs = [free_addr().await, free_addr().await, free_addr().await];
+        let a = node("a", addrs[0], &[addrs[1], addrs[2]]);
+        let b = node("b", addrs[1], &[addrs[0]]);
+        time::sleep(Duration::from_millis(300)).await;
+
+        publish(&a, "r/1", true).await;
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(b.retain_table().get_matches("r/1").len(), 1);
+
+        // A node starting later receives the retained messages once its peers connect.
+        let c = node("c", addrs[2], &[addrs[0]]);
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(c.retain_table().get_matches("r/1").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_takeover() {
+        let addrs = [free_addr().await, free_addr().await];
+        let a = node("a", addrs[0], &[addrs[1]]);
+        let b = node("b", addrs[1], &[addrs[0]]);
+        time::sleep(Duration::from_millis(300)).await;
+
+        let (tx, mut rx_a) = mpsc::channel(16);
+        a.add_client("client", tx).await;
+        let (tx, _rx_b) = mpsc::channel(16);
+        b.add_client("client", tx).await;
+        match time::timeout(Duration::from_millis(500), rx_a.recv()).await {
+            Ok(Some(Outgoing::Kick(reason))) => assert!(reason == KickReason::Takeover),
+            _ => panic!("client not taken over"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_authentication() {
+        let global: State = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let mut rx = subscriber(&global, "sub", "t/#").await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Connects as node "evil" and serves it as node "a"
+        let connect = || async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+            write_frame(&mut framed, Frame::Hello("evil".to_owned()))
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let secret = Some("secret".to_owned());
+            let served = serve_peer(
+                stream,
+                "a".into(),
+                secret,
+                global.clone(),
+                Default::default(),
+            );
+            (framed, tokio::spawn(served))
+        };
+
+        // No challenge
+        let (mut framed, served) = connect().await;
+        let publish = Frame::Publish(Box::new(message("t/1", false)));
+        write_frame(&mut framed, publish).await.unwrap();
+        assert!(matches!(served.await.unwrap(), Err(Error::InvalidFrame(_))));
+
+        // Wrong secret
+        let (mut framed, served) = connect().await;
+        let nonce = new_nonce();
+        write_frame(&mut framed, Frame::Challenge(nonce.clone()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_frame(&mut framed).await,
+            Ok(Frame::Hello(node_id)) if node_id == "a"
+        ));
+        let peer_nonce = read_challenge(&mut framed).await.unwrap();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"other");
+        let result = read_proof(&mut framed, &key, SERVE_ROLE, &nonce, "a").await;
+        assert!(matches!(result, Err(Error::Unauthorized)));
+        let proof = sign_proof(&key, CONNECT_ROLE, &peer_nonce, "evil");
+        write_frame(&mut framed, proof).await.unwrap();
+        let publish = Frame::Publish(Box::new(message("t/1", false)));
+        let _ = write_frame(&mut framed, publish).await;
+        assert!(matches!(served.await.unwrap(), Err(Error::Unauthorized)));
+
+        assert_eq!(recv_topic(&mut rx).await, None);
+    }
+}
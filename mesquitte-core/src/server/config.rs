@@ -1,4 +1,14 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use tokio::sync::Notify;
+
+use crate::types::connection::TlsInfo;
+
+/// Default time allowed for the TLS and WebSocket handshakes of a new connection.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default number of data streams a QUIC client may open besides the control stream.
+pub const DEFAULT_QUIC_DATA_STREAMS: usize = 16;
 
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -12,10 +22,80 @@ impl ServerConfig {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct TlsConfig {
     pub ca_file: Option<PathBuf>,
     pub cert_file: PathBuf,
     pub key_file: PathBuf,
     pub fail_if_no_peer_cert: bool,
+    /// PEM encoded certificate revocation lists checked against client
+    /// certificates. They are reloaded together with the certificates.
+    pub crl_files: Vec<PathBuf>,
+    pub revocation: RevocationPolicy,
+    /// Take the client identity from the verified client certificate.
+    pub peer_cert_identity: PeerCertIdentity,
+    /// Certificates selected by the SNI host name of the client, keyed by host
+    /// name or wildcard such as `*.example.com`. Others get `cert_file`.
+    pub sni_certs: HashMap<String, CertFiles>,
+    /// Check the certificate, key, CA and CRL files at this interval and reload them
+    /// when one has changed on disk.
+    pub reload_interval: Option<Duration>,
+    /// Reload the certificate, key, CA and CRL files whenever notified, e.g. from a
+    /// SIGHUP handler.
+    pub reload_signal: Option<Arc<Notify>>,
+}
+
+impl TlsConfig {
+    /// Serve `cert_file` without client certificates, the other options are
+    /// set on the returned config.
+    pub fn new<P: Into<PathBuf>>(cert_file: P, key_file: P) -> Self {
+        Self {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// How client certificates are checked against `crl_files`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RevocationPolicy {
+    /// Only check the client certificate, not the intermediates of its chain.
+    pub end_entity_only: bool,
+    /// Accept certificates whose revocation status is not covered by any CRL.
+    pub allow_unknown_status: bool,
+    /// Reject clients once a CRL is past its nextUpdate time.
+    pub enforce_expiration: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct CertFiles {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+/// Field of a client certificate used as MQTT client identity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerCertField {
+    CommonName,
+    /// The first DNS or URI subject alternative name.
+    SubjectAltName,
+}
+
+impl PeerCertField {
+    pub(crate) fn value(&self, tls: &TlsInfo) -> Option<String> {
+        match self {
+            PeerCertField::CommonName => tls.common_name.clone(),
+            PeerCertField::SubjectAltName => tls.subject_alt_names.first().cloned(),
+        }
+    }
+}
+
+/// When set, the certificate field replaces the client identifier or username
+/// sent in the CONNECT packet. Clients whose certificate lacks the field are
+/// refused.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerCertIdentity {
+    pub client_id: Option<PeerCertField>,
+    pub username: Option<PeerCertField>,
 }
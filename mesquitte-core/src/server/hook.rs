@@ -0,0 +1,289 @@
+use std::future::{self, Future};
+
+use futures_util::future::BoxFuture;
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter};
+
+use crate::types::{connection::ConnectionInfo, publish::PublishMessage, session::Session};
+
+/// Why a message published by a client did not reach some or all of its receivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The publisher is over its publish quota.
+    QuotaExceeded,
+    /// The message matched no subscription, or no member is left in the shared
+    /// subscription group it has to be delivered again to.
+    NoSubscribers,
+    /// The session of the receiver is closed.
+    ReceiverClosed,
+    /// A bridge kept more messages than it may while its remote broker is unreachable.
+    BufferFull,
+}
+
+fn done<'a>() -> BoxFuture<'a, ()> {
+    Box::pin(future::ready(()))
+}
+
+/// Callbacks run by the broker on client, session and message events, for auditing,
+/// custom routing or side effects. Every callback does nothing by default.
+///
+/// Callbacks are awaited in the connection task of the client concerned, a slow hook
+/// slows down that client only.
+pub trait Hook: Send + Sync {
+    /// A client sent its CONNECT packet, `client_id` is the identifier it asked for and
+    /// may be empty.
+    fn on_client_connecting<'a>(
+        &'a self,
+        client_id: &'a str,
+        connection: &'a ConnectionInfo,
+    ) -> BoxFuture<'a, ()> {
+        let _ = (client_id, connection);
+        done()
+    }
+
+    /// The client connection was accepted and its CONNACK written.
+    fn on_client_connected<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, ()> {
+        let _ = session;
+        done()
+    }
+
+    fn on_client_disconnected<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, ()> {
+        let _ = session;
+        done()
+    }
+
+    /// A client connected without a session to resume.
+    fn on_session_created<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, ()> {
+        let _ = session;
+        done()
+    }
+
+    /// The session of a client is discarded: its expiry interval elapsed, the client
+    /// disconnected with a clean session or it was kicked.
+    fn on_session_expired<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, ()> {
+        let _ = session;
+        done()
+    }
+
+    fn on_subscribe<'a>(
+        &'a self,
+        session: &'a Session,
+        filter: &'a TopicFilter,
+        granted_qos: QualityOfService,
+    ) -> BoxFuture<'a, ()> {
+        let _ = (session, filter, granted_qos);
+        done()
+    }
+
+    fn on_unsubscribe<'a>(
+        &'a self,
+        session: &'a Session,
+        filter: &'a TopicFilter,
+    ) -> BoxFuture<'a, ()> {
+        let _ = (session, filter);
+        done()
+    }
+
+    /// A message published by a client, or its last will, is about to be retained and
+    /// dispatched to the subscribers. The message returned is dispatched instead, `None`
+    /// drops it.
+    fn on_message_publish<'a>(
+        &'a self,
+        session: &'a Session,
+        message: PublishMessage,
+    ) -> BoxFuture<'a, Option<PublishMessage>> {
+        let _ = session;
+        Box::pin(future::ready(Some(message)))
+    }
+
+    /// A message is handed to the connection of a receiver.
+    fn on_message_delivered<'a>(
+        &'a self,
+        session: &'a Session,
+        message: &'a PublishMessage,
+    ) -> BoxFuture<'a, ()> {
+        let _ = (session, message);
+        done()
+    }
+
+    /// The receiver acknowledged a message, with PUBACK for QoS 1 and PUBCOMP for QoS 2.
+    fn on_message_acked<'a>(&'a self, session: &'a Session, packet_id: u16) -> BoxFuture<'a, ()> {
+        let _ = (session, packet_id);
+        done()
+    }
+
+    /// `client_id` is the publisher when the whole message is dropped, the receiver when
+    /// only its copy is.
+    fn on_message_dropped<'a>(
+        &'a self,
+        client_id: &'a str,
+        message: &'a PublishMessage,
+        reason: DropReason,
+    ) -> BoxFuture<'a, ()> {
+        let _ = (client_id, message, reason);
+        done()
+    }
+}
+
+/// Hooks registered on the broker, run in registration order.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Box<dyn Hook>>,
+}
+
+impl Hooks {
+    pub(crate) fn push(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    async fn run<'a, F, Fut>(&'a self, f: F)
+    where
+        F: Fn(&'a dyn Hook) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        for hook in &self.hooks {
+            f(hook.as_ref()).await;
+        }
+    }
+
+    pub(crate) async fn client_connecting(&self, client_id: &str, connection: &ConnectionInfo) {
+        self.run(|hook| hook.on_client_connecting(client_id, connection))
+            .await
+    }
+
+    pub(crate) async fn client_connected(&self, session: &Session) {
+        self.run(|hook| hook.on_client_connected(session)).await
+    }
+
+    pub(crate) async fn client_disconnected(&self, session: &Session) {
+        self.run(|hook| hook.on_client_disconnected(session)).await
+    }
+
+    pub(crate) async fn session_created(&self, session: &Session) {
+        self.run(|hook| hook.on_session_created(session)).await
+    }
+
+    pub(crate) async fn session_expired(&self, session: &Session) {
+        self.run(|hook| hook.on_session_expired(session)).await
+    }
+
+    pub(crate) async fn subscribe(
+        &self,
+        session: &Session,
+        filter: &TopicFilter,
+        granted_qos: QualityOfService,
+    ) {
+        self.run(|hook| hook.on_subscribe(session, filter, granted_qos))
+            .await
+    }
+
+    pub(crate) async fn unsubscribe(&self, session: &Session, filter: &TopicFilter) {
+        self.run(|hook| hook.on_unsubscribe(session, filter)).await
+    }
+
+    /// The message once modified by every hook, `None` as soon as one of them drops it.
+    pub(crate) async fn message_publish(
+        &self,
+        session: &Session,
+        mut message: PublishMessage,
+    ) -> Option<PublishMessage> {
+        for hook in &self.hooks {
+            message = hook.on_message_publish(session, message).await?;
+        }
+        Some(message)
+    }
+
+    pub(crate) async fn message_delivered(&self, session: &Session, message: &PublishMessage) {
+        self.run(|hook| hook.on_message_delivered(session, message))
+            .await
+    }
+
+    pub(crate) async fn message_acked(&self, session: &Session, packet_id: u16) {
+        self.run(|hook| hook.on_message_acked(session, packet_id))
+            .await
+    }
+
+    pub(crate) async fn message_dropped(
+        &self,
+        client_id: &str,
+        message: &PublishMessage,
+        reason: DropReason,
+    ) {
+        self.run(|hook| hook.on_message_dropped(client_id, message, reason))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use bytes::Bytes;
+    use mqtt_codec_kit::common::TopicName;
+
+    use super::*;
+
+    struct PublishHook<F>(F);
+
+    impl<F> Hook for PublishHook<F>
+    where
+        F: Fn(PublishMessage) -> Option<PublishMessage> + Send + Sync,
+    {
+        fn on_message_publish<'a>(
+            &'a self,
+            _session: &'a Session,
+            message: PublishMessage,
+        ) -> BoxFuture<'a, Option<PublishMessage>> {
+            Box::pin(future::ready((self.0)(message)))
+        }
+    }
+
+    fn message(topic_name: &str) -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new(topic_name).unwrap(),
+            Bytes::from_static(b"payload"),
+            QualityOfService::Level0,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_message_publish() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut hooks = Hooks::default();
+        hooks.push(Box::new(PublishHook(|mut message: PublishMessage| {
+            message.set_payload(Bytes::from_static(b"modified"));
+            Some(message)
+        })));
+        hooks.push(Box::new(PublishHook(|message: PublishMessage| {
+            (&message.topic_name()[..] != "drop").then_some(message)
+        })));
+        hooks.push(Box::new(PublishHook({
+            let calls = calls.clone();
+            move |message: PublishMessage| {
+                // Sees the message modified by the first hook
+                assert_eq!(&message.payload()[..], b"modified");
+                calls.fetch_add(1, Ordering::Relaxed);
+                Some(message)
+            }
+        })));
+        let session = Session::new("publisher".to_owned(), false, 12);
+
+        let published = hooks.message_publish(&session, message("t")).await;
+        assert_eq!(&published.unwrap().payload()[..], b"modified");
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Hooks after the one dropping the message are not run.
+        assert!(hooks
+            .message_publish(&session, message("drop"))
+            .await
+            .is_none());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
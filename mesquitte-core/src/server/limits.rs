@@ -0,0 +1,665 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::{store::queue::Queue, types::connection::ConnectionInfo};
+
+use super::state::GlobalState;
+
+// Drop expired ban list entries once the list grows beyond this.
+const BAN_LIST_PRUNE_LEN: usize = 1024;
+
+type IpConnections = DashMap<IpAddr, usize, ahash::RandomState>;
+
+/// Why a connection was refused by the connection limits. Unless the refusal
+/// happens before a TLS or WebSocket handshake, the client still receives a
+/// CONNACK with the matching reason code before it is disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// Too many connections on the listener, the broker or from one address.
+    Connections,
+    /// New connections arrive faster than the accept rate.
+    AcceptRate,
+}
+
+/// Token bucket refilled with `per_second` tokens, holding at most `burst`.
+#[derive(Debug, Clone, Copy)]
+pub struct AcceptRate {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimits {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub accept_rate: Option<AcceptRate>,
+}
+
+/// Counts the live connections of a listener or of the whole broker.
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    connections: Arc<AtomicUsize>,
+    per_ip: Arc<IpConnections>,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn acquire(&self, ip: Option<IpAddr>) -> Result<ConnectionPermit, LimitExceeded> {
+        let mut permit = ConnectionPermit {
+            connections: self.connections.clone(),
+            per_ip: None,
+        };
+        let connections = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+        if self
+            .limits
+            .max_connections
+            .is_some_and(|max| connections > max)
+        {
+            return Err(LimitExceeded::Connections);
+        }
+
+        if let (Some(ip), Some(max)) = (ip, self.limits.max_connections_per_ip) {
+            let exceeded = {
+                let mut count = self.per_ip.entry(ip).or_default();
+                *count += 1;
+                *count > max
+            };
+            permit.per_ip = Some((ip, self.per_ip.clone()));
+            if exceeded {
+                return Err(LimitExceeded::Connections);
+            }
+        }
+
+        // Only connections within the limits take a token, the permit is released
+        // when refused.
+        if let Some(rate) = self.limits.accept_rate {
+            if !self.bucket.lock().take(rate) {
+                return Err(LimitExceeded::AcceptRate);
+            }
+        }
+        Ok(permit)
+    }
+}
+
+#[derive(Debug, Default)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn take(&mut self, rate: AcceptRate) -> bool {
+        let burst = rate.burst.max(1) as f64;
+        if !self.has(1.0, rate.per_second as f64, burst) {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    // Refill the bucket and tell whether `amount` can be taken. Amounts larger
+    // than the burst only need a full bucket.
+    fn has(&mut self, amount: f64, per_second: f64, burst: f64) -> bool {
+        let now = Instant::now();
+        self.tokens = match self.updated_at {
+            Some(at) => {
+                (self.tokens + now.duration_since(at).as_secs_f64() * per_second).min(burst)
+            }
+            None => burst,
+        };
+        self.updated_at = Some(now);
+        self.tokens >= amount.min(burst)
+    }
+
+    // Take `amount` even when the bucket runs into debt, return how long to
+    // wait until the debt is paid off.
+    fn take_or_wait(&mut self, amount: f64, per_second: f64, burst: f64) -> Duration {
+        self.has(amount, per_second, burst);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / per_second)
+        }
+    }
+}
+
+/// Counts a connection against its limiter until dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectionPermit {
+    connections: Arc<AtomicUsize>,
+    per_ip: Option<(IpAddr, Arc<IpConnections>)>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+        if let Some((ip, per_ip)) = &self.per_ip {
+            if let Some(mut count) = per_ip.get_mut(ip) {
+                *count -= 1;
+            }
+            per_ip.remove_if(ip, |_, count| *count == 0);
+        }
+    }
+}
+
+/// What happens to a PUBLISH over the rate limits of its client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Delay reading from the client until it is within the limits again, the
+    /// transport pushes back on the client meanwhile.
+    #[default]
+    Throttle,
+    /// Drop the message. MQTT v5 clients get `QuotaExceeded` in the
+    /// acknowledgement, MQTT v3.1.1 ones cannot be told.
+    Reject,
+}
+
+/// Limits on the messages published by each client. The bursts allow one
+/// second worth of messages or bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PublishLimits {
+    pub max_messages_per_second: Option<u32>,
+    pub max_bytes_per_second: Option<u32>,
+    /// Larger payloads are always rejected.
+    pub max_payload_size: Option<usize>,
+    pub action: QuotaAction,
+}
+
+/// Publish counters of a client.
+#[derive(Debug, Default)]
+pub struct PublishCounters {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    throttled: AtomicU64,
+    rejected: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl PublishCounters {
+    /// Messages accepted from the client.
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes accepted from the client.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Messages delayed because the client was over its rate limits.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped because of the limits.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Messages to the client discarded because they exceed its Maximum
+    /// Packet Size.
+    pub fn discarded(&self) -> u64 {
+        self.discarded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_discarded(&self) {
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) enum QuotaCheck {
+    Allow,
+    Reject,
+}
+
+/// Until when reading from a throttled client is paused, shared by its session
+/// and the task reading its packets. The session keeps handling the packets
+/// already read and writing to the client meanwhile.
+#[derive(Debug, Default)]
+pub(crate) struct ReadPause {
+    until: Mutex<Option<Instant>>,
+}
+
+impl ReadPause {
+    fn pause(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut paused = self.until.lock();
+        *paused = Some(paused.map_or(until, |paused| paused.max(until)));
+    }
+
+    /// How long to wait before reading the next packet.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        let mut paused = self.until.lock();
+        let remaining = (*paused)?.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            *paused = None;
+        }
+        remaining
+    }
+}
+
+/// Rate limit state of one client.
+#[derive(Debug, Default)]
+pub(crate) struct PublishQuota {
+    limits: PublishLimits,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    counters: Arc<PublishCounters>,
+    read_pause: Arc<ReadPause>,
+}
+
+impl PublishQuota {
+    pub(crate) fn new(limits: PublishLimits, counters: Arc<PublishCounters>) -> Self {
+        Self {
+            limits,
+            messages: Default::default(),
+            bytes: Default::default(),
+            counters,
+            read_pause: Default::default(),
+        }
+    }
+
+    pub(crate) fn counters(&self) -> &Arc<PublishCounters> {
+        &self.counters
+    }
+
+    pub(crate) fn read_pause(&self) -> &Arc<ReadPause> {
+        &self.read_pause
+    }
+
+    /// Account a PUBLISH with `payload_len` bytes of payload. A throttled PUBLISH
+    /// is allowed, the reading of the next ones is paused instead.
+    pub(crate) fn check(&mut self, payload_len: usize) -> QuotaCheck {
+        let limits = self.limits;
+        if limits.max_payload_size.is_some_and(|max| payload_len > max) {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            return QuotaCheck::Reject;
+        }
+
+        let messages = limits
+            .max_messages_per_second
+            .map(|rate| (rate.max(1) as f64, 1.0));
+        let bytes = limits
+            .max_bytes_per_second
+            .map(|rate| (rate.max(1) as f64, payload_len as f64));
+        let check = match limits.action {
+            QuotaAction::Throttle => {
+                let mut wait = Duration::ZERO;
+                if let Some((rate, amount)) = messages {
+                    wait = wait.max(self.messages.take_or_wait(amount, rate, rate));
+                }
+                if let Some((rate, amount)) = bytes {
+                    wait = wait.max(self.bytes.take_or_wait(amount, rate, rate));
+                }
+                if !wait.is_zero() {
+                    self.counters.throttled.fetch_add(1, Ordering::Relaxed);
+                    self.read_pause.pause(wait);
+                }
+                QuotaCheck::Allow
+            }
+            QuotaAction::Reject => {
+                let allowed = messages
+                    .map_or(true, |(rate, amount)| self.messages.has(amount, rate, rate))
+                    && bytes.map_or(true, |(rate, amount)| self.bytes.has(amount, rate, rate));
+                if !allowed {
+                    self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    return QuotaCheck::Reject;
+                }
+                if let Some((_, amount)) = messages {
+                    self.messages.tokens -= amount;
+                }
+                if let Some((_, amount)) = bytes {
+                    self.bytes.tokens -= amount;
+                }
+                QuotaCheck::Allow
+            }
+        };
+        self.counters.messages.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes
+            .fetch_add(payload_len as u64, Ordering::Relaxed);
+        check
+    }
+}
+
+/// Ban clients after `max_failures` failed CONNECT attempts within `window`.
+#[derive(Debug, Clone, Copy)]
+pub struct BanPolicy {
+    pub max_failures: u32,
+    pub window: Duration,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+struct BanEntry {
+    failures: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Addresses temporarily refused because of repeated CONNECT or
+/// authentication failures.
+#[derive(Debug, Default)]
+pub struct BanList {
+    policy: Option<BanPolicy>,
+    entries: DashMap<IpAddr, BanEntry, ahash::RandomState>,
+}
+
+impl BanList {
+    pub fn new(policy: Option<BanPolicy>) -> Self {
+        Self {
+            policy,
+            entries: Default::default(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.entries
+            .get(&ip)
+            .and_then(|entry| entry.banned_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        let now = Instant::now();
+        self.entries.insert(
+            ip,
+            BanEntry {
+                failures: 0,
+                window_start: now,
+                banned_until: Some(now + duration),
+            },
+        );
+    }
+
+    pub fn unban(&self, ip: IpAddr) {
+        self.entries.remove(&ip);
+    }
+
+    pub fn record_failure(&self, ip: IpAddr) {
+        let Some(policy) = self.policy else {
+            return;
+        };
+        let now = Instant::now();
+        {
+            let mut entry = self.entries.entry(ip).or_insert(BanEntry {
+                failures: 0,
+                window_start: now,
+                banned_until: None,
+            });
+            let ban_expired = entry.banned_until.is_some_and(|until| until <= now);
+            if ban_expired || now.duration_since(entry.window_start) > policy.window {
+                *entry = BanEntry {
+                    failures: 0,
+                    window_start: now,
+                    banned_until: None,
+                };
+            }
+            entry.failures += 1;
+            if entry.banned_until.is_none() && entry.failures >= policy.max_failures {
+                log::warn!(
+                    "ban {ip} for {:?} after {} failed connects",
+                    policy.duration,
+                    entry.failures
+                );
+                entry.banned_until = Some(now + policy.duration);
+            }
+        }
+
+        if self.entries.len() > BAN_LIST_PRUNE_LEN {
+            self.entries.retain(|_, entry| match entry.banned_until {
+                Some(until) => until > now,
+                None => now.duration_since(entry.window_start) <= policy.window,
+            });
+        }
+    }
+}
+
+/// Check a new connection against the ban list, the global limits and the
+/// limits of its listener. A connection over a limit is marked on `connection`
+/// so the CONNECT handler can refuse it, no permit is returned then. Over a
+/// limit, connections that still need a TLS or WebSocket `handshake` are
+/// refused right away instead, a reconnect storm would pay for every handshake
+/// otherwise.
+pub(crate) fn admit<Q>(
+    listener: &ConnectionLimiter,
+    global: &GlobalState<Q>,
+    connection: &mut ConnectionInfo,
+    handshake: bool,
+) -> Result<Option<[ConnectionPermit; 2]>, Refused>
+where
+    Q: Queue,
+{
+    let ip = connection.remote_addr().map(|addr| addr.ip());
+    if ip.is_some_and(|ip| global.ban_list().is_banned(ip)) {
+        return Err(Refused::Banned);
+    }
+
+    let permits = global
+        .connection_limiter()
+        .acquire(ip)
+        .and_then(|global| Ok([global, listener.acquire(ip)?]));
+    match permits {
+        Ok(permits) => Ok(Some(permits)),
+        Err(exceeded) => {
+            log::info!("connection from {:?} over limit: {:?}", ip, exceeded);
+            if handshake {
+                return Err(Refused::LimitExceeded(exceeded));
+            }
+            connection.set_limit_exceeded(exceeded);
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Refused {
+    Banned,
+    LimitExceeded(LimitExceeded),
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::time;
+
+    use super::*;
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([192, 0, 2, last]))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accept_rate() {
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            accept_rate: Some(AcceptRate {
+                per_second: 2,
+                burst: 2,
+            }),
+            ..Default::default()
+        });
+
+        let permits = (0..2)
+            .map(|_| limiter.acquire(ip(1)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            limiter.acquire(ip(1)).unwrap_err(),
+            LimitExceeded::AcceptRate
+        );
+        assert_eq!(limiter.connections(), 2);
+
+        time::advance(Duration::from_millis(400)).await;
+        assert_eq!(
+            limiter.acquire(ip(2)).unwrap_err(),
+            LimitExceeded::AcceptRate
+        );
+        time::advance(Duration::from_millis(200)).await;
+        let permit = limiter.acquire(ip(2)).unwrap();
+        assert_eq!(
+            limiter.acquire(ip(2)).unwrap_err(),
+            LimitExceeded::AcceptRate
+        );
+
+        // the bucket holds no more than the burst
+        time::advance(Duration::from_secs(10)).await;
+        for _ in 0..2 {
+            limiter.acquire(ip(3)).unwrap();
+        }
+        assert_eq!(
+            limiter.acquire(ip(3)).unwrap_err(),
+            LimitExceeded::AcceptRate
+        );
+        drop((permits, permit));
+        assert_eq!(limiter.connections(), 0);
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+
+        let first = limiter.acquire(ip(1)).unwrap();
+        let second = limiter.acquire(ip(1)).unwrap();
+        assert_eq!(
+            limiter.acquire(ip(1)).unwrap_err(),
+            LimitExceeded::Connections
+        );
+        assert_eq!(limiter.connections(), 2);
+        assert_eq!(limiter.per_ip.get(&ip(1).unwrap()).as_deref(), Some(&2));
+
+        // dropping a permit releases the address
+        drop(first);
+        let third = limiter.acquire(ip(1)).unwrap();
+
+        let other = limiter.acquire(ip(2)).unwrap();
+        assert_eq!(
+            limiter.acquire(ip(3)).unwrap_err(),
+            LimitExceeded::Connections
+        );
+        assert_eq!(limiter.connections(), 3);
+
+        drop((second, third, other));
+        assert_eq!(limiter.connections(), 0);
+        assert!(limiter.per_ip.is_empty());
+        limiter.acquire(None).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refused_connection_keeps_accept_token() {
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            max_connections_per_ip: Some(1),
+            accept_rate: Some(AcceptRate {
+                per_second: 1,
+                burst: 2,
+            }),
+            ..Default::default()
+        });
+
+        let first = limiter.acquire(ip(1)).unwrap();
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.acquire(ip(1)).unwrap_err(),
+                LimitExceeded::Connections
+            );
+        }
+        assert_eq!(limiter.connections(), 1);
+        // The refusals above took no token.
+        let second = limiter.acquire(ip(2)).unwrap();
+        assert_eq!(
+            limiter.acquire(ip(3)).unwrap_err(),
+            LimitExceeded::AcceptRate
+        );
+        assert_eq!(limiter.connections(), 2);
+        drop((first, second));
+        assert_eq!(limiter.connections(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ban_list() {
+        let ban_list = BanList::new(Some(BanPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(10),
+            duration: Duration::from_secs(60),
+        }));
+        let addr = ip(1).unwrap();
+
+        // failures spread over more than the window are forgiven
+        for _ in 0..2 {
+            ban_list.record_failure(addr);
+        }
+        time::advance(Duration::from_secs(11)).await;
+        ban_list.record_failure(addr);
+        assert!(!ban_list.is_banned(addr));
+
+        for _ in 0..2 {
+            ban_list.record_failure(addr);
+        }
+        assert!(ban_list.is_banned(addr));
+        assert!(!ban_list.is_banned(ip(2).unwrap()));
+
+        time::advance(Duration::from_secs(59)).await;
+        assert!(ban_list.is_banned(addr));
+        time::advance(Duration::from_secs(1)).await;
+        assert!(!ban_list.is_banned(addr));
+
+        // the failures before the ban do not count anymore
+        ban_list.record_failure(addr);
+        assert!(!ban_list.is_banned(addr));
+
+        ban_list.ban(addr, Duration::from_secs(5));
+        assert!(ban_list.is_banned(addr));
+        ban_list.unban(addr);
+        assert!(!ban_list.is_banned(addr));
+    }
+
+    #[test]
+    fn test_publish_throttle() {
+        let limits = PublishLimits {
+            max_messages_per_second: Some(2),
+            ..Default::default()
+        };
+        let counters = Arc::new(PublishCounters::default());
+        let mut quota = PublishQuota::new(limits, counters.clone());
+
+        for _ in 0..2 {
+            assert!(matches!(quota.check(10), QuotaCheck::Allow));
+            assert!(quota.read_pause().remaining().is_none());
+        }
+        // Over the limit the message is still allowed, reading is paused instead.
+        assert!(matches!(quota.check(10), QuotaCheck::Allow));
+        let remaining = quota.read_pause().remaining().unwrap();
+        assert!(remaining > Duration::from_millis(400) && remaining <= Duration::from_millis(500));
+        assert_eq!(counters.throttled(), 1);
+        assert_eq!(counters.messages(), 3);
+        assert_eq!(counters.bytes(), 30);
+    }
+}
@@ -1,26 +1,560 @@
 use std::sync::Arc;
 
+use mqtt_codec_kit::common::ProtocolLevel;
+use rewind::Rewind;
 use state::GlobalState;
-use tokio::io::{split, AsyncRead, AsyncWrite};
+use tokio::io::{split, AsyncRead, AsyncReadExt as _, AsyncWrite};
 
-use crate::{protocols::v4::read_write_loop::read_write_loop, store::queue::Queue};
+#[cfg(feature = "quic")]
+use crate::protocols::v4::read_write_loop::read_write_loop_with_streams;
+use crate::{
+    protocols::{v4, v5},
+    store::queue::Queue,
+    types::connection::ConnectionInfo,
+};
 
+pub mod bridge;
+pub mod broker;
+pub mod cluster;
 pub mod config;
+pub mod hook;
+pub mod limits;
+#[cfg(any(feature = "mqtt", feature = "mqtts", feature = "ws", feature = "wss"))]
+mod proxy_protocol;
 #[cfg(feature = "quic")]
 pub mod quic;
+mod rewind;
+pub mod rule;
 #[cfg(feature = "rustls")]
 pub mod rustls;
 pub mod state;
 #[cfg(any(feature = "mqtt", feature = "mqtts"))]
 pub mod tcp;
+#[cfg(all(unix, feature = "uds"))]
+pub mod uds;
 #[cfg(any(feature = "ws", feature = "wss"))]
 pub mod ws;
 
-async fn process_client<S, Q>(stream: S, global: Arc<GlobalState<Q>>)
-where
+// Longest protocol name of a CONNECT packet, "MQIsdp" of MQTT 3.1.
+const MAX_PROTOCOL_NAME_LEN: usize = 6;
+
+/// Serves a client over an already established stream, e.g. one half of an in-memory
+/// `tokio::io::duplex` pipe, until it disconnects.
+pub async fn process_client<S, Q>(
+    stream: S,
+    connection: ConnectionInfo,
+    global: Arc<GlobalState<Q>>,
+) where
     S: AsyncRead + AsyncWrite + Send + 'static,
     Q: Queue + Send + 'static,
 {
-    let (rd, wr) = split(stream);
-    read_write_loop(rd, wr, global).await;
+    let (mut rd, wr) = split(stream);
+    let (prefix, level) = read_protocol_level(&mut rd).await;
+    let rd = Rewind::new(prefix, rd);
+    match level {
+        Some(ProtocolLevel::Version50) => {
+            v5::read_write_loop::read_write_loop(rd, wr, connection, global).await
+        }
+        _ => v4::read_write_loop::read_write_loop(rd, wr, connection, global).await,
+    }
+}
+
+#[cfg(feature = "quic")]
+async fn process_client_with_streams<S, D, Q>(
+    stream: S,
+    data_streams: tokio::sync::mpsc::Receiver<D>,
+    max_data_streams: usize,
+    connection: ConnectionInfo,
+    global: Arc<GlobalState<Q>>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    D: AsyncRead + Unpin + Send + 'static,
+    Q: Queue + Send + 'static,
+{
+    let (mut rd, wr) = split(stream);
+    let (prefix, level) = read_protocol_level(&mut rd).await;
+    let rd = Rewind::new(prefix, rd);
+    match level {
+        // v5 clients only use the control stream, dropping the receiver refuses the
+        // data streams they open.
+        Some(ProtocolLevel::Version50) => {
+            v5::read_write_loop::read_write_loop(rd, wr, connection, global).await
+        }
+        _ => {
+            read_write_loop_with_streams(rd, wr, data_streams, max_data_streams, connection, global)
+                .await
+        }
+    }
+}
+
+// Reads the beginning of the first packet up to the protocol level of a CONNECT, the bytes
+// read are given back to replay them. Anything else is left for the v4 loop to refuse.
+async fn read_protocol_level<R>(reader: &mut R) -> (Vec<u8>, Option<ProtocolLevel>)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut prefix = Vec::with_capacity(16);
+    let level = async {
+        // CONNECT packet type
+        if read_byte(reader, &mut prefix).await? >> 4 != 1 {
+            return None;
+        }
+        // Remaining length, at most 4 bytes
+        for _ in 0..4 {
+            if read_byte(reader, &mut prefix).await? & 0x80 == 0 {
+                break;
+            }
+        }
+        let name_len = u16::from_be_bytes([
+            read_byte(reader, &mut prefix).await?,
+            read_byte(reader, &mut prefix).await?,
+        ]);
+        if usize::from(name_len) > MAX_PROTOCOL_NAME_LEN {
+            return None;
+        }
+        for _ in 0..name_len {
+            read_byte(reader, &mut prefix).await?;
+        }
+        ProtocolLevel::from_u8(read_byte(reader, &mut prefix).await?).ok()
+    }
+    .await;
+    (prefix, level)
+}
+
+async fn read_byte<R>(reader: &mut R, prefix: &mut Vec<u8>) -> Option<u8>
+where
+    R: AsyncRead + Unpin,
+{
+    let byte = reader.read_u8().await.ok()?;
+    prefix.push(byte);
+    Some(byte)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use bytes::Bytes;
+    use futures_util::{future::BoxFuture, SinkExt as _, StreamExt as _};
+    use mqtt_codec_kit::{
+        common::{
+            qos::QoSWithPacketIdentifier, Encodable, QualityOfService, TopicFilter, TopicName,
+        },
+        v4::packet::{
+            ConnectPacket as V4ConnectPacket, DisconnectPacket as V4DisconnectPacket,
+            MqttDecoder as V4MqttDecoder, MqttEncoder as V4MqttEncoder, PingreqPacket,
+            PubackPacket as V4PubackPacket, PublishPacket, SubscribePacket as V4SubscribePacket,
+            VariablePacket as V4VariablePacket,
+        },
+        v5::{
+            control::{ConnectReasonCode, DisconnectReasonCode},
+            packet::{
+                connect::ConnectProperties, subscribe::SubscribeOptions,
+                ConnectPacket as V5ConnectPacket, DisconnectPacket, MqttDecoder, MqttEncoder,
+                PubackPacket, PublishPacket as V5PublishPacket, SubscribePacket, VariablePacket,
+            },
+        },
+    };
+    use tokio::{io::AsyncWriteExt as _, sync::mpsc, time};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use crate::{
+        server::{
+            hook::Hook,
+            limits::{BanPolicy, PublishLimits},
+        },
+        store::memory::queue::MemoryQueue,
+        types::{connection::Transport, publish::PublishMessage, session::Session},
+    };
+
+    use super::*;
+
+    // Serves a client which sends `input` and closes its side.
+    async fn serve(global: Arc<GlobalState<MemoryQueue>>, input: &[u8]) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut connection = ConnectionInfo::new(Transport::Tcp, None);
+        connection.set_remote_addr(([10, 0, 0, 1], 1883).into());
+        process_client(server, connection, global).await;
+    }
+
+    // Records the events of every client, modifies the payload of the messages
+    // published and drops those published on `drop`.
+    struct RecordingHook(mpsc::UnboundedSender<String>);
+
+    impl RecordingHook {
+        fn record<'a>(&'a self, event: String) -> BoxFuture<'a, ()> {
+            let _ = self.0.send(event);
+            Box::pin(async {})
+        }
+    }
+
+    impl Hook for RecordingHook {
+        fn on_client_connecting<'a>(
+            &'a self,
+            client_id: &'a str,
+            _connection: &'a ConnectionInfo,
+        ) -> BoxFuture<'a, ()> {
+            self.record(format!("connecting {client_id}"))
+        }
+
+        fn on_client_connected<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, ()> {
+            self.record(format!("connected {}", session.client_id()))
+        }
+
+        fn on_client_disconnected<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, ()> {
+            self.record(format!("disconnected {}", session.client_id()))
+        }
+
+        fn on_session_created<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, ()> {
+            self.record(format!("session created {}", session.client_id()))
+        }
+
+        fn on_session_expired<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, ()> {
+            self.record(format!("session expired {}", session.client_id()))
+        }
+
+        fn on_subscribe<'a>(
+            &'a self,
+            session: &'a Session,
+            filter: &'a TopicFilter,
+            granted_qos: QualityOfService,
+        ) -> BoxFuture<'a, ()> {
+            let filter: &str = filter;
+            let qos = granted_qos as u8;
+            self.record(format!("subscribe {} {filter} {qos}", session.client_id()))
+        }
+
+        fn on_message_publish<'a>(
+            &'a self,
+            session: &'a Session,
+            mut message: PublishMessage,
+        ) -> BoxFuture<'a, Option<PublishMessage>> {
+            let topic_name = message.topic_name().to_string();
+            let _ = self
+                .0
+                .send(format!("publish {} {topic_name}", session.client_id()));
+            message.set_payload(Bytes::from_static(b"modified"));
+            Box::pin(async move { (topic_name != "drop").then_some(message) })
+        }
+
+        fn on_message_delivered<'a>(
+            &'a self,
+            session: &'a Session,
+            message: &'a PublishMessage,
+        ) -> BoxFuture<'a, ()> {
+            let topic_name: &str = message.topic_name();
+            self.record(format!("delivered {} {topic_name}", session.client_id()))
+        }
+
+        fn on_message_acked<'a>(
+            &'a self,
+            session: &'a Session,
+            packet_id: u16,
+        ) -> BoxFuture<'a, ()> {
+            self.record(format!("acked {} {packet_id}", session.client_id()))
+        }
+    }
+
+    fn recording_state() -> (
+        Arc<GlobalState<MemoryQueue>>,
+        mpsc::UnboundedReceiver<String>,
+    ) {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let mut global = GlobalState::new(MemoryQueue::new(10, 10));
+        global.add_hook(RecordingHook(events_tx));
+        (Arc::new(global), events_rx)
+    }
+
+    // Events recorded until the session of `client_id` expired.
+    async fn events_until_expired(
+        events: &mut mpsc::UnboundedReceiver<String>,
+        client_id: &str,
+    ) -> Vec<String> {
+        let last = format!("session expired {client_id}");
+        let mut recorded = Vec::new();
+        while recorded.last() != Some(&last) {
+            match time::timeout(Duration::from_secs(1), events.recv()).await {
+                Ok(Some(event)) => recorded.push(event),
+                _ => panic!("session not expired, events: {recorded:?}"),
+            }
+        }
+        recorded
+    }
+
+    fn expected_events(client_id: &str) -> Vec<String> {
+        [
+            "connecting",
+            "session created",
+            "connected",
+            "subscribe t/# 1",
+            "publish t",
+            "delivered t",
+            "acked 1",
+            "publish drop",
+            "disconnected",
+            "session expired",
+        ]
+        .into_iter()
+        .map(|event| match event.split_once(' ') {
+            Some((name, arg)) if !event.starts_with("session") => {
+                format!("{name} {client_id} {arg}")
+            }
+            _ => format!("{event} {client_id}"),
+        })
+        .collect()
+    }
+
+    fn encode<P: Encodable>(packet: P) -> Vec<u8> {
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_read_protocol_level() {
+        let mut buf = Vec::new();
+        V5ConnectPacket::new("v5").encode(&mut buf).unwrap();
+        let mut reader = &buf[..];
+        let (prefix, level) = read_protocol_level(&mut reader).await;
+        assert_eq!(level, Some(ProtocolLevel::Version50));
+        assert_eq!(prefix, buf[..prefix.len()]);
+
+        let mut buf = Vec::new();
+        V4ConnectPacket::new("v4").encode(&mut buf).unwrap();
+        let (_, level) = read_protocol_level(&mut &buf[..]).await;
+        assert_eq!(level, Some(ProtocolLevel::Version311));
+
+        // PINGREQ
+        let (prefix, level) = read_protocol_level(&mut &[0xc0, 0x00][..]).await;
+        assert_eq!(level, None);
+        assert_eq!(prefix, [0xc0]);
+
+        let (prefix, level) = read_protocol_level(&mut &[0x10, 0x0a, 0x00][..]).await;
+        assert_eq!(level, None);
+        assert_eq!(prefix, [0x10, 0x0a, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_process_v5_client() {
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let (client, server) = tokio::io::duplex(1024);
+        let connection = ConnectionInfo::new(Transport::Tcp, None);
+        tokio::spawn(process_client(server, connection, global));
+
+        let (rd, wr) = split(client);
+        let mut reader = FramedRead::new(rd, MqttDecoder::new());
+        let mut writer = FramedWrite::new(wr, MqttEncoder::new());
+        writer
+            .send(VariablePacket::from(V5ConnectPacket::new("v5")))
+            .await
+            .unwrap();
+        match reader.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(connack))) => {
+                assert_eq!(connack.connect_reason_code(), ConnectReasonCode::Success);
+            }
+            other => panic!("unexpected packet: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ban_refused_connect() {
+        let mut global = GlobalState::new(MemoryQueue::new(10, 10));
+        global.set_ban_policy(Some(BanPolicy {
+            max_failures: 1,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(60),
+        }));
+        let global = Arc::new(global);
+        let addr = IpAddr::from([10, 0, 0, 1]);
+
+        // Closed, undecodable or not a CONNECT, nothing was refused.
+        serve(global.clone(), &[]).await;
+        serve(global.clone(), &[0xc0, 0x00]).await;
+        serve(global.clone(), &[0x10, 0x02, 0xff, 0xff]).await;
+        assert!(!global.ban_list().is_banned(addr));
+
+        let mut packet = V4ConnectPacket::new("");
+        packet.set_clean_session(false);
+        serve(global.clone(), &encode(packet)).await;
+        assert!(global.ban_list().is_banned(addr));
+        global.ban_list().unban(addr);
+
+        let mut packet = V5ConnectPacket::new("v5");
+        let mut properties = ConnectProperties::default();
+        properties.set_receive_maximum(Some(0));
+        packet.set_properties(properties);
+        serve(global.clone(), &encode(packet)).await;
+        assert!(global.ban_list().is_banned(addr));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alive_while_throttled() {
+        let mut global = GlobalState::new(MemoryQueue::new(10, 10));
+        global.set_publish_limits(PublishLimits {
+            max_bytes_per_second: Some(10),
+            ..Default::default()
+        });
+        let (client, server) = tokio::io::duplex(1024);
+        let connection = ConnectionInfo::new(Transport::Tcp, None);
+        tokio::spawn(process_client(server, connection, Arc::new(global)));
+
+        let (rd, wr) = split(client);
+        let mut reader = FramedRead::new(rd, V4MqttDecoder::new());
+        let mut writer = FramedWrite::new(wr, V4MqttEncoder::new());
+        let mut connect = V4ConnectPacket::new("throttled");
+        connect.set_keep_alive(1);
+        writer.send(V4VariablePacket::from(connect)).await.unwrap();
+        assert!(matches!(
+            reader.next().await,
+            Some(Ok(V4VariablePacket::ConnackPacket(_)))
+        ));
+
+        // 40 bytes at 10 bytes per second pause reading for 3s, twice the time
+        // a client with a 1s keep alive may stay silent.
+        let publish = PublishPacket::new(
+            TopicName::new("t").unwrap(),
+            QoSWithPacketIdentifier::Level1(1),
+            vec![0; 40],
+        );
+        writer.send(V4VariablePacket::from(publish)).await.unwrap();
+        assert!(matches!(
+            reader.next().await,
+            Some(Ok(V4VariablePacket::PubackPacket(_)))
+        ));
+        // The packet after the PUBLISH was already being read, the next one waits.
+        let started = tokio::time::Instant::now();
+        for _ in 0..2 {
+            writer
+                .send(V4VariablePacket::from(PingreqPacket::new()))
+                .await
+                .unwrap();
+            assert!(matches!(
+                reader.next().await,
+                Some(Ok(V4VariablePacket::PingrespPacket(_)))
+            ));
+        }
+        assert!(started.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_v4_hooks() {
+        let (global, mut events) = recording_state();
+        let (client, server) = tokio::io::duplex(1024);
+        let connection = ConnectionInfo::new(Transport::Tcp, None);
+        tokio::spawn(process_client(server, connection, global));
+
+        let (rd, wr) = split(client);
+        let mut reader = FramedRead::new(rd, V4MqttDecoder::new());
+        let mut writer = FramedWrite::new(wr, V4MqttEncoder::new());
+        let mut connect = V4ConnectPacket::new("v4");
+        connect.set_clean_session(true);
+        let packets = [
+            V4VariablePacket::from(connect),
+            V4VariablePacket::from(V4SubscribePacket::new(
+                1,
+                vec![(TopicFilter::new("t/#").unwrap(), QualityOfService::Level1)],
+            )),
+            V4VariablePacket::from(PublishPacket::new(
+                TopicName::new("t").unwrap(),
+                QoSWithPacketIdentifier::Level1(2),
+                "payload",
+            )),
+        ];
+        for packet in packets {
+            writer.send(packet).await.unwrap();
+        }
+        let publish = loop {
+            match reader.next().await {
+                Some(Ok(V4VariablePacket::PublishPacket(publish))) => break publish,
+                Some(Ok(_)) => {}
+                other => panic!("unexpected packet: {other:?}"),
+            }
+        };
+        assert_eq!(&publish.payload()[..], b"modified");
+        let packet_id = match publish.qos() {
+            QoSWithPacketIdentifier::Level1(packet_id) => packet_id,
+            qos => panic!("unexpected qos: {qos:?}"),
+        };
+        let packets = [
+            V4VariablePacket::from(V4PubackPacket::new(packet_id)),
+            V4VariablePacket::from(PublishPacket::new(
+                TopicName::new("drop").unwrap(),
+                QoSWithPacketIdentifier::Level0,
+                "payload",
+            )),
+            V4VariablePacket::from(V4DisconnectPacket::new()),
+        ];
+        for packet in packets {
+            writer.send(packet).await.unwrap();
+        }
+
+        assert_eq!(
+            events_until_expired(&mut events, "v4").await,
+            expected_events("v4")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_v5_hooks() {
+        let (global, mut events) = recording_state();
+        let (client, server) = tokio::io::duplex(1024);
+        let connection = ConnectionInfo::new(Transport::Tcp, None);
+        tokio::spawn(process_client(server, connection, global));
+
+        let (rd, wr) = split(client);
+        let mut reader = FramedRead::new(rd, MqttDecoder::new());
+        let mut writer = FramedWrite::new(wr, MqttEncoder::new());
+        let mut options = SubscribeOptions::default();
+        options.set_qos(QualityOfService::Level1);
+        let mut connect = V5ConnectPacket::new("v5");
+        connect.set_clean_session(true);
+        let packets = [
+            VariablePacket::from(connect),
+            VariablePacket::from(SubscribePacket::new(
+                1,
+                vec![(TopicFilter::new("t/#").unwrap(), options)],
+            )),
+            VariablePacket::from(V5PublishPacket::new(
+                TopicName::new("t").unwrap(),
+                QoSWithPacketIdentifier::Level1(2),
+                "payload",
+            )),
+        ];
+        for packet in packets {
+            writer.send(packet).await.unwrap();
+        }
+        let publish = loop {
+            match reader.next().await {
+                Some(Ok(VariablePacket::PublishPacket(publish))) => break publish,
+                Some(Ok(_)) => {}
+                other => panic!("unexpected packet: {other:?}"),
+            }
+        };
+        assert_eq!(&publish.payload()[..], b"modified");
+        let packet_id = match publish.qos() {
+            QoSWithPacketIdentifier::Level1(packet_id) => packet_id,
+            qos => panic!("unexpected qos: {qos:?}"),
+        };
+        let packets = [
+            VariablePacket::from(PubackPacket::new_success(packet_id)),
+            VariablePacket::from(V5PublishPacket::new(
+                TopicName::new("drop").unwrap(),
+                QoSWithPacketIdentifier::Level0,
+                "payload",
+            )),
+            VariablePacket::from(DisconnectPacket::new(
+                DisconnectReasonCode::NormalDisconnection,
+            )),
+        ];
+        for packet in packets {
+            writer.send(packet).await.unwrap();
+        }
+
+        assert_eq!(
+            events_until_expired(&mut events, "v5").await,
+            expected_events("v5")
+        );
+    }
 }
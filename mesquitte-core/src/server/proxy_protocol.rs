@@ -0,0 +1,468 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+
+use crate::types::connection::{ConnectionInfo, ProxyInfo, ProxyTlsInfo};
+
+// see: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+const V2_COMMAND_LOCAL: u8 = 0x0;
+const V2_COMMAND_PROXY: u8 = 0x1;
+
+const V2_FAMILY_UNSPEC: u8 = 0x0;
+const V2_FAMILY_INET: u8 = 0x1;
+const V2_FAMILY_INET6: u8 = 0x2;
+const V2_FAMILY_UNIX: u8 = 0x3;
+
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+const PP2_SUBTYPE_SSL_CIPHER: u8 = 0x23;
+
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+const PP2_CLIENT_CERT_SESS: u8 = 0x04;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Missing PROXY protocol header")]
+    MissingHeader,
+    #[error("Invalid PROXY protocol v1 header: {0}")]
+    InvalidV1Header(&'static str),
+    #[error("Invalid PROXY protocol v2 header: {0}")]
+    InvalidV2Header(&'static str),
+}
+
+/// Read a PROXY protocol v1 or v2 header from the start of the stream and record
+/// the client address it carries. Only the header bytes are consumed, so the TLS,
+/// WebSocket or MQTT handshake can continue on the same stream.
+pub(crate) async fn read_proxy_header<S>(
+    stream: &mut S,
+    connection: &mut ConnectionInfo,
+) -> Result<(), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // The shortest valid header, "PROXY UNKNOWN\r\n", is longer than the v2 signature.
+    let mut signature = [0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut signature).await?;
+
+    let proxy = if signature == V2_SIGNATURE {
+        read_v2(stream).await?
+    } else if signature.starts_with(V1_PREFIX) {
+        read_v1(stream, &signature).await?
+    } else {
+        return Err(Error::MissingHeader);
+    };
+
+    log::debug!("read PROXY protocol header: {:?}", proxy);
+    connection.set_proxy(proxy);
+    Ok(())
+}
+
+async fn read_v1<S>(stream: &mut S, prefix: &[u8]) -> Result<ProxyInfo, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(Error::InvalidV1Header("header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line =
+        str::from_utf8(&line[..line.len() - 2]).map_err(|_| Error::InvalidV1Header("not ascii"))?;
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("UNKNOWN") => return Ok(ProxyInfo::default()),
+        Some("TCP4") | Some("TCP6") => {}
+        _ => return Err(Error::InvalidV1Header("unsupported protocol")),
+    }
+
+    let mut next_field = || fields.next().ok_or(Error::InvalidV1Header("missing field"));
+    let src_ip: IpAddr = next_field()?
+        .parse()
+        .map_err(|_| Error::InvalidV1Header("invalid source address"))?;
+    let dst_ip: IpAddr = next_field()?
+        .parse()
+        .map_err(|_| Error::InvalidV1Header("invalid destination address"))?;
+    let src_port: u16 = next_field()?
+        .parse()
+        .map_err(|_| Error::InvalidV1Header("invalid source port"))?;
+    let dst_port: u16 = next_field()?
+        .parse()
+        .map_err(|_| Error::InvalidV1Header("invalid destination port"))?;
+
+    Ok(ProxyInfo {
+        source: Some(SocketAddr::new(src_ip, src_port)),
+        destination: Some(SocketAddr::new(dst_ip, dst_port)),
+        ..Default::default()
+    })
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<ProxyInfo, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family_protocol = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+
+    if version_command >> 4 != 2 {
+        return Err(Error::InvalidV2Header("unsupported version"));
+    }
+
+    let mut payload = vec![0u8; length as usize];
+    stream.read_exact(&mut payload).await?;
+
+    match version_command & 0x0F {
+        // Connections established by the proxy itself, e.g. health checks.
+        V2_COMMAND_LOCAL => return Ok(ProxyInfo::default()),
+        V2_COMMAND_PROXY => {}
+        _ => return Err(Error::InvalidV2Header("unsupported command")),
+    }
+
+    let (source, destination, tlvs) = match family_protocol >> 4 {
+        V2_FAMILY_UNSPEC => (None, None, &payload[..]),
+        V2_FAMILY_INET => {
+            let addresses = payload
+                .get(..12)
+                .ok_or(Error::InvalidV2Header("short IPv4 address block"))?;
+            let src_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[0..4]).unwrap());
+            let dst_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[4..8]).unwrap());
+            let src_port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            let dst_port = u16::from_be_bytes([addresses[10], addresses[11]]);
+            (
+                Some(SocketAddr::new(src_ip.into(), src_port)),
+                Some(SocketAddr::new(dst_ip.into(), dst_port)),
+                &payload[12..],
+            )
+        }
+        V2_FAMILY_INET6 => {
+            let addresses = payload
+                .get(..36)
+                .ok_or(Error::InvalidV2Header("short IPv6 address block"))?;
+            let src_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[0..16]).unwrap());
+            let dst_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[16..32]).unwrap());
+            let src_port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            let dst_port = u16::from_be_bytes([addresses[34], addresses[35]]);
+            (
+                Some(SocketAddr::new(src_ip.into(), src_port)),
+                Some(SocketAddr::new(dst_ip.into(), dst_port)),
+                &payload[36..],
+            )
+        }
+        V2_FAMILY_UNIX => {
+            let tlvs = payload
+                .get(216..)
+                .ok_or(Error::InvalidV2Header("short unix address block"))?;
+            (None, None, tlvs)
+        }
+        _ => return Err(Error::InvalidV2Header("unsupported address family")),
+    };
+
+    let mut proxy = ProxyInfo {
+        source,
+        destination,
+        ..Default::default()
+    };
+    for (kind, value) in parse_tlvs(tlvs)? {
+        match kind {
+            PP2_TYPE_ALPN => proxy.alpn = Some(value.to_vec()),
+            PP2_TYPE_AUTHORITY => proxy.authority = Some(String::from_utf8_lossy(value).into()),
+            PP2_TYPE_SSL => proxy.tls = parse_ssl_tlv(value)?,
+            _ => {}
+        }
+    }
+    Ok(proxy)
+}
+
+fn parse_ssl_tlv(value: &[u8]) -> Result<Option<ProxyTlsInfo>, Error> {
+    if value.len() < 5 {
+        return Err(Error::InvalidV2Header("short SSL TLV"));
+    }
+    let client = value[0];
+    let verify = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+    if client & PP2_CLIENT_SSL == 0 {
+        return Ok(None);
+    }
+
+    let client_cert = client & (PP2_CLIENT_CERT_CONN | PP2_CLIENT_CERT_SESS) != 0;
+    let mut tls = ProxyTlsInfo {
+        client_cert,
+        verified: client_cert && verify == 0,
+        ..Default::default()
+    };
+    for (kind, value) in parse_tlvs(&value[5..])? {
+        let value = Some(String::from_utf8_lossy(value).into());
+        match kind {
+            PP2_SUBTYPE_SSL_VERSION => tls.version = value,
+            PP2_SUBTYPE_SSL_CN => tls.common_name = value,
+            PP2_SUBTYPE_SSL_CIPHER => tls.cipher = value,
+            _ => {}
+        }
+    }
+    Ok(Some(tls))
+}
+
+fn parse_tlvs(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, Error> {
+    let mut tlvs = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 3 {
+            return Err(Error::InvalidV2Header("short TLV"));
+        }
+        let kind = data[0];
+        let length = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let value = data
+            .get(3..3 + length)
+            .ok_or(Error::InvalidV2Header("TLV length out of range"))?;
+        tlvs.push((kind, value));
+        data = &data[3 + length..];
+    }
+    Ok(tlvs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type ErrorCheck = fn(&Error) -> bool;
+
+    // The bytes after the header are left in the stream.
+    async fn read(mut input: &[u8]) -> Result<(ConnectionInfo, &[u8]), Error> {
+        let mut connection = ConnectionInfo::default();
+        read_proxy_header(&mut input, &mut connection).await?;
+        Ok((connection, input))
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        // Over TCP
+        header.push(family << 4 | 0x1);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    fn tlv(kind: u8, value: &[u8]) -> Vec<u8> {
+        let mut tlv = vec![kind];
+        tlv.extend((value.len() as u16).to_be_bytes());
+        tlv.extend(value);
+        tlv
+    }
+
+    fn addr(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let cases = [
+            (
+                "PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n",
+                addr("192.168.0.1:56324"),
+                addr("192.168.0.11:1883"),
+            ),
+            (
+                "PROXY TCP6 2001:db8::1 ::1 4000 8883\r\n",
+                addr("[2001:db8::1]:4000"),
+                addr("[::1]:8883"),
+            ),
+            ("PROXY UNKNOWN\r\n", None, None),
+            (
+                "PROXY UNKNOWN ffff:f::ffff ffff:f::ffff 65535 65535\r\n",
+                None,
+                None,
+            ),
+        ];
+        for (header, source, destination) in cases {
+            let input = [header.as_bytes(), b"\x10\x00"].concat();
+            let (connection, rest) = read(&input).await.unwrap();
+            let proxy = connection.proxy().unwrap();
+            assert_eq!(proxy.source, source, "{header:?}");
+            assert_eq!(proxy.destination, destination, "{header:?}");
+            assert_eq!(connection.remote_addr(), source, "{header:?}");
+            assert_eq!(rest, b"\x10\x00", "{header:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        // 192.168.0.1:56324 to 192.168.0.11:1883
+        let inet = [192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x07, 0x5b];
+        let input = [v2(V2_COMMAND_PROXY, V2_FAMILY_INET, &inet), vec![0x10]].concat();
+        let (connection, rest) = read(&input).await.unwrap();
+        let proxy = connection.proxy().unwrap();
+        assert_eq!(proxy.source, addr("192.168.0.1:56324"));
+        assert_eq!(proxy.destination, addr("192.168.0.11:1883"));
+        assert!(proxy.tls.is_none());
+        assert_eq!(connection.remote_addr(), addr("192.168.0.1:56324"));
+        assert_eq!(rest, [0x10]);
+
+        let mut inet6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        inet6.extend(Ipv6Addr::LOCALHOST.octets());
+        inet6.extend(4000u16.to_be_bytes());
+        inet6.extend(8883u16.to_be_bytes());
+        let (connection, _) = read(&v2(V2_COMMAND_PROXY, V2_FAMILY_INET6, &inet6))
+            .await
+            .unwrap();
+        let proxy = connection.proxy().unwrap();
+        assert_eq!(proxy.source, addr("[2001:db8::1]:4000"));
+        assert_eq!(proxy.destination, addr("[::1]:8883"));
+
+        // The addresses of LOCAL connections are ignored.
+        let input = [v2(V2_COMMAND_LOCAL, V2_FAMILY_INET, &inet), vec![0x10]].concat();
+        let (connection, rest) = read(&input).await.unwrap();
+        assert!(connection.proxy().unwrap().source.is_none());
+        assert!(connection.remote_addr().is_none());
+        assert_eq!(rest, [0x10]);
+
+        let (connection, _) = read(&v2(V2_COMMAND_PROXY, V2_FAMILY_UNSPEC, &[]))
+            .await
+            .unwrap();
+        assert!(connection.proxy().unwrap().source.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_v2_tlvs() {
+        let ssl = [
+            vec![PP2_CLIENT_SSL | PP2_CLIENT_CERT_CONN, 0, 0, 0, 0],
+            tlv(PP2_SUBTYPE_SSL_VERSION, b"TLSv1.3"),
+            tlv(PP2_SUBTYPE_SSL_CN, b"device-1"),
+            tlv(PP2_SUBTYPE_SSL_CIPHER, b"TLS_AES_128_GCM_SHA256"),
+        ]
+        .concat();
+        let payload = [
+            vec![10, 0, 0, 1, 10, 0, 0, 2, 0x30, 0x39, 0x07, 0x5b],
+            tlv(PP2_TYPE_ALPN, b"mqtt"),
+            tlv(PP2_TYPE_AUTHORITY, b"broker.example.com"),
+            // Unknown types are skipped.
+            tlv(0xE0, b"custom"),
+            tlv(PP2_TYPE_SSL, &ssl),
+        ]
+        .concat();
+        let (connection, _) = read(&v2(V2_COMMAND_PROXY, V2_FAMILY_INET, &payload))
+            .await
+            .unwrap();
+        let proxy = connection.proxy().unwrap();
+        assert_eq!(proxy.source, addr("10.0.0.1:12345"));
+        assert_eq!(proxy.destination, addr("10.0.0.2:1883"));
+        assert_eq!(proxy.alpn.as_deref(), Some(&b"mqtt"[..]));
+        assert_eq!(proxy.authority.as_deref(), Some("broker.example.com"));
+        let tls = proxy.tls.as_ref().unwrap();
+        assert_eq!(tls.version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(tls.common_name.as_deref(), Some("device-1"));
+        assert_eq!(tls.cipher.as_deref(), Some("TLS_AES_128_GCM_SHA256"));
+        assert!(tls.client_cert);
+        assert!(tls.verified);
+
+        // A certificate which failed verification, and a connection without TLS.
+        let cases = [
+            (
+                vec![PP2_CLIENT_SSL | PP2_CLIENT_CERT_SESS, 0, 0, 0, 1],
+                Some(false),
+            ),
+            (vec![0, 0, 0, 0, 0], None),
+        ];
+        for (ssl, verified) in cases {
+            let (connection, _) = read(&v2(
+                V2_COMMAND_PROXY,
+                V2_FAMILY_UNSPEC,
+                &tlv(PP2_TYPE_SSL, &ssl),
+            ))
+            .await
+            .unwrap();
+            let tls = connection.proxy().unwrap().tls.as_ref();
+            assert_eq!(tls.map(|tls| tls.verified), verified);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid() {
+        let mut bad_signature = v2(V2_COMMAND_PROXY, V2_FAMILY_UNSPEC, &[]);
+        bad_signature[11] = b'X';
+        let mut bad_version = v2(V2_COMMAND_PROXY, V2_FAMILY_UNSPEC, &[]);
+        bad_version[12] = 0x11;
+        let mut truncated = v2(V2_COMMAND_PROXY, V2_FAMILY_INET, &[0; 12]);
+        truncated.truncate(20);
+        let long_line = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LENGTH));
+
+        let cases: [(&[u8], ErrorCheck); 17] = [
+            (b"", |err| matches!(err, Error::Io(_))),
+            (b"PROXY TCP4", |err| matches!(err, Error::Io(_))),
+            (b"\x10\x0c\x00\x04MQTT\x04\x02\x00\x3c", |err| {
+                matches!(err, Error::MissingHeader)
+            }),
+            (&bad_signature, |err| matches!(err, Error::MissingHeader)),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 1 2", |err| {
+                matches!(err, Error::Io(_))
+            }),
+            (long_line.as_bytes(), |err| {
+                matches!(err, Error::InvalidV1Header("header too long"))
+            }),
+            (b"PROXY UDP4 1.2.3.4 5.6.7.8 1 2\r\n", |err| {
+                matches!(err, Error::InvalidV1Header("unsupported protocol"))
+            }),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 1\r\n", |err| {
+                matches!(err, Error::InvalidV1Header("missing field"))
+            }),
+            (b"PROXY TCP4 1.2.3 5.6.7.8 1 2\r\n", |err| {
+                matches!(err, Error::InvalidV1Header("invalid source address"))
+            }),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 1 65536\r\n", |err| {
+                matches!(err, Error::InvalidV1Header("invalid destination port"))
+            }),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 \xff 2\r\n", |err| {
+                matches!(err, Error::InvalidV1Header("not ascii"))
+            }),
+            (&truncated, |err| matches!(err, Error::Io(_))),
+            (&bad_version, |err| {
+                matches!(err, Error::InvalidV2Header("unsupported version"))
+            }),
+            (&v2(0x2, V2_FAMILY_UNSPEC, &[]), |err| {
+                matches!(err, Error::InvalidV2Header("unsupported command"))
+            }),
+            (&v2(V2_COMMAND_PROXY, V2_FAMILY_INET, &[0; 8]), |err| {
+                matches!(err, Error::InvalidV2Header("short IPv4 address block"))
+            }),
+            (&v2(V2_COMMAND_PROXY, 0x4, &[]), |err| {
+                matches!(err, Error::InvalidV2Header("unsupported address family"))
+            }),
+            (
+                &v2(
+                    V2_COMMAND_PROXY,
+                    V2_FAMILY_UNSPEC,
+                    &[PP2_TYPE_ALPN, 0, 8, b'm'],
+                ),
+                |err| matches!(err, Error::InvalidV2Header("TLV length out of range")),
+            ),
+        ];
+        for (input, expected) in cases {
+            let err = read(input).await.unwrap_err();
+            assert!(expected(&err), "{input:?}: {err}");
+        }
+
+        let short_ssl = v2(
+            V2_COMMAND_PROXY,
+            V2_FAMILY_UNSPEC,
+            &tlv(PP2_TYPE_SSL, &[1, 0]),
+        );
+        assert!(matches!(
+            read(&short_ssl).await,
+            Err(Error::InvalidV2Header("short SSL TLV"))
+        ));
+    }
+}
@@ -14,6 +14,12 @@ pub enum Error {
     Connection(#[from] s2n_quic::connection::Error),
     #[error("Connection broken")]
     ConnectionBroken,
+    #[error("TLS error: {0}")]
+    Tls(#[from] s2n_quic::provider::tls::default::error::Error),
+    #[error("Missing CA cert for client authentication")]
+    MissingCACert,
+    #[error("TLS option {0} is not supported by QUIC listeners")]
+    UnsupportedTlsConfig(&'static str),
     #[error(transparent)]
     V4VariablePacket(#[from] mqtt_codec_kit::v4::packet::VariablePacketError),
     #[error(transparent)]
@@ -1,20 +1,59 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use s2n_quic::{provider::tls, Server};
+use s2n_quic::{
+    application,
+    provider::{
+        event::{
+            events::{ActivePathUpdated, SocketAddress},
+            ConnectionInfo as EventConnectionInfo, ConnectionMeta, Subscriber,
+        },
+        tls,
+    },
+    stream::{PeerStream, ReceiveStream},
+    Connection, Server,
+};
+use tokio::sync::mpsc;
 
 use crate::{
-    server::{process_client, state::GlobalState},
+    server::{
+        config::{TlsConfig, DEFAULT_QUIC_DATA_STREAMS},
+        limits::{admit, ConnectionLimiter, ConnectionLimits},
+        process_client_with_streams,
+        state::GlobalState,
+    },
     store::queue::Queue,
+    types::connection::{ConnectionInfo, QuicPath, TlsInfo, Transport},
 };
 
 use super::Error;
 
+/// ALPN protocol identifier of MQTT over QUIC.
+pub const ALPN_MQTT: &[u8] = b"mqtt";
+
+/// MQTT over QUIC listener.
+///
+/// Every QUIC connection carries one MQTT session. The first bidirectional
+/// stream opened by the client is the control stream: it starts with CONNECT
+/// and carries every packet the broker sends. After CONNECT the client may open
+/// further bidirectional or unidirectional streams, e.g. one per topic, to send
+/// PUBLISH and acknowledgement packets without head-of-line blocking. They are
+/// read concurrently and belong to the session of the control stream.
 pub struct QuicServer<Q>
 where
     Q: Queue,
 {
     inner: Server,
+    name: Option<Arc<str>>,
+    max_data_streams: usize,
+    connection_failures: Arc<AtomicU64>,
     global: Arc<GlobalState<Q>>,
+    limiter: Arc<ConnectionLimiter>,
 }
 
 impl<Q> QuicServer<Q>
@@ -29,22 +68,206 @@ where
     where
         Error: From<<T as tls::TryInto>::Error>,
     {
-        let server = Server::builder().with_tls(tls)?.with_io(addr)?.start()?;
+        let server = Server::builder()
+            .with_tls(tls)?
+            .with_event(PathTracker)?
+            .with_io(addr)?
+            .start()?;
         Ok(QuicServer {
             inner: server,
+            name: None,
+            max_data_streams: DEFAULT_QUIC_DATA_STREAMS,
+            connection_failures: Default::default(),
             global,
+            limiter: Default::default(),
         })
     }
 
+    /// Bind with the certificate and client CA of `cfg`, negotiating ALPN
+    /// `mqtt`. CRLs, SNI certificates and client identities taken from client
+    /// certificates are not supported by the QUIC TLS provider and are rejected.
+    pub fn bind_tls(
+        addr: SocketAddr,
+        cfg: &TlsConfig,
+        global: Arc<GlobalState<Q>>,
+    ) -> Result<Self, Error> {
+        if !cfg.crl_files.is_empty() {
+            return Err(Error::UnsupportedTlsConfig("crl_files"));
+        }
+        if !cfg.sni_certs.is_empty() {
+            return Err(Error::UnsupportedTlsConfig("sni_certs"));
+        }
+        let identity = cfg.peer_cert_identity;
+        if identity.client_id.is_some() || identity.username.is_some() {
+            return Err(Error::UnsupportedTlsConfig("peer_cert_identity"));
+        }
+
+        let mut builder = tls::default::Server::builder()
+            .with_certificate(cfg.cert_file.as_path(), cfg.key_file.as_path())?
+            .with_application_protocols([ALPN_MQTT])?;
+        if cfg.fail_if_no_peer_cert {
+            let ca_file = cfg.ca_file.as_deref().ok_or(Error::MissingCACert)?;
+            builder = builder
+                .with_empty_trust_store()?
+                .with_trusted_certificate(ca_file)?
+                .with_client_authentication()?;
+        }
+        Self::bind(addr, builder.build()?, global)
+    }
+
+    /// Name of the listener, recorded on every accepted connection.
+    pub fn set_name<S: Into<Arc<str>>>(&mut self, name: S) {
+        self.name = Some(name.into());
+    }
+
+    /// Maximum number of data streams read concurrently besides the control
+    /// stream, 0 allows the control stream only.
+    pub fn set_max_data_streams(&mut self, max_data_streams: usize) {
+        self.max_data_streams = max_data_streams;
+    }
+
+    /// Limits applied to the connections of this listener, on top of the
+    /// global ones.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.limiter = Arc::new(ConnectionLimiter::new(limits));
+    }
+
+    /// Number of live connections on this listener.
+    pub fn connections(&self) -> usize {
+        self.limiter.connections()
+    }
+
+    /// Number of connections closed before a control stream was opened.
+    pub fn connection_failures(&self) -> u64 {
+        self.connection_failures.load(Ordering::Relaxed)
+    }
+
     pub async fn accept(mut self) -> Result<(), Error> {
-        while let Some(mut connection) = self.inner.accept().await {
-            let g = self.global.clone();
+        while let Some(connection) = self.inner.accept().await {
+            let mut info = connection_info(&connection, self.name.clone());
+            let Ok(permits) = admit(&self.limiter, &self.global, &mut info, false) else {
+                log::debug!("refuse banned quic client {:?}", info.remote_addr());
+                connection.close(application::Error::UNKNOWN);
+                continue;
+            };
+            let max_data_streams = self.max_data_streams;
+            let connection_failures = self.connection_failures.clone();
+            let global = self.global.clone();
             tokio::spawn(async move {
-                while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await {
-                    process_client(stream, g.clone()).await;
+                if let Err(err) = serve_connection(connection, info, max_data_streams, global).await
+                {
+                    connection_failures.fetch_add(1, Ordering::Relaxed);
+                    log::warn!("accept quic control stream failed: {err}");
                 }
+                drop(permits);
             });
         }
         Ok(())
     }
 }
+
+async fn serve_connection<Q>(
+    connection: Connection,
+    info: ConnectionInfo,
+    max_data_streams: usize,
+    global: Arc<GlobalState<Q>>,
+) -> Result<(), Error>
+where
+    Q: Queue + Send + 'static,
+{
+    let (handle, mut acceptor) = connection.split();
+    let control = acceptor
+        .accept_bidirectional_stream()
+        .await?
+        .ok_or(Error::ConnectionBroken)?;
+
+    let (streams_tx, streams_rx) = mpsc::channel(max_data_streams.max(1));
+    let streams = tokio::spawn(async move {
+        while let Ok(Some(stream)) = acceptor.accept().await {
+            let stream: ReceiveStream = match stream {
+                PeerStream::Bidirectional(stream) => stream.split().0,
+                PeerStream::Receive(stream) => stream,
+            };
+            if streams_tx.send(stream).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    process_client_with_streams(control, streams_rx, max_data_streams, info, global).await;
+    streams.abort();
+    handle.close(application::Error::UNKNOWN);
+    Ok(())
+}
+
+fn connection_info(connection: &Connection, name: Option<Arc<str>>) -> ConnectionInfo {
+    let mut info = ConnectionInfo::new(Transport::Quic, name);
+    let remote_addr = connection.remote_addr().ok();
+    if let Some(remote_addr) = remote_addr {
+        info.set_remote_addr(remote_addr);
+    }
+    if let Ok(path) = connection.query_event_context(|path: &Arc<QuicPath>| path.clone()) {
+        if let (None, Some(remote_addr)) = (path.remote_addr(), remote_addr) {
+            path.migrate(remote_addr);
+        }
+        info.set_quic_path(path);
+    }
+    // The QUIC TLS provider does not expose the client certificate, which
+    // `bind_tls` refuses to take an identity from.
+    info.set_tls(TlsInfo {
+        server_name: connection
+            .server_name()
+            .ok()
+            .flatten()
+            .map(|name| name.to_string()),
+        alpn: connection
+            .application_protocol()
+            .ok()
+            .map(|alpn| alpn.to_vec()),
+        ..Default::default()
+    });
+    info
+}
+
+// Keeps the QuicPath of every connection up to date when the client migrates.
+struct PathTracker;
+
+impl Subscriber for PathTracker {
+    type ConnectionContext = Arc<QuicPath>;
+
+    fn create_connection_context(
+        &mut self,
+        _meta: &ConnectionMeta,
+        _info: &EventConnectionInfo,
+    ) -> Self::ConnectionContext {
+        Arc::default()
+    }
+
+    fn on_active_path_updated(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &ActivePathUpdated,
+    ) {
+        log::debug!(
+            "quic connection#{} path: {} -> {}",
+            meta.id,
+            event.previous.remote_addr,
+            event.active.remote_addr,
+        );
+        if let Some(remote_addr) = socket_addr(&event.previous.remote_addr) {
+            context.migrate(remote_addr);
+        }
+        if let Some(remote_addr) = socket_addr(&event.active.remote_addr) {
+            context.migrate(remote_addr);
+        }
+    }
+}
+
+fn socket_addr(addr: &SocketAddress) -> Option<SocketAddr> {
+    match addr {
+        SocketAddress::IpV4 { ip, port, .. } => Some(SocketAddr::from((**ip, *port))),
+        SocketAddress::IpV6 { ip, port, .. } => Some(SocketAddr::from((**ip, *port))),
+        _ => None,
+    }
+}
@@ -0,0 +1,231 @@
+use std::{io, path::PathBuf, sync::OnceLock};
+
+use bytes::Bytes;
+use mqtt_codec_kit::common::{QualityOfService, TopicName};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt as _,
+    sync::mpsc,
+};
+
+use crate::types::publish::PublishMessage;
+
+use super::RuleError;
+
+/// What a rule does with the fields it selected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ActionConfig {
+    /// Publish the selected fields as a JSON object. `${field}` placeholders in the
+    /// topic are replaced by the value of the selected field.
+    Republish {
+        topic: String,
+        #[serde(default)]
+        qos: u8,
+        #[serde(default)]
+        retain: bool,
+    },
+    /// Append the selected fields to a file, one JSON object per line.
+    File { path: PathBuf },
+    /// Run the callback registered on the rule engine under this name.
+    Callback { name: String },
+}
+
+pub(super) enum Action {
+    Republish {
+        topic: String,
+        qos: QualityOfService,
+        retain: bool,
+    },
+    File(FileSink),
+    Callback(String),
+}
+
+impl TryFrom<&ActionConfig> for Action {
+    type Error = RuleError;
+
+    fn try_from(config: &ActionConfig) -> Result<Self, Self::Error> {
+        let action = match config {
+            ActionConfig::Republish { topic, qos, retain } => {
+                let qos = match qos {
+                    0 => QualityOfService::Level0,
+                    1 => QualityOfService::Level1,
+                    2 => QualityOfService::Level2,
+                    qos => return Err(RuleError::Action(format!("invalid qos {qos}"))),
+                };
+                Action::Republish {
+                    topic: topic.to_owned(),
+                    qos,
+                    retain: *retain,
+                }
+            }
+            ActionConfig::File { path } => Action::File(FileSink::new(path.to_owned())),
+            ActionConfig::Callback { name } => Action::Callback(name.to_owned()),
+        };
+        Ok(action)
+    }
+}
+
+/// Message published by a republish action.
+pub(super) fn republish(
+    topic: &str,
+    qos: QualityOfService,
+    retain: bool,
+    fields: &Map<String, Value>,
+) -> Result<PublishMessage, RuleError> {
+    let topic_name = TopicName::new(render_topic(topic, fields))
+        .map_err(|err| RuleError::Action(err.to_string()))?;
+    let payload = serde_json::to_vec(fields).map_err(|err| RuleError::Action(err.to_string()))?;
+    Ok(PublishMessage::new(
+        topic_name,
+        Bytes::from(payload),
+        qos,
+        retain,
+    ))
+}
+
+// `${field}` is replaced by the field value, strings without their quotes.
+fn render_topic(template: &str, fields: &Map<String, Value>) -> String {
+    let mut topic = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        topic.push_str(&rest[..start]);
+        match fields.get(&rest[start + 2..start + len]) {
+            Some(Value::String(value)) => topic.push_str(value),
+            Some(Value::Null) | None => {}
+            Some(value) => topic.push_str(&value.to_string()),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    topic.push_str(rest);
+    topic
+}
+
+// TODO: config file action queue size
+const FILE_QUEUE_SIZE: usize = 1024;
+
+/// Lines appended by a background task, the publishing clients never wait on the disk.
+/// The task is spawned on the first line written, rules may be loaded outside of the
+/// runtime, and ends once the sink is dropped and the queued lines are written.
+pub(super) struct FileSink {
+    path: PathBuf,
+    lines: OnceLock<mpsc::Sender<Vec<u8>>>,
+}
+
+impl FileSink {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lines: OnceLock::new(),
+        }
+    }
+
+    /// Queues a line, it is dropped when the writer lags too far behind.
+    pub(super) fn write(&self, fields: &Map<String, Value>) -> io::Result<()> {
+        let mut line = serde_json::to_vec(fields)?;
+        line.push(b'\n');
+
+        let lines = self.lines.get_or_init(|| {
+            let (lines_tx, lines_rx) = mpsc::channel(FILE_QUEUE_SIZE);
+            tokio::spawn(write_lines(self.path.clone(), lines_rx));
+            lines_tx
+        });
+        lines
+            .try_send(line)
+            .map_err(|err| io::Error::other(format!("queue line: {err}")))
+    }
+}
+
+// The file is opened on the first line, and again after a write error.
+async fn write_lines(path: PathBuf, mut lines: mpsc::Receiver<Vec<u8>>) {
+    let mut file: Option<File> = None;
+    while let Some(line) = lines.recv().await {
+        if file.is_none() {
+            match OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+            {
+                Ok(opened) => file = Some(opened),
+                Err(err) => {
+                    log::warn!("open rule file {}: {err}", path.display());
+                    continue;
+                }
+            }
+        }
+        let opened = file.as_mut().expect("opened file");
+        let mut result = opened.write_all(&line).await;
+        // Writes of tokio files complete in the background until flushed, once the
+        // queue is drained.
+        if result.is_ok() && lines.is_empty() {
+            result = opened.flush().await;
+        }
+        if let Err(err) = result {
+            log::warn!("write rule file {}: {err}", path.display());
+            file = None;
+        }
+    }
+    if let Some(mut file) = file {
+        if let Err(err) = file.flush().await {
+            log::warn!("write rule file {}: {err}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_sink() {
+        let path =
+            std::env::temp_dir().join(format!("mesquitte-rule-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let sink = FileSink::new(path.clone());
+        for i in 0..3 {
+            let Value::Object(fields) = json!({ "i": i }) else {
+                unreachable!()
+            };
+            sink.write(&fields).unwrap();
+        }
+        drop(sink);
+
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+            if content.lines().count() == 3 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(content, "{\"i\":0}\n{\"i\":1}\n{\"i\":2}\n");
+    }
+
+    #[test]
+    fn test_render_topic() {
+        let Value::Object(fields) = json!({ "id": "s-1", "n": 2, "none": null }) else {
+            unreachable!()
+        };
+        let cases = [
+            ("out/${id}/${n}", "out/s-1/2"),
+            ("out/${none}${missing}/x", "out//x"),
+            ("out/${id", "out/${id"),
+        ];
+        for (template, topic) in cases {
+            assert_eq!(render_topic(template, &fields), topic, "{template}");
+        }
+    }
+}
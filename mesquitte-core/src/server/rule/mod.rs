@@ -0,0 +1,261 @@
+use std::{
+    io,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use mqtt_codec_kit::common::{QualityOfService, TopicName};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::types::publish::PublishMessage;
+
+use self::action::{republish, Action};
+pub use self::{action::ActionConfig, sql::Statement};
+
+mod action;
+mod sql;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RuleError {
+    #[error("invalid rule sql: {0}")]
+    Sql(String),
+    #[error("invalid rule action: {0}")]
+    Action(String),
+    #[error("read rules: {0}")]
+    Io(#[from] io::Error),
+    #[error("parse rules: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Definition of a rule, as loaded from a JSON file by [`RuleEngine::load_file`]:
+///
+/// ```json
+/// {
+///     "id": "hot_rooms",
+///     "sql": "SELECT clientid, payload.temp AS temp FROM \"sensors/+/temp\" WHERE payload.temp > 30",
+///     "actions": [
+///         { "type": "republish", "topic": "alerts/${clientid}", "qos": 1 },
+///         { "type": "file", "path": "/var/log/hot_rooms.jsonl" },
+///         { "type": "callback", "name": "notify" }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleConfig {
+    pub id: String,
+    pub sql: String,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// Fields a rule selected from a message, handed to the callback actions.
+#[derive(Debug)]
+pub struct RuleOutput<'a> {
+    pub rule_id: &'a str,
+    pub client_id: &'a str,
+    pub topic_name: &'a TopicName,
+    pub fields: &'a Map<String, Value>,
+}
+
+pub type RuleCallback = Arc<dyn Fn(&RuleOutput<'_>) + Send + Sync>;
+
+struct Rule {
+    config: RuleConfig,
+    statement: Statement,
+    actions: Vec<Action>,
+}
+
+impl TryFrom<RuleConfig> for Rule {
+    type Error = RuleError;
+
+    fn try_from(config: RuleConfig) -> Result<Self, Self::Error> {
+        let statement = Statement::parse(&config.sql)?;
+        let actions = config
+            .actions
+            .iter()
+            .map(Action::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            config,
+            statement,
+            actions,
+        })
+    }
+}
+
+/// Rules run on every message published to the broker, before it is dispatched to the
+/// subscribers. Rules can be added, replaced and removed while the broker runs, messages
+/// being published meanwhile see the rules from before or after the change.
+#[derive(Default)]
+pub struct RuleEngine {
+    rules: ArcSwap<Vec<Arc<Rule>>>,
+    callbacks: DashMap<String, RuleCallback, ahash::RandomState>,
+}
+
+impl RuleEngine {
+    /// Adds the rules of a JSON file holding an array of [`RuleConfig`], none of them
+    /// when one is invalid.
+    pub fn load_file(&self, path: impl AsRef<Path>) -> Result<(), RuleError> {
+        let configs: Vec<RuleConfig> = serde_json::from_slice(&std::fs::read(path)?)?;
+        self.load(configs)
+    }
+
+    /// Adds the rules, none of them when one is invalid. Rules with the id of an existing
+    /// rule replace it.
+    pub fn load(&self, configs: impl IntoIterator<Item = RuleConfig>) -> Result<(), RuleError> {
+        let rules = configs
+            .into_iter()
+            .map(|config| Rule::try_from(config).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.rules.rcu(|current| {
+            let mut current = Vec::clone(current);
+            for rule in &rules {
+                match current.iter_mut().find(|r| r.config.id == rule.config.id) {
+                    Some(r) => *r = rule.clone(),
+                    None => current.push(rule.clone()),
+                }
+            }
+            current
+        });
+        Ok(())
+    }
+
+    pub fn add_rule(&self, config: RuleConfig) -> Result<(), RuleError> {
+        self.load([config])
+    }
+
+    pub fn remove_rule(&self, id: &str) -> bool {
+        let previous = self.rules.rcu(|current| {
+            let mut current = Vec::clone(current);
+            current.retain(|rule| rule.config.id != id);
+            current
+        });
+        previous.iter().any(|rule| rule.config.id == id)
+    }
+
+    pub fn set_rule_enabled(&self, id: &str, enabled: bool) -> bool {
+        let Some(mut config) = self.rule(id) else {
+            return false;
+        };
+        config.enabled = enabled;
+        // The sql and actions of a rule already loaded are valid.
+        self.add_rule(config).is_ok()
+    }
+
+    pub fn rule(&self, id: &str) -> Option<RuleConfig> {
+        self.rules
+            .load()
+            .iter()
+            .find(|rule| rule.config.id == id)
+            .map(|rule| rule.config.clone())
+    }
+
+    pub fn rules(&self) -> Vec<RuleConfig> {
+        self.rules
+            .load()
+            .iter()
+            .map(|rule| rule.config.clone())
+            .collect()
+    }
+
+    /// Callback run by the `callback` actions naming it, rules may refer to it before it
+    /// is registered.
+    pub fn register_callback(
+        &self,
+        name: impl Into<String>,
+        callback: impl Fn(&RuleOutput<'_>) + Send + Sync + 'static,
+    ) {
+        self.callbacks.insert(name.into(), Arc::new(callback));
+    }
+
+    pub fn unregister_callback(&self, name: &str) -> bool {
+        self.callbacks.remove(name).is_some()
+    }
+
+    // Cloned out of the map, callbacks may register other callbacks.
+    fn callback(&self, name: &str) -> Option<RuleCallback> {
+        self.callbacks
+            .get(name)
+            .map(|callback| callback.value().clone())
+    }
+
+    /// Runs the rules matching a message published by `client_id`, returns the messages
+    /// their republish actions publish.
+    pub(crate) fn apply(&self, client_id: &str, message: &PublishMessage) -> Vec<PublishMessage> {
+        let rules = self.rules.load_full();
+        let mut republished = Vec::new();
+        // Built for the first matching rule only
+        let mut input = None;
+        for rule in rules.iter() {
+            if !rule.config.enabled || !rule.statement.is_match(message.topic_name()) {
+                continue;
+            }
+            let input = input.get_or_insert_with(|| rule_input(client_id, message));
+            let Some(fields) = rule.statement.select(input) else {
+                continue;
+            };
+
+            for action in &rule.actions {
+                match action {
+                    Action::Republish { topic, qos, retain } => {
+                        match republish(topic, *qos, *retain, &fields) {
+                            Ok(message) => republished.push(message),
+                            Err(err) => log::warn!("rule {} republish: {err}", rule.config.id),
+                        }
+                    }
+                    Action::File(sink) => {
+                        if let Err(err) = sink.write(&fields) {
+                            log::warn!("rule {} write file: {err}", rule.config.id);
+                        }
+                    }
+                    Action::Callback(name) => match self.callback(name) {
+                        Some(callback) => callback(&RuleOutput {
+                            rule_id: &rule.config.id,
+                            client_id,
+                            topic_name: message.topic_name(),
+                            fields: &fields,
+                        }),
+                        None => log::warn!("rule {} unknown callback {name}", rule.config.id),
+                    },
+                }
+            }
+        }
+        republished
+    }
+}
+
+// The message as seen by the rule statements, payloads which are not valid JSON are
+// taken as strings.
+fn rule_input(client_id: &str, message: &PublishMessage) -> Value {
+    let payload = serde_json::from_slice(message.payload())
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(message.payload()).into_owned()));
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let topic: &str = message.topic_name();
+    let qos = match message.qos() {
+        QualityOfService::Level0 => 0,
+        QualityOfService::Level1 => 1,
+        QualityOfService::Level2 => 2,
+    };
+
+    let mut input = Map::new();
+    input.insert("clientid".to_owned(), client_id.into());
+    input.insert("topic".to_owned(), topic.into());
+    input.insert("qos".to_owned(), qos.into());
+    input.insert("retain".to_owned(), message.retain().into());
+    input.insert("timestamp".to_owned(), timestamp.into());
+    input.insert("payload".to_owned(), payload);
+    Value::Object(input)
+}
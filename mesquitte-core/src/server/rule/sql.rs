@@ -0,0 +1,590 @@
+use std::{cmp::Ordering, iter::Peekable, str::CharIndices};
+
+use mqtt_codec_kit::common::{TopicFilter, TopicNameRef};
+use serde_json::{Map, Number, Value};
+
+use super::RuleError;
+
+/// `SELECT fields FROM "topic/filter/#"[, "other/filter"] [WHERE condition]`
+///
+/// Fields are `*` or comma separated expressions, each one named by `AS alias` or by the
+/// last segment of its path. Paths such as `payload.sensor.temp` reach into the message:
+/// `clientid`, `topic`, `qos`, `retain`, `timestamp` and the `payload`, parsed as JSON when
+/// it is valid JSON. Conditions compare paths and literals with `=`, `!=`, `<>`, `<`, `<=`,
+/// `>`, `>=`, combined with `AND`, `OR`, `NOT` and parentheses. Strings are quoted with `'`
+/// or `"`, doubled to be escaped.
+#[derive(Debug, Clone)]
+pub struct Statement {
+    fields: Vec<Field>,
+    from: Vec<TopicFilter>,
+    condition: Option<Expr>,
+}
+
+#[derive(Debug, Clone)]
+enum Field {
+    All,
+    Expr { name: String, expr: Expr },
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Path(Vec<String>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Statement {
+    pub fn parse(sql: &str) -> Result<Self, RuleError> {
+        Parser::new(sql)?.statement()
+    }
+
+    pub fn is_match(&self, topic_name: &TopicNameRef) -> bool {
+        self.from
+            .iter()
+            .any(|filter| filter.get_matcher().is_match(topic_name))
+    }
+
+    /// Selected fields of a message, `None` when the condition does not hold.
+    pub fn select(&self, message: &Value) -> Option<Map<String, Value>> {
+        if let Some(condition) = &self.condition {
+            if condition.eval(message) != Value::Bool(true) {
+                return None;
+            }
+        }
+
+        let mut output = Map::new();
+        for field in &self.fields {
+            match field {
+                Field::All => {
+                    if let Value::Object(message) = message {
+                        output.extend(message.clone());
+                    }
+                }
+                Field::Expr { name, expr } => {
+                    output.insert(name.clone(), expr.eval(message));
+                }
+            }
+        }
+        Some(output)
+    }
+}
+
+impl Expr {
+    fn eval(&self, message: &Value) -> Value {
+        match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Path(path) => path
+                .iter()
+                .try_fold(message, |value, segment| match value {
+                    Value::Object(object) => object.get(segment),
+                    Value::Array(array) => segment.parse().ok().and_then(|i: usize| array.get(i)),
+                    _ => None,
+                })
+                .cloned()
+                .unwrap_or(Value::Null),
+            Expr::Not(expr) => match expr.eval(message) {
+                Value::Bool(value) => Value::Bool(!value),
+                _ => Value::Null,
+            },
+            Expr::Binary(left, BinaryOp::And, right) => Value::Bool(
+                left.eval(message) == Value::Bool(true) && right.eval(message) == Value::Bool(true),
+            ),
+            Expr::Binary(left, BinaryOp::Or, right) => Value::Bool(
+                left.eval(message) == Value::Bool(true) || right.eval(message) == Value::Bool(true),
+            ),
+            Expr::Binary(left, op, right) => {
+                let ordering = compare(&left.eval(message), &right.eval(message));
+                let holds = match op {
+                    BinaryOp::Eq => ordering == Some(Ordering::Equal),
+                    BinaryOp::Ne => ordering != Some(Ordering::Equal),
+                    BinaryOp::Lt => ordering == Some(Ordering::Less),
+                    BinaryOp::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                    BinaryOp::Gt => ordering == Some(Ordering::Greater),
+                    BinaryOp::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                };
+                Value::Bool(holds)
+            }
+        }
+    }
+}
+
+// Values of different types are neither equal nor ordered, numbers compare by value.
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64()?.partial_cmp(&right.as_f64()?),
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        (Value::Bool(left), Value::Bool(right)) => Some(left.cmp(right)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        (Value::Array(_), Value::Array(_)) | (Value::Object(_), Value::Object(_)) => {
+            (left == right).then_some(Ordering::Equal)
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    String(String),
+    Number(Number),
+    Star,
+    Comma,
+    Dot,
+    LParen,
+    RParen,
+    Op(BinaryOp),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(sql: &str) -> Result<Self, RuleError> {
+        Ok(Self {
+            tokens: tokenize(sql)?,
+            pos: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), RuleError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.unexpected(keyword))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> RuleError {
+        match self.peek() {
+            Some(token) => RuleError::Sql(format!("expect {expected}, got {token:?}")),
+            None => RuleError::Sql(format!("expect {expected}, got end of statement")),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, RuleError> {
+        self.expect_keyword("SELECT")?;
+        let mut fields = Vec::new();
+        loop {
+            fields.push(self.field()?);
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+
+        self.expect_keyword("FROM")?;
+        let mut from = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::String(filter)) => from
+                    .push(TopicFilter::new(filter).map_err(|err| RuleError::Sql(err.to_string()))?),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("a quoted topic filter"));
+                }
+            }
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+
+        let condition = if self.eat_keyword("WHERE") {
+            Some(self.or()?)
+        } else {
+            None
+        };
+        if self.peek().is_some() {
+            return Err(self.unexpected("end of statement"));
+        }
+
+        Ok(Statement {
+            fields,
+            from,
+            condition,
+        })
+    }
+
+    fn field(&mut self) -> Result<Field, RuleError> {
+        if self.eat(&Token::Star) {
+            return Ok(Field::All);
+        }
+        let expr = self.or()?;
+        let name = if self.eat_keyword("AS") {
+            match self.next() {
+                Some(Token::Ident(alias)) => alias,
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("an alias"));
+                }
+            }
+        } else if let Expr::Path(path) = &expr {
+            path.last().cloned().expect("non empty path")
+        } else {
+            return Err(RuleError::Sql(format!("field {expr:?} needs an alias")));
+        };
+        Ok(Field::Expr { name, expr })
+    }
+
+    fn or(&mut self) -> Result<Expr, RuleError> {
+        let mut expr = self.and()?;
+        while self.eat_keyword("OR") {
+            expr = Expr::Binary(Box::new(expr), BinaryOp::Or, Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, RuleError> {
+        let mut expr = self.not()?;
+        while self.eat_keyword("AND") {
+            expr = Expr::Binary(Box::new(expr), BinaryOp::And, Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, RuleError> {
+        if self.eat_keyword("NOT") {
+            Ok(Expr::Not(Box::new(self.not()?)))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, RuleError> {
+        let left = self.primary()?;
+        match self.peek() {
+            Some(Token::Op(op)) => {
+                let op = *op;
+                self.pos += 1;
+                Ok(Expr::Binary(Box::new(left), op, Box::new(self.primary()?)))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, RuleError> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+                if !self.eat(&Token::RParen) {
+                    return Err(self.unexpected("')'"));
+                }
+                Ok(expr)
+            }
+            Some(Token::String(value)) => Ok(Expr::Literal(Value::String(value))),
+            Some(Token::Number(value)) => Ok(Expr::Literal(Value::Number(value))),
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("true") => {
+                Ok(Expr::Literal(Value::Bool(true)))
+            }
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("false") => {
+                Ok(Expr::Literal(Value::Bool(false)))
+            }
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("null") => {
+                Ok(Expr::Literal(Value::Null))
+            }
+            Some(Token::Ident(ident)) => {
+                let mut path = vec![ident];
+                while self.eat(&Token::Dot) {
+                    match self.next() {
+                        Some(Token::Ident(segment)) => path.push(segment),
+                        // Array index
+                        Some(Token::Number(index)) if index.is_u64() => {
+                            path.push(index.to_string())
+                        }
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.unexpected("a field name"));
+                        }
+                    }
+                }
+                Ok(Expr::Path(path))
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected("an expression"))
+            }
+        }
+    }
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>, RuleError> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '*' => Token::Star,
+            ',' => Token::Comma,
+            '.' => Token::Dot,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '=' => Token::Op(BinaryOp::Eq),
+            '!' if next_is(&mut chars, '=') => Token::Op(BinaryOp::Ne),
+            '<' if next_is(&mut chars, '=') => Token::Op(BinaryOp::Le),
+            '<' if next_is(&mut chars, '>') => Token::Op(BinaryOp::Ne),
+            '<' => Token::Op(BinaryOp::Lt),
+            '>' if next_is(&mut chars, '=') => Token::Op(BinaryOp::Ge),
+            '>' => Token::Op(BinaryOp::Gt),
+            '\'' | '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, q)) if q == c => {
+                            if !next_is(&mut chars, c) {
+                                break;
+                            }
+                            value.push(c);
+                        }
+                        Some((_, other)) => value.push(other),
+                        None => {
+                            return Err(RuleError::Sql(format!("unterminated string {value:?}")))
+                        }
+                    }
+                }
+                Token::String(value)
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    // The dot of a path segment following an array index is not part of
+                    // the number, as in `payload.values.0.temp`.
+                    let prev_is_dot = matches!(tokens.last(), Some(Token::Dot));
+                    if c.is_ascii_digit()
+                        || (!prev_is_dot && matches!(c, '.' | 'e' | 'E' | '+' | '-'))
+                    {
+                        end = i + c.len_utf8();
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let number = &sql[start..end];
+                Token::Number(
+                    serde_json::from_str(number)
+                        .map_err(|_| RuleError::Sql(format!("invalid number {number}")))?,
+                )
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        end = i + c.len_utf8();
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Ident(sql[start..end].to_owned())
+            }
+            c => return Err(RuleError::Sql(format!("unexpected character {c:?}"))),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn next_is(chars: &mut Peekable<CharIndices<'_>>, expected: char) -> bool {
+    chars.next_if(|&(_, c)| c == expected).is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn message() -> Value {
+        json!({
+            "clientid": "sensor-1",
+            "topic": "sensors/kitchen/temp",
+            "qos": 1,
+            "retain": false,
+            "payload": {
+                "room": "it's \"warm\"",
+                "temp": 22.5,
+                "on": true,
+                "values": [{ "temp": 21 }, { "temp": 23 }],
+            },
+        })
+    }
+
+    fn select(sql: &str) -> Option<Map<String, Value>> {
+        Statement::parse(sql)
+            .unwrap_or_else(|err| panic!("parse {sql}: {err}"))
+            .select(&message())
+    }
+
+    #[test]
+    fn test_select_fields() {
+        let cases = [
+            (
+                "SELECT clientid FROM \"#\"",
+                json!({ "clientid": "sensor-1" }),
+            ),
+            (
+                "select payload.temp as t, qos FROM '#'",
+                json!({ "t": 22.5, "qos": 1 }),
+            ),
+            (
+                "SELECT payload.values.0.temp AS first, payload.values.1.temp FROM '#'",
+                json!({ "first": 21, "temp": 23 }),
+            ),
+            (
+                "SELECT payload.values.2.temp AS missing, payload.room.x AS none FROM '#'",
+                json!({ "missing": null, "none": null }),
+            ),
+            (
+                "SELECT 'it''s' AS single, \"say \"\"hi\"\"\" AS double, -1.5e2 AS number FROM '#'",
+                json!({ "single": "it's", "double": "say \"hi\"", "number": -150.0 }),
+            ),
+            (
+                "SELECT payload.temp > 20 AS hot, NOT retain AS fresh FROM '#'",
+                json!({ "hot": true, "fresh": true }),
+            ),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(select(sql).map(Value::Object), Some(expected), "{sql}");
+        }
+
+        let all = select("SELECT *, payload.temp AS temp FROM '#'").unwrap();
+        assert_eq!(all.len(), 6);
+        assert_eq!(all["topic"], "sensors/kitchen/temp");
+        assert_eq!(all["temp"], 22.5);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let cases = [
+            ("payload.temp = 22.5", true),
+            ("payload.temp > 22", true),
+            ("payload.temp >= 22.5", true),
+            ("payload.temp < 22.5", false),
+            ("payload.temp <= 22", false),
+            ("payload.temp != 22.5", false),
+            ("payload.temp <> 20", true),
+            ("payload.room = 'it''s \"warm\"'", true),
+            ("payload.room = \"it's \"\"warm\"\"\"", true),
+            ("payload.on = true AND qos = 1", true),
+            ("payload.values.1.temp = 23", true),
+            ("payload.missing = null", true),
+            // Values of different types are not equal.
+            ("qos = '1'", false),
+            ("qos <> '1'", true),
+            ("clientid > 1", false),
+            // NOT binds tighter than AND, which binds tighter than OR.
+            ("NOT qos = 1 OR qos = 1 AND retain = true", false),
+            ("NOT qos = 0 OR qos = 0 AND retain = true", true),
+            ("qos = 0 AND retain = false OR qos = 1", true),
+            ("qos = 0 AND (retain = false OR qos = 1)", false),
+            ("NOT (qos = 1 AND retain = false)", false),
+            ("NOT NOT qos = 1", true),
+            // Conditions which are not booleans do not hold.
+            ("payload.temp", false),
+            ("NOT payload.temp", false),
+        ];
+        for (condition, holds) in cases {
+            let sql = format!("SELECT qos FROM '#' WHERE {condition}");
+            assert_eq!(select(&sql).is_some(), holds, "{condition}");
+        }
+    }
+
+    #[test]
+    fn test_from_filters() {
+        let statement =
+            Statement::parse("SELECT * FROM 'sensors/+/temp', \"alerts/#\" WHERE qos > 0").unwrap();
+        let cases = [
+            ("sensors/kitchen/temp", true),
+            ("sensors/kitchen/humidity", false),
+            ("sensors/temp", false),
+            ("alerts", true),
+            ("alerts/fire/kitchen", true),
+            ("$SYS/alerts", false),
+        ];
+        for (topic_name, matched) in cases {
+            let topic_name = TopicNameRef::new(topic_name).unwrap();
+            assert_eq!(statement.is_match(topic_name), matched, "{topic_name:?}");
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let cases = [
+            "",
+            "SELECT",
+            "UPDATE t SET a = 1",
+            "SELECT * FROM",
+            "SELECT * FROM sensors",
+            "SELECT * FROM 'sensors/#/temp'",
+            "SELECT a, FROM '#'",
+            "SELECT a. FROM '#'",
+            "SELECT a.-1 FROM '#'",
+            "SELECT a AS FROM '#'",
+            "SELECT a AS 'alias' FROM '#'",
+            "SELECT 1 FROM '#'",
+            "SELECT qos = 1 FROM '#'",
+            "SELECT a FROM '#' WHERE",
+            "SELECT a FROM '#' WHERE (a = 1",
+            "SELECT a FROM '#' WHERE a = 1)",
+            "SELECT a FROM '#' WHERE a = 1 = 2",
+            "SELECT a FROM '#' WHERE a ! b",
+            "SELECT a FROM '#' WHERE a = 'unterminated",
+            "SELECT a FROM '#' WHERE a = 1.2.3",
+            "SELECT a FROM '#' WHERE a = -",
+            "SELECT a FROM '#' WHERE a = 1e",
+            "SELECT a FROM '#' WHERE NOT",
+            "SELECT a FROM '#' WHERE a AND",
+            "SELECT a FROM '#' extra",
+            "SELECT @ FROM '#'",
+            "SELECT a FROM '#';",
+        ];
+        for sql in cases {
+            assert!(
+                matches!(Statement::parse(sql), Err(RuleError::Sql(_))),
+                "{sql:?} parsed"
+            );
+        }
+    }
+}
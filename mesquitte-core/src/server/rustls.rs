@@ -1,12 +1,36 @@
-use std::{fs::File, io::BufReader, sync::Arc};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::SystemTime,
+};
 
-use rustls::{server::WebPkiClientVerifier, RootCertStore};
+use parking_lot::RwLock;
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore,
+};
+use tokio::time;
 use tokio_rustls::{
-    rustls::{Error as RustlsError, ServerConfig},
+    rustls::{Error as RustlsError, ServerConfig, ServerConnection},
     TlsAcceptor,
 };
+use webpki::EndEntityCert;
+
+use crate::types::connection::{ConnectionInfo, TlsInfo};
 
-use super::config::TlsConfig;
+use super::config::{CertFiles, PeerCertIdentity, TlsConfig};
+
+// id-at-commonName, 2.5.4.3
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+const DER_SET: u8 = 0x31;
+const DER_SEQUENCE: u8 = 0x30;
+const DER_OID: u8 = 0x06;
 
 #[derive(Debug, thiserror::Error)]
 #[error("Acceptor error")]
@@ -22,25 +46,32 @@ pub enum Error {
 }
 
 pub fn rustls_server_config(cfg: &TlsConfig) -> Result<ServerConfig, Error> {
-    let cert_file = &mut BufReader::new(File::open(cfg.cert_file.clone())?);
-    let key_file = &mut BufReader::new(File::open(cfg.key_file.clone())?);
-
-    let cert_chain = rustls_pemfile::certs(cert_file).collect::<Result<Vec<_>, _>>()?;
-    let key = rustls_pemfile::private_key(key_file)?
-        .ok_or(Error::InvalidServerKey("invalid server key".to_string()))?;
-
     let client_auth = if cfg.fail_if_no_peer_cert {
         match &cfg.ca_file {
             Some(ca) => {
-                let ca_file = &mut BufReader::new(File::open(ca)?);
-                let cert_chain = rustls_pemfile::certs(ca_file).collect::<Result<Vec<_>, _>>()?;
                 let mut client_auth_roots = RootCertStore::empty();
-                for root in cert_chain {
+                for root in load_certs(ca)? {
                     client_auth_roots
                         .add(root)
                         .map_err(|e| Error::InvalidCACert(e.to_string()))?;
                 }
-                WebPkiClientVerifier::builder(client_auth_roots.into())
+                let mut crls = Vec::new();
+                for crl_file in &cfg.crl_files {
+                    crls.extend(load_crls(crl_file)?);
+                }
+                let mut builder =
+                    WebPkiClientVerifier::builder(client_auth_roots.into()).with_crls(crls);
+                let revocation = cfg.revocation;
+                if revocation.end_entity_only {
+                    builder = builder.only_check_end_entity_revocation();
+                }
+                if revocation.allow_unknown_status {
+                    builder = builder.allow_unknown_revocation_status();
+                }
+                if revocation.enforce_expiration {
+                    builder = builder.enforce_revocation_expiration();
+                }
+                builder
                     .build()
                     .map_err(|e| Error::InvalidCACert(e.to_string()))?
             }
@@ -50,12 +81,553 @@ pub fn rustls_server_config(cfg: &TlsConfig) -> Result<ServerConfig, Error> {
         WebPkiClientVerifier::no_client_auth()
     };
 
-    ServerConfig::builder()
-        .with_client_cert_verifier(client_auth)
-        .with_single_cert(cert_chain, key)
-        .map_err(|e| Error::InvalidCACert(e.to_string()))
+    let builder = ServerConfig::builder().with_client_cert_verifier(client_auth);
+    let provider = builder.crypto_provider().clone();
+    let mut resolver = SniCertResolver {
+        default: certified_key(&provider, &cfg.cert_file, &cfg.key_file)?,
+        names: HashMap::with_capacity(cfg.sni_certs.len()),
+    };
+    for (name, files) in &cfg.sni_certs {
+        let key = certified_key(&provider, &files.cert_file, &files.key_file)?;
+        resolver.names.insert(name.to_ascii_lowercase(), key);
+    }
+    Ok(builder.with_cert_resolver(Arc::new(resolver)))
+}
+
+/// Build the acceptor and, when `reload_interval` or `reload_signal` is set,
+/// start a task reloading it while the acceptor is alive.
+pub fn rustls_acceptor(cfg: &TlsConfig) -> Result<ReloadableAcceptor, Error> {
+    let acceptor = ReloadableAcceptor::new(cfg.clone())?;
+    if cfg.reload_interval.is_some() || cfg.reload_signal.is_some() {
+        tokio::spawn(watch(Arc::downgrade(&acceptor.inner)));
+    }
+    Ok(acceptor)
+}
+
+/// TLS acceptor whose certificates, keys, client CAs and CRLs can be reloaded from
+/// disk without restarting the listener. Connections already established keep
+/// the configuration they were accepted with.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    inner: Arc<AcceptorInner>,
+}
+
+struct AcceptorInner {
+    cfg: TlsConfig,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(cfg: TlsConfig) -> Result<Self, Error> {
+        let config = Arc::new(rustls_server_config(&cfg)?);
+        Ok(Self {
+            inner: Arc::new(AcceptorInner {
+                cfg,
+                config: RwLock::new(config),
+            }),
+        })
+    }
+
+    /// Acceptor for the current configuration.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.inner.config.read().clone())
+    }
+
+    /// Load the configured files again. On error the current configuration is kept.
+    pub fn reload(&self) -> Result<(), Error> {
+        self.inner.reload()
+    }
+}
+
+impl AcceptorInner {
+    fn reload(&self) -> Result<(), Error> {
+        let config = rustls_server_config(&self.cfg)?;
+        *self.config.write() = Arc::new(config);
+        Ok(())
+    }
+
+    fn files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cfg.cert_file.as_path(), self.cfg.key_file.as_path()];
+        files.extend(self.cfg.ca_file.as_deref());
+        files.extend(self.cfg.crl_files.iter().map(PathBuf::as_path));
+        for CertFiles {
+            cert_file,
+            key_file,
+        } in self.cfg.sni_certs.values()
+        {
+            files.push(cert_file);
+            files.push(key_file);
+        }
+        files
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .into_iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+async fn watch(inner: Weak<AcceptorInner>) {
+    let (interval, signal, mut modified) = match inner.upgrade() {
+        Some(inner) => (
+            inner.cfg.reload_interval,
+            inner.cfg.reload_signal.clone(),
+            inner.modified(),
+        ),
+        None => return,
+    };
+    let mut ticker = interval.map(time::interval);
+    loop {
+        let forced = tokio::select! {
+            _ = async { ticker.as_mut().unwrap().tick().await }, if ticker.is_some() => false,
+            _ = async { signal.as_ref().unwrap().notified().await }, if signal.is_some() => true,
+        };
+        // Every listener using the acceptor is gone.
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let current = inner.modified();
+        if !forced && current == modified {
+            continue;
+        }
+        modified = current;
+        match inner.reload() {
+            Ok(()) => log::info!(
+                "reloaded tls certificates of {}",
+                inner.cfg.cert_file.display()
+            ),
+            Err(err) => log::error!("reload tls certificates failed: {err}"),
+        }
+    }
+}
+
+/// Picks the certificate by the SNI host name of the client hello. Names may be
+/// exact host names or wildcards like `*.example.com`, clients without SNI or
+/// with an unknown name get the default certificate.
+#[derive(Debug)]
+struct SniCertResolver {
+    default: Arc<CertifiedKey>,
+    names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniCertResolver {
+    fn lookup(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = server_name else {
+            return self.default.clone();
+        };
+        let name = name.to_ascii_lowercase();
+        let key = self.names.get(&name).or_else(|| {
+            let (_, parent) = name.split_once('.')?;
+            self.names.get(&format!("*.{parent}"))
+        });
+        key.unwrap_or(&self.default).clone()
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.lookup(client_hello.server_name()))
+    }
+}
+
+fn certified_key(
+    provider: &CryptoProvider,
+    cert_file: &Path,
+    key_file: &Path,
+) -> Result<Arc<CertifiedKey>, Error> {
+    let key = CertifiedKey::from_der(load_certs(cert_file)?, load_key(key_file)?, provider)
+        .map_err(|e| Error::InvalidServerKey(e.to_string()))?;
+    Ok(Arc::new(key))
+}
+
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let file = &mut BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(file).collect::<Result<Vec<_>, _>>()?)
+}
+
+fn load_crls(path: &Path) -> Result<Vec<CertificateRevocationListDer<'static>>, Error> {
+    let file = &mut BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::crls(file).collect::<Result<Vec<_>, _>>()?)
+}
+
+pub(crate) fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let file = &mut BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(file)?
+        .ok_or(Error::InvalidServerKey("invalid server key".to_string()))
+}
+
+/// Record SNI, ALPN and the client certificate of an accepted TLS session.
+pub(crate) fn set_tls_info(
+    connection: &mut ConnectionInfo,
+    session: &ServerConnection,
+    identity: PeerCertIdentity,
+) {
+    let mut tls = TlsInfo {
+        server_name: session.server_name().map(|name| name.to_owned()),
+        alpn: session.alpn_protocol().map(|alpn| alpn.to_vec()),
+        ..Default::default()
+    };
+    if let Some(certs) = session.peer_certificates() {
+        tls.peer_certificates = certs.iter().map(|cert| cert.to_vec()).collect();
+        if let Some(cert) = certs.first() {
+            match EndEntityCert::try_from(cert) {
+                Ok(cert) => {
+                    tls.common_name = common_name(cert.subject());
+                    tls.subject_alt_names = cert
+                        .valid_dns_names()
+                        .chain(cert.valid_uri_names())
+                        .map(|name| name.to_owned())
+                        .collect();
+                }
+                Err(err) => log::warn!("parse client certificate failed: {err}"),
+            }
+        }
+    }
+
+    let client_id = identity.client_id.map(|field| field.value(&tls));
+    let username = identity.username.map(|field| field.value(&tls));
+    if matches!(client_id, Some(None)) || matches!(username, Some(None)) {
+        connection.set_cert_identity_missing();
+    }
+    connection.set_cert_client_id(client_id.flatten());
+    connection.set_cert_username(username.flatten());
+    connection.set_tls(tls);
+}
+
+// Find the first common name in a DER encoded RDNSequence.
+fn common_name(subject: &[u8]) -> Option<String> {
+    let mut rdns = subject;
+    while let Some((DER_SET, rdn, rest)) = der_next(rdns) {
+        rdns = rest;
+        let mut attributes = rdn;
+        while let Some((DER_SEQUENCE, attribute, rest)) = der_next(attributes) {
+            attributes = rest;
+            if let Some((DER_OID, COMMON_NAME_OID, value)) = der_next(attribute) {
+                let (_, value, _) = der_next(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+// Tag, value and the bytes after a DER element
+type DerItem<'a> = (u8, &'a [u8], &'a [u8]);
+
+// Split the next DER element into tag, value and the remaining bytes.
+fn der_next(data: &[u8]) -> Option<DerItem<'_>> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+    let length = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7F) as usize;
+        if count == 0 || count > 4 || data.len() < count {
+            return None;
+        }
+        let (bytes, rest) = data.split_at(count);
+        data = rest;
+        bytes
+            .iter()
+            .fold(0, |acc, byte| (acc << 8) | *byte as usize)
+    };
+    if data.len() < length {
+        return None;
+    }
+    let (value, rest) = data.split_at(length);
+    Some((tag, value, rest))
 }
 
-pub fn rustls_acceptor(cfg: &TlsConfig) -> Result<TlsAcceptor, Error> {
-    Ok(TlsAcceptor::from(Arc::new(rustls_server_config(cfg)?)))
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rustls::{pki_types::ServerName, ClientConfig};
+    use tokio::sync::Notify;
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    const ORGANIZATION_OID: &[u8] = &[0x55, 0x04, 0x0A];
+    const DER_UTF8_STRING: u8 = 0x0C;
+
+    fn der(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut der = vec![tag];
+        match value.len() {
+            len @ 0..=0x7F => der.push(len as u8),
+            len @ 0x80..=0xFF => der.extend([0x81, len as u8]),
+            len => der.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        der.extend(value);
+        der
+    }
+
+    // A relative distinguished name with one attribute per (oid, value).
+    fn rdn(attributes: &[(&[u8], &str)]) -> Vec<u8> {
+        let attributes: Vec<u8> = attributes
+            .iter()
+            .flat_map(|(oid, value)| {
+                let attribute =
+                    [der(DER_OID, oid), der(DER_UTF8_STRING, value.as_bytes())].concat();
+                der(DER_SEQUENCE, &attribute)
+            })
+            .collect();
+        der(DER_SET, &attributes)
+    }
+
+    #[test]
+    fn test_der_next() {
+        let cases: [(&[u8], Option<DerItem>); 9] = [
+            (&[0x04, 0x02, 1, 2, 3], Some((0x04, &[1, 2], &[3]))),
+            (&[0x04, 0x00], Some((0x04, &[], &[]))),
+            (&[0x04, 0x81, 0x01, 1], Some((0x04, &[1], &[]))),
+            (&[0x04, 0x82, 0x00, 0x02, 1, 2], Some((0x04, &[1, 2], &[]))),
+            (&[], None),
+            (&[0x04], None),
+            (&[0x04, 0x03, 1, 2], None),
+            // Indefinite lengths are not DER, nor lengths beyond 4 bytes.
+            (&[0x04, 0x80, 1, 0, 0], None),
+            (&[0x04, 0x85, 0, 0, 0, 0, 1, 1], None),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(der_next(data), expected, "{data:?}");
+        }
+        assert_eq!(der_next(&[0x04, 0x82, 0x01]), None);
+        let long = der(0x04, &[7; 300]);
+        assert_eq!(der_next(&long), Some((0x04, &[7; 300][..], &[][..])));
+    }
+
+    #[test]
+    fn test_common_name() {
+        let cases = [
+            (rdn(&[(COMMON_NAME_OID, "device-1")]), Some("device-1")),
+            (
+                [
+                    rdn(&[(ORGANIZATION_OID, "acme")]),
+                    rdn(&[(COMMON_NAME_OID, "device-1")]),
+                    rdn(&[(COMMON_NAME_OID, "second")]),
+                ]
+                .concat(),
+                Some("device-1"),
+            ),
+            // Multi-valued RDN
+            (
+                rdn(&[(ORGANIZATION_OID, "acme"), (COMMON_NAME_OID, "device-1")]),
+                Some("device-1"),
+            ),
+            // Long form lengths
+            (
+                [
+                    rdn(&[(ORGANIZATION_OID, &"o".repeat(300))]),
+                    rdn(&[(COMMON_NAME_OID, &"c".repeat(200))]),
+                ]
+                .concat(),
+                Some(&*"c".repeat(200)),
+            ),
+            (rdn(&[(ORGANIZATION_OID, "acme")]), None),
+            (Vec::new(), None),
+        ];
+        for (subject, expected) in cases {
+            assert_eq!(common_name(&subject).as_deref(), expected, "{subject:?}");
+        }
+    }
+
+    #[test]
+    fn test_common_name_truncated() {
+        let subject = [
+            rdn(&[(ORGANIZATION_OID, "acme")]),
+            rdn(&[(COMMON_NAME_OID, "device-1")]),
+        ]
+        .concat();
+        for len in 0..subject.len() {
+            assert_eq!(common_name(&subject[..len]), None, "{len}");
+        }
+        assert_eq!(common_name(&subject).as_deref(), Some("device-1"));
+    }
+
+    #[test]
+    fn test_certificate_common_name() {
+        let pem = include_bytes!("../../examples/certs/cert.pem");
+        let certs = rustls_pemfile::certs(&mut &pem[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let cert = EndEntityCert::try_from(&certs[0]).unwrap();
+        // The subject is longer than 127 bytes.
+        assert!(cert.subject().len() > 0x7F);
+        assert_eq!(common_name(cert.subject()).as_deref(), Some("localca"));
+    }
+
+    fn example_file(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("examples/certs")
+            .join(name)
+    }
+
+    // Copies the example certificate and key into a directory of their own.
+    fn copy_example_files() -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("mesquitte-{}", nanoid::nanoid!()));
+        fs::create_dir(&dir).unwrap();
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+        fs::copy(example_file("cert.pem"), &cert_file).unwrap();
+        fs::copy(example_file("key.pem"), &key_file).unwrap();
+        (cert_file, key_file)
+    }
+
+    fn current_config(acceptor: &ReloadableAcceptor) -> Arc<ServerConfig> {
+        acceptor.inner.config.read().clone()
+    }
+
+    #[test]
+    fn test_sni_lookup() {
+        let provider = rustls::crypto::aws_lc_rs::default_provider();
+        let key = || {
+            certified_key(
+                &provider,
+                &example_file("cert.pem"),
+                &example_file("key.pem"),
+            )
+            .unwrap()
+        };
+        let (default, exact, wildcard) = (key(), key(), key());
+        let resolver = SniCertResolver {
+            default: default.clone(),
+            names: HashMap::from([
+                ("broker.example.com".to_owned(), exact.clone()),
+                ("*.example.com".to_owned(), wildcard.clone()),
+            ]),
+        };
+        let cases = [
+            (None, &default),
+            (Some("broker.example.com"), &exact),
+            (Some("Broker.Example.COM"), &exact),
+            (Some("web.example.com"), &wildcard),
+            // A wildcard matches a single label.
+            (Some("a.web.example.com"), &default),
+            (Some("example.com"), &default),
+            (Some("broker.example.org"), &default),
+        ];
+        for (name, expected) in cases {
+            assert!(Arc::ptr_eq(&resolver.lookup(name), expected), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let (cert_file, key_file) = copy_example_files();
+        let acceptor = ReloadableAcceptor::new(TlsConfig::new(&cert_file, &key_file)).unwrap();
+        let config = current_config(&acceptor);
+
+        // A broken key keeps the current configuration.
+        let key = fs::read(&key_file).unwrap();
+        fs::write(&key_file, b"").unwrap();
+        assert!(acceptor.reload().is_err());
+        assert!(Arc::ptr_eq(&current_config(&acceptor), &config));
+
+        fs::write(&key_file, key).unwrap();
+        acceptor.reload().unwrap();
+        assert!(!Arc::ptr_eq(&current_config(&acceptor), &config));
+        fs::remove_dir_all(cert_file.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_signal() {
+        let (cert_file, key_file) = copy_example_files();
+        let signal = Arc::new(Notify::new());
+        let mut cfg = TlsConfig::new(&cert_file, &key_file);
+        cfg.reload_signal = Some(signal.clone());
+        let acceptor = rustls_acceptor(&cfg).unwrap();
+        let config = current_config(&acceptor);
+
+        signal.notify_one();
+        time::timeout(Duration::from_secs(1), async {
+            while Arc::ptr_eq(&current_config(&acceptor), &config) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("reloaded on signal");
+        fs::remove_dir_all(cert_file.parent().unwrap()).unwrap();
+    }
+
+    fn test_file(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/certs")
+            .join(name)
+    }
+
+    // Client certificates of `ca.pem` checked against `crl_file`.
+    fn mtls_config(crl_file: PathBuf) -> TlsConfig {
+        let mut cfg = TlsConfig::new(test_file("server.pem"), test_file("server.key"));
+        cfg.ca_file = Some(test_file("ca.pem"));
+        cfg.fail_if_no_peer_cert = true;
+        cfg.crl_files = vec![crl_file];
+        cfg
+    }
+
+    // Whether the broker accepts the certificate `{client}.pem`.
+    async fn handshake(acceptor: TlsAcceptor, client: &str) -> bool {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&test_file("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(
+                load_certs(&test_file(&format!("{client}.pem"))).unwrap(),
+                load_key(&test_file(&format!("{client}.key"))).unwrap(),
+            )
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let (client_stream, server_stream) = tokio::io::duplex(16 * 1024);
+        let name = ServerName::try_from("localhost").unwrap();
+        let (_, accepted) = tokio::join!(
+            connector.connect(name, client_stream),
+            acceptor.accept(server_stream)
+        );
+        accepted.is_ok()
+    }
+
+    async fn accepts(cfg: &TlsConfig, client: &str) -> bool {
+        let config = rustls_server_config(cfg).unwrap();
+        handshake(TlsAcceptor::from(Arc::new(config)), client).await
+    }
+
+    #[tokio::test]
+    async fn test_revocation() {
+        let cfg = mtls_config(test_file("crl.pem"));
+        assert!(accepts(&cfg, "client").await);
+        assert!(!accepts(&cfg, "revoked").await);
+    }
+
+    #[tokio::test]
+    async fn test_revocation_policy() {
+        // No CRL of the issuer
+        let mut cfg = mtls_config(test_file("other-crl.pem"));
+        assert!(!accepts(&cfg, "client").await);
+        cfg.revocation.allow_unknown_status = true;
+        assert!(accepts(&cfg, "client").await);
+
+        let mut cfg = mtls_config(test_file("expired-crl.pem"));
+        assert!(accepts(&cfg, "client").await);
+        cfg.revocation.enforce_expiration = true;
+        assert!(!accepts(&cfg, "client").await);
+    }
+
+    #[tokio::test]
+    async fn test_crl_reload() {
+        let dir = std::env::temp_dir().join(format!("mesquitte-{}", nanoid::nanoid!()));
+        fs::create_dir(&dir).unwrap();
+        let crl_file = dir.join("crl.pem");
+        fs::copy(test_file("expired-crl.pem"), &crl_file).unwrap();
+        let acceptor = ReloadableAcceptor::new(mtls_config(crl_file.clone())).unwrap();
+        assert!(handshake(acceptor.acceptor(), "revoked").await);
+
+        fs::copy(test_file("crl.pem"), &crl_file).unwrap();
+        acceptor.reload().unwrap();
+        assert!(!handshake(acceptor.acceptor(), "revoked").await);
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -1,21 +1,41 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use dashmap::DashMap;
-use mqtt_codec_kit::common::{QualityOfService, TopicFilter};
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
 use tokio::{
     sync::mpsc::{self, channel},
     time,
 };
 
 use crate::{
+    server::{
+        cluster::ClusterEvent,
+        hook::{Hook, Hooks},
+        limits::{
+            BanList, BanPolicy, ConnectionLimiter, ConnectionLimits, PublishCounters,
+            PublishLimits, PublishQuota,
+        },
+        rule::RuleEngine,
+    },
     store::queue::Queue,
     types::{
-        client::AddClientReceipt, outgoing::Outgoing, retain_table::RetainTable,
-        topic_router::RouteTable,
+        client::AddClientReceipt,
+        outgoing::Outgoing,
+        publish::PublishMessage,
+        retain_table::RetainTable,
+        session::DEFAULT_MAX_PACKET_SIZE,
+        topic_router::{RouteTable, SharedClients, SharedStrategy},
     },
 };
 
-#[derive(Default)]
+/// Acknowledgement timeout of the messages of shared subscriptions, see
+/// [`GlobalState::set_shared_ack_timeout`].
+pub const DEFAULT_SHARED_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct GlobalState<Q>
 where
     Q: Queue,
@@ -23,10 +43,8 @@ where
     // TODO: metrics?
     // TODO: config content
     // max qos
-    // max connection ?
     // read channel size
     // outgoing channel size
-    // max packet size-> v3?
     // max inflight size
     // max inflight message size
     // retain table enable
@@ -39,12 +57,34 @@ where
     // min keep alive
     // config: Arc<Config>,
     clients: DashMap<String, mpsc::Sender<Outgoing>, ahash::RandomState>,
+    // Connections per client id, a client taking over its own session briefly has two.
+    online_clients: DashMap<String, usize, ahash::RandomState>,
     packets_queue: Q,
+    connection_limiter: ConnectionLimiter,
+    ban_list: BanList,
+    publish_limits: PublishLimits,
+    publish_counters: DashMap<String, Arc<PublishCounters>, ahash::RandomState>,
+    max_packet_size: Option<u32>,
+    shared_strategy: SharedStrategy,
+    group_shared_strategies: HashMap<String, SharedStrategy>,
+    shared_ack_timeout: Option<Duration>,
+    hooks: Hooks,
+    rule_engine: RuleEngine,
+    cluster: OnceLock<mpsc::Sender<ClusterEvent>>,
 
     route_table: RouteTable,
     retain_table: RetainTable,
 }
 
+impl<Q> Default for GlobalState<Q>
+where
+    Q: Queue + Default,
+{
+    fn default() -> Self {
+        Self::new(Q::default())
+    }
+}
+
 impl<Q> GlobalState<Q>
 where
     Q: Queue,
@@ -53,16 +93,187 @@ where
         Self {
             packets_queue,
             clients: Default::default(),
+            online_clients: Default::default(),
+            connection_limiter: Default::default(),
+            ban_list: Default::default(),
+            publish_limits: Default::default(),
+            publish_counters: Default::default(),
+            max_packet_size: Some(DEFAULT_MAX_PACKET_SIZE),
+            shared_strategy: Default::default(),
+            group_shared_strategies: Default::default(),
+            shared_ack_timeout: Some(DEFAULT_SHARED_ACK_TIMEOUT),
+            hooks: Default::default(),
+            rule_engine: Default::default(),
+            cluster: Default::default(),
             route_table: Default::default(),
             retain_table: Default::default(),
         }
     }
 
+    /// Limits applied to the connections of all listeners together.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.connection_limiter = ConnectionLimiter::new(limits);
+    }
+
+    pub fn set_ban_policy(&mut self, policy: Option<BanPolicy>) {
+        self.ban_list = BanList::new(policy);
+    }
+
+    /// Largest packet accepted from clients, fixed header included. Larger ones
+    /// are refused as soon as their fixed header is read. Defaults to
+    /// [`DEFAULT_MAX_PACKET_SIZE`], the largest packet of the protocol, `None`
+    /// leaves the size unchecked and unannounced to v5 clients.
+    pub fn set_max_packet_size(&mut self, max_packet_size: Option<u32>) {
+        self.max_packet_size = max_packet_size;
+    }
+
+    pub fn max_packet_size(&self) -> Option<u32> {
+        self.max_packet_size
+    }
+
+    /// Rate and size limits on the messages published by each client.
+    pub fn set_publish_limits(&mut self, limits: PublishLimits) {
+        self.publish_limits = limits;
+    }
+
+    pub fn publish_limits(&self) -> &PublishLimits {
+        &self.publish_limits
+    }
+
+    /// Publish counters of a client whose session is alive.
+    pub fn publish_counters(&self, client_id: &str) -> Option<Arc<PublishCounters>> {
+        self.publish_counters
+            .get(client_id)
+            .map(|c| c.value().clone())
+    }
+
+    pub(crate) fn publish_quota(&self, client_id: &str) -> PublishQuota {
+        let counters = self
+            .publish_counters
+            .entry(client_id.to_owned())
+            .or_default()
+            .clone();
+        PublishQuota::new(self.publish_limits, counters)
+    }
+
+    /// Strategy of the shared subscription groups without one of their own.
+    pub fn set_shared_strategy(&mut self, strategy: SharedStrategy) {
+        self.shared_strategy = strategy;
+    }
+
+    pub fn set_group_shared_strategy(&mut self, group_name: &str, strategy: SharedStrategy) {
+        self.group_shared_strategies
+            .insert(group_name.to_owned(), strategy);
+    }
+
+    pub fn shared_strategy(&self, group_name: &str) -> SharedStrategy {
+        self.group_shared_strategies
+            .get(group_name)
+            .copied()
+            .unwrap_or(self.shared_strategy)
+    }
+
+    /// How long a member of a shared subscription group has to acknowledge a QoS 1 or 2
+    /// message before it goes to another online member, `None` waits until the session
+    /// of the member ends. The member may still have received the message, which is
+    /// then received twice within the group.
+    pub fn set_shared_ack_timeout(&mut self, timeout: Option<Duration>) {
+        self.shared_ack_timeout = timeout;
+    }
+
+    pub fn shared_ack_timeout(&self) -> Option<Duration> {
+        self.shared_ack_timeout
+    }
+
+    /// Member of a shared subscription group receiving a message published by
+    /// `client_id` on `topic_name`, online members are preferred.
+    pub(crate) fn select_shared_client(
+        &self,
+        group_name: &str,
+        shared_clients: &SharedClients,
+        client_id: &str,
+        topic_name: &TopicName,
+    ) -> (String, QualityOfService) {
+        shared_clients.select(
+            self.shared_strategy(group_name),
+            client_id,
+            topic_name,
+            |member| self.outgoing_depth(member),
+        )
+    }
+
+    /// Online member of a shared subscription group other than `member` receiving a
+    /// message `member` did not acknowledge, `None` when there is none.
+    pub(crate) fn select_other_shared_client(
+        &self,
+        group_name: &str,
+        shared_clients: &SharedClients,
+        member: &str,
+        topic_name: &TopicName,
+    ) -> Option<(String, QualityOfService)> {
+        let depth = |client_id: &str| {
+            if client_id == member {
+                None
+            } else {
+                self.outgoing_depth(client_id)
+            }
+        };
+        let (client_id, qos) =
+            shared_clients.select(self.shared_strategy(group_name), member, topic_name, depth);
+        depth(&client_id).map(|_| (client_id, qos))
+    }
+
+    /// Runs `hook` on client, session and message events, after the hooks added before.
+    pub fn add_hook(&mut self, hook: impl Hook + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// Rules run on the messages published, they can be changed while the broker runs.
+    pub fn rule_engine(&self) -> &RuleEngine {
+        &self.rule_engine
+    }
+
+    // Set once, by the cluster this node runs.
+    pub(crate) fn set_cluster(&self, events: mpsc::Sender<ClusterEvent>) -> bool {
+        self.cluster.set(events).is_ok()
+    }
+
+    /// Hands a message published on this node to the other nodes of the cluster.
+    pub(crate) async fn forward_publish(&self, message: &PublishMessage) {
+        if message.forwarded_from().is_none() {
+            self.send_cluster_event(ClusterEvent::Publish(Box::new(message.clone())))
+                .await;
+        }
+    }
+
+    async fn send_cluster_event(&self, event: ClusterEvent) {
+        if let Some(events) = self.cluster.get() {
+            if let Err(err) = events.send(event).await {
+                log::warn!("send cluster event: {err}");
+            }
+        }
+    }
+
+    pub fn connection_limiter(&self) -> &ConnectionLimiter {
+        &self.connection_limiter
+    }
+
+    pub fn ban_list(&self) -> &BanList {
+        &self.ban_list
+    }
+
     pub async fn add_client(
         &self,
         client_id: &str,
         new_sender: mpsc::Sender<Outgoing>,
     ) -> AddClientReceipt {
+        // Sessions of the client on other nodes are evicted.
+        self.send_cluster_event(ClusterEvent::Takeover(client_id.to_owned()))
+            .await;
         if let Some(old_sender) = self.get_outgoing_sender(client_id) {
             if !old_sender.is_closed() {
                 let (control_sender, mut control_receiver) = channel(1);
@@ -99,11 +310,22 @@ where
         subscribes: impl IntoIterator<Item = &'a TopicFilter>,
     ) {
         self.clients.remove(client_id);
+        // Keep the counters while a session taking over the client holds them
+        // besides the map and the session going away.
+        self.publish_counters
+            .remove_if(client_id, |_, counters| Arc::strong_count(counters) <= 2);
         for filter in subscribes {
             self.route_table.unsubscribe(filter, client_id);
         }
     }
 
+    /// Drops the inflight packets kept for a session that ended.
+    pub(crate) async fn remove_packets(&self, client_id: &str) {
+        if let Err(err) = self.packets_queue.remove(client_id).await {
+            log::error!("client#{client_id} remove inflight packets: {err:?}");
+        }
+    }
+
     pub fn subscribe(&self, filter: &TopicFilter, id: &str, qos: QualityOfService) {
         self.route_table.subscribe(filter, id, qos);
     }
@@ -112,6 +334,42 @@ where
         self.route_table.unsubscribe(filter, id);
     }
 
+    /// Number of clients with a session, including disconnected ones whose
+    /// session has not expired yet.
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub(crate) fn set_client_online(&self, client_id: &str) {
+        *self.online_clients.entry(client_id.to_owned()).or_default() += 1;
+    }
+
+    pub(crate) fn set_client_offline(&self, client_id: &str) {
+        if let Some(mut count) = self.online_clients.get_mut(client_id) {
+            *count = count.saturating_sub(1);
+        }
+        self.online_clients
+            .remove_if(client_id, |_, count| *count == 0);
+    }
+
+    /// Whether a client is connected, a client with a session may be disconnected.
+    pub fn is_client_online(&self, client_id: &str) -> bool {
+        self.online_clients.contains_key(client_id)
+    }
+
+    /// Number of messages waiting to be written to a connected client, `None` when the
+    /// client is not connected.
+    pub fn outgoing_depth(&self, client_id: &str) -> Option<usize> {
+        if !self.is_client_online(client_id) {
+            return None;
+        }
+        let sender = self.get_outgoing_sender(client_id)?;
+        if sender.is_closed() {
+            return None;
+        }
+        Some(sender.max_capacity() - sender.capacity())
+    }
+
     pub fn get_outgoing_sender(&self, client_id: &str) -> Option<mpsc::Sender<Outgoing>> {
         self.clients.get(client_id).map(|s| s.value().clone())
     }
@@ -128,3 +386,59 @@ where
         &self.route_table
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::store::memory::queue::MemoryQueue;
+
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        let global = GlobalState::<MemoryQueue>::default();
+        assert_eq!(
+            global.shared_ack_timeout(),
+            Some(DEFAULT_SHARED_ACK_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn test_default_max_packet_size() {
+        let mut global = GlobalState::new(MemoryQueue::new(10, 10));
+        assert_eq!(global.max_packet_size(), Some(DEFAULT_MAX_PACKET_SIZE));
+        global.set_max_packet_size(None);
+        assert_eq!(global.max_packet_size(), None);
+    }
+
+    #[tokio::test]
+    async fn test_select_other_shared_client() {
+        let global = GlobalState::new(MemoryQueue::new(10, 10));
+        let filter = TopicFilter::new("$share/g/t").unwrap();
+        let mut receivers = Vec::new();
+        for client_id in ["a", "b", "c"] {
+            let (outgoing_tx, outgoing_rx) = mpsc::channel(4);
+            global.add_client(client_id, outgoing_tx).await;
+            global.subscribe(&filter, client_id, QualityOfService::Level1);
+            receivers.push(outgoing_rx);
+        }
+        global.set_client_online("a");
+        global.set_client_online("b");
+
+        let topic_name = TopicName::new("t").unwrap();
+        let routes = global.route_table().get_matches(&topic_name);
+        let members = routes[0].groups.get("g").unwrap();
+        // Neither the member itself nor the offline one.
+        for _ in 0..6 {
+            assert_eq!(
+                global.select_other_shared_client("g", members, "a", &topic_name),
+                Some(("b".to_owned(), QualityOfService::Level1))
+            );
+        }
+
+        global.set_client_offline("b");
+        assert_eq!(
+            global.select_other_shared_client("g", members, "a", &topic_name),
+            None
+        );
+    }
+}
@@ -1,11 +1,30 @@
 pub mod server;
 
+use crate::server::limits::{LimitExceeded, Refused};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Io Error : {0}")]
     Io(#[from] std::io::Error),
     #[error("Missing tls config")]
     MissingTlsConfig,
+    #[error("Handshake timeout")]
+    HandshakeTimeout,
+    #[error("Client address is banned")]
+    Banned,
+    #[error("Connection over limit: {0:?}")]
+    LimitExceeded(LimitExceeded),
+    #[error("PROXY protocol error: {0}")]
+    ProxyProtocol(#[from] crate::server::proxy_protocol::Error),
     #[error("Wrong tls config: {0}")]
     Rustls(#[from] crate::server::rustls::Error),
 }
+
+impl From<Refused> for Error {
+    fn from(refused: Refused) -> Self {
+        match refused {
+            Refused::Banned => Error::Banned,
+            Refused::LimitExceeded(exceeded) => Error::LimitExceeded(exceeded),
+        }
+    }
+}
@@ -1,12 +1,26 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, time};
 
 #[cfg(feature = "mqtts")]
 use crate::server::config::TlsConfig;
 use crate::{
-    server::{process_client, state::GlobalState},
+    server::{
+        config::DEFAULT_HANDSHAKE_TIMEOUT,
+        limits::{admit, ConnectionLimiter, ConnectionLimits},
+        process_client,
+        proxy_protocol::read_proxy_header,
+        state::GlobalState,
+    },
     store::queue::Queue,
+    types::connection::{ConnectionInfo, Transport},
 };
 
 use super::Error;
@@ -16,7 +30,12 @@ where
     Q: Queue,
 {
     inner: TcpListener,
+    name: Option<Arc<str>>,
     global: Arc<GlobalState<Q>>,
+    limiter: Arc<ConnectionLimiter>,
+    proxy_protocol: bool,
+    handshake_timeout: Duration,
+    handshake_failures: Arc<AtomicU64>,
 }
 
 impl<Q> TcpServer<Q>
@@ -27,16 +46,75 @@ where
         let listener = TcpListener::bind(addr).await?;
         Ok(Self {
             inner: listener,
+            name: None,
             global,
+            limiter: Default::default(),
+            proxy_protocol: false,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_failures: Default::default(),
         })
     }
 
+    /// Name of the listener, recorded on every accepted connection.
+    pub fn set_name<S: Into<Arc<str>>>(&mut self, name: S) {
+        self.name = Some(name.into());
+    }
+
+    /// Limits applied to the connections of this listener, on top of the
+    /// global ones.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.limiter = Arc::new(ConnectionLimiter::new(limits));
+    }
+
+    /// Number of live connections on this listener.
+    pub fn connections(&self) -> usize {
+        self.limiter.connections()
+    }
+
+    /// Expect a PROXY protocol v1 or v2 header at the start of every connection.
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.proxy_protocol = proxy_protocol;
+    }
+
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = handshake_timeout;
+    }
+
+    /// Number of connections dropped because their handshake failed or timed out.
+    pub fn handshake_failures(&self) -> u64 {
+        self.handshake_failures.load(Ordering::Relaxed)
+    }
+
     #[cfg(feature = "mqtt")]
     pub async fn accept(&self) -> Result<(), Error> {
-        while let Ok((stream, _addr)) = self.inner.accept().await {
+        while let Ok((mut stream, addr)) = self.inner.accept().await {
+            let name = self.name.clone();
             let global = self.global.clone();
+            let limiter = self.limiter.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let handshake_timeout = self.handshake_timeout;
+            let handshake_failures = self.handshake_failures.clone();
             tokio::spawn(async move {
-                process_client(stream, global).await;
+                let mut connection = ConnectionInfo::new(Transport::Tcp, name);
+                connection.set_remote_addr(addr);
+                let handshake = async {
+                    if proxy_protocol {
+                        read_proxy_header(&mut stream, &mut connection).await?;
+                    }
+                    admit(&limiter, &global, &mut connection, false).map_err(Error::from)
+                };
+                let permits = match time::timeout(handshake_timeout, handshake).await {
+                    Ok(permits) => permits,
+                    Err(_) => Err(Error::HandshakeTimeout),
+                };
+                match permits {
+                    Ok(_permits) => process_client(stream, connection, global).await,
+                    Err(Error::Banned) => log::debug!("refuse banned tcp client {addr}"),
+                    Err(err) => {
+                        handshake_failures.fetch_add(1, Ordering::Relaxed);
+                        log::warn!("accept tcp stream from {addr} failed: {err}");
+                    }
+                }
             });
         }
         Ok(())
@@ -44,21 +122,98 @@ where
 
     #[cfg(feature = "mqtts")]
     pub async fn accept_tls(&self, tls: &TlsConfig) -> Result<(), Error> {
-        use crate::server::rustls::rustls_acceptor;
+        use crate::server::rustls::{rustls_acceptor, set_tls_info};
 
         let acceptor = rustls_acceptor(tls)?;
-        while let Ok((stream, _addr)) = self.inner.accept().await {
-            match acceptor.accept(stream).await {
-                Ok(stream) => {
-                    let global = self.global.clone();
-                    tokio::spawn(async move { process_client(stream, global).await });
-                }
-                Err(err) => {
-                    log::warn!("accept tls stream failed: {err}");
-                    continue;
+        let identity = tls.peer_cert_identity;
+        while let Ok((mut stream, addr)) = self.inner.accept().await {
+            let acceptor = acceptor.acceptor();
+            let name = self.name.clone();
+            let global = self.global.clone();
+            let limiter = self.limiter.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let handshake_timeout = self.handshake_timeout;
+            let handshake_failures = self.handshake_failures.clone();
+            tokio::spawn(async move {
+                let mut connection = ConnectionInfo::new(Transport::Tls, name);
+                connection.set_remote_addr(addr);
+                let handshake = async {
+                    if proxy_protocol {
+                        read_proxy_header(&mut stream, &mut connection).await?;
+                    }
+                    let permits = admit(&limiter, &global, &mut connection, true)?;
+                    let stream = acceptor.accept(stream).await?;
+                    set_tls_info(&mut connection, stream.get_ref().1, identity);
+                    Ok::<_, Error>((stream, permits))
+                };
+                let stream = match time::timeout(handshake_timeout, handshake).await {
+                    Ok(stream) => stream,
+                    Err(_) => Err(Error::HandshakeTimeout),
+                };
+                match stream {
+                    Ok((stream, _permits)) => process_client(stream, connection, global).await,
+                    Err(Error::Banned) => log::debug!("refuse banned tls client {addr}"),
+                    Err(Error::LimitExceeded(_)) => {
+                        log::debug!("refuse tls client {addr} over limit")
+                    }
+                    Err(err) => {
+                        handshake_failures.fetch_add(1, Ordering::Relaxed);
+                        log::warn!("accept tls stream from {addr} failed: {err}");
+                    }
                 }
-            }
+            });
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::{io::AsyncReadExt as _, net::TcpStream};
+
+    use crate::store::memory::queue::MemoryQueue;
+
+    use super::*;
+
+    async fn free_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    // Waits for the broker to close the connection.
+    async fn closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0; 16];
+        matches!(
+            time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await,
+            Ok(Ok(0) | Err(_))
+        )
+    }
+
+    #[cfg(feature = "mqtts")]
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let addr = free_addr().await;
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let mut server = TcpServer::bind(addr, global).await.unwrap();
+        server.set_handshake_timeout(Duration::from_millis(100));
+        let server = Arc::new(server);
+        let tls = TlsConfig::new(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/examples/certs/cert.pem"),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/examples/certs/key.pem"),
+        );
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.accept_tls(&tls).await }
+        });
+
+        // Clients which never start the TLS handshake hold up neither the
+        // listener nor each other.
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(closed(&mut first).await);
+        assert!(closed(&mut second).await);
+        // Counted once the connection is closed
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.handshake_failures(), 2);
+    }
+}
@@ -0,0 +1,13 @@
+use std::path::PathBuf;
+
+pub mod server;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Io Error : {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Address in use: {0}")]
+    AddrInUse(PathBuf),
+    #[error("Not a socket file: {0}")]
+    NotSocket(PathBuf),
+}
@@ -0,0 +1,201 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{
+    server::{
+        limits::{admit, ConnectionLimiter, ConnectionLimits},
+        process_client,
+        state::GlobalState,
+    },
+    store::queue::Queue,
+    types::connection::{ConnectionInfo, PeerCredentials, Transport},
+};
+
+use super::Error;
+
+pub struct UdsServer<Q>
+where
+    Q: Queue,
+{
+    inner: UnixListener,
+    path: PathBuf,
+    name: Option<Arc<str>>,
+    global: Arc<GlobalState<Q>>,
+    limiter: Arc<ConnectionLimiter>,
+}
+
+impl<Q> UdsServer<Q>
+where
+    Q: Queue + Send + 'static,
+{
+    pub async fn bind<P: AsRef<Path>>(path: P, global: Arc<GlobalState<Q>>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        remove_stale_socket(path).await?;
+        let listener = UnixListener::bind(path)?;
+        Ok(Self {
+            inner: listener,
+            path: path.to_path_buf(),
+            name: None,
+            global,
+            limiter: Default::default(),
+        })
+    }
+
+    /// Name of the listener, recorded on every accepted connection.
+    pub fn set_name<S: Into<Arc<str>>>(&mut self, name: S) {
+        self.name = Some(name.into());
+    }
+
+    /// Limits applied to the connections of this listener, on top of the
+    /// global ones.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.limiter = Arc::new(ConnectionLimiter::new(limits));
+    }
+
+    /// Number of live connections on this listener.
+    pub fn connections(&self) -> usize {
+        self.limiter.connections()
+    }
+
+    pub async fn accept(&self) -> Result<(), Error> {
+        while let Ok((stream, _addr)) = self.inner.accept().await {
+            let mut connection = ConnectionInfo::new(Transport::Unix, self.name.clone());
+            match stream.peer_cred() {
+                Ok(cred) => connection.set_peer_credentials(PeerCredentials::new(
+                    cred.uid(),
+                    cred.gid(),
+                    cred.pid(),
+                )),
+                Err(err) => log::warn!("read unix socket peer credentials failed: {err}"),
+            }
+            // Unix sockets have no address to ban.
+            let Ok(permits) = admit(&self.limiter, &self.global, &mut connection, false) else {
+                continue;
+            };
+            let global = self.global.clone();
+            tokio::spawn(async move {
+                process_client(stream, connection, global).await;
+                drop(permits);
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<Q> Drop for UdsServer<Q>
+where
+    Q: Queue,
+{
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::warn!("remove unix socket {:?} failed: {err}", self.path);
+        }
+    }
+}
+
+/// Remove a socket file left behind by a previous process. A socket that still
+/// accepts connections belongs to a running server and is left untouched.
+async fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::NotSocket(path.to_path_buf()));
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(Error::AddrInUse(path.to_path_buf())),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+            log::info!("remove stale unix socket {:?}", path);
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixListener as StdUnixListener;
+
+    use futures_util::future::BoxFuture;
+    use mqtt_codec_kit::{common::Encodable, v4::packet::ConnectPacket};
+    use tokio::{io::AsyncWriteExt as _, sync::mpsc};
+
+    use crate::{server::hook::Hook, store::memory::queue::MemoryQueue};
+
+    use super::*;
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!("mesquitte-{}.sock", nanoid::nanoid!()))
+    }
+
+    // Sends the connection of every CONNECT to the test.
+    struct ConnectingHook(mpsc::UnboundedSender<ConnectionInfo>);
+
+    impl Hook for ConnectingHook {
+        fn on_client_connecting<'a>(
+            &'a self,
+            _client_id: &'a str,
+            connection: &'a ConnectionInfo,
+        ) -> BoxFuture<'a, ()> {
+            let _ = self.0.send(connection.clone());
+            Box::pin(async {})
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_stale_socket() {
+        let path = socket_path();
+        remove_stale_socket(&path).await.unwrap();
+
+        fs::write(&path, b"").unwrap();
+        assert!(matches!(
+            remove_stale_socket(&path).await,
+            Err(Error::NotSocket(_))
+        ));
+        fs::remove_file(&path).unwrap();
+
+        let listener = StdUnixListener::bind(&path).unwrap();
+        assert!(matches!(
+            remove_stale_socket(&path).await,
+            Err(Error::AddrInUse(_))
+        ));
+        // The socket file outlives the listener.
+        drop(listener);
+        assert!(path.exists());
+        remove_stale_socket(&path).await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_peer_credentials() {
+        let (connections_tx, mut connections_rx) = mpsc::unbounded_channel();
+        let mut global = GlobalState::new(MemoryQueue::new(10, 10));
+        global.add_hook(ConnectingHook(connections_tx));
+        let path = socket_path();
+        let server = UdsServer::bind(&path, Arc::new(global)).await.unwrap();
+        tokio::spawn(async move { server.accept().await });
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        let mut connect = Vec::new();
+        ConnectPacket::new("local").encode(&mut connect).unwrap();
+        stream.write_all(&connect).await.unwrap();
+
+        let connection = connections_rx.recv().await.unwrap();
+        assert_eq!(connection.transport(), Transport::Unix);
+        let cred = stream.peer_cred().unwrap();
+        assert_eq!(
+            connection.peer_credentials(),
+            Some(&PeerCredentials::new(cred.uid(), cred.gid(), cred.pid()))
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use std::{collections::HashMap, future::Future, io, sync::Arc};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tungstenite::http::{self, header, HeaderName, HeaderValue, StatusCode};
+
+use crate::server::rewind::Rewind;
+
+use super::Error;
+
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+pub type HttpRequest = http::Request<Vec<u8>>;
+pub type HttpResponse = http::Response<Vec<u8>>;
+
+type Handler = Arc<dyn Fn(HttpRequest) -> BoxFuture<'static, HttpResponse> + Send + Sync>;
+
+/// Handlers of plain HTTP requests, keyed by path.
+#[derive(Clone, Default)]
+pub(super) struct HttpRoutes {
+    routes: HashMap<String, Handler>,
+}
+
+impl HttpRoutes {
+    pub(super) fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub(super) fn add<F, Fut>(&mut self, path: String, handler: F)
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HttpResponse> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |req| Box::pin(handler(req)));
+        self.routes.insert(path, handler);
+    }
+
+    async fn call(&self, req: HttpRequest) -> HttpResponse {
+        match self.routes.get(req.uri().path()) {
+            Some(handler) => handler(req).await,
+            None => response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// Build a plain text response.
+pub fn response<B: Into<Vec<u8>>>(status: StatusCode, body: B) -> HttpResponse {
+    let mut resp = http::Response::new(body.into());
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    resp
+}
+
+/// What the client asked for in the first request on the connection.
+pub(super) enum Incoming<S> {
+    /// A WebSocket upgrade, the stream replays the request to the handshake.
+    Upgrade(Rewind<S>),
+    Http(Box<HttpRequest>, S),
+}
+
+/// Read the first request head and tell WebSocket upgrades from plain HTTP
+/// requests.
+pub(super) async fn read_request<S>(mut stream: S) -> Result<Incoming<S>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let head_len = loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() >= MAX_HEAD_SIZE {
+            return Err(Error::InvalidHttpRequest("request head too large"));
+        }
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    parsed
+        .parse(&buf[..head_len])
+        .map_err(|_| Error::InvalidHttpRequest("malformed request head"))?;
+
+    let upgrade = parsed.headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case(header::UPGRADE.as_str())
+            && h.value.eq_ignore_ascii_case(b"websocket")
+    });
+    if upgrade {
+        return Ok(Incoming::Upgrade(Rewind::new(buf, stream)));
+    }
+
+    let mut builder = http::Request::builder()
+        .method(parsed.method.unwrap_or_default())
+        .uri(parsed.path.unwrap_or_default());
+    let mut content_length = 0;
+    for h in parsed.headers.iter() {
+        if h.name.eq_ignore_ascii_case(header::CONTENT_LENGTH.as_str()) {
+            content_length = std::str::from_utf8(h.value)
+                .ok()
+                .and_then(|len| len.trim().parse().ok())
+                .ok_or(Error::InvalidHttpRequest("invalid content length"))?;
+        }
+        let name = HeaderName::from_bytes(h.name.as_bytes())
+            .map_err(|_| Error::InvalidHttpRequest("invalid header name"))?;
+        let value = HeaderValue::from_bytes(h.value)
+            .map_err(|_| Error::InvalidHttpRequest("invalid header value"))?;
+        builder = builder.header(name, value);
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err(Error::InvalidHttpRequest("request body too large"));
+    }
+
+    let mut body = buf.split_off(head_len);
+    body.truncate(content_length);
+    if body.len() < content_length {
+        let start = body.len();
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body[start..]).await?;
+    }
+    let req = builder
+        .body(body)
+        .map_err(|_| Error::InvalidHttpRequest("invalid request line"))?;
+    Ok(Incoming::Http(Box::new(req), stream))
+}
+
+/// Answer a plain HTTP request and close the connection.
+pub(super) async fn serve<S>(routes: &HttpRoutes, req: HttpRequest, mut stream: S) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    log::debug!("serve http request: {} {}", req.method(), req.uri());
+    let resp = routes.call(req).await;
+
+    let status = resp.status();
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    for (name, value) in resp.headers() {
+        if name == header::CONTENT_LENGTH || name == header::CONNECTION {
+            continue;
+        }
+        head.push_str(name.as_str());
+        head.push_str(": ");
+        head.push_str(&String::from_utf8_lossy(value.as_bytes()));
+        head.push_str("\r\n");
+    }
+    head.push_str(&format!(
+        "content-length: {}\r\nconnection: close\r\n\r\n",
+        resp.body().len()
+    ));
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(resp.body()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read(request: &[u8]) -> Result<Incoming<&[u8]>, Error> {
+        read_request(request).await
+    }
+
+    fn invalid<S>(result: Result<Incoming<S>, Error>) -> bool {
+        matches!(result, Err(Error::InvalidHttpRequest(_)))
+    }
+
+    fn request_with_body(body: &[u8]) -> Vec<u8> {
+        let head = format!(
+            "POST /admin HTTP/1.1\r\ncontent-length: {}\r\n\r\n",
+            body.len()
+        );
+        [head.as_bytes(), body].concat()
+    }
+
+    #[tokio::test]
+    async fn test_read_http_request() {
+        let request = request_with_body(b"body");
+        let Ok(Incoming::Http(req, _)) = read(&request).await else {
+            panic!("not an http request");
+        };
+        assert_eq!(req.method(), http::Method::POST);
+        assert_eq!(req.uri().path(), "/admin");
+        assert_eq!(req.body(), b"body");
+
+        let request = b"GET /mqtt HTTP/1.1\r\nUpgrade: WebSocket\r\n\r\nframes";
+        let Ok(Incoming::Upgrade(mut stream)) = read(request).await else {
+            panic!("not an upgrade");
+        };
+        // The handshake reads the request again.
+        let mut replayed = Vec::new();
+        stream.read_to_end(&mut replayed).await.unwrap();
+        assert_eq!(replayed, request);
+    }
+
+    #[tokio::test]
+    async fn test_read_request_limits() {
+        let mut head = b"GET / HTTP/1.1\r\n".to_vec();
+        while head.len() < MAX_HEAD_SIZE {
+            head.extend(b"x-padding: 0123456789012345678901234567890123456789\r\n");
+        }
+        assert!(invalid(read(&head).await));
+
+        let headers = |count| {
+            let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+            for i in 0..count {
+                request.extend(format!("x-header-{i}: 1\r\n").as_bytes());
+            }
+            request.extend(b"\r\n");
+            request
+        };
+        assert!(read(&headers(MAX_HEADERS)).await.is_ok());
+        assert!(invalid(read(&headers(MAX_HEADERS + 1)).await));
+
+        let body = vec![b'a'; MAX_BODY_SIZE];
+        assert!(read(&request_with_body(&body)).await.is_ok());
+        let body = vec![b'a'; MAX_BODY_SIZE + 1];
+        assert!(invalid(read(&request_with_body(&body)).await));
+
+        // The body ends before its content length.
+        let mut request = request_with_body(b"body");
+        request.pop();
+        assert!(matches!(read(&request).await, Err(Error::Io(_))));
+        assert!(matches!(
+            read(b"GET / HTTP/1.1\r\n").await,
+            Err(Error::Io(_))
+        ));
+    }
+}
@@ -1,7 +1,10 @@
-mod ws_stream;
+pub(crate) mod ws_stream;
 
+pub mod http;
 pub mod server;
 
+use crate::server::limits::{LimitExceeded, Refused};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Io Error : {0}")]
@@ -10,6 +13,25 @@ pub enum Error {
     Accept(#[from] tungstenite::Error),
     #[error("Missing tls config")]
     MissingTlsConfig,
+    #[error("Invalid HTTP request: {0}")]
+    InvalidHttpRequest(&'static str),
+    #[error("Handshake timeout")]
+    HandshakeTimeout,
+    #[error("Client address is banned")]
+    Banned,
+    #[error("Connection over limit: {0:?}")]
+    LimitExceeded(LimitExceeded),
+    #[error("PROXY protocol error: {0}")]
+    ProxyProtocol(#[from] crate::server::proxy_protocol::Error),
     #[error("Wrong tls config: {0}")]
     Rustls(#[from] crate::server::rustls::Error),
 }
+
+impl From<Refused> for Error {
+    fn from(refused: Refused) -> Self {
+        match refused {
+            Refused::Banned => Error::Banned,
+            Refused::LimitExceeded(exceeded) => Error::LimitExceeded(exceeded),
+        }
+    }
+}
@@ -1,25 +1,65 @@
-use std::{net::SocketAddr, sync::Arc};
+// The handshake callbacks must return tungstenite's `ErrorResponse`.
+#![allow(clippy::result_large_err)]
 
-use async_tungstenite::{accept_hdr_async, tokio::TokioAdapter};
-use tokio::net::TcpListener;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_tungstenite::{accept_hdr_async, tokio::TokioAdapter, WebSocketStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    time,
+};
 #[cfg(any(feature = "ws", feature = "wss"))]
-use tungstenite::{handshake::server::ErrorResponse, http};
+use tungstenite::{
+    handshake::server::ErrorResponse,
+    http::{self, header, HeaderValue, StatusCode},
+};
 
 #[cfg(feature = "wss")]
 use crate::server::config::TlsConfig;
 use crate::{
-    server::{process_client, state::GlobalState},
+    server::{
+        config::DEFAULT_HANDSHAKE_TIMEOUT,
+        limits::{admit, ConnectionLimiter, ConnectionLimits},
+        process_client,
+        proxy_protocol::read_proxy_header,
+        rewind::Rewind,
+        state::GlobalState,
+    },
     store::queue::Queue,
+    types::connection::{ConnectionInfo, Transport, WebSocketInfo},
+};
+
+use super::{
+    http::{read_request, response, serve, HttpRequest, HttpResponse, HttpRoutes, Incoming},
+    ws_stream::WsByteStream,
+    Error,
 };
 
-use super::{ws_stream::WsByteStream, Error};
+// see: [MQTT-6.0.0-3]
+const SUBPROTOCOLS: [&str; 3] = ["mqtt", "mqttv3.1", "mqttv5"];
 
 pub struct WsServer<Q>
 where
     Q: Queue,
 {
     inner: TcpListener,
+    name: Option<Arc<str>>,
     global: Arc<GlobalState<Q>>,
+    upgrade: Arc<UpgradePolicy>,
+    http_routes: Arc<HttpRoutes>,
+    limiter: Arc<ConnectionLimiter>,
+    proxy_protocol: bool,
+    handshake_timeout: Duration,
+    handshake_failures: Arc<AtomicU64>,
 }
 
 impl<Q> WsServer<Q>
@@ -30,60 +70,523 @@ where
         let listener = TcpListener::bind(addr).await?;
         Ok(Self {
             inner: listener,
+            name: None,
             global,
+            upgrade: Default::default(),
+            http_routes: Default::default(),
+            limiter: Default::default(),
+            proxy_protocol: false,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_failures: Default::default(),
         })
     }
 
+    /// Name of the listener, recorded on every accepted connection.
+    pub fn set_name<S: Into<Arc<str>>>(&mut self, name: S) {
+        self.name = Some(name.into());
+    }
+
+    /// Only upgrade requests for these paths, e.g. `/mqtt`. All paths are
+    /// accepted when empty.
+    pub fn set_paths<I, S>(&mut self, paths: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Arc::make_mut(&mut self.upgrade).paths = paths.into_iter().map(Into::into).collect();
+    }
+
+    /// Only upgrade requests whose `Origin` header is one of these, e.g.
+    /// `https://app.example.com`. All origins are accepted when empty.
+    pub fn set_allowed_origins<I, S>(&mut self, origins: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Arc::make_mut(&mut self.upgrade).allowed_origins =
+            origins.into_iter().map(Into::into).collect();
+    }
+
+    /// Answer plain HTTP requests for `path` on this listener, e.g. admin
+    /// endpoints. WebSocket upgrades are not affected.
+    pub fn add_http_route<P, F, Fut>(&mut self, path: P, handler: F)
+    where
+        P: Into<String>,
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HttpResponse> + Send + 'static,
+    {
+        Arc::make_mut(&mut self.http_routes).add(path.into(), handler);
+    }
+
+    /// Answer `/health` probes and serve `/metrics` in the Prometheus text
+    /// format. Readiness depends on the deployment, e.g. a bridge connected to
+    /// its remote broker, add it with [`Self::add_http_route`].
+    pub fn add_default_http_routes(&mut self)
+    where
+        Q: Sync,
+    {
+        self.add_http_route("/health", |_| async { response(StatusCode::OK, "ok") });
+
+        let global = self.global.clone();
+        let handshake_failures = self.handshake_failures.clone();
+        let listener = self.name.as_deref().unwrap_or_default().to_owned();
+        self.add_http_route("/metrics", move |_| {
+            let metrics = format!(
+                "# TYPE mesquitte_clients gauge\n\
+                 mesquitte_clients {}\n\
+                 # TYPE mesquitte_ws_handshake_failures_total counter\n\
+                 mesquitte_ws_handshake_failures_total{{listener=\"{}\"}} {}\n",
+                global.client_count(),
+                listener,
+                handshake_failures.load(Ordering::Relaxed),
+            );
+            async { response(StatusCode::OK, metrics) }
+        });
+    }
+
+    /// Limits applied to the connections of this listener, on top of the
+    /// global ones.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.limiter = Arc::new(ConnectionLimiter::new(limits));
+    }
+
+    /// Number of live connections on this listener.
+    pub fn connections(&self) -> usize {
+        self.limiter.connections()
+    }
+
+    /// Expect a PROXY protocol v1 or v2 header at the start of every connection.
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.proxy_protocol = proxy_protocol;
+    }
+
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = handshake_timeout;
+    }
+
+    /// Number of connections dropped because their handshake failed or timed out.
+    pub fn handshake_failures(&self) -> u64 {
+        self.handshake_failures.load(Ordering::Relaxed)
+    }
+
     #[cfg(feature = "ws")]
     pub async fn accept(&self) -> Result<(), Error> {
-        while let Ok((stream, _addr)) = self.inner.accept().await {
+        while let Ok((mut stream, addr)) = self.inner.accept().await {
+            let name = self.name.clone();
             let global = self.global.clone();
-            let ws_stream =
-                WsByteStream::new(accept_hdr_async(TokioAdapter::new(stream), ws_callback).await?);
-            tokio::spawn(async move { process_client(ws_stream, global).await });
+            let upgrade = self.upgrade.clone();
+            let http_routes = self.http_routes.clone();
+            let limiter = self.limiter.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let handshake_timeout = self.handshake_timeout;
+            let handshake_failures = self.handshake_failures.clone();
+            tokio::spawn(async move {
+                let mut connection = ConnectionInfo::new(Transport::WebSocket, name);
+                connection.set_remote_addr(addr);
+                let handshake = async {
+                    if proxy_protocol {
+                        read_proxy_header(&mut stream, &mut connection).await?;
+                    }
+                    let permits = admit(&limiter, &global, &mut connection, true)?;
+                    let handshake =
+                        handshake(stream, &upgrade, &http_routes, &mut connection).await?;
+                    Ok((handshake, permits))
+                };
+                let handshake = match time::timeout(handshake_timeout, handshake).await {
+                    Ok(handshake) => handshake,
+                    Err(_) => Err(Error::HandshakeTimeout),
+                };
+                match handshake {
+                    Ok((Handshake::WebSocket(ws_stream), _permits)) => {
+                        process_client(WsByteStream::new(*ws_stream), connection, global).await
+                    }
+                    Ok((Handshake::Http(req, stream), _)) => {
+                        if let Err(err) = serve(&http_routes, *req, stream).await {
+                            log::warn!("serve http request from {addr} failed: {err}");
+                        }
+                    }
+                    Err(Error::Banned) => log::debug!("refuse banned WebSocket client {addr}"),
+                    Err(Error::LimitExceeded(_)) => {
+                        log::debug!("refuse WebSocket client {addr} over limit")
+                    }
+                    Err(err) => {
+                        handshake_failures.fetch_add(1, Ordering::Relaxed);
+                        log::warn!("accept WebSocket stream from {addr} failed: {err}");
+                    }
+                }
+            });
         }
         Ok(())
     }
 
     #[cfg(feature = "wss")]
     pub async fn accept_tls(&self, tls: &TlsConfig) -> Result<(), Error> {
-        use crate::server::rustls::rustls_acceptor;
+        use crate::server::rustls::{rustls_acceptor, set_tls_info};
 
         let acceptor = rustls_acceptor(tls)?;
-        while let Ok((stream, _addr)) = self.inner.accept().await {
+        let identity = tls.peer_cert_identity;
+        while let Ok((mut stream, addr)) = self.inner.accept().await {
+            let acceptor = acceptor.acceptor();
+            let name = self.name.clone();
             let global = self.global.clone();
-            match acceptor.accept(stream).await {
-                Ok(stream) => {
-                    let ws_stream = WsByteStream::new(
-                        accept_hdr_async(TokioAdapter::new(stream), ws_callback).await?,
-                    );
-                    tokio::spawn(async move { process_client(ws_stream, global).await });
-                }
-                Err(err) => {
-                    log::error!("accept WebSocket tls stream failed: {err}");
-                    continue;
+            let upgrade = self.upgrade.clone();
+            let http_routes = self.http_routes.clone();
+            let limiter = self.limiter.clone();
+            let proxy_protocol = self.proxy_protocol;
+            let handshake_timeout = self.handshake_timeout;
+            let handshake_failures = self.handshake_failures.clone();
+            tokio::spawn(async move {
+                let mut connection = ConnectionInfo::new(Transport::WebSocketTls, name);
+                connection.set_remote_addr(addr);
+                let handshake = async {
+                    if proxy_protocol {
+                        read_proxy_header(&mut stream, &mut connection).await?;
+                    }
+                    let permits = admit(&limiter, &global, &mut connection, true)?;
+                    let stream = acceptor.accept(stream).await?;
+                    set_tls_info(&mut connection, stream.get_ref().1, identity);
+                    let handshake =
+                        handshake(stream, &upgrade, &http_routes, &mut connection).await?;
+                    Ok((handshake, permits))
+                };
+                let handshake = match time::timeout(handshake_timeout, handshake).await {
+                    Ok(handshake) => handshake,
+                    Err(_) => Err(Error::HandshakeTimeout),
+                };
+                match handshake {
+                    Ok((Handshake::WebSocket(ws_stream), _permits)) => {
+                        process_client(WsByteStream::new(*ws_stream), connection, global).await
+                    }
+                    Ok((Handshake::Http(req, stream), _)) => {
+                        if let Err(err) = serve(&http_routes, *req, stream).await {
+                            log::warn!("serve https request from {addr} failed: {err}");
+                        }
+                    }
+                    Err(Error::Banned) => log::debug!("refuse banned WebSocket tls client {addr}"),
+                    Err(Error::LimitExceeded(_)) => {
+                        log::debug!("refuse WebSocket tls client {addr} over limit")
+                    }
+                    Err(err) => {
+                        handshake_failures.fetch_add(1, Ordering::Relaxed);
+                        log::warn!("accept WebSocket tls stream from {addr} failed: {err}");
+                    }
                 }
-            }
+            });
         }
         Ok(())
     }
 }
 
+enum Handshake<S> {
+    WebSocket(Box<WebSocketStream<TokioAdapter<Rewind<S>>>>),
+    Http(Box<HttpRequest>, S),
+}
+
+// Upgrade to WebSocket, or hand plain HTTP requests to the routes if any.
+async fn handshake<S>(
+    stream: S,
+    upgrade: &UpgradePolicy,
+    http_routes: &HttpRoutes,
+    connection: &mut ConnectionInfo,
+) -> Result<Handshake<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = if http_routes.is_empty() {
+        Rewind::new(Vec::new(), stream)
+    } else {
+        match read_request(stream).await? {
+            Incoming::Upgrade(stream) => stream,
+            Incoming::Http(req, stream) => return Ok(Handshake::Http(req, stream)),
+        }
+    };
+    let callback =
+        |req: &http::Request<()>, resp: http::Response<()>| upgrade.check(req, resp, connection);
+    let ws_stream = accept_hdr_async(TokioAdapter::new(stream), callback).await?;
+    Ok(Handshake::WebSocket(Box::new(ws_stream)))
+}
+
+#[derive(Debug, Clone, Default)]
+struct UpgradePolicy {
+    paths: Vec<String>,
+    allowed_origins: Vec<String>,
+}
+
+impl UpgradePolicy {
+    fn check(
+        &self,
+        req: &http::Request<()>,
+        mut resp: http::Response<()>,
+        connection: &mut ConnectionInfo,
+    ) -> Result<http::Response<()>, ErrorResponse> {
+        let path = req.uri().path();
+        if !self.paths.is_empty() && !self.paths.iter().any(|p| p == path) {
+            log::info!("reject WebSocket upgrade on path: {path}");
+            return Err(error_response(StatusCode::NOT_FOUND, "unknown path"));
+        }
+
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned())
+        };
+        let origin = header(header::ORIGIN);
+        if !self.allowed_origins.is_empty() {
+            let allowed = origin.as_ref().is_some_and(|origin| {
+                self.allowed_origins
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            });
+            if !allowed {
+                log::info!("reject WebSocket upgrade from origin: {:?}", origin);
+                return Err(error_response(StatusCode::FORBIDDEN, "origin not allowed"));
+            }
+        }
+
+        let subprotocol = select_subprotocol(req, &mut resp)?;
+        connection.set_websocket(WebSocketInfo {
+            path: path.to_owned(),
+            subprotocol: subprotocol.map(|protocol| protocol.to_owned()),
+            origin,
+            authorization: header(header::AUTHORIZATION),
+            cookie: header(header::COOKIE),
+        });
+        Ok(resp)
+    }
+}
+
 #[cfg(any(feature = "ws", feature = "wss"))]
 pub fn ws_callback(
     req: &http::Request<()>,
     mut resp: http::Response<()>,
 ) -> Result<http::Response<()>, ErrorResponse> {
-    if let Some(protocol) = req.headers().get("Sec-WebSocket-Protocol") {
-        // see: [MQTT-6.0.0-3]
-        if protocol != "mqtt" && protocol != "mqttv3.1" {
-            log::info!("invalid WebSocket subprotocol name: {:?}", protocol);
-            return Err(http::Response::new(Some(
-                "invalid WebSocket subprotocol name".to_string(),
-            )));
+    select_subprotocol(req, &mut resp)?;
+    Ok(resp)
+}
+
+// Pick the first MQTT subprotocol offered by the client.
+fn select_subprotocol(
+    req: &http::Request<()>,
+    resp: &mut http::Response<()>,
+) -> Result<Option<&'static str>, ErrorResponse> {
+    let Some(protocols) = req.headers().get(header::SEC_WEBSOCKET_PROTOCOL) else {
+        return Ok(None);
+    };
+    let protocol = protocols.to_str().ok().and_then(|protocols| {
+        protocols
+            .split(',')
+            .map(str::trim)
+            .find_map(|offered| SUBPROTOCOLS.into_iter().find(|p| *p == offered))
+    });
+    match protocol {
+        Some(protocol) => {
+            resp.headers_mut().insert(
+                header::SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(protocol),
+            );
+            Ok(Some(protocol))
+        }
+        None => {
+            log::info!("invalid WebSocket subprotocol name: {:?}", protocols);
+            Err(error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid WebSocket subprotocol name",
+            ))
         }
-        resp.headers_mut()
-            .insert("Sec-WebSocket-Protocol", protocol.clone());
     }
-    Ok(resp)
+}
+
+fn error_response(status: StatusCode, reason: &str) -> ErrorResponse {
+    let mut resp = http::Response::new(Some(reason.to_string()));
+    *resp.status_mut() = status;
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use async_tungstenite::tokio::client_async;
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpStream,
+    };
+
+    use crate::store::memory::queue::MemoryQueue;
+
+    use super::*;
+
+    async fn free_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    async fn ws_server<F>(configure: F) -> (SocketAddr, Arc<WsServer<MemoryQueue>>)
+    where
+        F: FnOnce(&mut WsServer<MemoryQueue>),
+    {
+        let addr = free_addr().await;
+        let global = Arc::new(GlobalState::new(MemoryQueue::new(10, 10)));
+        let mut server = WsServer::bind(addr, global).await.unwrap();
+        configure(&mut server);
+        let server = Arc::new(server);
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await }
+        });
+        (addr, server)
+    }
+
+    // Sends `request` and reads until the broker closes the connection.
+    async fn exchange(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut resp = Vec::new();
+        time::timeout(Duration::from_secs(1), stream.read_to_end(&mut resp))
+            .await
+            .unwrap()
+            .unwrap();
+        String::from_utf8(resp).unwrap()
+    }
+
+    #[cfg(feature = "ws")]
+    #[tokio::test]
+    async fn test_handshake_failures() {
+        let (addr, server) = ws_server(|server| {
+            server.set_handshake_timeout(Duration::from_millis(100));
+        })
+        .await;
+
+        // Neither a request which is not an upgrade nor a silent client stops
+        // the listener.
+        exchange(addr, b"GET / HTTP/1.1\r\nHost: broker\r\n\r\n").await;
+        exchange(addr, b"").await;
+        // Counted once the connection is closed
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.handshake_failures(), 2);
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(client_async(format!("ws://{addr}/mqtt"), stream)
+            .await
+            .is_ok());
+    }
+
+    fn upgrade_request(path: &str, headers: &[(&str, &str)]) -> http::Request<()> {
+        let mut builder = http::Request::builder().uri(path);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn check(
+        policy: &UpgradePolicy,
+        req: &http::Request<()>,
+    ) -> Result<(http::Response<()>, ConnectionInfo), StatusCode> {
+        let mut connection = ConnectionInfo::new(Transport::WebSocket, None);
+        match policy.check(req, http::Response::new(()), &mut connection) {
+            Ok(resp) => Ok((resp, connection)),
+            Err(resp) => Err(resp.status()),
+        }
+    }
+
+    #[test]
+    fn test_upgrade_policy() {
+        let policy = UpgradePolicy {
+            paths: vec!["/mqtt".to_owned()],
+            allowed_origins: vec!["https://app.example.com".to_owned()],
+        };
+        let origin = ("origin", "https://APP.example.com");
+        let cases = [
+            (upgrade_request("/mqtt", &[origin]), Ok(())),
+            (upgrade_request("/mqtt?token=1", &[origin]), Ok(())),
+            (
+                upgrade_request("/other", &[origin]),
+                Err(StatusCode::NOT_FOUND),
+            ),
+            (upgrade_request("/mqtt", &[]), Err(StatusCode::FORBIDDEN)),
+            (
+                upgrade_request("/mqtt", &[("origin", "https://evil.example.com")]),
+                Err(StatusCode::FORBIDDEN),
+            ),
+        ];
+        for (req, expected) in cases {
+            assert_eq!(check(&policy, &req).map(|_| ()), expected, "{req:?}");
+        }
+
+        // Anything goes without paths nor origins.
+        let req = upgrade_request("/any", &[]);
+        assert!(check(&UpgradePolicy::default(), &req).is_ok());
+    }
+
+    #[test]
+    fn test_upgrade_headers() {
+        let req = upgrade_request(
+            "/mqtt",
+            &[
+                ("origin", "https://app.example.com"),
+                ("authorization", "Bearer token"),
+                ("cookie", "session=1"),
+            ],
+        );
+        let (_, connection) = check(&UpgradePolicy::default(), &req).unwrap();
+        let websocket = connection.websocket().unwrap();
+        assert_eq!(websocket.path, "/mqtt");
+        assert_eq!(websocket.origin.as_deref(), Some("https://app.example.com"));
+        assert_eq!(websocket.authorization.as_deref(), Some("Bearer token"));
+        assert_eq!(websocket.cookie.as_deref(), Some("session=1"));
+        assert_eq!(websocket.subprotocol, None);
+    }
+
+    #[test]
+    fn test_select_subprotocol() {
+        let cases = [
+            ("mqtt", Ok(Some("mqtt"))),
+            ("mqttv5", Ok(Some("mqttv5"))),
+            ("wamp, mqttv3.1, mqtt", Ok(Some("mqttv3.1"))),
+            ("wamp", Err(StatusCode::BAD_REQUEST)),
+        ];
+        for (offered, expected) in cases {
+            let req = upgrade_request("/", &[("sec-websocket-protocol", offered)]);
+            let mut resp = http::Response::new(());
+            let selected = select_subprotocol(&req, &mut resp).map_err(|resp| resp.status());
+            assert_eq!(selected, expected, "{offered}");
+            let header = resp.headers().get(header::SEC_WEBSOCKET_PROTOCOL);
+            assert_eq!(
+                header.and_then(|value| value.to_str().ok()),
+                expected.ok().flatten()
+            );
+        }
+
+        let mut resp = http::Response::new(());
+        let req = upgrade_request("/", &[]);
+        assert_eq!(select_subprotocol(&req, &mut resp).ok(), Some(None));
+    }
+
+    #[cfg(feature = "ws")]
+    #[tokio::test]
+    async fn test_default_http_routes() {
+        let (addr, _server) = ws_server(|server| {
+            server.set_name("public");
+            server.add_default_http_routes();
+        })
+        .await;
+        let get = |path: &str| format!("GET {path} HTTP/1.1\r\nHost: broker\r\n\r\n");
+
+        let resp = exchange(addr, get("/health").as_bytes()).await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        assert!(resp.ends_with("\r\n\r\nok"), "{resp}");
+
+        let resp = exchange(addr, get("/metrics").as_bytes()).await;
+        assert!(resp.contains("\nmesquitte_clients 0\n"), "{resp}");
+        assert!(resp.contains("\nmesquitte_ws_handshake_failures_total{listener=\"public\"} 0\n"));
+
+        let resp = exchange(addr, get("/ready").as_bytes()).await;
+        assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"), "{resp}");
+
+        // Upgrades still reach the broker.
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(client_async(format!("ws://{addr}/mqtt"), stream)
+            .await
+            .is_ok());
+    }
 }
@@ -87,7 +87,7 @@ where
                         return Poll::Ready(Some(Err(e)));
                     }
                     err => {
-                        return Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, err))));
+                        return Poll::Ready(Some(Err(io::Error::other(err))));
                     }
                 },
             }
@@ -208,7 +208,7 @@ where
                             return Poll::Ready(Err(e));
                         }
                         err => {
-                            return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                            return Poll::Ready(Err(io::Error::other(err)));
                         }
                     }
                 }
@@ -224,7 +224,7 @@ where
                             let e = io::Error::new(io::ErrorKind::NotConnected, "Already closed");
                             Poll::Ready(Err(e))
                         }
-                        err => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err))),
+                        err => Poll::Ready(Err(io::Error::other(err))),
                     }
                 } else {
                     this.state.write = WriteState::Ready;
@@ -253,7 +253,7 @@ where
                     return Poll::Ready(Err(e));
                 }
                 err => {
-                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                    return Poll::Ready(Err(io::Error::other(err)));
                 }
             }
         }
@@ -273,7 +273,7 @@ where
                         "Already closed",
                     )))
                 }
-                err => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err))),
+                err => return Poll::Ready(Err(io::Error::other(err))),
             }
         }
         Poll::Ready(Ok(()))
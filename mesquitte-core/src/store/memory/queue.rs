@@ -6,9 +6,14 @@ use parking_lot::Mutex;
 
 use crate::{
     store::queue::Queue,
-    types::publish::{get_unix_ts, IncomingPublishPacket, OutgoingPublishPacket},
+    types::publish::{get_unix_ts, IncomingPublishPacket, OutgoingPublishPacket, PublishMessage},
 };
 
+/// Inflight packets kept per client by [`MemoryQueue::default`].
+pub const DEFAULT_MAX_INFLIGHT: u16 = 1024;
+/// Seconds before [`MemoryQueue::default`] resends an unacknowledged packet.
+pub const DEFAULT_INFLIGHT_TIMEOUT: u64 = 30;
+
 pub struct MemoryQueue {
     max_inflight: u16,
     // The ack packet timeout, when reached resent the packet
@@ -36,6 +41,12 @@ impl MemoryQueue {
     }
 }
 
+impl Default for MemoryQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_INFLIGHT, DEFAULT_INFLIGHT_TIMEOUT)
+    }
+}
+
 impl Queue for MemoryQueue {
     type Error = ();
 
@@ -46,10 +57,12 @@ impl Queue for MemoryQueue {
         message: crate::types::publish::PublishMessage,
     ) -> Result<bool, Self::Error> {
         let mut incoming_packets = self.qos2_packets.lock();
-        let packets = incoming_packets
-            .entry(client_id.to_string())
-            .or_insert_with(VecDeque::new);
+        let packets = incoming_packets.entry(client_id.to_string()).or_default();
 
+        // Resent before its PUBREC reached the client, the first copy is released.
+        if packets.iter().any(|packet| packet.packet_id() == packet_id) {
+            return Ok(false);
+        }
         if packets.len() >= self.max_inflight.into() {
             log::error!(
                 "drop incoming packet {:?}, queue is full: {}",
@@ -70,9 +83,7 @@ impl Queue for MemoryQueue {
         message: crate::types::publish::PublishMessage,
     ) -> Result<bool, Self::Error> {
         let mut outgoing_packets = self.outgoing_packets.lock();
-        let packets = outgoing_packets
-            .entry(client_id.to_string())
-            .or_insert_with(VecDeque::new);
+        let packets = outgoing_packets.entry(client_id.to_string()).or_default();
 
         if packets.len() >= self.max_inflight.into() {
             log::error!(
@@ -147,6 +158,27 @@ impl Queue for MemoryQueue {
         }
     }
 
+    async fn pubrel(
+        &self,
+        client_id: &str,
+        target_pid: u16,
+    ) -> Result<Option<PublishMessage>, Self::Error> {
+        match self.qos2_packets.lock().get_mut(client_id) {
+            Some(queue) => {
+                let released = queue
+                    .iter()
+                    .position(|packet| packet.packet_id() == target_pid)
+                    .and_then(|pos| queue.remove(pos))
+                    .map(|packet| packet.message().to_owned());
+                if released.is_some() {
+                    Self::shrink_queue(queue);
+                }
+                Ok(released)
+            }
+            None => Ok(None),
+        }
+    }
+
     async fn clean_incoming(&self, client_id: &str) -> Result<(), Self::Error> {
         if let Some(queue) = self.qos2_packets.lock().get_mut(client_id) {
             let mut changed = false;
@@ -234,3 +266,52 @@ impl Queue for MemoryQueue {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use mqtt_codec_kit::common::TopicName;
+
+    use super::*;
+
+    fn message(payload: &'static str) -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new("t").unwrap(),
+            Bytes::from(payload),
+            QualityOfService::Level2,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_qos2_resent_packet() {
+        let queue = MemoryQueue::new(2, 10);
+        assert!(!queue
+            .push_qos2_back("c", 1, message("first"))
+            .await
+            .unwrap());
+        assert!(!queue
+            .push_qos2_back("c", 1, message("resent"))
+            .await
+            .unwrap());
+        assert!(!queue
+            .push_qos2_back("c", 2, message("second"))
+            .await
+            .unwrap());
+        // Only new packets count against the limit.
+        assert!(queue
+            .push_qos2_back("c", 3, message("third"))
+            .await
+            .unwrap());
+        assert!(!queue
+            .push_qos2_back("c", 2, message("second"))
+            .await
+            .unwrap());
+
+        let released = queue.pubrel("c", 1).await.unwrap().unwrap();
+        assert_eq!(released.payload(), "first");
+        assert!(queue.pubrel("c", 1).await.unwrap().is_none());
+        assert!(queue.pubrel("c", 2).await.unwrap().is_some());
+        assert!(queue.pubrel("c", 3).await.unwrap().is_none());
+    }
+}
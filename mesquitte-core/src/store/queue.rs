@@ -5,16 +5,16 @@ use mqtt_codec_kit::common::QualityOfService;
 use crate::types::publish::{IncomingPublishPacket, OutgoingPublishPacket, PublishMessage};
 
 pub trait Queue: Sized + Send + Sync {
-    type Error: Debug;
+    type Error: Debug + Send;
 
     /// Push a incoming packet into queue, return if the queue is full.
-    /// only QoS2
+    /// only QoS2, a packet id already in the queue is kept once.
     fn push_qos2_back(
         &self,
         client_id: &str,
         packet_id: u16,
         message: PublishMessage,
-    ) -> impl Future<Output = Result<bool, Self::Error>>;
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
 
     /// Push a outgoing packet into queue, return if the queue is full.
     fn push_outgoing_back(
@@ -23,39 +23,52 @@ pub trait Queue: Sized + Send + Sync {
         packet_id: u16,
         subscribe_qos: QualityOfService,
         message: PublishMessage,
-    ) -> impl Future<Output = Result<bool, Self::Error>>;
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
 
     fn pubrec(
         &self,
         client_id: &str,
         target_pid: u16,
-    ) -> impl Future<Output = Result<bool, Self::Error>>;
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
 
     fn puback(
         &self,
         client_id: &str,
         target_pid: u16,
-    ) -> impl Future<Output = Result<bool, Self::Error>>;
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
 
     fn pubcomp(
         &self,
         client_id: &str,
         target_pid: u16,
-    ) -> impl Future<Output = Result<bool, Self::Error>>;
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
 
-    fn clean_incoming(&self, client_id: &str) -> impl Future<Output = Result<(), Self::Error>>;
+    /// Take the QoS2 incoming packet released by a PUBREL.
+    fn pubrel(
+        &self,
+        client_id: &str,
+        target_pid: u16,
+    ) -> impl Future<Output = Result<Option<PublishMessage>, Self::Error>> + Send;
+
+    fn clean_incoming(
+        &self,
+        client_id: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
-    fn clean_outgoing(&self, client_id: &str) -> impl Future<Output = Result<(), Self::Error>>;
+    fn clean_outgoing(
+        &self,
+        client_id: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
     fn get_ready_incoming_packets(
         &self,
         client_id: &str,
-    ) -> impl Future<Output = Result<Option<Vec<IncomingPublishPacket>>, Self::Error>>;
+    ) -> impl Future<Output = Result<Option<Vec<IncomingPublishPacket>>, Self::Error>> + Send;
 
     fn get_unsent_outgoing_packets(
         &self,
         client_id: &str,
-    ) -> impl Future<Output = Result<Option<Vec<OutgoingPublishPacket>>, Self::Error>>;
+    ) -> impl Future<Output = Result<Option<Vec<OutgoingPublishPacket>>, Self::Error>> + Send;
 
-    fn remove(&self, client_id: &str) -> impl Future<Output = Result<(), Self::Error>>;
+    fn remove(&self, client_id: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
 }
@@ -1,5 +1,6 @@
 use std::future::Future;
 
+use bytes::Bytes;
 use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
 // #[cfg(feature = "v5")]
 use mqtt_codec_kit::v5::control::PublishProperties;
@@ -9,12 +10,34 @@ pub struct RetainContent {
     // the publisher client id
     client_id: String,
     topic_name: TopicName,
-    payload: Vec<u8>,
+    payload: Bytes,
     // #[cfg(feature = "v5")]
     properties: Option<PublishProperties>,
     qos: QualityOfService,
 }
 
+impl RetainContent {
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn topic_name(&self) -> &TopicName {
+        &self.topic_name
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn properties(&self) -> Option<&PublishProperties> {
+        self.properties.as_ref()
+    }
+
+    pub fn qos(&self) -> QualityOfService {
+        self.qos
+    }
+}
+
 pub trait Retain {
     type Error;
 
@@ -16,6 +16,20 @@ pub struct RouteContent {
     shared_clients: Option<HashMap<String, RouteOptions>>,
 }
 
+impl RouteContent {
+    pub fn topic_filter(&self) -> &TopicFilter {
+        &self.topic_filter
+    }
+
+    pub fn clients(&self) -> &HashMap<String, RouteOptions> {
+        &self.clients
+    }
+
+    pub fn shared_clients(&self) -> Option<&HashMap<String, RouteOptions>> {
+        self.shared_clients.as_ref()
+    }
+}
+
 pub trait Router {
     type Error;
 
@@ -37,5 +51,5 @@ pub trait Router {
         topic_filter: &TopicFilter,
     ) -> impl Future<Output = Result<bool, Self::Error>>;
 
-    async fn remove_client(&self, client_id: &str);
+    fn remove_client(&self, client_id: &str) -> impl Future<Output = ()>;
 }
@@ -0,0 +1,253 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+
+use crate::server::limits::LimitExceeded;
+
+/// The transport a client connected over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Tls,
+    WebSocket,
+    WebSocketTls,
+    Quic,
+    Unix,
+}
+
+/// Credentials of the process on the other end of a Unix domain socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    uid: u32,
+    gid: u32,
+    pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    pub fn new(uid: u32, gid: u32, pid: Option<i32>) -> Self {
+        Self { uid, gid, pid }
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+}
+
+/// TLS details reported by a proxy that terminated TLS in front of the broker.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyTlsInfo {
+    pub version: Option<String>,
+    pub cipher: Option<String>,
+    pub common_name: Option<String>,
+    /// The client presented a certificate on this connection or session.
+    pub client_cert: bool,
+    /// The proxy verified the client certificate successfully.
+    pub verified: bool,
+}
+
+/// TLS details of a connection terminated by the broker.
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    pub server_name: Option<String>,
+    pub alpn: Option<Vec<u8>>,
+    /// DER encoded certificate chain presented by the client, end-entity first.
+    pub peer_certificates: Vec<Vec<u8>>,
+    /// Subject common name of the client certificate.
+    pub common_name: Option<String>,
+    /// DNS and URI subject alternative names of the client certificate.
+    pub subject_alt_names: Vec<String>,
+}
+
+/// Information recovered from a PROXY protocol header.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyInfo {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+    pub authority: Option<String>,
+    pub alpn: Option<Vec<u8>>,
+    pub tls: Option<ProxyTlsInfo>,
+}
+
+/// Details of the HTTP upgrade request of a WebSocket connection. Browsers
+/// cannot set MQTT credentials themselves, so cookies or bearer tokens sent
+/// with the upgrade are kept for authentication.
+#[derive(Debug, Clone, Default)]
+pub struct WebSocketInfo {
+    pub path: String,
+    pub subprotocol: Option<String>,
+    pub origin: Option<String>,
+    pub authorization: Option<String>,
+    pub cookie: Option<String>,
+}
+
+/// Network path of a QUIC connection. Clients may migrate to a new address,
+/// e.g. when switching networks, without reconnecting the MQTT session.
+#[derive(Debug, Default)]
+pub struct QuicPath {
+    remote_addr: Mutex<Option<SocketAddr>>,
+    migrations: AtomicU64,
+}
+
+impl QuicPath {
+    /// Current address of the client.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        *self.remote_addr.lock()
+    }
+
+    /// Number of times the client moved to another address.
+    pub fn migrations(&self) -> u64 {
+        self.migrations.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn migrate(&self, remote_addr: SocketAddr) {
+        let mut current = self.remote_addr.lock();
+        if current.is_some_and(|addr| addr != remote_addr) {
+            self.migrations.fetch_add(1, Ordering::Relaxed);
+        }
+        *current = Some(remote_addr);
+    }
+}
+
+/// Transport level information about a client connection, available to
+/// authentication when the CONNECT packet is handled.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    transport: Transport,
+    listener: Option<Arc<str>>,
+    remote_addr: Option<SocketAddr>,
+    peer_credentials: Option<PeerCredentials>,
+    proxy: Option<ProxyInfo>,
+    tls: Option<TlsInfo>,
+    quic_path: Option<Arc<QuicPath>>,
+    websocket: Option<WebSocketInfo>,
+    limit_exceeded: Option<LimitExceeded>,
+    // identity taken from the client certificate, overrides the CONNECT packet
+    cert_client_id: Option<String>,
+    cert_username: Option<String>,
+    cert_identity_missing: bool,
+}
+
+impl ConnectionInfo {
+    pub fn new(transport: Transport, listener: Option<Arc<str>>) -> Self {
+        Self {
+            transport,
+            listener,
+            ..Default::default()
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Name of the listener which accepted the connection.
+    pub fn listener(&self) -> Option<&str> {
+        self.listener.as_deref()
+    }
+
+    /// Address of the client, recovered from the PROXY protocol header when
+    /// the listener sits behind a load balancer.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn set_remote_addr(&mut self, remote_addr: SocketAddr) {
+        self.remote_addr = Some(remote_addr);
+    }
+
+    pub fn peer_credentials(&self) -> Option<&PeerCredentials> {
+        self.peer_credentials.as_ref()
+    }
+
+    pub fn set_peer_credentials(&mut self, peer_credentials: PeerCredentials) {
+        self.peer_credentials = Some(peer_credentials);
+    }
+
+    pub fn proxy(&self) -> Option<&ProxyInfo> {
+        self.proxy.as_ref()
+    }
+
+    pub fn set_proxy(&mut self, proxy: ProxyInfo) {
+        if let Some(source) = proxy.source {
+            self.remote_addr = Some(source);
+        }
+        self.proxy = Some(proxy);
+    }
+
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+
+    pub fn set_tls(&mut self, tls: TlsInfo) {
+        self.tls = Some(tls);
+    }
+
+    /// Live path of a QUIC connection, `remote_addr` is where the client
+    /// connected from.
+    pub fn quic_path(&self) -> Option<&Arc<QuicPath>> {
+        self.quic_path.as_ref()
+    }
+
+    pub fn set_quic_path(&mut self, quic_path: Arc<QuicPath>) {
+        self.quic_path = Some(quic_path);
+    }
+
+    pub fn websocket(&self) -> Option<&WebSocketInfo> {
+        self.websocket.as_ref()
+    }
+
+    pub fn set_websocket(&mut self, websocket: WebSocketInfo) {
+        self.websocket = Some(websocket);
+    }
+
+    /// Set when the connection was accepted over a connection limit, its
+    /// CONNECT is refused.
+    pub fn limit_exceeded(&self) -> Option<LimitExceeded> {
+        self.limit_exceeded
+    }
+
+    pub fn set_limit_exceeded(&mut self, limit_exceeded: LimitExceeded) {
+        self.limit_exceeded = Some(limit_exceeded);
+    }
+
+    pub fn cert_client_id(&self) -> Option<&str> {
+        self.cert_client_id.as_deref()
+    }
+
+    pub fn set_cert_client_id(&mut self, client_id: Option<String>) {
+        self.cert_client_id = client_id;
+    }
+
+    pub fn cert_username(&self) -> Option<&str> {
+        self.cert_username.as_deref()
+    }
+
+    pub fn set_cert_username(&mut self, username: Option<String>) {
+        self.cert_username = username;
+    }
+
+    /// Set when the client certificate lacks a field configured as its client
+    /// id or username, its CONNECT is refused.
+    pub fn cert_identity_missing(&self) -> bool {
+        self.cert_identity_missing
+    }
+
+    pub fn set_cert_identity_missing(&mut self) {
+        self.cert_identity_missing = true;
+    }
+}
@@ -1,4 +1,5 @@
 pub mod client;
+pub mod connection;
 pub mod outgoing;
 pub mod publish;
 pub mod retain_content;
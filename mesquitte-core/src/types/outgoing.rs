@@ -8,12 +8,15 @@ use super::publish::PublishMessage;
 #[derive(PartialEq)]
 pub enum KickReason {
     FromAdmin,
+    /// The client connected to another node of the cluster.
+    Takeover,
 }
 
 impl Display for KickReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             KickReason::FromAdmin => write!(f, "kicked by admin"),
+            KickReason::Takeover => write!(f, "taken over on another node"),
         }
     }
 }
@@ -1,16 +1,20 @@
 use std::cmp;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::time::SystemTime;
 
-use mqtt_codec_kit::common::{QualityOfService, TopicName};
+use bytes::Bytes;
+use mqtt_codec_kit::common::{
+    qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName,
+};
 // #[cfg(feature = "v4")]
 use mqtt_codec_kit::v4::{
-    packet::connect::LastWill as V4LastWill, packet::PublishPacket as V4PublishPacket,
+    packet::connect::LastWill as V4LastWill, packet::PublishFrame as V4PublishFrame,
+    packet::PublishPacket as V4PublishPacket,
 };
 // #[cfg(feature = "v5")]
 use mqtt_codec_kit::v5::{
     control::PublishProperties, packet::connect::LastWill as V5LastWill,
-    packet::PublishPacket as V5PublishPacket,
+    packet::PublishFrame as V5PublishFrame, packet::PublishPacket as V5PublishPacket,
 };
 
 use super::retain_content::RetainContent;
@@ -18,19 +22,46 @@ use super::retain_content::RetainContent;
 #[derive(Debug, Clone)]
 pub struct PublishMessage {
     topic_name: TopicName,
-    payload: Vec<u8>,
+    payload: Bytes,
     qos: QualityOfService,
     retain: bool,
     dup: bool,
     properties: Option<PublishProperties>,
+    shared_filter: Option<TopicFilter>,
+    bridged_by: Option<Arc<str>>,
+    forwarded_from: Option<Arc<str>>,
+    frames: Arc<PublishFrames>,
+}
+
+// Encoded on the first delivery to a client of each protocol version, then shared by all
+// clones of the message.
+#[derive(Debug, Default)]
+struct PublishFrames {
+    v4: OnceLock<V4PublishFrame>,
+    v5: OnceLock<V5PublishFrame>,
 }
 
 impl PublishMessage {
+    pub fn new(topic_name: TopicName, payload: Bytes, qos: QualityOfService, retain: bool) -> Self {
+        Self {
+            topic_name,
+            payload,
+            qos,
+            retain,
+            dup: false,
+            properties: None,
+            shared_filter: None,
+            bridged_by: None,
+            forwarded_from: None,
+            frames: Default::default(),
+        }
+    }
+
     pub fn topic_name(&self) -> &TopicName {
         &self.topic_name
     }
 
-    pub fn payload(&self) -> &[u8] {
+    pub fn payload(&self) -> &Bytes {
         &self.payload
     }
 
@@ -46,6 +77,10 @@ impl PublishMessage {
         self.dup = true
     }
 
+    pub fn clear_dup(&mut self) {
+        self.dup = false
+    }
+
     pub fn retain(&self) -> bool {
         self.retain
     }
@@ -53,52 +88,139 @@ impl PublishMessage {
     pub fn properties(&self) -> Option<&PublishProperties> {
         self.properties.as_ref()
     }
+
+    /// Shared subscription, as `$share/{group}/{filter}`, this copy of the message is
+    /// delivered through.
+    pub fn shared_filter(&self) -> Option<&TopicFilter> {
+        self.shared_filter.as_ref()
+    }
+
+    pub fn set_shared_filter(&mut self, shared_filter: Option<TopicFilter>) {
+        self.shared_filter = shared_filter;
+    }
+
+    /// Local client of the bridge this message came in through, it is not sent back to
+    /// that bridge.
+    pub fn bridged_by(&self) -> Option<&str> {
+        self.bridged_by.as_deref()
+    }
+
+    pub fn set_bridged_by(&mut self, bridged_by: Option<Arc<str>>) {
+        self.bridged_by = bridged_by;
+    }
+
+    /// Node of the cluster this message was published on, it is not forwarded to the
+    /// other nodes again.
+    pub fn forwarded_from(&self) -> Option<&str> {
+        self.forwarded_from.as_deref()
+    }
+
+    pub fn set_forwarded_from(&mut self, node_id: Option<Arc<str>>) {
+        self.forwarded_from = node_id;
+    }
+
+    pub fn set_topic_name(&mut self, topic_name: TopicName) {
+        self.topic_name = topic_name;
+        self.clear_frames();
+    }
+
+    pub fn set_payload(&mut self, payload: Bytes) {
+        self.payload = payload;
+        self.clear_frames();
+    }
+
+    pub fn set_qos(&mut self, qos: QualityOfService) {
+        self.qos = qos;
+    }
+
+    pub fn set_retain(&mut self, retain: bool) {
+        self.retain = retain;
+    }
+
+    pub fn set_properties(&mut self, properties: Option<PublishProperties>) {
+        self.properties = properties;
+        self.clear_frames();
+    }
+
+    // The frames of a modified message must be encoded again, clones of the message
+    // keep the former ones.
+    fn clear_frames(&mut self) {
+        self.frames = Default::default();
+    }
+
+    /// Encoded v3.1/v3.1.1 `PUBLISH` frame without any flags or packet identifier
+    pub fn v4_frame(&self) -> &V4PublishFrame {
+        self.frames.v4.get_or_init(|| {
+            V4PublishFrame::new(
+                &self.topic_name,
+                QoSWithPacketIdentifier::Level0,
+                self.payload.clone(),
+            )
+        })
+    }
+
+    /// Encoded v5 `PUBLISH` frame without any flags or packet identifier
+    pub fn v5_frame(&self) -> &V5PublishFrame {
+        self.frames.v5.get_or_init(|| {
+            V5PublishFrame::new(
+                &self.topic_name,
+                QoSWithPacketIdentifier::Level0,
+                self.properties
+                    .as_ref()
+                    .unwrap_or(&PublishProperties::default()),
+                self.payload.clone(),
+            )
+        })
+    }
 }
 
 impl From<V4PublishPacket> for PublishMessage {
     fn from(packet: V4PublishPacket) -> Self {
-        let mut payload = vec![0u8; packet.payload().len()];
-        payload.copy_from_slice(packet.payload());
-
         Self {
             topic_name: packet.topic_name().to_owned(),
-            payload,
+            payload: packet.payload().clone(),
             qos: packet.qos().into(),
             retain: packet.retain(),
             dup: packet.dup(),
             properties: None,
+            shared_filter: None,
+            bridged_by: None,
+            forwarded_from: None,
+            frames: Default::default(),
         }
     }
 }
 
 impl From<V5PublishPacket> for PublishMessage {
     fn from(packet: V5PublishPacket) -> Self {
-        let mut payload = vec![0u8; packet.payload().len()];
-        payload.copy_from_slice(packet.payload());
-
         Self {
             topic_name: packet.topic_name().to_owned(),
-            payload,
+            payload: packet.payload().clone(),
             qos: packet.qos().into(),
             retain: packet.retain(),
             dup: packet.dup(),
             properties: Some(packet.properties().to_owned()),
+            shared_filter: None,
+            bridged_by: None,
+            forwarded_from: None,
+            frames: Default::default(),
         }
     }
 }
 
 impl From<Arc<RetainContent>> for PublishMessage {
     fn from(packet: Arc<RetainContent>) -> Self {
-        let mut payload = vec![0u8; packet.payload().len()];
-        payload.copy_from_slice(packet.payload());
-
         Self {
             topic_name: packet.topic_name().to_owned(),
-            payload,
+            payload: packet.payload().clone(),
             qos: packet.qos().to_owned(),
             retain: false,
             dup: false,
             properties: packet.properties().cloned(),
+            shared_filter: None,
+            bridged_by: None,
+            forwarded_from: None,
+            frames: Default::default(),
         }
     }
 }
@@ -106,8 +228,7 @@ impl From<Arc<RetainContent>> for PublishMessage {
 // #[cfg(feature = "v4")]
 impl From<V4LastWill> for PublishMessage {
     fn from(value: V4LastWill) -> Self {
-        let mut payload = vec![0u8; value.message().0.len()];
-        payload.copy_from_slice(&value.message().0);
+        let payload = Bytes::copy_from_slice(&value.message().0);
 
         Self {
             topic_name: value.topic().to_owned(),
@@ -116,6 +237,10 @@ impl From<V4LastWill> for PublishMessage {
             retain: value.retain(),
             properties: None,
             dup: false,
+            shared_filter: None,
+            bridged_by: None,
+            forwarded_from: None,
+            frames: Default::default(),
         }
     }
 }
@@ -123,8 +248,7 @@ impl From<V4LastWill> for PublishMessage {
 // #[cfg(feature = "v5")]
 impl From<V5LastWill> for PublishMessage {
     fn from(value: V5LastWill) -> Self {
-        let mut payload = vec![0u8; value.message().0.len()];
-        payload.copy_from_slice(&value.message().0);
+        let payload = Bytes::copy_from_slice(&value.message().0);
 
         let mut publish_properties = PublishProperties::default();
         let properties = value.properties();
@@ -144,6 +268,10 @@ impl From<V5LastWill> for PublishMessage {
             retain: value.retain(),
             dup: false,
             properties: Some(publish_properties),
+            shared_filter: None,
+            bridged_by: None,
+            forwarded_from: None,
+            frames: Default::default(),
         }
     }
 }
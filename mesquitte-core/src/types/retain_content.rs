@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use mqtt_codec_kit::common::{QualityOfService, TopicName};
 // #[cfg(feature = "v5")]
 use mqtt_codec_kit::v5::control::PublishProperties;
@@ -9,7 +10,7 @@ pub struct RetainContent {
     // the publisher client id
     client_id: String,
     topic_name: TopicName,
-    payload: Vec<u8>,
+    payload: Bytes,
     // #[cfg(feature = "v5")]
     properties: Option<PublishProperties>,
     qos: QualityOfService,
@@ -20,8 +21,8 @@ impl RetainContent {
         &self.topic_name
     }
 
-    pub fn payload(&self) -> &[u8] {
-        self.payload.as_ref()
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
     }
 
     pub fn properties(&self) -> Option<&PublishProperties> {
@@ -45,7 +46,7 @@ where
         Self {
             client_id: client_id.into(),
             topic_name: packet.topic_name().clone(),
-            payload: packet.payload().into(),
+            payload: packet.payload().clone(),
             qos: packet.qos(),
             properties: packet.properties().map(|p| p.to_owned()),
         }
@@ -1,9 +1,17 @@
-use hashbrown::HashSet;
-use mqtt_codec_kit::common::TopicFilter;
+use std::time::Duration;
+
+use hashbrown::{HashMap, HashSet};
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter};
 use mqtt_codec_kit::v4::packet::connect::LastWill as V4LastWill;
 use mqtt_codec_kit::v5::packet::connect::LastWill as V5LastWill;
 use tokio::time::Instant;
 
+use crate::server::limits::PublishQuota;
+
+use super::{connection::ConnectionInfo, publish::PublishMessage};
+
+/// Largest packet the protocol can encode: a 5 bytes fixed header and the maximum
+/// remaining length.
 pub const DEFAULT_MAX_PACKET_SIZE: u32 = 5 + 268_435_455;
 
 #[derive(Debug, Clone)]
@@ -21,10 +29,15 @@ pub struct Session {
 
     client_id: String,
     username: Option<String>,
+    connection: ConnectionInfo,
     keep_alive: u16,
     clean_session: bool,
     last_will: Option<LastWill>,
     subscriptions: HashSet<TopicFilter, ahash::RandomState>,
+    publish_quota: PublishQuota,
+    // Messages of shared subscriptions sent with QoS 1 or 2 and not acknowledged yet,
+    // with when and with which QoS they were sent.
+    shared_inflight: HashMap<u16, (Instant, QualityOfService, PublishMessage), ahash::RandomState>,
 
     authorized: bool,
     assigned_client_id: bool,
@@ -63,10 +76,13 @@ impl Session {
             client_id,
             assigned_client_id,
             username: None,
+            connection: ConnectionInfo::default(),
             keep_alive: 0,
             clean_session: true,
             last_will: None,
             subscriptions: HashSet::with_hasher(ahash::RandomState::new()),
+            publish_quota: PublishQuota::default(),
+            shared_inflight: HashMap::with_hasher(ahash::RandomState::new()),
 
             authorized: false,
             client_disconnected: false,
@@ -105,6 +121,59 @@ impl Session {
         self.username = username
     }
 
+    pub fn connection(&self) -> &ConnectionInfo {
+        &self.connection
+    }
+
+    pub fn set_connection(&mut self, connection: ConnectionInfo) {
+        self.connection = connection
+    }
+
+    pub(crate) fn publish_quota(&mut self) -> &mut PublishQuota {
+        &mut self.publish_quota
+    }
+
+    pub(crate) fn set_publish_quota(&mut self, publish_quota: PublishQuota) {
+        self.publish_quota = publish_quota
+    }
+
+    /// Starts the acknowledgement timeout of a message of a shared subscription.
+    pub(crate) fn track_shared(
+        &mut self,
+        packet_id: u16,
+        qos: QualityOfService,
+        message: PublishMessage,
+    ) {
+        self.shared_inflight
+            .insert(packet_id, (Instant::now(), qos, message));
+    }
+
+    pub(crate) fn ack_shared(&mut self, packet_id: u16) -> bool {
+        self.shared_inflight.remove(&packet_id).is_some()
+    }
+
+    /// Takes the messages of shared subscriptions not acknowledged within `timeout`.
+    pub(crate) fn take_expired_shared(
+        &mut self,
+        timeout: Duration,
+    ) -> Vec<(u16, QualityOfService, PublishMessage)> {
+        let now = Instant::now();
+        let expired: Vec<u16> = self
+            .shared_inflight
+            .iter()
+            .filter(|(_, (sent_at, _, _))| now.duration_since(*sent_at) >= timeout)
+            .map(|(packet_id, _)| *packet_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|packet_id| {
+                self.shared_inflight
+                    .remove(&packet_id)
+                    .map(|(_, qos, message)| (packet_id, qos, message))
+            })
+            .collect()
+    }
+
     pub fn keep_alive(&self) -> u16 {
         self.keep_alive
     }
@@ -279,3 +348,50 @@ impl Session {
         self.authentication_method = Some(authentication_method.to_owned());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use mqtt_codec_kit::common::TopicName;
+    use tokio::time;
+
+    use super::*;
+
+    fn message(payload: &'static str) -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new("t").unwrap(),
+            Bytes::from(payload),
+            QualityOfService::Level1,
+            false,
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shared_ack_timeout() {
+        let timeout = Duration::from_secs(10);
+        let mut session = Session::new("member".to_owned(), false, 12);
+        session.track_shared(1, QualityOfService::Level1, message("first"));
+        time::advance(Duration::from_secs(5)).await;
+        session.track_shared(2, QualityOfService::Level2, message("second"));
+        session.track_shared(3, QualityOfService::Level1, message("acked"));
+        assert!(session.take_expired_shared(timeout).is_empty());
+
+        assert!(session.ack_shared(3));
+        assert!(!session.ack_shared(3));
+        time::advance(Duration::from_secs(5)).await;
+        let expired = session.take_expired_shared(timeout);
+        assert_eq!(expired.len(), 1);
+        let (packet_id, qos, message) = &expired[0];
+        assert_eq!(*packet_id, 1);
+        assert_eq!(*qos, QualityOfService::Level1);
+        assert_eq!(&message.payload()[..], b"first");
+        assert!(!session.ack_shared(1));
+
+        time::advance(Duration::from_secs(5)).await;
+        let expired = session.take_expired_shared(timeout);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, 2);
+        assert_eq!(expired[0].1, QualityOfService::Level2);
+        assert!(session.take_expired_shared(timeout).is_empty());
+    }
+}
@@ -1,30 +1,50 @@
-use std::{hash::Hash, ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use ahash::RandomState;
+use arc_swap::ArcSwap;
 use hashbrown::HashMap;
 use mqtt_codec_kit::common::{
     QualityOfService, TopicFilter, TopicName, MATCH_ALL_STR, MATCH_ONE_STR,
 };
-use parking_lot::RwLock;
+use parking_lot::Mutex;
+use rand::Rng as _;
 
 use super::retain_table::split_topic;
 
+/// Subscriptions by topic filter.
+///
+/// Lookups walk an immutable snapshot of the trie without taking any lock. Subscribe and
+/// unsubscribe are serialized and publish the next snapshot, which shares everything but
+/// the path of the topic filter with the previous one.
 #[derive(Default)]
 pub struct RouteTable {
-    nodes: RwLock<HashMap<String, RouteNode>>,
+    root: ArcSwap<RouteNode>,
+    levels: Mutex<Levels>,
 }
 
+/// Persistent map, a copy only duplicates the entries being changed.
+type PersistentMap<K, V> = im::HashMap<K, V, RandomState>;
+
+/// Topic level shared by every node and snapshot using it.
+type Level = Arc<str>;
+
+#[derive(Default, Clone)]
 struct RouteNode {
-    content: Arc<RwLock<RouteContent>>,
-    nodes: Arc<RwLock<HashMap<String, RouteNode>>>,
+    content: Option<Arc<RouteContent>>,
+    nodes: PersistentMap<Level, Arc<RouteNode>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct RouteContent {
-    /// Returned RouteContent always have topic_filter
-    pub topic_filter: Option<TopicFilter>,
-    pub clients: HashMap<String, QualityOfService>,
-    pub groups: HashMap<String, SharedClients>,
+    pub topic_filter: TopicFilter,
+    pub clients: PersistentMap<String, QualityOfService>,
+    pub groups: PersistentMap<String, SharedClients>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -32,22 +52,54 @@ pub struct SharedClients {
     hash_builder: RandomState,
     items: Vec<(String, QualityOfService)>,
     index: HashMap<String, usize>,
+    selection: Arc<SharedSelection>,
+}
+
+/// How the member of a shared subscription group receiving a message is picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SharedStrategy {
+    /// Members take turns.
+    #[default]
+    RoundRobin,
+    Random,
+    /// Messages from the same publisher go to the same member.
+    HashClientId,
+    /// Messages with the same topic name go to the same member.
+    HashTopic,
+    /// Messages go to the same member for as long as it is online.
+    Sticky,
+    /// Messages go to the member with the fewest messages waiting to be sent.
+    LeastInflight,
+}
+
+// Selection state, updated from snapshots and kept by the copies of the group made by
+// later subscribes.
+#[derive(Debug, Default)]
+struct SharedSelection {
+    next: AtomicUsize,
+    sticky: Mutex<Option<String>>,
+}
+
+// Interned topic levels with the number of nodes using them, only accessed by writers.
+#[derive(Default)]
+struct Levels {
+    interned: HashMap<Level, usize>,
 }
 
 impl RouteTable {
-    pub fn get_matches(&self, topic_name: &TopicName) -> Vec<Arc<RwLock<RouteContent>>> {
+    pub fn get_matches(&self, topic_name: &TopicName) -> Vec<Arc<RouteContent>> {
         let (topic_item, rest_items) = split_topic(topic_name);
         let mut filters = Vec::new();
 
-        let nodes = self.nodes.read();
-        if let Some(node) = nodes.get(topic_item) {
+        let root = self.root.load();
+        if let Some(node) = root.nodes.get(topic_item) {
             node.get_matches(topic_item, rest_items, &mut filters);
         }
         // [MQTT-4.7.2-1] The Server MUST NOT match Topic Filters starting with a
         // wildcard character (# or +) with Topic Names beginning with a $ character
         if !topic_name.starts_with('$') {
             for item in [MATCH_ALL_STR, MATCH_ONE_STR] {
-                if let Some(node) = nodes.get(item) {
+                if let Some(node) = root.nodes.get(item) {
                     node.get_matches(item, rest_items, &mut filters);
                 }
             }
@@ -55,6 +107,15 @@ impl RouteTable {
         filters
     }
 
+    /// Topic filters with at least one subscriber, sorted. Shared subscriptions are
+    /// listed by their filter without the group.
+    pub fn topic_filters(&self) -> Vec<TopicFilter> {
+        let mut filters = Vec::new();
+        self.root.load().collect_filters(&mut filters);
+        filters.sort_unstable();
+        filters
+    }
+
     pub fn subscribe(&self, topic_filter: &TopicFilter, id: &str, qos: QualityOfService) {
         if let Some((shared_group_name, shared_filter)) = topic_filter.shared_info() {
             self.subscribe_shared(
@@ -75,13 +136,17 @@ impl RouteTable {
         qos: QualityOfService,
         group: Option<String>,
     ) {
-        let (filter_item, rest_items) = split_topic(topic_filter);
-        // Since subscribe is not an frequent action, string clone here is acceptable.
-        self.nodes
-            .write()
-            .entry(filter_item.to_string())
-            .or_insert_with(RouteNode::new)
-            .insert(topic_filter, rest_items, id, qos, group);
+        let mut levels = self.levels.lock();
+        let mut root = RouteNode::clone(&self.root.load());
+        root.insert(
+            &mut levels,
+            topic_filter,
+            Some(topic_filter),
+            id,
+            qos,
+            group,
+        );
+        self.root.store(Arc::new(root));
     }
 
     pub fn unsubscribe(&self, topic_filter: &TopicFilter, id: &str) {
@@ -97,28 +162,26 @@ impl RouteTable {
     }
 
     fn unsubscribe_shared(&self, topic_filter: &TopicFilter, id: &str, group: Option<&str>) {
-        let (filter_item, rest_items) = split_topic(topic_filter.deref());
-        // bool variable is for resolve dead lock of access `self.nodes`
-        let mut remove_node = false;
-        let mut nodes = self.nodes.write();
-        if let Some(node) = nodes.get_mut(filter_item) {
-            remove_node = node.remove(rest_items, id, group);
-        }
-        if remove_node {
-            nodes.remove(filter_item);
-        }
+        let mut levels = self.levels.lock();
+        let mut root = RouteNode::clone(&self.root.load());
+        root.remove(&mut levels, Some(topic_filter.deref()), id, group);
+        self.root.store(Arc::new(root));
     }
 }
 
 impl RouteNode {
-    fn new() -> RouteNode {
-        RouteNode {
-            content: Arc::new(RwLock::new(RouteContent {
-                topic_filter: None,
-                clients: HashMap::new(),
-                groups: HashMap::new(),
-            })),
-            nodes: Arc::new(RwLock::new(HashMap::new())),
+    fn is_empty(&self) -> bool {
+        self.content.is_none() && self.nodes.is_empty()
+    }
+
+    fn collect_filters(&self, filters: &mut Vec<TopicFilter>) {
+        if let Some(content) = self.content.as_ref() {
+            if !content.is_empty() {
+                filters.push(content.topic_filter.clone());
+            }
+        }
+        for node in self.nodes.values() {
+            node.collect_filters(filters);
         }
     }
 
@@ -126,39 +189,40 @@ impl RouteNode {
         &self,
         prev_item: &str,
         topic_items: Option<&str>,
-        filters: &mut Vec<Arc<RwLock<RouteContent>>>,
+        filters: &mut Vec<Arc<RouteContent>>,
     ) {
         if prev_item == MATCH_ALL_STR {
-            if !self.content.read().is_empty() {
-                filters.push(Arc::clone(&self.content));
+            if let Some(content) = self.content.as_ref() {
+                filters.push(Arc::clone(content));
             }
         } else if let Some(topic_items) = topic_items {
-            let nodes = self.nodes.read();
             let (topic_item, rest_items) = split_topic(topic_items);
             for item in [topic_item, MATCH_ALL_STR, MATCH_ONE_STR] {
-                if let Some(node) = nodes.get(item) {
+                if let Some(node) = self.nodes.get(item) {
                     node.get_matches(item, rest_items, filters);
                 }
             }
         } else {
-            if !self.content.read().is_empty() {
-                filters.push(Arc::clone(&self.content));
+            if let Some(content) = self.content.as_ref() {
+                filters.push(Arc::clone(content));
             }
 
             // Topic name "abc" will match topic filter "abc/#", since "#" also represent parent level.
-            // NOTE: [locks]
-            //   * nodes read
-            //   * content read
-            if let Some(node) = self.nodes.read().get(MATCH_ALL_STR) {
-                if !node.content.read().is_empty() {
-                    filters.push(Arc::clone(&node.content));
-                }
+            if let Some(content) = self
+                .nodes
+                .get(MATCH_ALL_STR)
+                .and_then(|node| node.content.as_ref())
+            {
+                filters.push(Arc::clone(content));
             }
         }
     }
 
+    // Nodes and contents still shared with the current snapshot are copied before being
+    // changed.
     fn insert(
-        &self,
+        &mut self,
+        levels: &mut Levels,
         topic_filter: &TopicFilter,
         filter_items: Option<&str>,
         id: &str,
@@ -167,21 +231,26 @@ impl RouteNode {
     ) {
         if let Some(filter_items) = filter_items {
             let (filter_item, rest_items) = split_topic(filter_items);
-            self.nodes
-                .write()
-                .entry(filter_item.to_string())
-                .or_insert_with(RouteNode::new)
-                .insert(topic_filter, rest_items, id, qos, group);
-        } else {
-            let mut content = self.content.write();
-            if content.topic_filter.is_none() {
-                content.topic_filter = Some(topic_filter.clone());
+            if !self.nodes.contains_key(filter_item) {
+                self.nodes
+                    .insert(levels.intern(filter_item), Default::default());
             }
+            let node = self.nodes.get_mut(filter_item).expect("route node");
+            Arc::make_mut(node).insert(levels, topic_filter, rest_items, id, qos, group);
+        } else {
+            let content = self.content.get_or_insert_with(|| {
+                Arc::new(RouteContent {
+                    topic_filter: topic_filter.clone(),
+                    clients: Default::default(),
+                    groups: Default::default(),
+                })
+            });
+            let content = Arc::make_mut(content);
             if let Some(name) = group {
                 content
                     .groups
                     .entry(name)
-                    .or_insert_with(SharedClients::default)
+                    .or_default()
                     .insert((id.to_owned(), qos));
             } else {
                 content.clients.insert(id.to_owned(), qos);
@@ -189,31 +258,25 @@ impl RouteNode {
         }
     }
 
-    fn remove(&self, filter_items: Option<&str>, id: &str, group: Option<&str>) -> bool {
+    // Returns whether the node is left empty.
+    fn remove(
+        &mut self,
+        levels: &mut Levels,
+        filter_items: Option<&str>,
+        id: &str,
+        group: Option<&str>,
+    ) -> bool {
         if let Some(filter_items) = filter_items {
             let (filter_item, rest_items) = split_topic(filter_items);
-            // bool variables are for resolve dead lock of access `self.nodes`
-
-            // NOTE: [locks]
-            //   * nodes write
-            //   * content read
-            let mut nodes = self.nodes.write();
-            let mut remove_node = false;
-            if let Some(node) = nodes.get_mut(filter_item) {
-                if node.remove(rest_items, id, group) {
-                    remove_node = true;
+            if let Some(node) = self.nodes.get_mut(filter_item) {
+                if Arc::make_mut(node).remove(levels, rest_items, id, group) {
+                    if let Some((level, _)) = self.nodes.remove_with_key(filter_item) {
+                        levels.release(&level);
+                    }
                 }
             }
-            let remove_parent = if remove_node {
-                nodes.remove(filter_item);
-                // NOTE: careful lock order
-                self.content.read().is_empty() && nodes.is_empty()
-            } else {
-                false
-            };
-            return remove_parent;
-        } else {
-            let mut content = self.content.write();
+        } else if let Some(content) = self.content.as_mut() {
+            let content = Arc::make_mut(content);
             if let Some(name) = group {
                 if let Some(shared_clients) = content.groups.get_mut(name) {
                     shared_clients.remove(id);
@@ -225,13 +288,10 @@ impl RouteNode {
                 content.clients.remove(id);
             }
             if content.is_empty() {
-                content.topic_filter = None;
-                if self.nodes.read().is_empty() {
-                    return true;
-                }
+                self.content = None;
             }
         }
-        false
+        self.is_empty()
     }
 }
 
@@ -241,17 +301,81 @@ impl RouteContent {
     }
 }
 
-impl SharedClients {
-    pub fn get_by_hash<T: Hash>(&self, data: T) -> (String, QualityOfService) {
-        let number = self.hash_builder.hash_one(data);
-        self.get_by_number(number)
+impl Levels {
+    fn intern(&mut self, level: &str) -> Level {
+        if let Some((level, nodes)) = self.interned.get_key_value_mut(level) {
+            *nodes += 1;
+            return Arc::clone(level);
+        }
+        let level = Level::from(level);
+        self.interned.insert(Arc::clone(&level), 1);
+        level
+    }
+
+    fn release(&mut self, level: &str) {
+        if let Some(nodes) = self.interned.get_mut(level) {
+            *nodes -= 1;
+            if *nodes == 0 {
+                self.interned.remove(level);
+            }
+        }
     }
+}
 
-    pub fn get_by_number(&self, number: u64) -> (String, QualityOfService) {
+impl SharedClients {
+    /// Picks the member receiving a message published by `client_id` on `topic_name`.
+    /// `depth` gives the number of messages waiting to be sent to a member and `None` when
+    /// it is offline. The next online member replaces an offline one, if all of them are
+    /// offline the message still goes to the member picked by `strategy`.
+    pub fn select<F>(
+        &self,
+        strategy: SharedStrategy,
+        client_id: &str,
+        topic_name: &TopicName,
+        depth: F,
+    ) -> (String, QualityOfService)
+    where
+        F: Fn(&str) -> Option<usize>,
+    {
         // Empty SharedClients MUST already removed from parent data structure immediately.
         debug_assert!(!self.items.is_empty());
-        let idx = number as usize % self.items.len();
-        let (client_id, qos) = &self.items[idx];
+        let len = self.items.len();
+        let start = match strategy {
+            SharedStrategy::RoundRobin | SharedStrategy::LeastInflight => {
+                self.selection.next.fetch_add(1, Ordering::Relaxed)
+            }
+            SharedStrategy::Random => rand::thread_rng().gen_range(0..len),
+            SharedStrategy::HashClientId => self.hash_builder.hash_one(client_id) as usize,
+            SharedStrategy::HashTopic => self.hash_builder.hash_one(topic_name) as usize,
+            SharedStrategy::Sticky => self
+                .selection
+                .sticky
+                .lock()
+                .as_ref()
+                .and_then(|client_id| self.index.get(client_id).copied())
+                .unwrap_or_default(),
+        };
+
+        let mut candidates = (0..len)
+            .map(|offset| &self.items[(start + offset) % len])
+            .filter_map(|item| depth(&item.0).map(|depth| (depth, item)));
+        let selected = if strategy == SharedStrategy::LeastInflight {
+            // The first of equally loaded members, starting from a different one each time.
+            candidates.min_by(|(a, _), (b, _)| a.cmp(b))
+        } else {
+            candidates.next()
+        };
+        let (client_id, qos) = match selected {
+            Some((_, item)) => item,
+            None => &self.items[start % len],
+        };
+
+        if strategy == SharedStrategy::Sticky {
+            let mut sticky = self.selection.sticky.lock();
+            if sticky.as_deref() != Some(client_id.as_str()) {
+                *sticky = Some(client_id.to_owned());
+            }
+        }
         (client_id.to_owned(), qos.to_owned())
     }
 
@@ -270,16 +394,384 @@ impl SharedClients {
 
     fn remove(&mut self, item_key: &str) {
         if let Some(idx) = self.index.remove(item_key) {
-            if self.items.len() == 1 {
-                self.items.clear();
-            } else {
-                let last_client_id = self.items.last().expect("shared items").0.to_owned();
-                self.items.swap_remove(idx);
-                self.index.insert(last_client_id, idx);
-                if self.items.capacity() >= 16 && self.items.capacity() >= (self.items.len() << 2) {
-                    self.items.shrink_to(self.items.len() << 1);
-                }
+            self.items.swap_remove(idx);
+            // the last item moved into the hole, unless it was the removed one
+            if let Some((client_id, _)) = self.items.get(idx) {
+                self.index.insert(client_id.to_owned(), idx);
+            }
+            if self.items.capacity() >= 16 && self.items.capacity() >= (self.items.len() << 2) {
+                self.items.shrink_to(self.items.len() << 1);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn shared_clients(ids: &[&str]) -> SharedClients {
+        let mut shared_clients = SharedClients::default();
+        for id in ids {
+            shared_clients.insert((id.to_string(), QualityOfService::Level1));
+        }
+        shared_clients
+    }
+
+    fn select_id<F>(
+        shared_clients: &SharedClients,
+        strategy: SharedStrategy,
+        client_id: &str,
+        topic_name: &str,
+        depth: F,
+    ) -> String
+    where
+        F: Fn(&str) -> Option<usize>,
+    {
+        let topic_name = TopicName::new(topic_name).unwrap();
+        shared_clients
+            .select(strategy, client_id, &topic_name, depth)
+            .0
+    }
+
+    fn online(_: &str) -> Option<usize> {
+        Some(0)
+    }
+
+    #[test]
+    fn test_select_round_robin() {
+        let shared_clients = shared_clients(&["a", "b", "c"]);
+        let selected = (0..6)
+            .map(|_| {
+                select_id(
+                    &shared_clients,
+                    SharedStrategy::RoundRobin,
+                    "p",
+                    "t",
+                    online,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(selected, ["a", "b", "c", "a", "b", "c"]);
+
+        // offline members are skipped
+        let depth = |id: &str| (id != "b").then_some(0);
+        let selected = (0..3)
+            .map(|_| select_id(&shared_clients, SharedStrategy::RoundRobin, "p", "t", depth))
+            .collect::<Vec<_>>();
+        assert_eq!(selected, ["a", "c", "c"]);
+    }
+
+    #[test]
+    fn test_select_hash() {
+        let shared_clients = shared_clients(&["a", "b", "c"]);
+
+        let by_topic = select_id(
+            &shared_clients,
+            SharedStrategy::HashTopic,
+            "p0",
+            "t",
+            online,
+        );
+        for idx in 1..10 {
+            let publisher = format!("p{idx}");
+            let selected = select_id(
+                &shared_clients,
+                SharedStrategy::HashTopic,
+                &publisher,
+                "t",
+                online,
+            );
+            assert_eq!(selected, by_topic);
+        }
+
+        let by_client = select_id(
+            &shared_clients,
+            SharedStrategy::HashClientId,
+            "p",
+            "t0",
+            online,
+        );
+        for idx in 1..10 {
+            let topic_name = format!("t{idx}");
+            let selected = select_id(
+                &shared_clients,
+                SharedStrategy::HashClientId,
+                "p",
+                &topic_name,
+                online,
+            );
+            assert_eq!(selected, by_client);
+        }
+    }
+
+    #[test]
+    fn test_select_sticky() {
+        let shared_clients = shared_clients(&["a", "b", "c"]);
+        for _ in 0..3 {
+            assert_eq!(
+                select_id(&shared_clients, SharedStrategy::Sticky, "p", "t", online),
+                "a"
+            );
+        }
+
+        let depth = |id: &str| (id != "a").then_some(0);
+        for _ in 0..3 {
+            assert_eq!(
+                select_id(&shared_clients, SharedStrategy::Sticky, "p", "t", depth),
+                "b"
+            );
+        }
+
+        // the member that came back does not take over again
+        assert_eq!(
+            select_id(&shared_clients, SharedStrategy::Sticky, "p", "t", online),
+            "b"
+        );
+    }
+
+    #[test]
+    fn test_select_least_inflight() {
+        let shared_clients = shared_clients(&["a", "b", "c"]);
+        let depth = |id: &str| match id {
+            "a" => Some(5),
+            "b" => Some(1),
+            "c" => Some(3),
+            _ => None,
+        };
+        for _ in 0..3 {
+            assert_eq!(
+                select_id(
+                    &shared_clients,
+                    SharedStrategy::LeastInflight,
+                    "p",
+                    "t",
+                    depth
+                ),
+                "b"
+            );
+        }
+
+        let depth = |id: &str| if id == "b" { None } else { Some(2) };
+        let selected = (0..4)
+            .map(|_| {
+                select_id(
+                    &shared_clients,
+                    SharedStrategy::LeastInflight,
+                    "p",
+                    "t",
+                    depth,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert!(selected.iter().all(|id| id != "b"));
+        assert!(selected.iter().any(|id| id == "a"));
+        assert!(selected.iter().any(|id| id == "c"));
+    }
+
+    #[test]
+    fn test_select_all_offline() {
+        let shared_clients = shared_clients(&["a", "b", "c"]);
+        let offline = |_: &str| None;
+        let selected = (0..3)
+            .map(|_| {
+                select_id(
+                    &shared_clients,
+                    SharedStrategy::RoundRobin,
+                    "p",
+                    "t",
+                    offline,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(selected, ["a", "b", "c"]);
+
+        let by_topic = select_id(&shared_clients, SharedStrategy::HashTopic, "p", "t", online);
+        assert_eq!(
+            select_id(
+                &shared_clients,
+                SharedStrategy::HashTopic,
+                "p",
+                "t",
+                offline
+            ),
+            by_topic
+        );
+        assert_eq!(
+            select_id(&shared_clients, SharedStrategy::Sticky, "p", "t", offline),
+            "a"
+        );
+    }
+
+    fn matched_filters(table: &RouteTable, topic_name: &str) -> Vec<String> {
+        let mut filters = table
+            .get_matches(&TopicName::new(topic_name).unwrap())
+            .iter()
+            .map(|content| content.topic_filter.to_string())
+            .collect::<Vec<_>>();
+        filters.sort_unstable();
+        filters
+    }
+
+    fn subscribe(table: &RouteTable, topic_filter: &str, id: &str) {
+        table.subscribe(
+            &TopicFilter::new(topic_filter).unwrap(),
+            id,
+            QualityOfService::Level1,
+        );
+    }
+
+    fn unsubscribe(table: &RouteTable, topic_filter: &str, id: &str) {
+        table.unsubscribe(&TopicFilter::new(topic_filter).unwrap(), id);
+    }
+
+    #[test]
+    fn test_route_wildcards() {
+        let table = RouteTable::default();
+        for topic_filter in ["a/b", "a/#", "a/+", "a/+/c", "#", "+/b"] {
+            subscribe(&table, topic_filter, "c1");
+        }
+
+        assert_eq!(matched_filters(&table, "a"), ["#", "a/#"]);
+        assert_eq!(
+            matched_filters(&table, "a/b"),
+            ["#", "+/b", "a/#", "a/+", "a/b"]
+        );
+        assert_eq!(matched_filters(&table, "a/x/c"), ["#", "a/#", "a/+/c"]);
+        assert_eq!(matched_filters(&table, "b/c"), ["#"]);
+    }
+
+    #[test]
+    fn test_route_dollar_topics() {
+        let table = RouteTable::default();
+        for topic_filter in ["#", "+/info", "$SYS/#", "$SYS/+"] {
+            subscribe(&table, topic_filter, "c1");
+        }
+
+        assert_eq!(matched_filters(&table, "$SYS/info"), ["$SYS/#", "$SYS/+"]);
+        assert_eq!(matched_filters(&table, "SYS/info"), ["#", "+/info"]);
+    }
+
+    #[test]
+    fn test_route_shared() {
+        let table = RouteTable::default();
+        subscribe(&table, "$share/g1/a/+", "c1");
+        subscribe(&table, "$share/g1/a/+", "c2");
+        subscribe(&table, "$share/g2/a/+", "c3");
+        subscribe(&table, "a/+", "c4");
+
+        let matches = table.get_matches(&TopicName::new("a/b").unwrap());
+        assert_eq!(matches.len(), 1);
+        let content = &matches[0];
+        assert_eq!(content.topic_filter.to_string(), "a/+");
+        assert!(content.clients.contains_key("c4"));
+        assert_eq!(content.groups.len(), 2);
+        assert_eq!(content.groups["g1"].items.len(), 2);
+        assert_eq!(content.groups["g2"].items.len(), 1);
+        assert_eq!(table.topic_filters(), [TopicFilter::new("a/+").unwrap()]);
+
+        unsubscribe(&table, "$share/g1/a/+", "c1");
+        unsubscribe(&table, "$share/g2/a/+", "c3");
+        let matches = table.get_matches(&TopicName::new("a/b").unwrap());
+        let content = &matches[0];
+        assert_eq!(content.groups.len(), 1);
+        assert_eq!(
+            content.groups["g1"].items,
+            [("c2".to_owned(), QualityOfService::Level1)]
+        );
+
+        // an unknown group or member changes nothing
+        unsubscribe(&table, "$share/g3/a/+", "c2");
+        unsubscribe(&table, "$share/g1/a/+", "c4");
+        assert_eq!(
+            table.get_matches(&TopicName::new("a/b").unwrap())[0]
+                .groups
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_route_prune() {
+        let table = RouteTable::default();
+        let subscriptions = [
+            ("a/b/c", "c1"),
+            ("a/b/c", "c2"),
+            ("a/b", "c1"),
+            ("a/+/c/#", "c3"),
+            ("$share/g/a/b/c", "c4"),
+            ("x/y", "c1"),
+        ];
+        for (topic_filter, id) in subscriptions {
+            subscribe(&table, topic_filter, id);
+        }
+
+        unsubscribe(&table, "a/b/c", "c1");
+        unsubscribe(&table, "a/b/c", "c2");
+        // the node still holds the shared subscription
+        assert_eq!(matched_filters(&table, "a/b/c"), ["a/+/c/#", "a/b/c"]);
+        unsubscribe(&table, "$share/g/a/b/c", "c4");
+        assert_eq!(matched_filters(&table, "a/b/c"), ["a/+/c/#"]);
+        {
+            let root = table.root.load();
+            let b = &root.nodes["a"].nodes["b"];
+            assert!(b.content.is_some());
+            assert!(!b.nodes.contains_key("c"));
+        }
+
+        unsubscribe(&table, "a/b", "c1");
+        unsubscribe(&table, "a/+/c/#", "c3");
+        assert!(!table.root.load().nodes.contains_key("a"));
+        assert_eq!(table.topic_filters(), [TopicFilter::new("x/y").unwrap()]);
+        assert_eq!(table.levels.lock().interned.len(), 2);
+
+        unsubscribe(&table, "x/y", "c1");
+        assert!(table.root.load().is_empty());
+        assert!(table.levels.lock().interned.is_empty());
+    }
+
+    #[test]
+    fn test_route_levels() {
+        let table = RouteTable::default();
+        subscribe(&table, "a/b", "c1");
+        subscribe(&table, "a/b", "c2");
+        subscribe(&table, "b/a", "c1");
+        subscribe(&table, "a/a/a", "c1");
+        {
+            let levels = table.levels.lock();
+            // one per node using the level
+            assert_eq!(levels.interned["a"], 4);
+            assert_eq!(levels.interned["b"], 2);
+        }
+
+        unsubscribe(&table, "a/a/a", "c1");
+        assert_eq!(table.levels.lock().interned["a"], 2);
+
+        for (topic_filter, id) in [("a/b", "c1"), ("a/b", "c2"), ("b/a", "c1")] {
+            unsubscribe(&table, topic_filter, id);
+        }
+        assert!(table.levels.lock().interned.is_empty());
+        assert!(table.root.load().is_empty());
+    }
+
+    #[test]
+    fn test_shared_clients_remove() {
+        let mut shared_clients = shared_clients(&["a", "b", "c"]);
+
+        shared_clients.remove("c");
+        assert!(!shared_clients.index.contains_key("c"));
+        shared_clients.insert(("c".to_owned(), QualityOfService::Level2));
+        assert_eq!(shared_clients.items.len(), 3);
+        assert_eq!(shared_clients.index["c"], 2);
+
+        shared_clients.remove("a");
+        assert_eq!(shared_clients.items[0].0, "c");
+        assert_eq!(shared_clients.index["c"], 0);
+        assert_eq!(shared_clients.index["b"], 1);
+
+        shared_clients.remove("b");
+        shared_clients.remove("c");
+        assert!(shared_clients.is_empty());
+        assert!(shared_clients.index.is_empty());
+    }
+}
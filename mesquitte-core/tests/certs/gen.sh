@@ -0,0 +1,59 @@
+#!/bin/sh
+# Certificates and CRLs of the mutual TLS tests, run from this directory to
+# generate them again.
+set -e
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+# ca_init <name>: a self-signed CA with an empty revocation database
+ca_init() {
+    mkdir -p "$work/$1"
+    touch "$work/$1/index.txt"
+    echo 1000 > "$work/$1/crlnumber"
+    cat > "$work/$1/ca.cnf" <<CNF
+[ca]
+default_ca = ca_default
+[ca_default]
+database = $work/$1/index.txt
+crlnumber = $work/$1/crlnumber
+default_md = sha256
+CNF
+    openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+        -keyout "$work/$1/ca.key" -out "$1.pem" -days 36500 -subj "/CN=$1" \
+        -addext basicConstraints=critical,CA:TRUE \
+        -addext keyUsage=critical,keyCertSign,cRLSign
+}
+
+# issue <ca> <name> <extensions>
+issue() {
+    openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+        -keyout "$2.key" -out "$work/$2.csr" -subj "/CN=$2"
+    openssl x509 -req -in "$work/$2.csr" -CA "$1.pem" -CAkey "$work/$1/ca.key" \
+        -out "$2.pem" -days 36500 -set_serial "0x$(openssl rand -hex 8)" \
+        -extfile /dev/stdin <<EXT
+basicConstraints=critical,CA:FALSE
+$3
+EXT
+}
+
+# crl <ca> <file> <last update> <next update>
+crl() {
+    openssl ca -config "$work/$1/ca.cnf" -gencrl -cert "$1.pem" \
+        -keyfile "$work/$1/ca.key" -crl_lastupdate "$3" -crl_nextupdate "$4" \
+        -out "$2"
+}
+
+ca_init ca
+ca_init other-ca
+issue ca server "subjectAltName=DNS:localhost
+extendedKeyUsage=serverAuth"
+issue ca client "extendedKeyUsage=clientAuth"
+issue ca revoked "extendedKeyUsage=clientAuth"
+
+crl ca expired-crl.pem 20240101000000Z 20240201000000Z
+openssl ca -config "$work/ca/ca.cnf" -revoke revoked.pem \
+    -cert ca.pem -keyfile "$work/ca/ca.key"
+crl ca crl.pem 20240101000000Z 21240101000000Z
+crl other-ca other-crl.pem 20240101000000Z 21240101000000Z
+rm other-ca.pem
@@ -0,0 +1,107 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use mesquitte_client::{Client, ClientOptions, Event, Events, Message, Protocol};
+use mesquitte_core::{
+    server::{process_client, state::GlobalState},
+    store::memory::queue::MemoryQueue,
+    types::connection::{ConnectionInfo, Transport},
+};
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+use tokio::{net::TcpListener, time};
+
+/// Serves the clients connecting to the returned address.
+async fn broker() -> SocketAddr {
+    let global = Arc::new(GlobalState::new(MemoryQueue::new(100, 100)));
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    tokio::spawn(async move {
+        while let Ok((stream, peer_addr)) = listener.accept().await {
+            let mut connection = ConnectionInfo::new(Transport::Tcp, None);
+            connection.set_remote_addr(peer_addr);
+            tokio::spawn(process_client(stream, connection, global.clone()));
+        }
+    });
+    addr
+}
+
+async fn connect(addr: SocketAddr, client_id: &str) -> (Client, Events) {
+    let mut options = ClientOptions::new(addr.to_string(), client_id);
+    options.protocol = Protocol::V4;
+    let (client, mut events) = Client::connect(options).await.expect("connect");
+    match next_event(&mut events).await {
+        Event::Connected { session_present } => assert!(!session_present),
+        event => panic!("unexpected event {event:?}"),
+    }
+    (client, events)
+}
+
+async fn next_event(events: &mut Events) -> Event {
+    time::timeout(Duration::from_secs(2), events.recv())
+        .await
+        .expect("event timeout")
+        .expect("client closed")
+}
+
+async fn next_message(events: &mut Events) -> Message {
+    match next_event(events).await {
+        Event::Message(message) => message,
+        event => panic!("unexpected event {event:?}"),
+    }
+}
+
+fn topic(name: &str) -> TopicName {
+    TopicName::new(name).unwrap()
+}
+
+#[tokio::test]
+async fn test_publish_subscribe() {
+    let addr = broker().await;
+    let (publisher, _events) = connect(addr, "publisher").await;
+    let mut retained = Message::new(topic("sensors/1"), "21.5", QualityOfService::Level1);
+    retained.set_retain(true);
+    publisher.publish(retained).await.unwrap();
+
+    let (subscriber, mut events) = connect(addr, "subscriber").await;
+    let granted = subscriber
+        .subscribe(
+            TopicFilter::new("sensors/#").unwrap(),
+            QualityOfService::Level1,
+        )
+        .await
+        .unwrap();
+    assert_eq!(granted, QualityOfService::Level1);
+    let message = next_message(&mut events).await;
+    assert_eq!(&message.topic_name()[..], "sensors/1");
+    assert_eq!(message.payload(), &b"21.5"[..]);
+    assert!(message.retain());
+
+    for qos in [QualityOfService::Level0, QualityOfService::Level1] {
+        publisher
+            .publish(Message::new(topic("sensors/2"), "22.0", qos))
+            .await
+            .unwrap();
+        let message = next_message(&mut events).await;
+        assert_eq!(&message.topic_name()[..], "sensors/2");
+        assert_eq!(message.qos(), qos);
+        assert!(!message.retain());
+    }
+
+    subscriber
+        .unsubscribe(TopicFilter::new("sensors/#").unwrap())
+        .await
+        .unwrap();
+    publisher
+        .publish(Message::new(
+            topic("sensors/3"),
+            "23.0",
+            QualityOfService::Level1,
+        ))
+        .await
+        .unwrap();
+    assert!(time::timeout(Duration::from_millis(200), events.recv())
+        .await
+        .is_err());
+
+    subscriber.disconnect().await.unwrap();
+    publisher.disconnect().await.unwrap();
+}
@@ -0,0 +1,248 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use mqtt_codec_kit::common::{
+    qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+const SMALL_PAYLOAD: usize = 64;
+const LARGE_PAYLOAD: usize = 16 * 1024;
+
+fn topic_name() -> TopicName {
+    TopicName::new("building/3/floor/12/room/1207/sensor/temperature").expect("topic name")
+}
+
+fn topic_filters() -> Vec<TopicFilter> {
+    [
+        "building/+/floor/+/room/+/sensor/#",
+        "building/3/floor/12/#",
+        "$share/monitors/building/3/+/+/+/+/sensor/temperature",
+    ]
+    .into_iter()
+    .map(|filter| TopicFilter::new(filter).expect("topic filter"))
+    .collect()
+}
+
+mod v4 {
+    use mqtt_codec_kit::v4::{
+        control::ConnectReturnCode,
+        packet::{
+            suback::SubscribeReturnCode, ConnackPacket, ConnectPacket, DisconnectPacket,
+            PingreqPacket, PingrespPacket, PubackPacket, PubcompPacket, PublishPacket,
+            PubrecPacket, PubrelPacket, SubackPacket, SubscribePacket, UnsubackPacket,
+            UnsubscribePacket,
+        },
+    };
+
+    pub use mqtt_codec_kit::v4::packet::{MqttDecoder, MqttEncoder, VariablePacket};
+
+    use super::*;
+
+    pub fn packets() -> Vec<(&'static str, VariablePacket)> {
+        let mut connect = ConnectPacket::new("sensor-1207");
+        connect.set_keep_alive(30);
+        connect.set_username(Some("sensor".to_owned()));
+        connect.set_password(Some("secret".to_owned()));
+
+        let publish = |qos, size| PublishPacket::new(topic_name(), qos, vec![0x5a; size]).into();
+
+        vec![
+            ("connect", connect.into()),
+            (
+                "connack",
+                ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted).into(),
+            ),
+            (
+                "publish_qos0_small",
+                publish(QoSWithPacketIdentifier::Level0, SMALL_PAYLOAD),
+            ),
+            (
+                "publish_qos1_small",
+                publish(QoSWithPacketIdentifier::Level1(10), SMALL_PAYLOAD),
+            ),
+            (
+                "publish_qos1_large",
+                publish(QoSWithPacketIdentifier::Level1(10), LARGE_PAYLOAD),
+            ),
+            ("puback", PubackPacket::new(10).into()),
+            ("pubrec", PubrecPacket::new(10).into()),
+            ("pubrel", PubrelPacket::new(10).into()),
+            ("pubcomp", PubcompPacket::new(10).into()),
+            (
+                "subscribe",
+                SubscribePacket::new(
+                    10,
+                    topic_filters()
+                        .into_iter()
+                        .map(|filter| (filter, QualityOfService::Level1))
+                        .collect(),
+                )
+                .into(),
+            ),
+            (
+                "suback",
+                SubackPacket::new(10, vec![SubscribeReturnCode::MaximumQoSLevel1; 3]).into(),
+            ),
+            (
+                "unsubscribe",
+                UnsubscribePacket::new(10, topic_filters()).into(),
+            ),
+            ("unsuback", UnsubackPacket::new(10).into()),
+            ("pingreq", PingreqPacket::new().into()),
+            ("pingresp", PingrespPacket::new().into()),
+            ("disconnect", DisconnectPacket::new().into()),
+        ]
+    }
+}
+
+mod v5 {
+    use mqtt_codec_kit::v5::{
+        control::{
+            AuthenticateReasonCode, ConnectReasonCode, DisconnectReasonCode, PublishProperties,
+            PubrecReasonCode, PubrelReasonCode,
+        },
+        packet::{
+            connect::ConnectProperties, suback::SubscribeReasonCode, subscribe::SubscribeOptions,
+            unsuback::UnsubscribeReasonCode, AuthPacket, ConnackPacket, ConnectPacket,
+            DisconnectPacket, PingreqPacket, PingrespPacket, PubackPacket, PubcompPacket,
+            PublishPacket, PubrecPacket, PubrelPacket, SubackPacket, SubscribePacket,
+            UnsubackPacket, UnsubscribePacket,
+        },
+    };
+
+    pub use mqtt_codec_kit::v5::packet::{MqttDecoder, MqttEncoder, VariablePacket};
+
+    use super::*;
+
+    pub fn packets() -> Vec<(&'static str, VariablePacket)> {
+        let mut connect = ConnectPacket::new("sensor-1207");
+        connect.set_keep_alive(30);
+        connect.set_username(Some("sensor".to_owned()));
+        connect.set_password(Some("secret".to_owned()));
+        let mut properties = ConnectProperties::default();
+        properties.set_session_expiry_interval(Some(3600));
+        properties.set_receive_maximum(Some(128));
+        connect.set_properties(properties);
+
+        let publish = |qos, size| {
+            let mut properties = PublishProperties::default();
+            properties.set_message_expiry_interval(Some(60));
+            properties.set_content_type(Some("application/json".to_owned()));
+            let mut packet = PublishPacket::new(topic_name(), qos, vec![0x5a; size]);
+            packet.set_properties(properties);
+            packet.into()
+        };
+
+        vec![
+            ("connect", connect.into()),
+            (
+                "connack",
+                ConnackPacket::new(false, ConnectReasonCode::Success).into(),
+            ),
+            (
+                "publish_qos0_small",
+                publish(QoSWithPacketIdentifier::Level0, SMALL_PAYLOAD),
+            ),
+            (
+                "publish_qos1_small",
+                publish(QoSWithPacketIdentifier::Level1(10), SMALL_PAYLOAD),
+            ),
+            (
+                "publish_qos1_large",
+                publish(QoSWithPacketIdentifier::Level1(10), LARGE_PAYLOAD),
+            ),
+            ("puback", PubackPacket::new_success(10).into()),
+            (
+                "pubrec",
+                PubrecPacket::new(10, PubrecReasonCode::Success).into(),
+            ),
+            (
+                "pubrel",
+                PubrelPacket::new(10, PubrelReasonCode::Success).into(),
+            ),
+            ("pubcomp", PubcompPacket::new_success(10).into()),
+            (
+                "subscribe",
+                SubscribePacket::new(
+                    10,
+                    topic_filters()
+                        .into_iter()
+                        .map(|filter| (filter, SubscribeOptions::default()))
+                        .collect(),
+                )
+                .into(),
+            ),
+            (
+                "suback",
+                SubackPacket::new(10, vec![SubscribeReasonCode::GrantedQos1; 3]).into(),
+            ),
+            (
+                "unsubscribe",
+                UnsubscribePacket::new(10, topic_filters()).into(),
+            ),
+            (
+                "unsuback",
+                UnsubackPacket::new(10, vec![UnsubscribeReasonCode::Success; 3]).into(),
+            ),
+            ("pingreq", PingreqPacket::new().into()),
+            ("pingresp", PingrespPacket::new().into()),
+            (
+                "disconnect",
+                DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection).into(),
+            ),
+            (
+                "auth",
+                AuthPacket::new(AuthenticateReasonCode::Success).into(),
+            ),
+        ]
+    }
+}
+
+macro_rules! bench_codec {
+    ($name:ident, $version:ident) => {
+        fn $name(c: &mut Criterion) {
+            let mut group = c.benchmark_group(stringify!($version));
+            for (name, packet) in $version::packets() {
+                let mut encoded = BytesMut::new();
+                $version::MqttEncoder::new()
+                    .encode(packet.clone(), &mut encoded)
+                    .expect("encode packet");
+                group.throughput(Throughput::Bytes(encoded.len() as u64));
+
+                group.bench_with_input(BenchmarkId::new("encode", name), &packet, |b, packet| {
+                    let mut encoder = $version::MqttEncoder::new();
+                    let mut dst = BytesMut::with_capacity(encoded.len());
+                    b.iter_batched(
+                        || packet.clone(),
+                        |packet| {
+                            dst.clear();
+                            encoder.encode(packet, &mut dst).expect("encode packet");
+                        },
+                        BatchSize::SmallInput,
+                    )
+                });
+                group.bench_with_input(BenchmarkId::new("decode", name), &encoded, |b, encoded| {
+                    let mut decoder = $version::MqttDecoder::new();
+                    b.iter_batched(
+                        || encoded.clone(),
+                        |mut src| {
+                            let packet: $version::VariablePacket = decoder
+                                .decode(&mut src)
+                                .expect("decode packet")
+                                .expect("complete packet");
+                            packet
+                        },
+                        BatchSize::SmallInput,
+                    )
+                });
+            }
+            group.finish();
+        }
+    };
+}
+
+bench_codec!(v4_codec, v4);
+bench_codec!(v5_codec, v5);
+
+criterion_group!(benches, v4_codec, v5_codec);
+criterion_main!(benches);
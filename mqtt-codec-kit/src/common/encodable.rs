@@ -7,6 +7,7 @@ use std::{
 };
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
 
 pub trait Encodable {
     /// Encodes to writer
@@ -28,21 +29,21 @@ impl<T: Encodable> Encodable for Option<T> {
     }
 }
 
-impl<'a> Encodable for &'a str {
+impl Encodable for &str {
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
-        assert!(self.as_bytes().len() <= u16::MAX as usize);
+        assert!(self.len() <= u16::MAX as usize);
 
         writer
-            .write_u16::<BigEndian>(self.as_bytes().len() as u16)
+            .write_u16::<BigEndian>(self.len() as u16)
             .and_then(|_| writer.write_all(self.as_bytes()))
     }
 
     fn encoded_length(&self) -> u32 {
-        2 + self.as_bytes().len() as u32
+        2 + self.len() as u32
     }
 }
 
-impl<'a> Encodable for &'a [u8] {
+impl Encodable for &[u8] {
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
         writer.write_all(self)
     }
@@ -72,6 +73,16 @@ impl Encodable for Vec<u8> {
     }
 }
 
+impl Encodable for Bytes {
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        (&self[..]).encode(writer)
+    }
+
+    fn encoded_length(&self) -> u32 {
+        (&self[..]).encoded_length()
+    }
+}
+
 impl Encodable for () {
     fn encode<W: Write>(&self, _: &mut W) -> Result<(), io::Error> {
         Ok(())
@@ -23,9 +23,6 @@ impl Decodable for KeepAlive {
     type Cond = ();
 
     fn decode_with<R: Read>(reader: &mut R, _rest: ()) -> Result<KeepAlive, io::Error> {
-        reader
-            .read_u16::<BigEndian>()
-            .map(KeepAlive)
-            .map_err(From::from)
+        reader.read_u16::<BigEndian>().map(KeepAlive)
     }
 }
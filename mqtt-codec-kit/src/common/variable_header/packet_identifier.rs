@@ -23,9 +23,6 @@ impl Decodable for PacketIdentifier {
     type Cond = ();
 
     fn decode_with<R: Read>(reader: &mut R, _rest: ()) -> Result<PacketIdentifier, io::Error> {
-        reader
-            .read_u16::<BigEndian>()
-            .map(PacketIdentifier)
-            .map_err(From::from)
+        reader.read_u16::<BigEndian>().map(PacketIdentifier)
     }
 }
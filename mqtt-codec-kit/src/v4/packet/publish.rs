@@ -2,6 +2,8 @@
 
 use std::io::{self, Read, Write};
 
+use bytes::Bytes;
+
 use crate::{
     common::{
         packet::{DecodablePacket, EncodablePacket},
@@ -20,13 +22,13 @@ pub struct PublishPacket {
     fixed_header: FixedHeader,
     topic_name: TopicName,
     packet_identifier: Option<PacketIdentifier>,
-    payload: Vec<u8>,
+    payload: Bytes,
 }
 
 encodable_packet!(PublishPacket(topic_name, packet_identifier, payload));
 
 impl PublishPacket {
-    pub fn new<P: Into<Vec<u8>>>(
+    pub fn new<P: Into<Bytes>>(
         topic_name: TopicName,
         qos: QoSWithPacketIdentifier,
         payload: P,
@@ -49,7 +51,7 @@ impl PublishPacket {
     }
 
     pub fn dup(&self) -> bool {
-        self.fixed_header.packet_type.flags() & 0x80 != 0
+        self.fixed_header.packet_type.flags() & 0b1000 != 0
     }
 
     pub fn set_qos(&mut self, qos: QoSWithPacketIdentifier) {
@@ -94,11 +96,11 @@ impl PublishPacket {
         &self.topic_name
     }
 
-    pub fn payload(&self) -> &[u8] {
+    pub fn payload(&self) -> &Bytes {
         &self.payload
     }
 
-    pub fn set_payload<P: Into<Vec<u8>>>(&mut self, payload: P) {
+    pub fn set_payload<P: Into<Bytes>>(&mut self, payload: P) {
         self.payload = payload.into();
         self.fix_header_remaining_len();
     }
@@ -110,6 +112,37 @@ impl DecodablePacket for PublishPacket {
     type Error = PacketError<Self>;
 
     fn decode_packet<R: Read>(reader: &mut R, fixed_header: Self::F) -> Result<Self, Self::Error> {
+        let (mut packet, payload_len) =
+            PublishPacket::decode_variable_header(reader, fixed_header)?;
+        packet.payload = Vec::<u8>::decode_with(reader, Some(payload_len))?.into();
+        Ok(packet)
+    }
+}
+
+impl PublishPacket {
+    /// Decode the packet from its remaining part, the payload refers to `data` without
+    /// copying it
+    pub fn decode_from_bytes(
+        data: Bytes,
+        fixed_header: FixedHeader,
+    ) -> Result<Self, PacketError<Self>> {
+        let mut reader = &data[..];
+        let (mut packet, payload_len) =
+            PublishPacket::decode_variable_header(&mut reader, fixed_header)?;
+        let start = data.len() - reader.len();
+        let end = start + payload_len as usize;
+        if end > data.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        packet.payload = data.slice(start..end);
+        Ok(packet)
+    }
+
+    // Decode everything but the payload, returns the length of the payload
+    fn decode_variable_header<R: Read>(
+        reader: &mut R,
+        fixed_header: FixedHeader,
+    ) -> Result<(Self, u32), PacketError<Self>> {
         let topic_name = TopicName::decode(reader)?;
 
         let qos = (fixed_header.packet_type.flags() & 0b0110) >> 1;
@@ -126,14 +159,15 @@ impl DecodablePacket for PublishPacket {
                 .unwrap_or(0);
         let payload_len = fixed_header.remaining_length - vhead_len;
 
-        let payload = Vec::<u8>::decode_with(reader, Some(payload_len))?;
-
-        Ok(PublishPacket {
-            fixed_header,
-            topic_name,
-            packet_identifier,
-            payload,
-        })
+        Ok((
+            PublishPacket {
+                fixed_header,
+                topic_name,
+                packet_identifier,
+                payload: Bytes::new(),
+            },
+            payload_len,
+        ))
     }
 }
 
@@ -190,6 +224,100 @@ impl EncodablePacket for PublishPacketRef<'_> {
     }
 }
 
+/// `PUBLISH` packet encoded once, for writing the same message to many receivers
+///
+/// The encoded topic name and the payload are shared by all clones of the frame, only the
+/// fixed header flags and the packet identifier are set per receiver.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PublishFrame {
+    fixed_header: FixedHeader,
+    topic_name: Bytes,
+    packet_identifier: Option<PacketIdentifier>,
+    payload: Bytes,
+}
+
+encodable_packet!(PublishFrame(topic_name, packet_identifier, payload));
+
+impl PublishFrame {
+    pub fn new(
+        topic_name: &TopicNameRef,
+        qos: QoSWithPacketIdentifier,
+        payload: Bytes,
+    ) -> PublishFrame {
+        let mut encoded_topic_name = Vec::with_capacity(topic_name.encoded_length() as usize);
+        topic_name
+            .encode(&mut encoded_topic_name)
+            .expect("writing to a Vec never fails");
+
+        let (qos, pkid) = qos.split();
+        let mut frame = PublishFrame {
+            fixed_header: FixedHeader::new(PacketType::publish(qos), 0),
+            topic_name: encoded_topic_name.into(),
+            packet_identifier: pkid.map(PacketIdentifier),
+            payload,
+        };
+        frame.fix_header_remaining_len();
+        frame
+    }
+
+    pub fn set_dup(&mut self, dup: bool) {
+        self.fixed_header
+            .packet_type
+            .update_flags(|flags| (flags & !(1 << 3)) | (dup as u8) << 3)
+    }
+
+    pub fn dup(&self) -> bool {
+        self.fixed_header.packet_type.flags() & 0b1000 != 0
+    }
+
+    pub fn set_qos(&mut self, qos: QoSWithPacketIdentifier) {
+        let (qos, pkid) = qos.split();
+        self.fixed_header
+            .packet_type
+            .update_flags(|flags| (flags & !0b0110) | (qos as u8) << 1);
+        self.packet_identifier = pkid.map(PacketIdentifier);
+        self.fix_header_remaining_len();
+    }
+
+    pub fn qos(&self) -> QoSWithPacketIdentifier {
+        match self.packet_identifier {
+            None => QoSWithPacketIdentifier::Level0,
+            Some(pkid) => {
+                let qos_val = (self.fixed_header.packet_type.flags() & 0b0110) >> 1;
+                match qos_val {
+                    1 => QoSWithPacketIdentifier::Level1(pkid.0),
+                    2 => QoSWithPacketIdentifier::Level2(pkid.0),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    pub fn set_retain(&mut self, ret: bool) {
+        self.fixed_header
+            .packet_type
+            .update_flags(|flags| (flags & !0b0001) | (ret as u8))
+    }
+
+    pub fn retain(&self) -> bool {
+        self.fixed_header.packet_type.flags() & 0b0001 != 0
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+}
+
+impl From<&PublishPacket> for PublishFrame {
+    fn from(packet: &PublishPacket) -> Self {
+        let mut frame =
+            PublishFrame::new(packet.topic_name(), packet.qos(), packet.payload().clone());
+        frame.set_dup(packet.dup());
+        frame.set_retain(packet.retain());
+        frame
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -204,7 +332,7 @@ mod test {
         let mut packet = PublishPacket::new(
             TopicName::new("a/b").unwrap(),
             QoSWithPacketIdentifier::Level1(40306),
-            b"{\"msg\":\"hello, world!\"}",
+            Bytes::from_static(b"{\"msg\":\"hello, world!\"}"),
         );
 
         packet.set_retain(true);
@@ -227,7 +355,7 @@ mod test {
         let expected = PublishPacket::new(
             TopicName::new("a/b").unwrap(),
             QoSWithPacketIdentifier::Level0,
-            b"{\"msg\":\"hello, world!\"}",
+            Bytes::from_static(b"{\"msg\":\"hello, world!\"}"),
         );
 
         assert_eq!(expected, packet);
@@ -249,4 +377,89 @@ mod test {
 
         assert_eq!(packet, decoded);
     }
+
+    #[test]
+    fn test_publish_packet_dup() {
+        let mut packet = PublishPacket::new(
+            TopicName::new("a/b".to_owned()).unwrap(),
+            QoSWithPacketIdentifier::Level1(10),
+            b"Hello world!".to_vec(),
+        );
+        assert!(!packet.dup());
+        packet.set_dup(true);
+        assert!(packet.dup());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(buf[0], 0x3a);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = PublishPacket::decode(&mut decode_buf).unwrap();
+        assert!(decoded.dup());
+        assert_eq!(decoded.qos(), QoSWithPacketIdentifier::Level1(10));
+    }
+
+    #[test]
+    fn test_publish_packet_decode_from_bytes() {
+        let packet = PublishPacket::new(
+            TopicName::new("a/b".to_owned()).unwrap(),
+            QoSWithPacketIdentifier::Level1(10),
+            b"Hello world!".to_vec(),
+        );
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let mut reader = Cursor::new(&buf[..]);
+        let fixed_header = FixedHeader::decode(&mut reader).unwrap();
+        let header_len = reader.position() as usize;
+        let data = Bytes::from(buf).slice(header_len..);
+        let decoded = PublishPacket::decode_from_bytes(data.clone(), fixed_header).unwrap();
+
+        assert_eq!(packet, decoded);
+        // The payload is a view into the decoded data
+        assert_eq!(
+            decoded.payload().as_ptr(),
+            data[data.len() - decoded.payload().len()..].as_ptr()
+        );
+    }
+
+    #[test]
+    fn test_publish_frame_encode_as_packet() {
+        let mut packet = PublishPacket::new(
+            TopicName::new("a/b").unwrap(),
+            QoSWithPacketIdentifier::Level0,
+            Bytes::from_static(b"Hello world!"),
+        );
+        let frame = PublishFrame::from(&packet);
+
+        for (qos, dup, retain) in [
+            (QoSWithPacketIdentifier::Level0, false, true),
+            (QoSWithPacketIdentifier::Level1(10), true, false),
+            (QoSWithPacketIdentifier::Level2(65535), false, false),
+        ] {
+            let mut receiver_frame = frame.clone();
+            receiver_frame.set_qos(qos);
+            receiver_frame.set_dup(dup);
+            receiver_frame.set_retain(retain);
+            packet.set_qos(qos);
+            packet.set_dup(dup);
+            packet.set_retain(retain);
+
+            assert_eq!(receiver_frame.qos(), qos);
+            assert_eq!(receiver_frame.dup(), dup);
+            assert_eq!(receiver_frame.retain(), retain);
+
+            let mut expected = Vec::new();
+            packet.encode(&mut expected).unwrap();
+            let mut buf = Vec::new();
+            receiver_frame.encode(&mut buf).unwrap();
+
+            assert_eq!(expected, buf);
+            assert_eq!(receiver_frame.encoded_length() as usize, buf.len());
+        }
+
+        // Clones share the payload
+        assert_eq!(frame.clone().payload().as_ptr(), frame.payload().as_ptr());
+    }
 }
@@ -60,9 +60,6 @@ impl Decodable for AuthenticateReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<AuthenticateReasonCode, VariableHeaderError> {
-        reader
-            .read_u8()
-            .map(AuthenticateReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(AuthenticateReasonCode::try_from)?
     }
 }
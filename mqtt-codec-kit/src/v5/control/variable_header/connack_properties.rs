@@ -235,6 +235,9 @@ impl ConnackProperties {
         if self.subscription_identifiers_available.is_some() {
             len += 1 + 1;
         }
+        if self.shared_subscription_available.is_some() {
+            len += 1 + 1;
+        }
         if self.server_keep_alive.is_some() {
             len += 1 + 2;
         }
@@ -117,9 +117,6 @@ impl Decodable for ConnectReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<ConnectReasonCode, VariableHeaderError> {
-        reader
-            .read_u8()
-            .map(ConnectReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(ConnectReasonCode::try_from)?
     }
 }
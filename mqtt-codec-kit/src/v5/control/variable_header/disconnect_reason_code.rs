@@ -173,9 +173,6 @@ impl Decodable for DisconnectReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<DisconnectReasonCode, VariableHeaderError> {
-        reader
-            .read_u8()
-            .map(DisconnectReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(DisconnectReasonCode::try_from)?
     }
 }
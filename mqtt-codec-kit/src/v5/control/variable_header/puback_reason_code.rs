@@ -78,9 +78,6 @@ impl Decodable for PubackReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<PubackReasonCode, VariableHeaderError> {
-        reader
-            .read_u8()
-            .map(PubackReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(PubackReasonCode::try_from)?
     }
 }
@@ -57,9 +57,6 @@ impl Decodable for PubcompReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<PubcompReasonCode, VariableHeaderError> {
-        reader
-            .read_u8()
-            .map(PubcompReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(PubcompReasonCode::try_from)?
     }
 }
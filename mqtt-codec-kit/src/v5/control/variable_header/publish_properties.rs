@@ -60,6 +60,11 @@ impl PublishProperties {
         self.fix_total_length();
     }
 
+    pub fn clear_user_properties(&mut self) {
+        self.user_properties.clear();
+        self.fix_total_length();
+    }
+
     pub fn add_subscription_identifier(&mut self, subscription_identifier: usize) {
         self.subscription_identifiers.push(subscription_identifier);
         self.fix_total_length();
@@ -78,9 +78,6 @@ impl Decodable for PubrecReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<PubrecReasonCode, VariableHeaderError> {
-        reader
-            .read_u8()
-            .map(PubrecReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(PubrecReasonCode::try_from)?
     }
 }
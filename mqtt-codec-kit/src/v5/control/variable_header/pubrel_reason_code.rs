@@ -57,9 +57,6 @@ impl Decodable for PubrelReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<PubrelReasonCode, VariableHeaderError> {
-        reader
-            .read_u8()
-            .map(PubrelReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(PubrelReasonCode::try_from)?
     }
 }
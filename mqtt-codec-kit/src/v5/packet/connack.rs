@@ -133,4 +133,27 @@ mod test {
 
         assert_eq!(packet, decoded);
     }
+
+    #[test]
+    fn test_connack_packet_shared_subscription_available() {
+        let mut packet = ConnackPacket::new(false, ConnectReasonCode::Success);
+
+        let mut properties = ConnackProperties::default();
+        properties.set_shared_subscription_available(Some(1));
+        properties.set_max_qos(Some(1));
+
+        packet.set_properties(properties);
+
+        let expected = b"\x20\x07\x00\x00\x04\x24\x01\x2a\x01";
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        assert_eq!(&expected[..], &buf[..]);
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = ConnackPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+    }
 }
@@ -59,7 +59,7 @@ pub use self::{
     pingresp::PingrespPacket,
     puback::PubackPacket,
     pubcomp::PubcompPacket,
-    publish::{PublishPacket, PublishPacketRef},
+    publish::{PublishFrame, PublishPacket, PublishPacketRef},
     pubrec::PubrecPacket,
     pubrel::PubrelPacket,
     suback::SubackPacket,
@@ -185,8 +185,30 @@ macro_rules! impl_variable_packet {
         impl VariablePacket {
             /// Asynchronously parse a packet from a `tokio::io::AsyncRead`
             pub async fn parse<A: AsyncRead + Unpin>(rdr: &mut A) -> Result<Self, VariablePacketError> {
-                use std::io::Cursor;
                 let fixed_header = FixedHeader::parse(rdr).await?;
+                Self::parse_with_header(rdr, fixed_header).await
+            }
+
+            /// Like `parse`, but rejects packets larger than `max_packet_size` bytes before
+            /// reading their remaining part
+            pub async fn parse_with_max_size<A: AsyncRead + Unpin>(
+                rdr: &mut A,
+                max_packet_size: u32,
+            ) -> Result<Self, VariablePacketError> {
+                let fixed_header = FixedHeader::parse(rdr).await?;
+                let size = crate::common::Encodable::encoded_length(&fixed_header)
+                    + fixed_header.remaining_length;
+                if size > max_packet_size {
+                    return Err(VariablePacketError::PacketTooLarge(size, max_packet_size));
+                }
+                Self::parse_with_header(rdr, fixed_header).await
+            }
+
+            async fn parse_with_header<A: AsyncRead + Unpin>(
+                rdr: &mut A,
+                fixed_header: FixedHeader,
+            ) -> Result<Self, VariablePacketError> {
+                use std::io::Cursor;
 
                 let mut buffer = vec![0u8; fixed_header.remaining_length as usize];
                 rdr.read_exact(&mut buffer).await?;
@@ -297,6 +319,8 @@ macro_rules! impl_variable_packet {
             ReservedPacket(u8, Vec<u8>),
             #[error(transparent)]
             IoError(#[from] io::Error),
+            #[error("packet size ({0}) exceeds the maximum packet size ({1})")]
+            PacketTooLarge(u32, u32),
             $(
                 #[error(transparent)]
                 $errname(#[from] PacketError<$name>),
@@ -350,6 +374,7 @@ mod codec {
 
     pub struct MqttDecoder {
         state: DecodeState,
+        max_packet_size: Option<u32>,
     }
 
     enum DecodeState {
@@ -367,8 +392,26 @@ mod codec {
         pub const fn new() -> Self {
             MqttDecoder {
                 state: DecodeState::Start,
+                max_packet_size: None,
             }
         }
+
+        /// Decoder rejecting packets larger than `max_packet_size` bytes, fixed header included,
+        /// as soon as their fixed header is decoded
+        pub const fn with_max_packet_size(max_packet_size: u32) -> Self {
+            MqttDecoder {
+                state: DecodeState::Start,
+                max_packet_size: Some(max_packet_size),
+            }
+        }
+
+        pub fn max_packet_size(&self) -> Option<u32> {
+            self.max_packet_size
+        }
+
+        pub fn set_max_packet_size(&mut self, max_packet_size: Option<u32>) {
+            self.max_packet_size = max_packet_size;
+        }
     }
 
     impl Default for MqttDecoder {
@@ -429,6 +472,10 @@ mod codec {
                 match &mut self.state {
                     DecodeState::Start => match decode_header(&src[..]) {
                         Some(Ok((typ, length, header_size))) => {
+                            let size = header_size as u32 + length;
+                            if let Some(max) = self.max_packet_size.filter(|max| size > *max) {
+                                return Err(VariablePacketError::PacketTooLarge(size, max));
+                            }
                             src.advance(header_size);
                             self.state = DecodeState::Packet { length, typ };
                             continue;
@@ -451,6 +498,12 @@ mod codec {
                                     packet_type: typ,
                                     remaining_length: length,
                                 };
+                                if typ.control_type() == ControlType::Publish {
+                                    // Share the read buffer with the payload instead of copying it
+                                    let data = src.split_to(length as usize).freeze();
+                                    let packet = PublishPacket::decode_from_bytes(data, header)?;
+                                    return Ok(Some(packet.into()));
+                                }
                                 return decode_with_header(&mut src.reader(), header).map(Some);
                             }
                             DecodePacketType::Reserved(code) => {
@@ -499,6 +552,13 @@ mod codec {
                 encode: MqttEncoder::new(),
             }
         }
+
+        pub const fn with_max_packet_size(max_packet_size: u32) -> Self {
+            MqttCodec {
+                decode: MqttDecoder::with_max_packet_size(max_packet_size),
+                encode: MqttEncoder::new(),
+            }
+        }
     }
 
     impl Default for MqttCodec {
@@ -575,6 +635,55 @@ mod test {
         assert_eq!(var_packet, decoded_packet);
     }
 
+    #[cfg(all(feature = "v5", feature = "parse"))]
+    #[tokio::test]
+    async fn test_variable_packet_async_parse_max_size() {
+        // PUBLISH with a remaining length of 1 MiB, the body is never read
+        let buf = [0x30, 0x80, 0x80, 0x40];
+
+        let mut async_buf = &buf[..];
+        match VariablePacket::parse_with_max_size(&mut async_buf, 1024).await {
+            Err(VariablePacketError::PacketTooLarge(size, max)) => {
+                assert_eq!(size, 4 + 1024 * 1024);
+                assert_eq!(max, 1024);
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "tokio-codec")]
+    #[test]
+    fn test_decoder_max_packet_size() {
+        use bytes::BytesMut;
+        use tokio_util::codec::Decoder;
+
+        let packet = VariablePacket::new(ConnectPacket::new("1234".to_owned()));
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let size = buf.len() as u32;
+
+        let mut decoder = MqttDecoder::with_max_packet_size(size);
+        let mut src = BytesMut::from(&buf[..]);
+        assert_eq!(decoder.decode(&mut src).unwrap(), Some(packet));
+
+        let mut decoder = MqttDecoder::with_max_packet_size(size - 1);
+        let mut src = BytesMut::from(&buf[..]);
+        match decoder.decode(&mut src) {
+            Err(VariablePacketError::PacketTooLarge(actual, max)) => {
+                assert_eq!(actual, size);
+                assert_eq!(max, size - 1);
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        // Rejected as soon as the fixed header is complete
+        let mut src = BytesMut::from(&[0x30, 0x80, 0x80, 0x40][..]);
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(VariablePacketError::PacketTooLarge(..))
+        ));
+    }
+
     #[cfg(feature = "tokio-codec")]
     #[tokio::test]
     async fn test_variable_packet_framed() {
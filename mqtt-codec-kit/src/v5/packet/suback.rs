@@ -54,6 +54,11 @@ impl SubackPacket {
     pub fn set_packet_identifier(&mut self, pkid: u16) {
         self.packet_identifier.0 = pkid;
     }
+
+    pub fn set_properties(&mut self, properties: SubackProperties) {
+        self.properties = properties;
+        self.fix_header_remaining_len();
+    }
 }
 
 impl DecodablePacket for SubackPacket {
@@ -174,8 +179,8 @@ impl TryFrom<u8> for SubscribeReasonCode {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            SUCCESS => Ok(Self::GrantedQos0),
-            NO_MATCHING_SUBSCRIBERS => Ok(Self::GrantedQos1),
+            GRANTED_QOS_0 => Ok(Self::GrantedQos0),
+            GRANTED_QOS_1 => Ok(Self::GrantedQos1),
             GRANTED_QOS_2 => Ok(Self::GrantedQos2),
             UNSPECIFIED_ERROR => Ok(Self::UnspecifiedError),
             IMPLEMENTATION_SPECIFIC_ERROR => Ok(Self::ImplementationSpecificError),
@@ -209,10 +214,7 @@ impl Decodable for SubscribeReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<SubscribeReasonCode, SubackPacketError> {
-        reader
-            .read_u8()
-            .map(SubscribeReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(SubscribeReasonCode::try_from)?
     }
 }
 
@@ -258,7 +260,12 @@ mod test {
 
     #[test]
     pub fn test_suback_packet_basic() {
-        let subscribes = vec![SubscribeReasonCode::GrantedQos0];
+        let subscribes = vec![
+            SubscribeReasonCode::GrantedQos0,
+            SubscribeReasonCode::GrantedQos1,
+            SubscribeReasonCode::GrantedQos2,
+            SubscribeReasonCode::NotAuthorized,
+        ];
 
         let packet = SubackPacket::new(10001, subscribes);
 
@@ -270,4 +277,23 @@ mod test {
 
         assert_eq!(packet, decoded);
     }
+
+    #[test]
+    pub fn test_suback_packet_with_properties() {
+        let subscribes = vec![SubscribeReasonCode::GrantedQos0];
+
+        let mut properties = SubackProperties::default();
+        properties.set_reason_string(Some("Ok".to_string()));
+
+        let mut packet = SubackPacket::new(10001, subscribes);
+        packet.set_properties(properties);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = SubackPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+    }
 }
@@ -201,10 +201,7 @@ impl Decodable for UnsubscribeReasonCode {
         reader: &mut R,
         _rest: (),
     ) -> Result<UnsubscribeReasonCode, UnsubackPacketError> {
-        reader
-            .read_u8()
-            .map(UnsubscribeReasonCode::try_from)?
-            .map_err(From::from)
+        reader.read_u8().map(UnsubscribeReasonCode::try_from)?
     }
 }
 